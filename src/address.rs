@@ -0,0 +1,305 @@
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::network::constants::Network;
+use bitcoin::util::base58;
+use bitcoin::Script;
+use viacoin_bech32::constants::Network as B32Network;
+use viacoin_bech32::{u5, WitnessProgram};
+
+use errors::*;
+
+/// Address encoding parameters (base58 version bytes and bech32 network) of a Viacoin network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddressParams {
+    pub name: &'static str, // as given to `--address-network`
+    pub pubkey_prefix: u8,
+    pub script_prefix: u8,
+    pub bech_network: B32Network,
+    pub bech_hrp: &'static str, // of `bech_network`, for error messages
+}
+
+impl AddressParams {
+    pub fn for_network(network: &Network) -> AddressParams {
+        match network {
+            Network::Bitcoin => AddressParams {
+                name: "mainnet",
+                pubkey_prefix: 71,
+                script_prefix: 33,
+                bech_network: B32Network::Viacoin,
+                bech_hrp: "via",
+            },
+            Network::Testnet => AddressParams {
+                name: "testnet",
+                pubkey_prefix: 127,
+                script_prefix: 196,
+                bech_network: B32Network::ViacoinTestnet,
+                bech_hrp: "tvia",
+            },
+            Network::Regtest => AddressParams {
+                name: "regtest",
+                pubkey_prefix: 111,
+                script_prefix: 196,
+                bech_network: B32Network::Regtest,
+                bech_hrp: "bcrt",
+            },
+        }
+    }
+
+    // The networks whose addresses may be confused with this one's (e.g. testnet and regtest share
+    // their P2SH version byte), to name them in error messages.
+    fn others(&self) -> Vec<AddressParams> {
+        [Network::Bitcoin, Network::Testnet, Network::Regtest]
+            .iter()
+            .map(AddressParams::for_network)
+            .filter(|params| params.name != self.name)
+            .collect()
+    }
+
+    fn mismatch(&self, addr: &str, other: Option<&AddressParams>) -> Error {
+        let expected = format!(
+            "expected a Viacoin {} address (base58 version {} or {}, or bech32 prefix {})",
+            self.name, self.pubkey_prefix, self.script_prefix, self.bech_hrp
+        );
+        match other {
+            Some(other) => format!("Address {} is a {} address, {}", addr, other.name, expected),
+            None => format!("Invalid address {}: {}", addr, expected),
+        }
+        .into()
+    }
+
+    fn base58(&self, prefix: u8, hash: &[u8]) -> String {
+        let mut data = Vec::with_capacity(1 + hash.len());
+        data.push(prefix);
+        data.extend_from_slice(hash);
+        base58::check_encode_slice(&data)
+    }
+
+    fn bech32(&self, program: &[u8]) -> Option<String> {
+        WitnessProgram::new(
+            u5::try_from_u8(0).expect("0<32"),
+            program.to_vec(),
+            self.bech_network,
+        )
+        .ok()
+        .map(|program| program.to_address())
+    }
+}
+
+// We can't rely on the Address::p2{...}h helpers, since they expect the pre-image data (which we
+// don't have) and encode using the library's own prefixes - so the payload is encoded manually.
+// Scripts come from the chain, so any (possibly malformed) script must be handled without panicking.
+pub fn script_to_address(script: &Script, network: &Network) -> Option<String> {
+    let params = AddressParams::for_network(network);
+    if script.is_p2pkh() {
+        Some(params.base58(params.pubkey_prefix, &script[3..23]))
+    } else if script.is_p2sh() {
+        Some(params.base58(params.script_prefix, &script[2..22]))
+    } else if script.is_v0_p2wpkh() {
+        params.bech32(&script[2..22])
+    } else if script.is_v0_p2wsh() {
+        params.bech32(&script[2..34])
+    } else {
+        None
+    }
+}
+
+/// Parses a base58 or bech32 address, making sure it belongs to the given network: the error
+/// names the expected network (and the address' one, if it belongs to another network).
+pub fn address_to_script(addr: &str, network: &Network) -> Result<Script> {
+    let params = AddressParams::for_network(network);
+    if let Ok(data) = base58::from_check(addr) {
+        if data.len() != 21 {
+            return Err(params.mismatch(addr, None));
+        }
+        let (prefix, hash) = (data[0], &data[1..]);
+        return Ok(if prefix == params.pubkey_prefix {
+            Builder::new()
+                .push_opcode(opcodes::All::OP_DUP)
+                .push_opcode(opcodes::All::OP_HASH160)
+                .push_slice(hash)
+                .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                .push_opcode(opcodes::All::OP_CHECKSIG)
+                .into_script()
+        } else if prefix == params.script_prefix {
+            Builder::new()
+                .push_opcode(opcodes::All::OP_HASH160)
+                .push_slice(hash)
+                .push_opcode(opcodes::All::OP_EQUAL)
+                .into_script()
+        } else {
+            let others = params.others();
+            let other = others
+                .iter()
+                .find(|other| prefix == other.pubkey_prefix || prefix == other.script_prefix);
+            return Err(params.mismatch(addr, other));
+        });
+    }
+    let program = WitnessProgram::from_address(addr).map_err(|_| params.mismatch(addr, None))?;
+    if program.network() != params.bech_network {
+        let others = params.others();
+        let other = others
+            .iter()
+            .find(|other| program.network() == other.bech_network);
+        return Err(params.mismatch(addr, other));
+    }
+    Ok(Script::from(program.to_scriptpubkey()))
+}
+
+#[cfg(test)]
+mod tests {
+    use address::{address_to_script, script_to_address, AddressParams};
+    use bitcoin::network::constants::Network;
+    use bitcoin::Script;
+
+    fn script(prefix: &[u8], hash: &[u8], suffix: &[u8]) -> Script {
+        Script::from([prefix, hash, suffix].concat())
+    }
+
+    #[test]
+    fn test_script_to_address() {
+        let hash20: Vec<u8> = (1..21).collect();
+        let hash32: Vec<u8> = (1..33).collect();
+        let p2pkh = script(&[0x76, 0xa9, 0x14], &hash20, &[0x88, 0xac]);
+        let p2sh = script(&[0xa9, 0x14], &hash20, &[0x87]);
+        let p2wpkh = script(&[0x00, 0x14], &hash20, &[]);
+        let p2wsh = script(&[0x00, 0x20], &hash32, &[]);
+
+        let cases = vec![
+            (
+                Network::Bitcoin,
+                "Va69zgapoGopnDag15VBNgUDnBwmjFWfP4",
+                "EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp",
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5z5tpwxqergd3c8g7rusq0u024e",
+            ),
+            (
+                Network::Testnet,
+                "t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs",
+                "2MsLZ5FqqYpjM1Q1W4X81zMVZTF9gdbhVwd",
+                "tvia1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc550402a",
+                "tvia1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5z5tpwxqergd3c8g7rusqynt52v",
+            ),
+            (
+                Network::Regtest,
+                "mfcHP2WMCVLsVZA8yrovmhMgxNFW9r98xw",
+                "2MsLZ5FqqYpjM1Q1W4X81zMVZTF9gdbhVwd",
+                "bcrt1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5phstwt",
+                "bcrt1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5z5tpwxqergd3c8g7rusq7snjn6",
+            ),
+        ];
+        for (network, pkh, sh, wpkh, wsh) in cases {
+            assert_eq!(script_to_address(&p2pkh, &network).unwrap(), pkh);
+            assert_eq!(script_to_address(&p2sh, &network).unwrap(), sh);
+            assert_eq!(script_to_address(&p2wpkh, &network).unwrap(), wpkh);
+            assert_eq!(script_to_address(&p2wsh, &network).unwrap(), wsh);
+        }
+
+        let op_return = Script::from(vec![0x6a, 0x01, 0x00]);
+        assert!(script_to_address(&op_return, &Network::Bitcoin).is_none());
+    }
+
+    #[test]
+    fn test_malformed_script_to_address() {
+        let malformed: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x76, 0xa9, 0x14],                   // truncated p2pkh
+            vec![0xa9, 0x14, 0x01, 0x87],             // p2sh with a short hash
+            vec![0x00, 0x14],                         // v0 witness program without its push
+            vec![0x00, 0x20, 0x01],                   // truncated p2wsh
+            vec![0x4c],                               // OP_PUSHDATA1 without a length
+            vec![0x4d, 0xff],                         // truncated OP_PUSHDATA2 length
+            vec![0x4e, 0xff, 0xff, 0xff, 0xff, 0x01], // OP_PUSHDATA4 past the end
+            vec![0x51, 0x14, 0x01, 0x02],             // push past the end
+            vec![0xff; 100],                          // invalid opcodes
+        ];
+        for bytes in malformed {
+            let script = Script::from(bytes);
+            for network in &[Network::Bitcoin, Network::Testnet, Network::Regtest] {
+                assert!(script_to_address(&script, network).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_address_to_script() {
+        let hash20: Vec<u8> = (1..21).collect();
+        let p2pkh = script(&[0x76, 0xa9, 0x14], &hash20, &[0x88, 0xac]);
+        let p2sh = script(&[0xa9, 0x14], &hash20, &[0x87]);
+        let p2wpkh = script(&[0x00, 0x14], &hash20, &[]);
+
+        for (addr, expected) in vec![
+            ("Va69zgapoGopnDag15VBNgUDnBwmjFWfP4", &p2pkh),
+            ("EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp", &p2sh),
+            ("via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh", &p2wpkh),
+        ] {
+            assert_eq!(
+                &address_to_script(addr, &Network::Bitcoin).unwrap(),
+                expected
+            );
+        }
+
+        // valid addresses of other networks
+        assert!(
+            address_to_script("t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs", &Network::Bitcoin).is_err()
+        );
+        assert!(address_to_script(
+            "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+            &Network::Testnet
+        )
+        .is_err());
+        // invalid checksum
+        assert!(
+            address_to_script("Va69zgapoGopnDag15VBNgUDnBwmjFWfP5", &Network::Bitcoin).is_err()
+        );
+        assert!(address_to_script("notanaddress", &Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let hash20: Vec<u8> = (1..21).collect();
+        let hash32: Vec<u8> = (1..33).collect();
+        let scripts = vec![
+            script(&[0x76, 0xa9, 0x14], &hash20, &[0x88, 0xac]),
+            script(&[0xa9, 0x14], &hash20, &[0x87]),
+            script(&[0x00, 0x14], &hash20, &[]),
+            script(&[0x00, 0x20], &hash32, &[]),
+        ];
+        for network in &[Network::Bitcoin, Network::Testnet, Network::Regtest] {
+            let params = AddressParams::for_network(network);
+            for (index, script) in scripts.iter().enumerate() {
+                let addr = script_to_address(script, network).unwrap();
+                if index >= 2 {
+                    assert!(addr.starts_with(&format!("{}1", params.bech_hrp)));
+                }
+                assert_eq!(&address_to_script(&addr, network).unwrap(), script);
+            }
+        }
+    }
+
+    #[test]
+    fn test_other_network_address() {
+        let error = |addr, network| address_to_script(addr, &network).unwrap_err().to_string();
+        let expected =
+            "expected a Viacoin mainnet address (base58 version 71 or 33, or bech32 prefix via)";
+        assert_eq!(
+            error("t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs", Network::Bitcoin),
+            format!(
+                "Address t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs is a testnet address, {}",
+                expected
+            )
+        );
+        assert_eq!(
+            error("notanaddress", Network::Bitcoin),
+            format!("Invalid address notanaddress: {}", expected)
+        );
+        assert!(error(
+            "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+            Network::Regtest
+        )
+        .starts_with("Address via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh is a mainnet address, expected a Viacoin regtest address"));
+        // testnet and regtest share the P2SH version byte
+        assert!(
+            address_to_script("2MsLZ5FqqYpjM1Q1W4X81zMVZTF9gdbhVwd", &Network::Regtest).is_ok()
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use bitcoin::util::hash::Sha256dHash;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+use metrics::{Histogram, HistogramOpts, Metrics};
 use {daemon, index, signal::Waiter, store};
 
 use errors::*;
@@ -10,6 +13,8 @@ pub struct App {
     index: index::Index,
     daemon: daemon::Daemon,
     tip: Mutex<Sha256dHash>,
+    paused: AtomicBool, // for maintenance (e.g. backing up the DB)
+    compaction_duration: Histogram,
 }
 
 impl App {
@@ -17,16 +22,25 @@ impl App {
         store: store::DBStore,
         index: index::Index,
         daemon: daemon::Daemon,
+        metrics: &Metrics,
     ) -> Result<Arc<App>> {
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
             tip: Mutex::new(Sha256dHash::default()),
+            paused: AtomicBool::new(false),
+            compaction_duration: metrics.histogram(
+                HistogramOpts::new(
+                    "db_compaction_duration",
+                    "Full DB compactions duration (in seconds)",
+                )
+                .buckets(vec![10., 30., 60., 300., 600., 1800., 3600., 7200.]),
+            ),
         }))
     }
 
-    fn write_store(&self) -> &store::WriteStore {
+    pub fn write_store(&self) -> &store::WriteStore {
         &self.store
     }
     // TODO: use index for queries.
@@ -40,11 +54,84 @@ impl App {
         &self.daemon
     }
 
+    /// Makes sure all written rows are persisted (e.g. before shutting down).
+    pub fn flush(&self) {
+        self.write_store().flush();
+    }
+
+    /// Stops updating the index (and the mempool), returning once the in-flight index update (if
+    /// any) is stopped after its current batch and the DB is flushed, so that it isn't written to
+    /// until `resume()`.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        let _tip = self.tip.lock().expect("failed to lock tip"); // held during index updates
+        self.flush();
+    }
+
+    /// The next update catches up with all the blocks found while paused.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Fully compacts the DB (taking a while), unless it's read-only, paused (for maintenance) or
+    /// already being compacted.
+    pub fn compact(&self) -> Result<()> {
+        self.check_compaction()?;
+        let elapsed = self.store.compact_all();
+        self.compaction_duration
+            .observe(elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1e3);
+        Ok(())
+    }
+
+    /// Fails if `compact()` would.
+    pub fn check_compaction(&self) -> Result<()> {
+        if self.store.is_read_only() {
+            bail!("the DB is read-only");
+        }
+        if self.is_paused() {
+            bail!("the DB is paused for maintenance");
+        }
+        if self.is_compacting() {
+            bail!("the DB is already being compacted");
+        }
+        Ok(())
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.store.is_compacting()
+    }
+
+    /// The DB's size on disk, in bytes.
+    pub fn db_disk_size(&self) -> u64 {
+        self.store.disk_size()
+    }
+
+    /// Logs the address index's cost (see `index::log_address_index()`).
+    pub fn log_address_index(&self) {
+        index::log_address_index(&self.store);
+    }
+
+    /// The DB's row count and size per key prefix (scanning the whole DB).
+    pub fn db_prefix_sizes(&self) -> BTreeMap<u8, (usize, u64)> {
+        self.store.prefix_sizes()
+    }
+
     pub fn update(&self, signal: &Waiter) -> Result<bool> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
+        if self.is_paused() {
+            return Ok(false);
+        }
         let new_block = *tip != self.daemon().getbestblockhash()?;
         if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+            // stopped after the current batch once paused, so that pause() doesn't wait for the
+            // whole update (e.g. the initial sync with --serve-during-sync)
+            *tip = self
+                .index()
+                .update_until(&self.store, &signal, || self.is_paused())?;
         }
         Ok(new_block)
     }
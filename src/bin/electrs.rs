@@ -3,11 +3,17 @@ extern crate electrs;
 extern crate error_chain;
 #[macro_use]
 extern crate log;
+extern crate stderrlog;
+extern crate time;
 
 use electrs::rest;
 use error_chain::ChainedError;
+use log::LevelFilter;
+use std::fs::{self, File};
+use std::io::BufWriter;
 use std::process;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use electrs::{
     app::App,
@@ -15,41 +21,101 @@ use electrs::{
     config::Config,
     daemon::Daemon,
     errors::*,
-    index::Index,
+    export::export_utxos,
+    index::{log_address_index, Index, OpReturnRow},
+    logger::{FileLogger, LogFormat, RotatingFile},
     metrics::Metrics,
     query::Query,
     signal::Waiter,
-    store::{full_compaction, is_fully_compacted, verify_index_compatibility, DBStore},
+    store::{
+        full_compaction, is_fully_compacted, opreturn_indexed, skip_full_compaction,
+        verify_index_compatibility, CompactionSchedule, DBStore,
+    },
+    util::spawn_thread,
+    verify::{verify_index, Verifier},
+    zmq::Notifier,
 };
 
-fn run_server(config: Config) -> Result<()> {
-    let signal = Waiter::new();
+fn run_server(config: Config, signal: Waiter) -> Result<()> {
     let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
+    metrics.start(config.ready_max_lag, config.monitoring_auth.clone());
 
     let daemon = Daemon::new(
         &config.daemon_dir,
-        config.daemon_rpc_addr,
-        config.cookie_getter(),
-        config.network_type,
+        config.daemon_rpc_endpoints(),
+        config.daemon_options(),
+        config.network_params,
         signal.clone(),
         &metrics,
     )?;
+    // The HTTP server isn't started until the index is synced (unless --serve-during-sync), so
+    // nothing is served meanwhile.
+    if config.no_wait_sync {
+        warn!("not waiting for viacoind's initial block download (--no-wait-sync)");
+    } else {
+        daemon.wait_for_sync(config.wait_sync_max_gap)?;
+    }
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
+    let open_store = || {
+        DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ config.jsonrpc_import,
+            &config.db_options,
+        )
+    };
+    let store = if config.no_index {
+        DBStore::open_read_only(&config.db_path, &config.db_options)?
+    } else {
+        open_store()
+    };
+    let store = match verify_index_compatibility(&store, &config) {
+        Err(Error(ErrorKind::IncompatibleDB(ref msg), _)) if config.auto_reindex => {
+            warn!("incompatible database ({}), reindexing", msg);
+            drop(store);
+            fs::remove_dir_all(&config.db_path)
+                .chain_err(|| format!("failed to remove {:?}", config.db_path))?;
+            let store = open_store();
+            verify_index_compatibility(&store, &config)?;
+            store
+        }
+        result => result.map(|()| store)?,
+    };
+    // before loading the index, as it can't be loaded from a broken header chain
+    if config.verify || config.snapshot.is_some() {
+        if config.verify {
+            let problems = verify_index(&store, config.extended_db_enabled);
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            if !problems.is_empty() {
+                bail!("the index has {} problems", problems.len());
+            }
+            info!("no problems found in the index");
+        }
+        if let Some(ref dir) = config.snapshot {
+            info!("copying the DB to {:?}", dir);
+            store.snapshot(dir)?;
+        }
+        return Ok(());
+    }
     let index = Index::load(&store, &daemon, &metrics, &config)?;
 
-    verify_index_compatibility(&store, &config);
-
+    // the address index's cost is logged once, when built by the initial indexing (or migration)
+    let new_address_index = config.extended_db_enabled && !is_fully_compacted(&store);
     let store = if is_fully_compacted(&store) {
         store // initial import and full compaction are over
+    } else if config.no_index {
+        bail!("the DB's initial indexing isn't over (run without --no-index first)");
+    } else if config.serve_during_sync {
+        store.flush();
+        skip_full_compaction(store) // indexed below, while serving the blocks indexed so far
     } else {
         if config.jsonrpc_import {
             index.update(&store, &signal)?; // slower: uses JSONRPC for fetching blocks
             full_compaction(store)
         } else {
             // faster, but uses more memory
-            let store = bulk::index_blk_files(&daemon, &config, &metrics, store)?;
+            let store = bulk::index_blk_files(&daemon, &config, &metrics, &signal, store)?;
             let store = full_compaction(store);
             index.reload(&store); // make sure the block header index is up-to-date
             store
@@ -57,34 +123,275 @@ fn run_server(config: Config) -> Result<()> {
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon)?;
-    let query = Query::new(app.clone(), config.extended_db_enabled, &metrics);
+    if config.extended_db_enabled {
+        index.init_utxo_stats(&store)?;
+    }
+    if new_address_index && !config.serve_during_sync {
+        log_address_index(&store);
+    }
+    if opreturn_indexed(&store) {
+        let (count, size) = store.prefix_size(&OpReturnRow::filter_prefix(&[]));
+        info!(
+            "OP_RETURN index: {} payloads, {:.1} MB (uncompressed)",
+            count,
+            size as f64 / 1e6
+        );
+    }
 
-    let mut server = None; // HTTP REST server
-    loop {
+    if let Some(ref path) = config.export_utxos {
+        // the index isn't updated from here on, so the snapshot is taken at a single height
+        let height = index.best_height();
+        info!("exporting UTXOs at height {} to {:?}", height, path);
+        let file = File::create(path).chain_err(|| format!("failed to create {:?}", path))?;
+        let (count, total) = export_utxos(&store, |h| index.get_header(h), BufWriter::new(file))?;
+        info!(
+            "exported {} UTXOs at height {}, holding {} satoshis",
+            count, height, total
+        );
+        return Ok(());
+    }
+
+    let app = App::new(store, index, daemon, &metrics)?;
+    if config.compact_on_startup {
+        // catch up first, so that no index writes happen during the compaction
         app.update(&signal)?;
-        query.update_mempool()?;
-
-        if server.is_none() {
-            let info = app.daemon().getblockchaininfo()?;
-            if info.initialblockdownload == false && info.verificationprogress > 0.9999 {
-                server = Some(rest::run_server(&config, query.clone()));
-            } else {
-                warn!("bitcoind not fully synced waiting");
+        app.compact()?;
+    }
+    let verifier = if config.verification_sampling > 0 {
+        let daemon = app.daemon().reconnect()?;
+        Some(Verifier::start(
+            daemon,
+            config.verification_sampling,
+            &metrics,
+        ))
+    } else {
+        None
+    };
+    let query = Query::new(app.clone(), verifier, &metrics, &config);
+
+    if let Some(ref path) = config.mempool_file {
+        // the daemon may have dropped some of them meanwhile (e.g. confirmed or evicted)
+        match query.load_mempool(path) {
+            Ok((loaded, discarded)) => info!(
+                "reloaded {} mempool transactions from {:?} ({} discarded)",
+                loaded, path, discarded
+            ),
+            Err(err) => warn!("failed to reload the mempool from {:?}: {}", path, err),
+        }
+    }
+
+    let mut notifier = Notifier::new(&metrics);
+    if let Some(addr) = config.zmq_block_addr {
+        notifier.subscribe(addr, "hashblock");
+    }
+    if let Some(addr) = config.zmq_tx_addr {
+        notifier.subscribe(addr, "rawtx");
+    }
+
+    let mut compaction_schedule = config.auto_compact_interval_hours.map(|hours| {
+        CompactionSchedule::new(
+            Duration::from_secs(hours * 3600),
+            config.auto_compact_window,
+        )
+    });
+    let sync = metrics.sync_state();
+    let mut server = None; // HTTP REST server
+    let mut initial_sync = if config.serve_during_sync {
+        let (tx, rx) = mpsc::channel();
+        let app = app.clone();
+        let signal = signal.clone();
+        spawn_thread("initial-sync", move || {
+            let result = app.update(&signal);
+            if result.is_ok() && new_address_index && !app.is_paused() {
+                app.log_address_index();
+            }
+            tx.send(result).expect("failed to send initial sync result");
+        });
+        Some(rx)
+    } else {
+        None
+    };
+    let result = loop {
+        let updated = if config.no_index {
+            Ok(false) // only the mempool is kept up-to-date
+        } else if let Some(rx) = initial_sync.take() {
+            // the blocks are served as they're indexed, until the initial sync is over
+            loop {
+                if server.is_none() && app.index().best_header().is_some() {
+                    server = Some(rest::run_server(&config, query.clone(), &metrics));
+                    sync.set_serving();
+                }
+                match rx.recv_timeout(Duration::from_secs(1)) {
+                    Ok(result) => break result,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        break Err("initial sync thread panicked".into())
+                    }
+                }
+            }
+        } else {
+            app.update(&signal)
+        };
+        if let Err(err) = updated.and_then(|_| query.update_mempool()) {
+            break Err(err);
+        }
+        sync.heartbeat();
+
+        if let Some(ref mut schedule) = compaction_schedule {
+            // in the background: shutting down doesn't wait for it (its output is discarded)
+            if schedule.is_due(Instant::now(), time::now_utc().tm_hour as u32) {
+                match query.start_compaction() {
+                    Ok(()) => info!("started the scheduled DB compaction"),
+                    Err(err) => warn!("scheduled DB compaction not started: {}", err),
+                }
+            }
+        }
+
+        // also keeps the daemon's height up-to-date for the readiness probe
+        match app.daemon().getblockchaininfo() {
+            Ok(ref info)
+                if info.initialblockdownload == false && info.verificationprogress > 0.9999 =>
+            {
+                if server.is_none() {
+                    server = Some(rest::run_server(&config, query.clone(), &metrics));
+                    sync.set_serving();
+                }
             }
+            Ok(_) => warn!("bitcoind not fully synced waiting"),
+            Err(err) => break Err(err),
         }
 
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
-            info!("stopping server: {}", err);
-            break;
+        // while notified by ZMQ, polling is only a safety net (e.g. for missed notifications)
+        let interval = if notifier.is_connected() { 30 } else { 5 };
+        if let Err(err) = signal.wait_for(Duration::from_secs(interval), notifier.receiver()) {
+            break Err(err);
         }
+    };
+    info!("stopping server: {}", result.as_ref().unwrap_err());
+    if let Some(server) = server {
+        info!("waiting for in-flight REST requests");
+        server.stop();
+    }
+    if let Some(ref path) = config.mempool_file {
+        match query.save_mempool(path) {
+            Ok(count) => info!("saved {} mempool transactions to {:?}", count, path),
+            Err(err) => warn!("failed to save the mempool to {:?}: {}", path, err),
+        }
+    }
+    info!("flushing DB");
+    app.flush();
+    match result {
+        Err(Error(ErrorKind::Interrupt(_), _)) => Ok(()),
+        result => result,
+    }
+}
+
+// Each network instance runs on its own threads, sharing nothing but the signals' handling: once
+// one of them fails, the other ones are shut down.
+fn run_instances(configs: Vec<Config>, signal: Waiter) -> Result<()> {
+    let instances: Vec<_> = configs
+        .into_iter()
+        .map(|config| {
+            let name = config.network_params.name;
+            let signal = signal.clone();
+            let handle = spawn_thread(name, move || {
+                let result = run_server(config, signal.clone());
+                if result.is_err() {
+                    signal.shutdown();
+                }
+                result
+            });
+            (name, handle)
+        })
+        .collect();
+    let mut failed = vec![];
+    for (name, handle) in instances {
+        match handle.join().expect("instance thread panicked") {
+            Ok(()) => info!("{} instance stopped", name),
+            Err(e) => {
+                error!("{} instance failed: {}", name, e.display_chain());
+                failed.push(name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed instances: {}", failed.join(", "));
     }
     Ok(())
 }
 
+fn init_logging(config: &Config) -> Result<()> {
+    // stderrlog can't filter the access log apart from the other records
+    if config.log_file.is_some()
+        || config.log_format != LogFormat::Text
+        || config.access_log_level != LevelFilter::Off
+    {
+        let file = match config.log_file {
+            Some(ref path) => Some(RotatingFile::open(
+                path,
+                config.log_max_size,
+                config.log_files,
+            )?),
+            None => None, // stderr
+        };
+        return FileLogger::new(file, config.verbosity, config.timestamp, config.log_format)
+            .access_level(config.access_log_level)
+            .init();
+    }
+    let mut log = stderrlog::new();
+    log.verbosity(config.verbosity);
+    log.timestamp(if config.timestamp {
+        stderrlog::Timestamp::Millisecond
+    } else {
+        stderrlog::Timestamp::Off
+    });
+    log.init().chain_err(|| "logging initialization failed")
+}
+
 fn main() {
-    let config = Config::from_args();
-    if let Err(e) = run_server(config) {
+    let mut configs = match Config::from_args() {
+        Ok(configs) => configs,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e.display_chain());
+            process::exit(1);
+        }
+    };
+    // the logging options are shared by all the network instances
+    if let Err(e) = init_logging(&configs[0]) {
+        eprintln!("{}", e.display_chain());
+        process::exit(1);
+    }
+    for config in &configs {
+        info!("{:?}", config); // without its secrets
+        for (flag, addr) in config.public_listeners() {
+            warn!(
+                "{} {} is reachable from other networks (not a loopback nor private address): make sure it's meant to be exposed",
+                flag, addr
+            );
+        }
+        if let Some(sizes) = config.auto_cache_sizes {
+            info!(
+                "--auto-cache-memory {} MB: RocksDB block cache of {} MB, {} cached histories, {}",
+                sizes.budget_mb,
+                sizes.db_cache_mb,
+                sizes.history_cache_size,
+                sizes.tx_cache_size.map_or(
+                    "no transaction cache (without --light)".to_owned(),
+                    |size| format!("{} cached transactions", size)
+                )
+            );
+        }
+    }
+    let signal = Waiter::new();
+    let result = if configs.len() == 1 {
+        run_server(configs.remove(0), signal)
+    } else {
+        warn!(
+            "serving several networks from a single process is experimental (--network-instance)"
+        );
+        run_instances(configs, signal)
+    };
+    if let Err(e) = result {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
     }
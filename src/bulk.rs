@@ -1,49 +1,274 @@
+use bincode;
 use bitcoin::blockdata::block::Block;
-use bitcoin::consensus::encode::{deserialize, Decodable};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::network::constants::Network;
 use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
 use libc;
-use std::collections::HashSet;
+use log::Level;
+use page_size;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Cursor, Seek, SeekFrom};
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
 use std::sync::{
     mpsc::{Receiver, SyncSender},
-    Arc, Mutex,
+    Arc, Condvar, Mutex,
 };
 use std::thread;
+use std::time::{Duration, Instant};
 
 use config::Config;
 use daemon::Daemon;
 use index::{index_block, last_indexed_block, read_indexed_blockhashes};
-use metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use store::{DBStore, Row, WriteStore};
-use util::{spawn_thread, HeaderList, SyncChannel};
+use metrics::{
+    Counter, CounterVec, FloatGauge, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec,
+    MetricOpts, Metrics, MovingRate, SyncState,
+};
+use signal::Waiter;
+use store::{opreturn_indexed, DBStore, ReadStore, Row, WriteStore};
+use util::{full_hash, spawn_thread, FullHash, HeaderList, SyncChannel};
 
 use errors::*;
 
+const RATE_SMOOTHING: f64 = 0.3; // weight of the last progress interval in the indexing rate
+const SMALL_BUFFER_MB: usize = 128; // a blk*.dat file's max size: smaller buffers index one at a time
+const STAGES: [&str; 3] = ["read", "index", "write"];
+
+/// Bounds the bytes buffered between the bulk indexing stages: the blk*.dat files read and not yet
+/// indexed, and the rows indexed and not yet written. Acquiring bytes blocks until there's room
+/// for them, except when nothing is buffered (so that a file larger than the budget can't stall).
+/// A file's bytes are exchanged for its rows' ones without waiting, as the rows may be larger
+/// than the file (with the extended DB): only new files wait for the rows to be written.
+struct ByteBudget {
+    capacity: usize,
+    buffered: Mutex<(usize, usize)>, // currently, and at most
+    released: Condvar,
+    gauge: Gauge,
+}
+
+impl ByteBudget {
+    fn new(capacity: usize, gauge: Gauge) -> ByteBudget {
+        ByteBudget {
+            capacity,
+            buffered: Mutex::new((0, 0)),
+            released: Condvar::new(),
+            gauge,
+        }
+    }
+
+    fn acquire(&self, bytes: usize) {
+        let mut buffered = self.buffered.lock().unwrap();
+        while buffered.0 > 0 && buffered.0 + bytes > self.capacity {
+            buffered = self.released.wait(buffered).unwrap();
+        }
+        buffered.0 += bytes;
+        buffered.1 = buffered.1.max(buffered.0);
+        self.gauge.set(buffered.0 as i64);
+    }
+
+    // Replaces `held` acquired bytes by `bytes`, even if they don't fit.
+    fn exchange(&self, held: usize, bytes: usize) {
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered.0 = buffered.0 - held + bytes;
+        buffered.1 = buffered.1.max(buffered.0);
+        self.gauge.set(buffered.0 as i64);
+        self.released.notify_all();
+    }
+
+    fn release(&self, bytes: usize) {
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered.0 -= bytes;
+        self.gauge.set(buffered.0 as i64);
+        self.released.notify_all();
+    }
+
+    fn peak(&self) -> usize {
+        self.buffered.lock().unwrap().1
+    }
+}
+
+fn rows_size(rows: &[Row]) -> usize {
+    rows.iter().map(|row| row.key.len() + row.value.len()).sum()
+}
+
+// Written together with the rows of a fully indexed blk*.dat file (in the same batch), so it can
+// be skipped when an interrupted bulk import is resumed.
+#[derive(Serialize, Deserialize)]
+struct BlkFileProgress {
+    size: u64,
+    blockhashes: Vec<FullHash>, // all the blocks found in the file (including skipped ones)
+}
+
+// A read-only private mapping of a whole file, unmapped when dropped.
+struct Mmap {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping isn't shared: it is owned by the thread parsing its blocks.
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    fn new(file: &fs::File, len: usize) -> io::Result<Mmap> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // the blocks are parsed in order, so the kernel can read ahead more aggressively
+        unsafe { libc::madvise(addr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { addr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+// The contents of a blk*.dat file: read into memory, or memory-mapped (with --mmap-blocks).
+enum Blob {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Blob {
+    fn read(path: &Path) -> io::Result<Blob> {
+        fs::read(path).map(Blob::Read)
+    }
+
+    // Files smaller than a page (e.g. an empty one, which can't be mapped) are read instead.
+    fn map(path: &Path) -> io::Result<Blob> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < page_size::get() {
+            return Blob::read(path);
+        }
+        Ok(Blob::Mapped(Mmap::new(&file, len)?))
+    }
+}
+
+impl Deref for Blob {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Blob::Read(data) => &data[..],
+            Blob::Mapped(mmap) => &mmap[..],
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+fn blkfile_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .expect("invalid blk*.dat file name")
+}
+
+fn blkfile_progress_row(path: &Path, progress: &BlkFileProgress) -> Row {
+    Row {
+        key: [b"P", blkfile_name(path).as_bytes()].concat(),
+        value: bincode::serialize(progress).unwrap(),
+    }
+}
+
+fn read_blkfiles_progress(store: &ReadStore) -> HashMap<String, BlkFileProgress> {
+    store
+        .scan(b"P")
+        .into_iter()
+        .map(|row| {
+            let name = String::from_utf8(row.key[1..].to_vec()).expect("invalid progress key");
+            (name, bincode::deserialize(&row.value).unwrap())
+        })
+        .collect()
+}
+
 struct Parser {
     magic: u32,
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<Sha256dHash>>,
+    indexed_height: Mutex<Option<usize>>, // see indexed_height()
     // metrics
     duration: HistogramVec,
     block_count: CounterVec,
     bytes_read: Histogram,
+    bytes_total: Counter,
+    indexed_blocks: Gauge,
+    total_blocks: Gauge,
+    height: Gauge,
+    rate: FloatGauge,
+    stage_bytes: CounterVec,
+    stage_rate: GaugeVec,
+    stage_rates: Mutex<Vec<MovingRate>>, // of STAGES
+    budget: ByteBudget,
+    sync: Arc<SyncState>,
     extended_db_enabled: bool,
+    index_opreturn: bool,
+    network: Network,
+    mmap_blocks: bool,
 }
 
 impl Parser {
     fn new(
-        daemon: &Daemon,
+        magic: u32,
+        current_headers: HeaderList,
         metrics: &Metrics,
         indexed_blockhashes: HashSet<Sha256dHash>,
         extended_db_enabled: bool,
-    ) -> Result<Arc<Parser>> {
-        Ok(Arc::new(Parser {
-            magic: daemon.magic(),
-            current_headers: load_headers(daemon)?,
+        index_opreturn: bool,
+        network: Network,
+        mmap_blocks: bool,
+        buffer_bytes: usize,
+    ) -> Arc<Parser> {
+        let total_blocks = metrics.gauge(MetricOpts::new(
+            "bulk_total_blocks",
+            "# of blocks in the best chain (to be bulk indexed)",
+        ));
+        total_blocks.set(current_headers.len() as i64);
+        let indexed_blocks = metrics.gauge(MetricOpts::new(
+            "bulk_indexed_blocks",
+            "# of bulk indexed blocks (including the ones indexed before a restart)",
+        ));
+        indexed_blocks.set(indexed_blockhashes.len() as i64);
+        Arc::new(Parser {
+            magic,
+            current_headers,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
+            indexed_height: Mutex::new(None),
             extended_db_enabled,
+            index_opreturn,
+            network,
+            mmap_blocks,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("parse_duration", "blk*.dat parsing duration (in seconds)"),
                 &["step"],
@@ -57,7 +282,115 @@ impl Parser {
                 "parse_bytes_read",
                 "# of bytes read (from blk*.dat)",
             )),
-        }))
+            bytes_total: metrics.counter(MetricOpts::new(
+                "bulk_bytes_read",
+                "total # of bytes read from blk*.dat files",
+            )),
+            indexed_blocks,
+            total_blocks,
+            height: metrics.gauge(MetricOpts::new(
+                "bulk_index_height",
+                "Height up to which all the blocks are bulk indexed",
+            )),
+            rate: metrics.float_gauge(MetricOpts::new(
+                "bulk_index_rate",
+                "# of bulk indexed blocks per second (moving average)",
+            )),
+            stage_bytes: metrics.counter_vec(
+                MetricOpts::new(
+                    "bulk_stage_bytes",
+                    "# of bytes read, indexed (as rows) and written by the bulk indexing",
+                ),
+                &["stage"],
+            ),
+            stage_rate: metrics.gauge_vec(
+                MetricOpts::new(
+                    "bulk_stage_rate",
+                    "MB per second read, indexed (as rows) and written by the bulk indexing \
+                     (moving average)",
+                ),
+                &["stage"],
+            ),
+            stage_rates: Mutex::new(
+                STAGES
+                    .iter()
+                    .map(|_| MovingRate::new(RATE_SMOOTHING, 0))
+                    .collect(),
+            ),
+            budget: ByteBudget::new(
+                buffer_bytes,
+                metrics.gauge(MetricOpts::new(
+                    "bulk_buffered_bytes",
+                    "# of bytes of blk*.dat files and rows waiting to be indexed or written",
+                )),
+            ),
+            sync: metrics.sync_state(),
+        })
+    }
+
+    // A blk*.dat file can be skipped if it wasn't modified since it was indexed, and all of its
+    // blocks that belong to the current best chain were indexed (they may not have been, if a
+    // reorg happened since).
+    fn is_indexed(&self, path: &Path, progress: &BlkFileProgress) -> bool {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() == progress.size => (),
+            _ => return false,
+        }
+        let indexed_blockhashes = self.indexed_blockhashes.lock().unwrap();
+        progress.blockhashes.iter().all(|hash| {
+            let blockhash: Sha256dHash = deserialize(hash).unwrap();
+            self.current_headers.header_by_hash(&blockhash).is_none()
+                || indexed_blockhashes.contains(&blockhash)
+        })
+    }
+
+    // The height up to which all the best chain's blocks are indexed (as they're found in any
+    // order in the blk*.dat files), advancing from the previous one.
+    fn indexed_height(&self) -> Option<usize> {
+        let indexed_blockhashes = self.indexed_blockhashes.lock().unwrap();
+        let mut height = self.indexed_height.lock().unwrap();
+        let mut next = height.map_or(0, |height| height + 1);
+        while let Some(header) = self.current_headers.header_by_height(next) {
+            if !indexed_blockhashes.contains(header.hash()) {
+                break;
+            }
+            *height = Some(next);
+            next += 1;
+        }
+        *height
+    }
+
+    // Updates the indexing rate with the blocks indexed over the `elapsed` interval.
+    fn log_progress(&self, rate: &mut MovingRate, elapsed: Duration) {
+        let indexed = self.indexed_blocks.get() as u64;
+        let total = self.total_blocks.get() as u64;
+        let blocks_per_sec = rate.update(indexed, elapsed);
+        self.rate.set(blocks_per_sec);
+        let mut stage_rates = self.stage_rates.lock().unwrap();
+        for (stage, stage_rate) in STAGES.iter().zip(stage_rates.iter_mut()) {
+            let bytes = self.stage_bytes.with_label_values(&[stage]).get() as u64;
+            self.stage_rate
+                .with_label_values(&[stage])
+                .set(stage_rate.update(bytes, elapsed) / 1e6);
+        }
+        let height = self.indexed_height();
+        if let Some(height) = height {
+            self.height.set(height as i64);
+            self.sync.set_index_height(height);
+        }
+        log_fields!(
+            Level::Info,
+            { indexed: indexed, total: total, height: height, rate: blocks_per_sec },
+            "indexed {}/{} blocks ({:.1}%), up to height {}, at {:.1} blocks/s, ETA {} ({} MB read, {} MB buffered)",
+            indexed,
+            total,
+            100.0 * indexed as f64 / total.max(1) as f64,
+            height.map_or("none".to_owned(), |height| height.to_string()),
+            blocks_per_sec,
+            rate.eta(total).map_or("unknown".to_owned(), format_duration),
+            self.bytes_total.get() / 1_000_000,
+            self.budget.gauge.get() / 1_000_000
+        );
     }
 
     fn last_indexed_row(&self) -> Row {
@@ -72,24 +405,38 @@ impl Parser {
         last_indexed_block(last_header.hash())
     }
 
-    fn read_blkfile(&self, path: &Path) -> Result<Vec<u8>> {
+    fn read_blkfile(&self, path: &Path) -> Result<Blob> {
         let timer = self.duration.with_label_values(&["read"]).start_timer();
-        let blob = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        let blob = if self.mmap_blocks {
+            Blob::map(path)
+        } else {
+            Blob::read(path)
+        }
+        .chain_err(|| format!("failed to read {:?}", path))?;
         timer.observe_duration();
         self.bytes_read.observe(blob.len() as f64);
+        self.bytes_total.inc_by(blob.len() as i64);
+        self.stage_bytes
+            .with_label_values(&["read"])
+            .inc_by(blob.len() as i64);
         return Ok(blob);
     }
 
-    fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
+    fn index_blkfile(&self, path: &Path, blob: Blob) -> Result<Vec<Row>> {
+        let mut progress = BlkFileProgress {
+            size: blob.len() as u64,
+            blockhashes: vec![],
+        };
         let timer = self.duration.with_label_values(&["parse"]).start_timer();
-        let blocks = parse_blocks(blob, self.magic)?;
+        let blocks = parse_blocks(&blob, self.magic)?;
         timer.observe_duration();
 
         let mut rows = Vec::<Row>::new();
         let timer = self.duration.with_label_values(&["index"]).start_timer();
         for block in blocks {
             let blockhash = block.bitcoin_hash();
-            if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
+            progress.blockhashes.push(full_hash(&blockhash[..]));
+            if let Some(header) = self.current_headers.header_by_hash(&blockhash) {
                 if self
                     .indexed_blockhashes
                     .lock()
@@ -100,8 +447,11 @@ impl Parser {
                         &block,
                         header.height() as u32,
                         self.extended_db_enabled,
+                        self.index_opreturn,
+                        self.network,
                     ));
                     self.block_count.with_label_values(&["indexed"]).inc();
+                    self.indexed_blocks.inc();
                 } else {
                     self.block_count.with_label_values(&["duplicate"]).inc();
                 }
@@ -111,6 +461,7 @@ impl Parser {
             }
         }
         timer.observe_duration();
+        rows.push(blkfile_progress_row(path, &progress));
 
         let timer = self.duration.with_label_values(&["sort"]).start_timer();
         rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
@@ -119,32 +470,41 @@ impl Parser {
     }
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
-    let mut cursor = Cursor::new(&blob);
-    let mut blocks = vec![];
-    let max_pos = blob.len() as u64;
-    while cursor.position() < max_pos {
-        match u32::consensus_decode(&mut cursor) {
-            Ok(value) => {
-                if magic != value {
-                    cursor
-                        .seek(SeekFrom::Current(-3))
-                        .expect("failed to seek back");
-                    continue;
-                }
-            }
-            Err(_) => break, // EOF
-        };
-        let block_size = u32::consensus_decode(&mut cursor).chain_err(|| "no block size")?;
-        let start = cursor.position() as usize;
-        cursor
-            .seek(SeekFrom::Current(block_size as i64))
-            .chain_err(|| format!("seek {} failed", block_size))?;
-        let end = cursor.position() as usize;
+fn read_u32(data: &[u8]) -> u32 {
+    data.iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | u32::from(*byte))
+}
 
+// Each block is prefixed by the network magic and its (little-endian) size. The magic is scanned
+// for, skipping the zero-filled space preallocated at the end of the file.
+fn parse_blocks(blob: &[u8], magic: u32) -> Result<Vec<Block>> {
+    let mut blocks = vec![];
+    let mut pos = 0;
+    while pos + 4 <= blob.len() {
+        if read_u32(&blob[pos..pos + 4]) != magic {
+            pos += 1;
+            continue;
+        }
+        pos += 4;
+        if pos + 4 > blob.len() {
+            bail!("no block size at {}", pos);
+        }
+        let block_size = read_u32(&blob[pos..pos + 4]) as usize;
+        let start = pos + 4;
+        let end = start + block_size;
+        if end > blob.len() {
+            bail!(
+                "block at {}..{} past the end of the file ({} bytes)",
+                start,
+                end,
+                blob.len()
+            );
+        }
         let block: Block = deserialize(&blob[start..end])
             .chain_err(|| format!("failed to parse block at {}..{}", start, end))?;
         blocks.push(block);
+        pos = end;
     }
     Ok(blocks)
 }
@@ -175,15 +535,27 @@ fn set_open_files_limit(limit: libc::rlim_t) {
 }
 
 type JoinHandle = thread::JoinHandle<Result<()>>;
-type BlobReceiver = Arc<Mutex<Receiver<(Vec<u8>, PathBuf)>>>;
+type BlobReceiver = Arc<Mutex<Receiver<(Blob, PathBuf, usize)>>>;
 
-fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver, JoinHandle) {
+// Each file is read once there's room for it in the parser's budget, which it holds (along with
+// its size) until indexed.
+fn start_reader(
+    blk_files: Vec<PathBuf>,
+    parser: Arc<Parser>,
+    signal: Waiter,
+) -> (BlobReceiver, JoinHandle) {
     let chan = SyncChannel::new(0);
     let blobs = chan.sender();
     let handle = spawn_thread("bulk_read", move || -> Result<()> {
         for path in blk_files {
+            if signal.poll().is_err() {
+                info!("stopped reading blk*.dat files, finishing indexed files");
+                break;
+            }
+            let size = fs::metadata(&path).map_or(0, |metadata| metadata.len() as usize);
+            parser.budget.acquire(size);
             blobs
-                .send((parser.read_blkfile(&path)?, path))
+                .send((parser.read_blkfile(&path)?, path, size))
                 .expect("failed to send blk*.dat contents");
         }
         Ok(())
@@ -191,21 +563,36 @@ fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver,
     (Arc::new(Mutex::new(chan.into_receiver())), handle)
 }
 
+// The file's budget is exchanged for its rows' one: an indexer waiting for room while the reader
+// holds the next file (and the writer has nothing to release) would never get it.
 fn start_indexer(
     blobs: BlobReceiver,
     parser: Arc<Parser>,
-    writer: SyncSender<(Vec<Row>, PathBuf)>,
+    writer: SyncSender<(Vec<Row>, PathBuf, usize)>,
+    queue_depth: Gauge,
 ) -> JoinHandle {
     spawn_thread("bulk_index", move || -> Result<()> {
         loop {
             let msg = blobs.lock().unwrap().recv();
-            if let Ok((blob, path)) = msg {
-                let rows = parser
-                    .index_blkfile(blob)
-                    .chain_err(|| format!("failed to index {:?}", path))?;
+            if let Ok((blob, path, blob_size)) = msg {
+                let rows = match parser.index_blkfile(&path, blob) {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        parser.budget.release(blob_size);
+                        return Err(e).chain_err(|| format!("failed to index {:?}", path));
+                    }
+                };
+                let size = rows_size(&rows);
+                parser.budget.exchange(blob_size, size);
+                parser
+                    .stage_bytes
+                    .with_label_values(&["index"])
+                    .inc_by(size as i64);
+                // before sending, so that the writer's dec() never precedes it
+                queue_depth.inc();
                 writer
-                    .send((rows, path))
-                    .expect("failed to send indexed rows")
+                    .send((rows, path, size))
+                    .expect("failed to send indexed rows");
             } else {
                 debug!("no more blocks to index");
                 break;
@@ -215,32 +602,51 @@ fn start_indexer(
     })
 }
 
-pub fn index_blk_files(
-    daemon: &Daemon,
-    config: &Config,
-    metrics: &Metrics,
+// Reads the blk*.dat files, indexes them with `threads` indexers, and writes their rows.
+fn run_pipeline(
+    blk_files: Vec<PathBuf>,
+    parser: Arc<Parser>,
     store: DBStore,
-) -> Result<DBStore> {
-    set_open_files_limit(2048); // twice the default `ulimit -n` value
-    let blk_files = daemon.list_blk_files()?;
-    info!("indexing {} blk*.dat files", blk_files.len());
-    let indexed_blockhashes = read_indexed_blockhashes(&store);
-    debug!("found {} indexed blocks", indexed_blockhashes.len());
-    let parser = Parser::new(
-        daemon,
-        metrics,
-        indexed_blockhashes,
-        config.extended_db_enabled,
-    )?;
-    let (blobs, reader) = start_reader(blk_files, parser.clone());
-    let rows_chan = SyncChannel::new(0);
-    let indexers: Vec<JoinHandle> = (0..config.bulk_index_threads)
-        .map(|_| start_indexer(blobs.clone(), parser.clone(), rows_chan.sender()))
+    threads: usize,
+    queue_depth: usize,
+    progress_interval: Duration,
+    metrics: &Metrics,
+    signal: &Waiter,
+) -> DBStore {
+    let (blobs, reader) = start_reader(blk_files, parser.clone(), signal.clone());
+    // bounds the # of indexed blk*.dat files kept in memory while waiting to be written
+    let rows_chan = SyncChannel::new(queue_depth);
+    let queue_depth = metrics.gauge(MetricOpts::new(
+        "bulk_queue_depth",
+        "# of indexed blk*.dat files waiting to be written",
+    ));
+    let indexers: Vec<JoinHandle> = (0..threads)
+        .map(|_| {
+            start_indexer(
+                blobs.clone(),
+                parser.clone(),
+                rows_chan.sender(),
+                queue_depth.clone(),
+            )
+        })
         .collect();
-    Ok(spawn_thread("bulk_writer", move || -> DBStore {
-        for (rows, path) in rows_chan.into_receiver() {
+    spawn_thread("bulk_writer", move || -> DBStore {
+        let mut last_log = Instant::now();
+        let mut rate = MovingRate::new(RATE_SMOOTHING, parser.indexed_blocks.get() as u64);
+        for (rows, path, size) in rows_chan.into_receiver() {
+            queue_depth.dec();
             trace!("indexed {:?}: {} rows", path, rows.len());
+            // the file's progress row is part of this batch, so it is persisted only with its rows
             store.write(rows);
+            parser.budget.release(size);
+            parser
+                .stage_bytes
+                .with_label_values(&["write"])
+                .inc_by(size as i64);
+            if last_log.elapsed() >= progress_interval {
+                parser.log_progress(&mut rate, last_log.elapsed());
+                last_log = Instant::now();
+            }
         }
         reader
             .join()
@@ -252,9 +658,379 @@ pub fn index_blk_files(
                 .expect("indexer panicked")
                 .expect("indexing failed")
         });
+        parser.log_progress(&mut rate, last_log.elapsed());
+        info!(
+            "at most {} MB were buffered",
+            parser.budget.peak() / 1_000_000
+        );
         store.write(vec![parser.last_indexed_row()]);
         store
     })
     .join()
-    .expect("writer panicked"))
+    .expect("writer panicked")
+}
+
+pub fn index_blk_files(
+    daemon: &Daemon,
+    config: &Config,
+    metrics: &Metrics,
+    signal: &Waiter,
+    store: DBStore,
+) -> Result<DBStore> {
+    set_open_files_limit(2048); // twice the default `ulimit -n` value
+    let blk_files = daemon.list_blk_files()?;
+    let indexed_blockhashes = read_indexed_blockhashes(&store);
+    debug!("found {} indexed blocks", indexed_blockhashes.len());
+    let parser = Parser::new(
+        daemon.magic(),
+        load_headers(daemon)?,
+        metrics,
+        indexed_blockhashes,
+        config.extended_db_enabled,
+        opreturn_indexed(&store),
+        config.network_type,
+        config.mmap_blocks,
+        config.bulk_buffer_mb << 20,
+    );
+    let progress = read_blkfiles_progress(&store);
+    let (indexed_files, blk_files): (Vec<PathBuf>, Vec<PathBuf>) =
+        blk_files.into_iter().partition(|path| {
+            progress
+                .get(blkfile_name(path))
+                .map_or(false, |p| parser.is_indexed(path, p))
+        });
+    info!(
+        "indexing {} blk*.dat files ({} already indexed)",
+        blk_files.len(),
+        indexed_files.len()
+    );
+    // a buffer that can't hold a file per indexer would only keep them waiting
+    let threads = if config.bulk_buffer_mb < SMALL_BUFFER_MB {
+        info!(
+            "indexing a single blk*.dat file at a time (--bulk-buffer-mb {} is below {})",
+            config.bulk_buffer_mb, SMALL_BUFFER_MB
+        );
+        1
+    } else {
+        config.bulk_index_threads
+    };
+    let store = run_pipeline(
+        blk_files,
+        parser,
+        store,
+        threads,
+        config.bulk_queue_depth,
+        Duration::from_secs(config.progress_interval),
+        metrics,
+        signal,
+    );
+    // an interrupted import is resumed on restart (the DB is flushed when the store is closed)
+    signal.poll()?;
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::Block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::TxOut;
+    use bitcoin::consensus::encode::{deserialize, serialize};
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::BitcoinHash;
+    use bulk::{
+        format_duration, parse_blocks, read_blkfiles_progress, run_pipeline, Blob, ByteBudget,
+        Parser, STAGES,
+    };
+    use index::read_indexed_blockhashes;
+    use metrics::{MetricOpts, Metrics};
+    use page_size;
+    use signal::Waiter;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use store::{DBOptions, DBStore, ReadStore, WriteStore};
+    use testutil::temp_path;
+    use util::HeaderList;
+
+    fn le32(value: u32) -> Vec<u8> {
+        (0..4).map(|i| (value >> (8 * i)) as u8).collect()
+    }
+
+    // a block with a single (unique) coinbase transaction, on top of prev_blockhash
+    fn make_block(prev_blockhash: &[u8], height: u8) -> Block {
+        let header = [&le32(1)[..], prev_blockhash, &[0u8; 32], &[0u8; 12]].concat();
+        let coinbase = [
+            &le32(1)[..],
+            &[1],
+            &[0u8; 32],
+            &[0xff; 4],
+            &[1, height],
+            &[0xff; 4],
+            &[1],
+            &[0u8; 8],
+            &[1, 0x51],
+            &[0u8; 4],
+        ]
+        .concat();
+        deserialize(&[header, vec![1], coinbase].concat()).unwrap()
+    }
+
+    fn make_blkfile(blocks: &[Block]) -> Vec<u8> {
+        let mut blob = vec![];
+        for block in blocks {
+            let data = serialize(block);
+            blob.extend(le32(Network::Regtest.magic()));
+            blob.extend(le32(data.len() as u32));
+            blob.extend(data);
+        }
+        blob
+    }
+
+    fn make_blocks(count: u8) -> Vec<Block> {
+        let mut blocks = vec![];
+        let mut prev_blockhash = vec![0u8; 32];
+        for height in 0..count {
+            let block = make_block(&prev_blockhash, height);
+            prev_blockhash = serialize(&block.bitcoin_hash());
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn make_parser(
+        blocks: &[Block],
+        store: &DBStore,
+        extended_db_enabled: bool,
+        mmap_blocks: bool,
+        buffer_bytes: usize,
+    ) -> Arc<Parser> {
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(blocks.iter().map(|b| b.header.clone()).collect());
+        headers.apply(entries);
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        Parser::new(
+            Network::Regtest.magic(),
+            headers,
+            &metrics,
+            read_indexed_blockhashes(store),
+            extended_db_enabled,
+            false,
+            Network::Regtest,
+            mmap_blocks,
+            buffer_bytes,
+        )
+    }
+
+    fn index(parser: &Parser, path: &Path, store: &DBStore) {
+        let blob = parser.read_blkfile(path).unwrap();
+        store.write(parser.index_blkfile(path, blob).unwrap());
+    }
+
+    fn dump(store: &DBStore) -> Vec<(Vec<u8>, Vec<u8>)> {
+        store
+            .scan(b"")
+            .into_iter()
+            .map(|row| (row.key, row.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_resume_bulk_indexing() {
+        let dir = temp_path("bulk");
+        fs::create_dir_all(&dir).unwrap();
+
+        let blocks = make_blocks(4);
+        let blk_files: Vec<_> = blocks
+            .chunks(2)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let path = dir.join(format!("blk{:05}.dat", i));
+                fs::write(&path, make_blkfile(chunk)).unwrap();
+                path
+            })
+            .collect();
+
+        // index the first file, then "crash" (before the last indexed block is written)
+        let store = DBStore::open(&dir.join("db"), false, &DBOptions::default());
+        let parser = make_parser(&blocks, &store, false, false, 1 << 20);
+        assert_eq!(parser.indexed_height(), None);
+        index(&parser, &blk_files[0], &store);
+        assert_eq!(parser.indexed_height(), Some(1));
+        drop(parser);
+        drop(store);
+
+        let store = DBStore::open(&dir.join("db"), false, &DBOptions::default());
+        let parser = make_parser(&blocks, &store, false, false, 1 << 20);
+        let progress = read_blkfiles_progress(&store);
+        assert!(parser.is_indexed(&blk_files[0], &progress["blk00000.dat"]));
+        assert!(!progress.contains_key("blk00001.dat"));
+        // re-reading an indexed file only rewrites its progress row
+        let blob = parser.read_blkfile(&blk_files[0]).unwrap();
+        assert_eq!(parser.index_blkfile(&blk_files[0], blob).unwrap().len(), 1);
+        assert_eq!(parser.indexed_height(), Some(1));
+        index(&parser, &blk_files[1], &store);
+        assert_eq!(parser.indexed_blocks.get(), 4);
+        assert_eq!(parser.indexed_height(), Some(3));
+
+        // same rows as indexing everything at once
+        let expected = DBStore::open(&dir.join("expected"), false, &DBOptions::default());
+        let fresh = make_parser(&blocks, &expected, false, false, 1 << 20);
+        assert_eq!(fresh.indexed_blockhashes.lock().unwrap().len(), 0);
+        // out of order, as the blocks may be stored in the blk*.dat files
+        index(&fresh, &blk_files[1], &expected);
+        assert_eq!(fresh.indexed_height(), None);
+        index(&fresh, &blk_files[0], &expected);
+        assert_eq!(fresh.indexed_height(), Some(3));
+        assert_eq!(dump(&store), dump(&expected));
+
+        drop(store);
+        drop(expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bounded_buffer() {
+        let dir = temp_path("bulk-buffer");
+        fs::create_dir_all(&dir).unwrap();
+
+        // 20 kB blocks, in files of 2 blocks: the buffer holds at most 2 files at once
+        let mut blocks = make_blocks(12);
+        for block in &mut blocks {
+            block.txdata[0].output.push(TxOut {
+                value: 0,
+                script_pubkey: Script::from(vec![0x6a; 20_000]),
+            });
+        }
+        let blk_files: Vec<_> = blocks
+            .chunks(2)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let path = dir.join(format!("blk{:05}.dat", i));
+                fs::write(&path, make_blkfile(chunk)).unwrap();
+                path
+            })
+            .collect();
+        let file_size = fs::metadata(&blk_files[0]).unwrap().len() as usize;
+        let buffer_bytes = 2 * file_size + file_size / 2;
+
+        let store = DBStore::open(&dir.join("db"), false, &DBOptions::default());
+        let parser = make_parser(&blocks, &store, false, false, buffer_bytes);
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let store = run_pipeline(
+            blk_files.clone(),
+            parser.clone(),
+            store,
+            /*threads=*/ 4,
+            /*queue_depth=*/ 4,
+            Duration::from_secs(3600),
+            &metrics,
+            &Waiter::new(),
+        );
+        assert_eq!(parser.indexed_height(), Some(11));
+        assert!(parser.budget.peak() >= file_size);
+        assert!(parser.budget.peak() <= buffer_bytes);
+        assert_eq!(parser.budget.gauge.get(), 0); // all released
+        for stage in &STAGES {
+            assert!(parser.stage_bytes.with_label_values(&[stage]).get() > 0);
+        }
+
+        // the extended DB's rows (with the raw transactions) are larger than the room left by the
+        // next file: they're buffered anyway, instead of waiting for the reader holding it
+        let extended = DBStore::open(&dir.join("extended"), false, &DBOptions::default());
+        let buffer_bytes = file_size + file_size / 2;
+        let parser = make_parser(&blocks, &extended, true, false, buffer_bytes);
+        let extended = run_pipeline(
+            blk_files.clone(),
+            parser.clone(),
+            extended,
+            /*threads=*/ 2,
+            /*queue_depth=*/ 4,
+            Duration::from_secs(3600),
+            &metrics,
+            &Waiter::new(),
+        );
+        assert_eq!(parser.indexed_height(), Some(11));
+        let rows_bytes = parser.stage_bytes.with_label_values(&["index"]).get() as usize;
+        assert!(rows_bytes / blk_files.len() > buffer_bytes - file_size);
+        assert_eq!(parser.budget.gauge.get(), 0);
+
+        // a file larger than the whole buffer is still read, on its own
+        let budget = ByteBudget::new(10, metrics.gauge(MetricOpts::new("test", "test")));
+        budget.acquire(25);
+        budget.release(25);
+        budget.acquire(4);
+        budget.acquire(6);
+        assert_eq!(budget.peak(), 25);
+        assert_eq!(budget.gauge.get(), 10);
+        budget.exchange(4, 12); // without waiting for room
+        assert_eq!(budget.peak(), 25);
+        assert_eq!(budget.gauge.get(), 18);
+
+        drop(store);
+        drop(extended);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_duration() {
+        let format = |secs| format_duration(Duration::from_secs(secs));
+        assert_eq!(format(0), "0m00s");
+        assert_eq!(format(125), "2m05s");
+        assert_eq!(format(3600), "1h00m");
+        assert_eq!(format(100 * 3600 + 59 * 60 + 59), "100h59m");
+    }
+
+    #[test]
+    fn test_mmap_blkfile() {
+        let dir = temp_path("mmap");
+        fs::create_dir_all(&dir).unwrap();
+        let blocks = make_blocks(3);
+
+        // like bitcoind's, padded with zeros (preallocated space), and spanning a few pages
+        let mut blob = make_blkfile(&blocks);
+        blob.resize(3 * page_size::get() + 1, 0);
+        let large = dir.join("blk00000.dat");
+        fs::write(&large, &blob).unwrap();
+        let small = dir.join("blk00001.dat");
+        fs::write(&small, make_blkfile(&blocks[..1])).unwrap();
+        let empty = dir.join("blk00002.dat");
+        fs::write(&empty, b"").unwrap();
+
+        let magic = Network::Regtest.magic();
+        match Blob::map(&large).unwrap() {
+            Blob::Mapped(mmap) => {
+                assert_eq!(&mmap[..], &blob[..]);
+                assert_eq!(parse_blocks(&mmap, magic).unwrap(), blocks);
+            }
+            Blob::Read(_) => panic!("{:?} should be mapped", large),
+        }
+        // smaller than a page: read instead
+        match Blob::map(&small).unwrap() {
+            Blob::Read(data) => assert_eq!(parse_blocks(&data, magic).unwrap(), &blocks[..1]),
+            Blob::Mapped(_) => panic!("{:?} should be read", small),
+        }
+        assert!(parse_blocks(&Blob::map(&empty).unwrap(), magic)
+            .unwrap()
+            .is_empty());
+
+        // a truncated block fails the file
+        let truncated = make_blkfile(&blocks[..1]);
+        assert!(parse_blocks(&truncated[..truncated.len() - 1], magic).is_err());
+
+        // same rows as reading the files
+        let mapped = DBStore::open(&dir.join("mapped"), false, &DBOptions::default());
+        let read = DBStore::open(&dir.join("read"), false, &DBOptions::default());
+        let mapped_parser = make_parser(&blocks, &mapped, false, true, 1 << 20);
+        let read_parser = make_parser(&blocks, &read, false, false, 1 << 20);
+        for path in &[&large, &small, &empty] {
+            index(&mapped_parser, path, &mapped);
+            index(&read_parser, path, &read);
+        }
+        assert_eq!(dump(&mapped), dump(&read));
+
+        drop(mapped);
+        drop(read);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,39 +1,236 @@
+use base64;
 use bitcoin::network::constants::Network;
+use bitcoin::util::hash::Sha256dHash;
 use clap::{App, Arg};
 use dirs::home_dir;
+use libc;
+use log::LevelFilter;
 use num_cpus;
+use page_size;
+use std::fmt;
 use std::fs;
-use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use stderrlog;
+use std::time::Duration;
 
-use daemon::CookieGetter;
+use daemon::{CookieGetter, DaemonOptions};
+use logger::LogFormat;
+use network::NetworkParams;
+use rest::Backpressure;
+use store::{Compression, DBOptions};
+use util::Secret;
+use zmq;
 
 use errors::*;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     // See below for the documentation of each field:
-    pub log: stderrlog::StdErrLog,
-    pub network_type: Network,
+    pub verbosity: usize,
+    pub timestamp: bool,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: u64, // in bytes
+    pub log_files: usize,
+    pub log_format: LogFormat,
+    pub access_log_level: LevelFilter, // of the REST access log, regardless of the verbosity
+    pub network_params: NetworkParams,
+    pub network_type: Network, // the library's network, i.e. `network_params.network`
+    pub address_network: Network,
     pub db_path: PathBuf,
+    pub db_options: DBOptions,
     pub daemon_dir: PathBuf,
-    pub daemon_rpc_addr: SocketAddr,
-    pub cookie: Option<String>,
+    pub daemon_rpc_addrs: Vec<SocketAddr>,
+    pub daemon_rpc_connections: usize,
+    pub daemon_warmup_timeout: u64,  // in seconds
+    pub daemon_timeout: u64,         // in seconds
+    pub daemon_retries: Option<u32>, // None retrying connection failures forever
+    pub daemon_batch_size: usize,
+    pub daemon_dedup: bool, // identical in-flight daemon requests share a single call
+    pub zmq_block_addr: Option<SocketAddr>, // the daemon's -zmqpubhashblock endpoint
+    pub zmq_tx_addr: Option<SocketAddr>, // the daemon's -zmqpubrawtx endpoint
+    pub cookies: Vec<Secret<String>>, // 'user:password'
+    pub cookie_file: Option<PathBuf>, // used instead of the daemon directory's .cookie
     pub electrum_rpc_addr: SocketAddr,
     pub http_addr: SocketAddr,
+    pub http_unix_socket: Option<PathBuf>, // listened on instead of `http_addr`
+    pub cors: Option<String>,
+    pub trusted_proxies: Vec<IpAddr>, // whose X-Forwarded-For headers are used in the access log
+    pub admin_token: Option<AdminToken>,
+    pub http_auth: Option<HttpAuth>, // required on every REST request
+    pub rest_keepalive: usize,       // max # of requests per connection, 0 disabling keep-alive
+    pub rest_keepalive_timeout: u64, // in seconds
+    pub rest_workers: usize,
+    pub rest_queue_depth: usize,
+    pub rest_backpressure: Backpressure,
+    pub rate_limit: u32, // expensive REST requests per second per client, 0 disabling it
+    pub rate_limit_burst: u32,
+    pub rate_limit_loopback: bool, // whether local clients are also rate-limited
+    pub reject_during_compaction: bool, // of the expensive REST requests, with 503
+    pub prefix_search_limit: usize, // max # of results
+    pub prefix_search_scan_limit: usize, // max # of matching keys, before the prefix is too broad
     pub monitoring_addr: SocketAddr,
+    pub monitoring_auth: Option<HttpAuth>, // required on every monitoring request
+    pub ready_max_lag: usize,
     pub jsonrpc_import: bool,
     pub index_batch_size: usize,
+    pub index_batch_bytes: Option<usize>, // budget of the blocks indexed together, over index_batch_size
+    pub slow_batch_threshold: u64,        // in milliseconds
+    pub large_batch_threshold: usize,     // # of rows
     pub bulk_index_threads: usize,
+    pub bulk_queue_depth: usize,
+    pub bulk_buffer_mb: usize,
+    pub mmap_blocks: bool,
+    pub progress_interval: u64, // in seconds
     pub tx_cache_size: usize,
+    pub tx_cache_disk_mb: usize, // 0 disabling the on-disk transaction cache (only with --light)
+    pub history_cache_size: usize, // # of scripts
+    pub max_history: usize,      // funding txs per script, 0 meaning no limit
+    pub auto_cache_sizes: Option<CacheSizes>, // computed from --auto-cache-memory
     pub extended_db_enabled: bool,
     pub prevout_enabled: bool,
+    pub prevout_budget: usize, // # of whole previous transactions loaded per REST response
+    pub cache_merkle_proofs: bool,
+    pub index_opreturn: bool, // requested, only built for the DBs created with it
+    pub address_rpc: bool,    // serve addressindex-style RPCs on POST /rpc
+    pub internal_api: bool,   // serve GET /internal/status (operational details)
+    pub export_utxos: Option<PathBuf>,
+    pub snapshot: Option<PathBuf>, // the directory to copy the DB to
+    pub verify: bool,
+    pub mempool_file: Option<PathBuf>, // the tracked mempool, saved on shutdown
+    pub mempool_max_txs: usize,        // 0 meaning no limit
+    pub block_fetch_retries: usize,    // of the blocks fetched for REST, on transient failures
+    pub verification_sampling: usize,  // cross-check one in N history answers, 0 meaning never
+    pub compact_on_startup: bool,
+    pub auto_compact_interval_hours: Option<u64>, // full compactions in the background
+    pub auto_compact_window: Option<(u32, u32)>,  // UTC hours [start, end) to start them in
+    pub auto_reindex: bool,
+    pub no_index: bool, // only serve queries from an already indexed DB, never writing to it
+    pub serve_during_sync: bool, // serve the blocks indexed so far, during the initial sync
+    pub ignore_tip_mismatch: bool, // only log it, instead of refusing to index
+    pub no_wait_sync: bool, // start indexing while the daemon is in initial block download
+    pub wait_sync_max_gap: Option<usize>, // # of blocks the daemon may still have to download
+}
+
+const ADMIN_TOKEN_MIN_LEN: usize = 16;
+
+const HISTORY_CACHE_SIZE: usize = 10_000; // # of scripts
+
+// The --auto-cache-memory budget split (in percents), the rest going to RocksDB's block cache.
+// The transactions are only cached with --light (they're read from the DB otherwise).
+const AUTO_CACHE_HISTORY_PERCENT: usize = 20;
+const AUTO_CACHE_TX_PERCENT: usize = 30;
+const AUTO_CACHE_MIN_MB: usize = 16;
+// rough in-memory sizes of the cache entries
+const HISTORY_CACHE_ENTRY_BYTES: usize = 4096;
+const TX_CACHE_ENTRY_BYTES: usize = 1024;
+
+/// The cache sizes computed from an `--auto-cache-memory` budget (overriding `--db-cache-mb` and
+/// `--tx-cache-size`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheSizes {
+    pub budget_mb: usize,
+    pub db_cache_mb: usize,
+    pub history_cache_size: usize,    // # of scripts
+    pub tx_cache_size: Option<usize>, // # of transactions (only with --light)
+}
+
+impl CacheSizes {
+    fn split(budget_mb: usize, light: bool) -> CacheSizes {
+        let budget = budget_mb << 20;
+        let history_bytes = budget / 100 * AUTO_CACHE_HISTORY_PERCENT;
+        let tx_bytes = if light {
+            budget / 100 * AUTO_CACHE_TX_PERCENT
+        } else {
+            0
+        };
+        CacheSizes {
+            budget_mb,
+            db_cache_mb: (budget - history_bytes - tx_bytes) >> 20,
+            history_cache_size: history_bytes / HISTORY_CACHE_ENTRY_BYTES,
+            tx_cache_size: if light {
+                Some(tx_bytes / TX_CACHE_ENTRY_BYTES)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// The system's physical memory (in MB), if it can be detected.
+fn physical_memory_mb() -> Option<usize> {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    if pages <= 0 {
+        return None;
+    }
+    Some((pages as usize).saturating_mul(page_size::get()) >> 20)
+}
+
+/// The token authorizing the admin endpoints, left out of the (logged) `Debug` output.
+#[derive(Clone)]
+pub struct AdminToken(String);
+
+// Compares in constant time, so that secrets can't be guessed from response times.
+fn secret_matches(secret: &[u8], value: &[u8]) -> bool {
+    secret.len() == value.len()
+        && secret
+            .iter()
+            .zip(value.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl AdminToken {
+    /// Compares in constant time, so that the token can't be guessed from response times.
+    pub fn matches(&self, token: &str) -> bool {
+        secret_matches(self.0.as_bytes(), token.as_bytes())
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AdminToken(..)")
+    }
+}
+
+/// The `WWW-Authenticate` challenge of the responses to unauthenticated requests.
+pub const HTTP_AUTH_CHALLENGE: &str = "Basic realm=\"electrs\", charset=\"UTF-8\"";
+
+/// The 'user:password' credentials required (with HTTP Basic auth) by an HTTP server, left out of
+/// the (logged) `Debug` output.
+#[derive(Clone)]
+pub struct HttpAuth(String);
+
+impl HttpAuth {
+    /// Whether this `Authorization` header value holds the credentials (compared in constant time).
+    pub fn matches(&self, authorization: Option<&str>) -> bool {
+        let encoded = match authorization {
+            Some(value)
+                if value
+                    .get(..6)
+                    .map_or(false, |s| s.eq_ignore_ascii_case("Basic ")) =>
+            {
+                value[6..].trim()
+            }
+            _ => return false,
+        };
+        match base64::decode(encoded) {
+            Ok(credentials) => secret_matches(self.0.as_bytes(), &credentials),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Debug for HttpAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HttpAuth(..)")
+    }
 }
 
 impl Config {
-    pub fn from_args() -> Config {
+    /// Parses the command line arguments (exiting on invalid ones, like clap does): a config per
+    /// `--network-instance`, or a single one without them.
+    pub fn from_args() -> Result<Vec<Config>> {
         let m = App::new("Electrum Rust Server")
             .version(crate_version!())
             .arg(
@@ -47,12 +244,66 @@ impl Config {
                     .long("timestamp")
                     .help("Prepend log lines with a timestamp"),
             )
+            .arg(
+                Arg::with_name("log_file")
+                    .long("log-file")
+                    .help("Write logs to this file instead of stderr, rotating it by size")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("log_max_size")
+                    .long("log-max-size")
+                    .help("Size (in MiB) at which the log file is rotated")
+                    .default_value("10"),
+            )
+            .arg(
+                Arg::with_name("log_files")
+                    .long("log-files")
+                    .help("Number of log files to keep, including the current one")
+                    .default_value("5"),
+            )
+            .arg(
+                Arg::with_name("log_format")
+                    .long("log-format")
+                    .help("Log lines format ('text' or 'json', as an object per line with the key fields of the logged events)")
+                    .default_value("text"),
+            )
+            .arg(
+                Arg::with_name("access_log_level")
+                    .long("access-log-level")
+                    .help("Level of the REST access log lines ('off', 'error', 'warn', 'info', 'debug' or 'trace'), independently of the verbosity")
+                    .default_value("off"),
+            )
             .arg(
                 Arg::with_name("db_dir")
                     .long("db-dir")
                     .help("Directory to store index database (default: ./db/)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("db_compression")
+                    .long("db-compression")
+                    .help("RocksDB compression algorithm ('none', 'snappy', 'lz4' or 'zstd'). Changing it requires a reindex")
+                    .default_value("snappy"),
+            )
+            .arg(
+                Arg::with_name("db_cache_mb")
+                    .long("db-cache-mb")
+                    .help("RocksDB block cache size in MB (default: use RocksDB's default)")
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("db_max_open_files")
+                    .long("db-max-open-files")
+                    .help("Maximum number of files RocksDB keeps open (default: 16 during bulk import, 256 otherwise)")
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("db_parallelism")
+                    .long("db-parallelism")
+                    .help("Number of RocksDB background compaction threads (default: use RocksDB's default)")
+                    .default_value("0"),
+            )
             .arg(
                 Arg::with_name("daemon_dir")
                     .long("daemon-dir")
@@ -62,13 +313,50 @@ impl Config {
             .arg(
                 Arg::with_name("cookie")
                     .long("cookie")
-                    .help("JSONRPC authentication cookie ('USER:PASSWORD', default: read from ~/.viacoin/.cookie)")
+                    .help("JSONRPC authentication cookie ('USER:PASSWORD', default: read from ~/.viacoin/.cookie). May be repeated to set a different cookie for each daemon RPC address")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("cookie_file")
+                    .long("cookie-file")
+                    .help("File holding the JSONRPC authentication cookie ('USER:PASSWORD'), re-read on each request (instead of giving it with --cookie)")
                     .takes_value(true),
             )
             .arg(
                 Arg::with_name("network")
                     .long("network")
-                    .help("Select Bitcoin network type ('mainnet', 'testnet' or 'regtest')")
+                    .help("Select Viacoin network type ('mainnet', 'testnet' or 'regtest')")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("network_instance")
+                    .long("network-instance")
+                    .help("Experimental: serve this network from the same process, with its default directories and addresses (given twice, instead of --network)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .conflicts_with("network"),
+            )
+            .arg(
+                Arg::with_name("custom_network")
+                    .long("custom-network")
+                    .help("Index a custom test network having this genesis block hash, otherwise like --network (its addresses and daemon chain), refusing a daemon on another chain. Its DB is kept in the 'custom' subdirectory")
+                    .takes_value(true)
+                    .conflicts_with("network_instance"),
+            )
+            .arg(
+                Arg::with_name("custom_network_ports")
+                    .long("custom-network-ports")
+                    .help("The custom network's default daemon RPC, HTTP and monitoring ports ('DAEMON,HTTP,MONITORING', default: --network's)")
+                    .takes_value(true)
+                    .requires("custom_network"),
+            )
+            .arg(
+                Arg::with_name("address_network")
+                    .long("address-network")
+                    .help("Encode addresses in responses using another network's prefixes ('mainnet', 'testnet' or 'regtest', default: same as --network)")
                     .takes_value(true),
             )
             .arg(
@@ -80,13 +368,156 @@ impl Config {
             .arg(
                 Arg::with_name("http_addr")
                     .long("http-addr")
-                    .help("HTTP server 'addr:port' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
+                    .help("HTTP server 'addr:port' (or 'unix:<path>' for a Unix domain socket) to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("cors")
+                    .long("cors")
+                    .help("Origin allowed to make cross-origin requests to the HTTP server ('*' for any, default: none)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("trusted_proxies")
+                    .long("trusted-proxies")
+                    .help("Comma-separated IP addresses of the reverse proxies whose X-Forwarded-For header gives the client address in the access log (default: none)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("rest_keepalive")
+                    .long("rest-keepalive")
+                    .help("Number of HTTP requests served over a kept-alive connection before closing it (0 to disable keep-alive)")
+                    .default_value("1000")
+            )
+            .arg(
+                Arg::with_name("rest_keepalive_timeout")
+                    .long("rest-keepalive-timeout")
+                    .help("Seconds after which a kept-alive HTTP connection is closed (after its current request)")
+                    .default_value("120")
+            )
+            .arg(
+                Arg::with_name("rest_workers")
+                    .long("rest-workers")
+                    .help("Number of threads handling the HTTP requests (default: use the # of CPUs)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("rest_queue_depth")
+                    .long("rest-queue-depth")
+                    .help("Number of HTTP requests waiting for a worker thread before applying --rest-backpressure")
+                    .default_value("1000")
+            )
+            .arg(
+                Arg::with_name("rest_backpressure")
+                    .long("rest-backpressure")
+                    .help("What to do with the HTTP requests beyond --rest-queue-depth: 'queue' them anyway, or 'reject' them with a 503")
+                    .possible_values(&["queue", "reject"])
+                    .default_value("queue")
+            )
+            .arg(
+                Arg::with_name("rate_limit")
+                    .long("rate-limit")
+                    .help("Expensive HTTP requests (e.g. histories) per second allowed to each client IP, the cheap ones being allowed 10 times more (0 to disable)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("rate_limit_burst")
+                    .long("rate-limit-burst")
+                    .help("Expensive HTTP requests a client IP may make at once, above --rate-limit (10 times more for the cheap ones)")
+                    .default_value("20")
+            )
+            .arg(
+                Arg::with_name("rate_limit_loopback")
+                    .long("rate-limit-loopback")
+                    .help("Also rate-limit the local (loopback) clients, which are exempt by default")
+            )
+            .arg(
+                Arg::with_name("reject_during_compaction")
+                    .long("reject-during-compaction")
+                    .help("Refuse the expensive HTTP requests (e.g. histories) with a 503 while the DB is being fully compacted, to keep serving the cheap ones quickly")
+            )
+            .arg(
+                Arg::with_name("prefix_search_limit")
+                    .long("prefix-search-limit")
+                    .help("Maximum number of results returned by the prefix searches (e.g. /address-prefix)")
+                    .default_value("10")
+            )
+            .arg(
+                Arg::with_name("prefix_search_scan_limit")
+                    .long("prefix-search-scan-limit")
+                    .help("Maximum number of keys scanned by a prefix search, failing it as too broad past this")
+                    .default_value("1000")
+            )
+            .arg(
+                Arg::with_name("daemon_rpc_connections")
+                    .long("daemon-rpc-connections")
+                    .help("Number of connections to the daemon, for concurrent JSONRPC requests")
+                    .default_value("4")
+            )
+            .arg(
+                Arg::with_name("daemon_warmup_timeout")
+                    .long("daemon-warmup-timeout")
+                    .help("Seconds to keep retrying JSONRPC requests while the daemon is warming up (e.g. loading its block index)")
+                    .default_value("600")
+            )
+            .arg(
+                Arg::with_name("daemon_timeout")
+                    .long("daemon-timeout")
+                    .help("Seconds to wait for the daemon to connect, accept or answer a JSONRPC call, before retrying it")
+                    .default_value("300")
+            )
+            .arg(
+                Arg::with_name("daemon_retries")
+                    .long("daemon-retries")
+                    .help("Number of times a JSONRPC call is retried after connection failures and timeouts, before failing (default: retry forever)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("daemon_batch_size")
+                    .long("daemon-batch-size")
+                    .help("Maximum number of requests per batched JSONRPC call (e.g. to fetch transactions with --light)")
+                    .default_value("100")
+            )
+            .arg(
+                Arg::with_name("daemon_dedup")
+                    .long("daemon-dedup")
+                    .help("Send identical concurrent daemon requests (e.g. for a popular block or transaction) only once, sharing the result")
+            )
+            .arg(
+                Arg::with_name("zmq_block_addr")
+                    .long("zmq-block-addr")
+                    .alias("zmq-addr")
+                    .help("The daemon's -zmqpubhashblock 'tcp://addr:port' endpoint, for updating the index as soon as a block is found (default: polling only)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("zmq_tx_addr")
+                    .long("zmq-tx-addr")
+                    .help("The daemon's -zmqpubrawtx 'tcp://addr:port' endpoint, for updating the mempool as soon as a transaction is received (default: polling only)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("admin_token_file")
+                    .long("admin-token-file")
+                    .help("File holding the token enabling the HTTP server's /admin endpoints (default: disabled)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("http_auth")
+                    .long("http-auth")
+                    .help("Require HTTP Basic auth with these 'user:password' credentials on every REST request (default: open)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("monitoring_auth")
+                    .long("monitoring-auth")
+                    .help("Require HTTP Basic auth with these 'user:password' credentials on every monitoring request (default: open)")
                     .takes_value(true),
             )
             .arg(
                 Arg::with_name("daemon_rpc_addr")
                     .long("daemon-rpc-addr")
-                    .help("Viacoin daemon JSONRPC 'addr:port' to connect (default: 127.0.0.1:5222 for mainnet, 127.0.0.1:25222 for testnet and 127.0.0.1:25222 for regtest)")
+                    .help("Viacoin daemon JSONRPC 'addr:port' to connect, or a comma-separated list of them to fail over to, in order of preference (default: 127.0.0.1:5222 for mainnet, 127.0.0.1:25222 for testnet and 127.0.0.1:25222 for regtest)")
                     .takes_value(true),
             )
             .arg(
@@ -95,6 +526,12 @@ impl Config {
                     .help("Prometheus monitoring 'addr:port' to listen on (default: 127.0.0.1:4224 for mainnet, 127.0.0.1:14224 for testnet and 127.0.0.1:24224 for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("ready_max_lag")
+                    .long("ready-max-lag")
+                    .help("Number of blocks the index may lag behind the daemon while /readyz reports ready")
+                    .default_value("2"),
+            )
             .arg(
                 Arg::with_name("jsonrpc_import")
                     .long("jsonrpc-import")
@@ -106,18 +543,77 @@ impl Config {
                     .help("Number of blocks to get in one JSONRPC request from viacoind")
                     .default_value("100"),
             )
+            .arg(
+                Arg::with_name("index_batch_bytes")
+                    .long("index-batch-bytes")
+                    .help("Index together the blocks totalling up to this many bytes (serialized), instead of --index-batch-size blocks (default: off)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("slow_batch_threshold")
+                    .long("slow-batch-threshold")
+                    .help("Log (at info level) the index batches taking longer than this to process (in milliseconds)")
+                    .default_value("10000"),
+            )
+            .arg(
+                Arg::with_name("large_batch_threshold")
+                    .long("large-batch-threshold")
+                    .help("Log (at info level) the index batches writing more than this # of rows")
+                    .default_value("1000000"),
+            )
             .arg(
                 Arg::with_name("bulk_index_threads")
                     .long("bulk-index-threads")
                     .help("Number of threads used for bulk indexing (default: use the # of CPUs)")
                     .default_value("0")
             )
+            .arg(
+                Arg::with_name("bulk_queue_depth")
+                    .long("bulk-queue-depth")
+                    .help("Number of indexed blk*.dat files to keep in memory while waiting for the DB writer during bulk indexing (0: each indexer waits for the writer to take its rows)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("bulk_buffer_mb")
+                    .long("bulk-buffer-mb")
+                    .help("Total size (in MB) of the blk*.dat files and indexed rows kept in memory during bulk indexing, the files being read only once there's room (indexing a single file at a time below 128 MB)")
+                    .default_value("256")
+            )
+            .arg(
+                Arg::with_name("mmap_blocks")
+                    .long("mmap-blocks")
+                    .help("Memory-map the blk*.dat files during bulk indexing, instead of reading them"),
+            )
+            .arg(
+                Arg::with_name("progress_interval")
+                    .long("progress-interval")
+                    .help("Log the bulk indexing progress (and update its metrics) every this many seconds")
+                    .default_value("10"),
+            )
             .arg(
                 Arg::with_name("tx_cache_size")
                     .long("tx-cache-size")
                     .help("Number of transactions to keep in for query LRU cache")
                     .default_value("10000")  // should be enough for a small wallet.
             )
+            .arg(
+                Arg::with_name("tx_cache_disk_mb")
+                    .long("tx-cache-disk-mb")
+                    .help("Size of the on-disk cache of the transactions fetched from the daemon with --light, kept in the DB across restarts (in MB, 0 to disable)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("max_history")
+                    .long("max-history")
+                    .help("Refuse the history, stats and UTXO queries of the scripts funded by more than this many confirmed transactions (0: no limit)")
+                    .default_value("100000"),
+            )
+            .arg(
+                Arg::with_name("auto_cache_memory")
+                    .long("auto-cache-memory")
+                    .help("Memory budget (in MB) split across the RocksDB block cache and the history and transaction caches, overriding --db-cache-mb and --tx-cache-size")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("light")
                     .long("light")
@@ -128,141 +624,1988 @@ impl Config {
                     .long("disable-prevout")
                     .help("Don't attach previous output details to inputs")
             )
+            .arg(
+                Arg::with_name("prevout_budget")
+                    .long("prevout-budget")
+                    .help("Load at most this many whole previous transactions to attach the inputs' prevouts of a REST response (the other prevouts are left unknown)")
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::with_name("cache_merkle_proofs")
+                    .long("cache-merkle-proofs")
+                    .help("Cache the computed merkle proofs in the DB (trading disk space for CPU)")
+            )
+            .arg(
+                Arg::with_name("address_rpc")
+                    .long("address-rpc")
+                    .help("Serve viacoind's addressindex RPCs (getaddresstxids, getaddressbalance, getaddressutxos) on POST /rpc")
+            )
+            .arg(
+                Arg::with_name("internal_api")
+                    .long("internal-api")
+                    .help("Serve the indexing progress, DB size and caches usage on GET /internal/status")
+            )
+            .arg(
+                Arg::with_name("index_opreturn")
+                    .long("index-opreturn")
+                    .help("Index the OP_RETURN payloads of the confirmed transactions, for /opreturn (grows the DB; only built for a new DB)")
+            )
+            .arg(
+                Arg::with_name("compact_on_startup")
+                    .long("compact-on-startup")
+                    .help("Fully compact the DB once the index is up-to-date, before serving requests (may take a while)")
+            )
+            .arg(
+                Arg::with_name("auto_compact_interval_hours")
+                    .long("auto-compact-interval-hours")
+                    .help("Fully compact the DB in the background every this # of hours (default: never)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("auto_compact_window")
+                    .long("auto-compact-window")
+                    .help("Only start the background compactions during these UTC hours, as 'start-end' (e.g. '2-5', or '22-4' around midnight)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("auto_reindex")
+                    .long("auto-reindex")
+                    .help("Wipe and rebuild the DB if it's incompatible with this release or configuration (instead of refusing to start)")
+            )
+            .arg(
+                Arg::with_name("no_index")
+                    .long("no-index")
+                    .help("Only serve queries from an already indexed DB, opened read-only (the index isn't updated, but the mempool is)")
+            )
+            .arg(
+                Arg::with_name("serve_during_sync")
+                    .long("serve-during-sync")
+                    .help("Start the REST server during the initial sync, serving the blocks indexed so far (requires --jsonrpc-import)")
+            )
+            .arg(
+                Arg::with_name("ignore_tip_mismatch")
+                    .long("ignore-tip-mismatch")
+                    .help("Keep indexing when the daemon's tip isn't its block at the tip's height (only log it)")
+            )
+            .arg(
+                Arg::with_name("no_wait_sync")
+                    .long("no-wait-sync")
+                    .help("Don't wait for the daemon's initial block download to be over before indexing")
+            )
+            .arg(
+                Arg::with_name("wait_sync_max_gap")
+                    .long("wait-sync-max-gap")
+                    .help("Stop waiting for the daemon's initial block download once it has at most this # of blocks left to download (default: wait until it's over)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("export_utxos")
+                    .long("export-utxos")
+                    .help("Write the UTXO set to this file (as newline-delimited JSON) and exit")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("snapshot")
+                    .long("snapshot")
+                    .help("Copy the DB to this (new) directory and exit")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("verify")
+                    .long("verify")
+                    .help("Check the index for missing or corrupted rows and exit (with a non-zero status if any are found)")
+            )
+            .arg(
+                Arg::with_name("mempool_file")
+                    .long("mempool-file")
+                    .help("Save the tracked mempool to this file on shutdown, and reload it on startup (keeping the transactions still in the daemon's mempool)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("mempool_max_txs")
+                    .long("mempool-max-txs")
+                    .help("Track at most this many mempool transactions, evicting the ones paying the lowest fee rate beyond it (0: no limit)")
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("block_fetch_retries")
+                    .long("block-fetch-retries")
+                    .help("Retry the blocks fetched from the daemon for REST queries this many times on transient failures, before failing with 503")
+                    .default_value("2"),
+            )
+            .arg(
+                Arg::with_name("verification_sampling")
+                    .long("verification-sampling")
+                    .help("Cross-check one in N script history and UTXO answers against the daemon, in the background, logging and counting the mismatches (0: never)")
+                    .default_value("0"),
+            )
             .get_matches();
 
-        let network_name = m.value_of("network").unwrap_or("mainnet");
-        let network_type = match network_name {
-            "mainnet" => Network::Bitcoin,
-            "testnet" => Network::Testnet,
-            "regtest" => Network::Regtest,
-            _ => panic!("unsupported Bitcoin network: {:?}", network_name),
-        };
-        let db_dir = Path::new(m.value_of("db_dir").unwrap_or("./db"));
-        let db_path = db_dir.join(network_name);
-
-        let default_daemon_port = match network_type {
-            Network::Bitcoin => 5222,
-            Network::Testnet => 25222,
-            Network::Regtest => 25222,
-        };
-        let default_electrum_port = match network_type {
-            Network::Bitcoin => 50001,
-            Network::Testnet => 60001,
-            Network::Regtest => 60401,
-        };
-        let default_http_port = match network_type {
-            Network::Bitcoin => 3000,
-            Network::Testnet => 3001,
-            Network::Regtest => 3002,
-        };
-        let default_monitoring_port = match network_type {
-            Network::Bitcoin => 4224,
-            Network::Testnet => 14224,
-            Network::Regtest => 24224,
-        };
-
-        let daemon_rpc_addr: SocketAddr = m
-            .value_of("daemon_rpc_addr")
-            .unwrap_or(&format!("127.0.0.1:{}", default_daemon_port))
-            .parse()
-            .expect("invalid Bitcoind RPC address");
-        let electrum_rpc_addr: SocketAddr = m
-            .value_of("electrum_rpc_addr")
-            .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port))
-            .parse()
-            .expect("invalid Electrum RPC address");
-        let http_addr: SocketAddr = m
-            .value_of("http_addr")
-            .unwrap_or(&format!("127.0.0.1:{}", default_http_port))
-            .parse()
-            .expect("invalid HTTP server address");
-        let monitoring_addr: SocketAddr = m
-            .value_of("monitoring_addr")
-            .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port))
-            .parse()
-            .expect("invalid Prometheus monitoring address");
-
-        let mut daemon_dir = m
-            .value_of("daemon_dir")
-            .map(|p| PathBuf::from(p))
-            .unwrap_or_else(|| {
-                let mut default_dir = home_dir().expect("no homedir");
-                default_dir.push(".viacoin");
-                default_dir
-            });
-        match network_type {
-            Network::Bitcoin => (),
-            Network::Testnet => daemon_dir.push("testnet3"),
-            Network::Regtest => daemon_dir.push("regtest"),
-        }
-        let cookie = m.value_of("cookie").map(|s| s.to_owned());
-
-        let mut log = stderrlog::new();
-        log.verbosity(m.occurrences_of("verbosity") as usize);
-        log.timestamp(if m.is_present("timestamp") {
-            stderrlog::Timestamp::Millisecond
-        } else {
-            stderrlog::Timestamp::Off
-        });
-        log.init().expect("logging initialization failed");
-        let mut bulk_index_threads = value_t_or_exit!(m, "bulk_index_threads", usize);
-        if bulk_index_threads == 0 {
-            bulk_index_threads = num_cpus::get();
-        }
-        let config = Config {
-            log,
-            network_type,
-            db_path,
-            daemon_dir,
-            daemon_rpc_addr,
-            cookie,
-            electrum_rpc_addr,
-            http_addr,
-            monitoring_addr,
-            jsonrpc_import: m.is_present("jsonrpc_import"),
-            index_batch_size: value_t_or_exit!(m, "index_batch_size", usize),
-            bulk_index_threads,
-            tx_cache_size: value_t_or_exit!(m, "tx_cache_size", usize),
-            extended_db_enabled: !m.is_present("light"),
-            prevout_enabled: !m.is_present("disable_prevout"),
-        };
-        eprintln!("{:?}", config);
-        config
+        let mut network = NetworkParams::from_name(m.value_of("network").unwrap_or("mainnet"))?;
+        if let Some(genesis_hash) = m.value_of("custom_network") {
+            let ports = match m.value_of("custom_network_ports") {
+                Some(ports) => Some(parse_ports(ports)?),
+                None => None,
+            };
+            network = NetworkParams::custom(network, parse_genesis_hash(genesis_hash)?, ports);
+        }
+        let mut builder = ConfigBuilder::default()
+            .verbosity(m.occurrences_of("verbosity") as usize)
+            .timestamp(m.is_present("timestamp"))
+            .log_max_size(value_t_or_exit!(m, "log_max_size", u64) * 1024 * 1024)
+            .log_files(value_t_or_exit!(m, "log_files", usize))
+            .log_format(parse_log_format(m.value_of("log_format").unwrap())?)
+            .access_log_level(parse_log_level(m.value_of("access_log_level").unwrap())?)
+            .network(network)
+            .db_dir(PathBuf::from(m.value_of("db_dir").unwrap_or("./db")))
+            .db_options(DBOptions {
+                compression: parse_compression(m.value_of("db_compression").unwrap())?,
+                cache_mb: value_t_or_exit!(m, "db_cache_mb", usize),
+                max_open_files: value_t_or_exit!(m, "db_max_open_files", usize),
+                parallelism: value_t_or_exit!(m, "db_parallelism", usize),
+            })
+            .cookies(
+                m.values_of("cookie")
+                    .map_or(vec![], |values| values.map(|s| s.to_owned()).collect()),
+            )
+            .ready_max_lag(value_t_or_exit!(m, "ready_max_lag", usize))
+            .daemon_rpc_connections(value_t_or_exit!(m, "daemon_rpc_connections", usize))
+            .daemon_warmup_timeout(value_t_or_exit!(m, "daemon_warmup_timeout", u64))
+            .daemon_timeout(value_t_or_exit!(m, "daemon_timeout", u64))
+            .daemon_batch_size(value_t_or_exit!(m, "daemon_batch_size", usize))
+            .daemon_dedup(m.is_present("daemon_dedup"))
+            .rest_keepalive(value_t_or_exit!(m, "rest_keepalive", usize))
+            .rest_keepalive_timeout(value_t_or_exit!(m, "rest_keepalive_timeout", u64))
+            .rest_workers(value_t_or_exit!(m, "rest_workers", usize))
+            .rest_queue_depth(value_t_or_exit!(m, "rest_queue_depth", usize))
+            .rest_backpressure(parse_backpressure(
+                m.value_of("rest_backpressure").unwrap(),
+            )?)
+            .rate_limit(value_t_or_exit!(m, "rate_limit", u32))
+            .rate_limit_burst(value_t_or_exit!(m, "rate_limit_burst", u32))
+            .rate_limit_loopback(m.is_present("rate_limit_loopback"))
+            .reject_during_compaction(m.is_present("reject_during_compaction"))
+            .prefix_search_limit(value_t_or_exit!(m, "prefix_search_limit", usize))
+            .prefix_search_scan_limit(value_t_or_exit!(m, "prefix_search_scan_limit", usize))
+            .jsonrpc_import(m.is_present("jsonrpc_import"))
+            .index_batch_size(value_t_or_exit!(m, "index_batch_size", usize))
+            .slow_batch_threshold(value_t_or_exit!(m, "slow_batch_threshold", u64))
+            .large_batch_threshold(value_t_or_exit!(m, "large_batch_threshold", usize))
+            .bulk_index_threads(value_t_or_exit!(m, "bulk_index_threads", usize))
+            .bulk_queue_depth(value_t_or_exit!(m, "bulk_queue_depth", usize))
+            .bulk_buffer_mb(value_t_or_exit!(m, "bulk_buffer_mb", usize))
+            .mmap_blocks(m.is_present("mmap_blocks"))
+            .progress_interval(value_t_or_exit!(m, "progress_interval", u64))
+            .tx_cache_size(value_t_or_exit!(m, "tx_cache_size", usize))
+            .tx_cache_disk_mb(value_t_or_exit!(m, "tx_cache_disk_mb", usize))
+            .max_history(value_t_or_exit!(m, "max_history", usize))
+            .mempool_max_txs(value_t_or_exit!(m, "mempool_max_txs", usize))
+            .block_fetch_retries(value_t_or_exit!(m, "block_fetch_retries", usize))
+            .verification_sampling(value_t_or_exit!(m, "verification_sampling", usize))
+            .extended_db_enabled(!m.is_present("light"))
+            .prevout_enabled(!m.is_present("disable_prevout"))
+            .prevout_budget(value_t_or_exit!(m, "prevout_budget", usize))
+            .cache_merkle_proofs(m.is_present("cache_merkle_proofs"))
+            .index_opreturn(m.is_present("index_opreturn"))
+            .address_rpc(m.is_present("address_rpc"))
+            .internal_api(m.is_present("internal_api"))
+            .compact_on_startup(m.is_present("compact_on_startup"))
+            .verify(m.is_present("verify"))
+            .auto_reindex(m.is_present("auto_reindex"))
+            .no_index(m.is_present("no_index"))
+            .serve_during_sync(m.is_present("serve_during_sync"))
+            .ignore_tip_mismatch(m.is_present("ignore_tip_mismatch"))
+            .no_wait_sync(m.is_present("no_wait_sync"));
+        if let Some(path) = m.value_of("log_file") {
+            builder = builder.log_file(PathBuf::from(path));
+        }
+        if let Some(name) = m.value_of("address_network") {
+            builder = builder.address_network(NetworkParams::from_name(name)?.network);
+        }
+        if m.is_present("auto_cache_memory") {
+            builder = builder.auto_cache_memory(value_t_or_exit!(m, "auto_cache_memory", usize));
+        }
+        if m.is_present("daemon_retries") {
+            builder = builder.daemon_retries(value_t_or_exit!(m, "daemon_retries", u32));
+        }
+        if m.is_present("wait_sync_max_gap") {
+            builder = builder.wait_sync_max_gap(value_t_or_exit!(m, "wait_sync_max_gap", usize));
+        }
+        if let Some(path) = m.value_of("export_utxos") {
+            builder = builder.export_utxos(PathBuf::from(path));
+        }
+        if m.is_present("index_batch_bytes") {
+            builder = builder.index_batch_bytes(value_t_or_exit!(m, "index_batch_bytes", usize));
+        }
+        if m.is_present("auto_compact_interval_hours") {
+            builder = builder.auto_compact_interval_hours(value_t_or_exit!(
+                m,
+                "auto_compact_interval_hours",
+                u64
+            ));
+        }
+        if let Some(window) = m.value_of("auto_compact_window") {
+            builder = builder.auto_compact_window(parse_hour_window(window)?);
+        }
+        if let Some(dir) = m.value_of("snapshot") {
+            builder = builder.snapshot(PathBuf::from(dir));
+        }
+        if let Some(path) = m.value_of("mempool_file") {
+            builder = builder.mempool_file(PathBuf::from(path));
+        }
+        if let Some(dir) = m.value_of("daemon_dir") {
+            builder = builder.daemon_dir(PathBuf::from(dir));
+        }
+        if let Some(addrs) = m.value_of("daemon_rpc_addr") {
+            builder = builder.daemon_rpc_addrs(
+                addrs
+                    .split(',')
+                    .map(|addr| parse_addr(addr.trim(), "Bitcoind RPC"))
+                    .collect::<Result<Vec<SocketAddr>>>()?,
+            );
+        }
+        if let Some(endpoint) = m.value_of("zmq_block_addr") {
+            builder = builder.zmq_block_addr(zmq::parse_endpoint(endpoint)?);
+        }
+        if let Some(endpoint) = m.value_of("zmq_tx_addr") {
+            builder = builder.zmq_tx_addr(zmq::parse_endpoint(endpoint)?);
+        }
+        if let Some(addr) = m.value_of("electrum_rpc_addr") {
+            builder = builder.electrum_rpc_addr(parse_addr(addr, "Electrum RPC")?);
+        }
+        if let Some(addr) = m.value_of("http_addr") {
+            builder = match unix_socket_path(addr) {
+                Some(path) => builder.http_unix_socket(path),
+                None => builder.http_addr(parse_addr(addr, "HTTP server")?),
+            };
+        }
+        if let Some(origin) = m.value_of("cors") {
+            builder = builder.cors(origin.to_owned());
+        }
+        if let Some(addrs) = m.value_of("trusted_proxies") {
+            builder = builder.trusted_proxies(
+                addrs
+                    .split(',')
+                    .map(|addr| parse_ip(addr.trim(), "trusted proxy"))
+                    .collect::<Result<Vec<IpAddr>>>()?,
+            );
+        }
+        if let Some(path) = m.value_of("cookie_file") {
+            builder = builder.cookie_file(PathBuf::from(path));
+        }
+        if let Some(path) = m.value_of("admin_token_file") {
+            let token = fs::read_to_string(path)
+                .chain_err(|| format!("failed to read admin token from {:?}", path))?;
+            builder = builder.admin_token(token.trim().to_owned());
+        }
+        if let Some(credentials) = m.value_of("http_auth") {
+            builder = builder.http_auth(credentials.to_owned());
+        }
+        if let Some(credentials) = m.value_of("monitoring_auth") {
+            builder = builder.monitoring_auth(credentials.to_owned());
+        }
+        if let Some(addr) = m.value_of("monitoring_addr") {
+            builder = builder.monitoring_addr(parse_addr(addr, "Prometheus monitoring")?);
+        }
+        match m.values_of("network_instance") {
+            Some(names) => builder.build_instances(
+                &names
+                    .map(NetworkParams::from_name)
+                    .collect::<Result<Vec<NetworkParams>>>()?,
+            ),
+            None => Ok(vec![builder.build()?]),
+        }
     }
 
-    pub fn cookie_getter(&self) -> Arc<CookieGetter> {
-        if let Some(ref value) = self.cookie {
+    fn cookie_getter(&self, index: usize) -> Arc<CookieGetter> {
+        // a single cookie is shared by all the daemon RPC addresses
+        if let Some(value) = self.cookies.get(index).or(self.cookies.first()) {
             Arc::new(StaticCookie {
-                value: value.as_bytes().to_vec(),
+                value: Secret::new(value.expose().as_bytes().to_vec()),
             })
         } else {
             Arc::new(CookieFile {
-                daemon_dir: self.daemon_dir.clone(),
+                path: self
+                    .cookie_file
+                    .clone()
+                    .unwrap_or_else(|| self.daemon_dir.join(".cookie")),
             })
         }
     }
+
+    /// The listeners reachable from other networks (bound to a public or the unspecified address),
+    /// to be warned about on startup.
+    pub fn public_listeners(&self) -> Vec<(&'static str, SocketAddr)> {
+        vec![
+            (
+                "--http-addr",
+                Some(self.http_addr).filter(|_| self.http_unix_socket.is_none()),
+            ),
+            ("--monitoring-addr", Some(self.monitoring_addr)),
+        ]
+        .into_iter()
+        .filter_map(|(flag, addr)| addr.map(|addr| (flag, addr)))
+        .filter(|(_, addr)| is_public(&addr.ip()))
+        .collect()
+    }
+
+    /// The daemon RPC addresses (in order of preference), with their cookie getters.
+    pub fn daemon_rpc_endpoints(&self) -> Vec<(SocketAddr, Arc<CookieGetter>)> {
+        self.daemon_rpc_addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| (*addr, self.cookie_getter(i)))
+            .collect()
+    }
+
+    /// The daemon RPC connection and retry settings.
+    pub fn daemon_options(&self) -> DaemonOptions {
+        DaemonOptions {
+            connections: self.daemon_rpc_connections,
+            warmup_timeout: Duration::from_secs(self.daemon_warmup_timeout),
+            timeout: Duration::from_secs(self.daemon_timeout),
+            max_retries: self.daemon_retries,
+            batch_size: self.daemon_batch_size,
+            dedup: self.daemon_dedup,
+        }
+    }
 }
 
-struct StaticCookie {
-    value: Vec<u8>,
+/// Builds a `Config` without parsing the command line nor touching any global state (unlike
+/// `Config::from_args()`), e.g. when embedding electrs. Unset addresses and directories default
+/// to the ones of the selected network.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    verbosity: usize,
+    timestamp: bool,
+    log_file: Option<PathBuf>,
+    log_max_size: u64,
+    log_files: usize,
+    log_format: LogFormat,
+    access_log_level: LevelFilter,
+    network_params: NetworkParams,
+    address_network: Option<Network>,
+    db_dir: PathBuf,
+    db_options: DBOptions,
+    daemon_dir: Option<PathBuf>,
+    daemon_rpc_addrs: Vec<SocketAddr>,
+    daemon_rpc_connections: usize,
+    daemon_warmup_timeout: u64,
+    daemon_timeout: u64,
+    daemon_retries: Option<u32>,
+    daemon_batch_size: usize,
+    daemon_dedup: bool,
+    zmq_block_addr: Option<SocketAddr>,
+    zmq_tx_addr: Option<SocketAddr>,
+    cookies: Vec<Secret<String>>,
+    cookie_file: Option<PathBuf>,
+    electrum_rpc_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    http_unix_socket: Option<PathBuf>,
+    cors: Option<String>,
+    trusted_proxies: Vec<IpAddr>,
+    admin_token: Option<Secret<String>>,
+    http_auth: Option<Secret<String>>,
+    monitoring_auth: Option<Secret<String>>,
+    rest_keepalive: usize,
+    rest_keepalive_timeout: u64,
+    rest_workers: usize, // 0 means the # of CPUs
+    rest_queue_depth: usize,
+    rest_backpressure: Backpressure,
+    rate_limit: u32,
+    rate_limit_burst: u32,
+    rate_limit_loopback: bool,
+    reject_during_compaction: bool,
+    prefix_search_limit: usize,
+    prefix_search_scan_limit: usize,
+    monitoring_addr: Option<SocketAddr>,
+    ready_max_lag: usize,
+    jsonrpc_import: bool,
+    index_batch_size: usize,
+    index_batch_bytes: Option<usize>,
+    slow_batch_threshold: u64,
+    large_batch_threshold: usize,
+    bulk_index_threads: usize, // 0 means the # of CPUs
+    bulk_queue_depth: usize,
+    bulk_buffer_mb: usize,
+    mmap_blocks: bool,
+    progress_interval: u64,
+    tx_cache_size: usize,
+    tx_cache_disk_mb: usize,
+    auto_cache_memory: Option<usize>, // in MB
+    max_history: usize,
+    extended_db_enabled: bool,
+    prevout_enabled: bool,
+    prevout_budget: usize,
+    cache_merkle_proofs: bool,
+    index_opreturn: bool,
+    address_rpc: bool,
+    internal_api: bool,
+    export_utxos: Option<PathBuf>,
+    snapshot: Option<PathBuf>,
+    verify: bool,
+    mempool_file: Option<PathBuf>,
+    mempool_max_txs: usize,
+    block_fetch_retries: usize,
+    verification_sampling: usize,
+    compact_on_startup: bool,
+    auto_compact_interval_hours: Option<u64>,
+    auto_compact_window: Option<(u32, u32)>,
+    auto_reindex: bool,
+    no_index: bool,
+    serve_during_sync: bool,
+    ignore_tip_mismatch: bool,
+    no_wait_sync: bool,
+    wait_sync_max_gap: Option<usize>,
 }
 
-impl CookieGetter for StaticCookie {
-    fn get(&self) -> Result<Vec<u8>> {
-        Ok(self.value.clone())
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            verbosity: 0,
+            timestamp: false,
+            log_file: None,
+            log_max_size: 10 * 1024 * 1024,
+            log_files: 5,
+            log_format: LogFormat::Text,
+            access_log_level: LevelFilter::Off,
+            network_params: NetworkParams::mainnet(),
+            address_network: None,
+            db_dir: PathBuf::from("./db"),
+            db_options: DBOptions::default(),
+            daemon_dir: None,
+            daemon_rpc_addrs: vec![],
+            daemon_rpc_connections: 4,
+            daemon_warmup_timeout: 600,
+            daemon_timeout: 300,
+            daemon_retries: None,
+            daemon_batch_size: 100,
+            daemon_dedup: false,
+            zmq_block_addr: None,
+            zmq_tx_addr: None,
+            cookies: vec![],
+            cookie_file: None,
+            electrum_rpc_addr: None,
+            http_addr: None,
+            http_unix_socket: None,
+            cors: None,
+            trusted_proxies: vec![],
+            admin_token: None,
+            http_auth: None,
+            monitoring_auth: None,
+            rest_keepalive: 1000,
+            rest_keepalive_timeout: 120,
+            rest_workers: 0,
+            rest_queue_depth: 1000,
+            rest_backpressure: Backpressure::Queue,
+            rate_limit: 0,
+            rate_limit_burst: 20,
+            rate_limit_loopback: false,
+            reject_during_compaction: false,
+            prefix_search_limit: 10,
+            prefix_search_scan_limit: 1000,
+            monitoring_addr: None,
+            ready_max_lag: 2,
+            jsonrpc_import: false,
+            index_batch_size: 100,
+            index_batch_bytes: None,
+            slow_batch_threshold: 10_000,
+            large_batch_threshold: 1_000_000,
+            bulk_index_threads: 0,
+            bulk_queue_depth: 0,
+            bulk_buffer_mb: 256,
+            mmap_blocks: false,
+            progress_interval: 10,
+            tx_cache_size: 10_000,
+            tx_cache_disk_mb: 0,
+            auto_cache_memory: None,
+            max_history: 100_000,
+            extended_db_enabled: true,
+            prevout_enabled: true,
+            prevout_budget: 1000,
+            cache_merkle_proofs: false,
+            index_opreturn: false,
+            address_rpc: false,
+            internal_api: false,
+            export_utxos: None,
+            snapshot: None,
+            verify: false,
+            mempool_file: None,
+            mempool_max_txs: 0,
+            block_fetch_retries: 2,
+            verification_sampling: 0,
+            compact_on_startup: false,
+            auto_compact_interval_hours: None,
+            auto_compact_window: None,
+            auto_reindex: false,
+            no_index: false,
+            serve_during_sync: false,
+            ignore_tip_mismatch: false,
+            no_wait_sync: false,
+            wait_sync_max_gap: None,
+        }
     }
 }
 
-struct CookieFile {
-    daemon_dir: PathBuf,
-}
+impl ConfigBuilder {
+    pub fn verbosity(mut self, verbosity: usize) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
 
-impl CookieGetter for CookieFile {
-    fn get(&self) -> Result<Vec<u8>> {
-        let path = self.daemon_dir.join(".cookie");
-        let contents = fs::read(&path).chain_err(|| {
-            ErrorKind::Connection(format!("failed to read cookie from {:?}", path))
-        })?;
-        Ok(contents)
+    pub fn timestamp(mut self, timestamp: bool) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Logs are written to stderr unless a log file is set.
+    pub fn log_file(mut self, path: PathBuf) -> Self {
+        self.log_file = Some(path);
+        self
+    }
+
+    /// The size (in bytes) at which the log file is rotated.
+    pub fn log_max_size(mut self, size: u64) -> Self {
+        self.log_max_size = size;
+        self
+    }
+
+    pub fn log_files(mut self, count: usize) -> Self {
+        self.log_files = count;
+        self
+    }
+
+    pub fn log_format(mut self, format: LogFormat) -> Self {
+        self.log_format = format;
+        self
+    }
+
+    /// The level of the REST access log lines (`Off` to disable them), which aren't filtered by
+    /// the verbosity.
+    pub fn access_log_level(mut self, level: LevelFilter) -> Self {
+        self.access_log_level = level;
+        self
+    }
+
+    pub fn network(mut self, params: NetworkParams) -> Self {
+        self.network_params = params;
+        self
+    }
+
+    pub fn address_network(mut self, network: Network) -> Self {
+        self.address_network = Some(network);
+        self
+    }
+
+    /// The database is stored in a per-network subdirectory.
+    pub fn db_dir(mut self, dir: PathBuf) -> Self {
+        self.db_dir = dir;
+        self
+    }
+
+    pub fn db_options(mut self, options: DBOptions) -> Self {
+        self.db_options = options;
+        self
+    }
+
+    /// The daemon's base data directory (its per-network subdirectory is used, except on mainnet).
+    pub fn daemon_dir(mut self, dir: PathBuf) -> Self {
+        self.daemon_dir = Some(dir);
+        self
+    }
+
+    pub fn daemon_rpc_addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.daemon_rpc_addrs = addrs;
+        self
+    }
+
+    pub fn cookies(mut self, cookies: Vec<String>) -> Self {
+        self.cookies = cookies.into_iter().map(Secret::new).collect();
+        self
+    }
+
+    pub fn cookie_file(mut self, path: PathBuf) -> Self {
+        self.cookie_file = Some(path);
+        self
+    }
+
+    pub fn electrum_rpc_addr(mut self, addr: SocketAddr) -> Self {
+        self.electrum_rpc_addr = Some(addr);
+        self
+    }
+
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    pub fn http_unix_socket(mut self, path: PathBuf) -> Self {
+        self.http_unix_socket = Some(path);
+        self
+    }
+
+    /// The origin allowed to make cross-origin requests to the HTTP server (or `*` for any).
+    pub fn cors(mut self, origin: String) -> Self {
+        self.cors = Some(origin);
+        self
+    }
+
+    /// The reverse proxies trusted to set the X-Forwarded-For header of the REST requests.
+    pub fn trusted_proxies(mut self, addrs: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = addrs;
+        self
+    }
+
+    pub fn daemon_rpc_connections(mut self, connections: usize) -> Self {
+        self.daemon_rpc_connections = connections;
+        self
+    }
+
+    pub fn daemon_warmup_timeout(mut self, secs: u64) -> Self {
+        self.daemon_warmup_timeout = secs;
+        self
+    }
+
+    pub fn daemon_timeout(mut self, secs: u64) -> Self {
+        self.daemon_timeout = secs;
+        self
+    }
+
+    pub fn daemon_retries(mut self, retries: u32) -> Self {
+        self.daemon_retries = Some(retries);
+        self
+    }
+
+    pub fn daemon_batch_size(mut self, size: usize) -> Self {
+        self.daemon_batch_size = size;
+        self
+    }
+
+    pub fn daemon_dedup(mut self, enabled: bool) -> Self {
+        self.daemon_dedup = enabled;
+        self
+    }
+
+    pub fn zmq_block_addr(mut self, addr: SocketAddr) -> Self {
+        self.zmq_block_addr = Some(addr);
+        self
+    }
+
+    pub fn zmq_tx_addr(mut self, addr: SocketAddr) -> Self {
+        self.zmq_tx_addr = Some(addr);
+        self
+    }
+
+    pub fn admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(Secret::new(token));
+        self
+    }
+
+    /// The 'user:password' credentials required on every REST request.
+    pub fn http_auth(mut self, credentials: String) -> Self {
+        self.http_auth = Some(Secret::new(credentials));
+        self
+    }
+
+    /// The 'user:password' credentials required on every monitoring request.
+    pub fn monitoring_auth(mut self, credentials: String) -> Self {
+        self.monitoring_auth = Some(Secret::new(credentials));
+        self
+    }
+
+    pub fn rest_keepalive(mut self, max_requests: usize) -> Self {
+        self.rest_keepalive = max_requests;
+        self
+    }
+
+    pub fn rest_keepalive_timeout(mut self, secs: u64) -> Self {
+        self.rest_keepalive_timeout = secs;
+        self
+    }
+
+    pub fn rest_workers(mut self, threads: usize) -> Self {
+        self.rest_workers = threads;
+        self
+    }
+
+    pub fn rest_queue_depth(mut self, depth: usize) -> Self {
+        self.rest_queue_depth = depth;
+        self
+    }
+
+    pub fn rest_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.rest_backpressure = backpressure;
+        self
+    }
+
+    /// The expensive REST requests (e.g. histories) allowed per second to each client IP (the
+    /// cheap ones being allowed 10 times more), 0 disabling the rate limiting.
+    pub fn rate_limit(mut self, requests_per_sec: u32) -> Self {
+        self.rate_limit = requests_per_sec;
+        self
+    }
+
+    pub fn rate_limit_burst(mut self, requests: u32) -> Self {
+        self.rate_limit_burst = requests;
+        self
+    }
+
+    /// Whether the loopback clients are rate-limited too (they're exempt by default).
+    pub fn rate_limit_loopback(mut self, enabled: bool) -> Self {
+        self.rate_limit_loopback = enabled;
+        self
+    }
+
+    /// Whether the expensive REST requests are refused (with 503) during full DB compactions.
+    pub fn reject_during_compaction(mut self, enabled: bool) -> Self {
+        self.reject_during_compaction = enabled;
+        self
+    }
+
+    pub fn prefix_search_limit(mut self, limit: usize) -> Self {
+        self.prefix_search_limit = limit;
+        self
+    }
+
+    pub fn prefix_search_scan_limit(mut self, limit: usize) -> Self {
+        self.prefix_search_scan_limit = limit;
+        self
+    }
+
+    pub fn monitoring_addr(mut self, addr: SocketAddr) -> Self {
+        self.monitoring_addr = Some(addr);
+        self
+    }
+
+    /// The number of blocks the index may lag behind the daemon while reported as ready.
+    pub fn ready_max_lag(mut self, blocks: usize) -> Self {
+        self.ready_max_lag = blocks;
+        self
+    }
+
+    pub fn jsonrpc_import(mut self, enabled: bool) -> Self {
+        self.jsonrpc_import = enabled;
+        self
+    }
+
+    pub fn index_batch_size(mut self, size: usize) -> Self {
+        self.index_batch_size = size;
+        self
+    }
+
+    /// Indexes the blocks in batches of up to this many serialized bytes (a larger block making
+    /// its own batch), so that the memory used doesn't depend on the blocks' sizes:
+    /// `index_batch_size` then only limits the blocks fetched per request.
+    pub fn index_batch_bytes(mut self, bytes: usize) -> Self {
+        self.index_batch_bytes = Some(bytes);
+        self
+    }
+
+    pub fn slow_batch_threshold(mut self, millis: u64) -> Self {
+        self.slow_batch_threshold = millis;
+        self
+    }
+
+    pub fn large_batch_threshold(mut self, rows: usize) -> Self {
+        self.large_batch_threshold = rows;
+        self
+    }
+
+    pub fn bulk_index_threads(mut self, threads: usize) -> Self {
+        self.bulk_index_threads = threads;
+        self
+    }
+
+    pub fn bulk_queue_depth(mut self, depth: usize) -> Self {
+        self.bulk_queue_depth = depth;
+        self
+    }
+
+    pub fn bulk_buffer_mb(mut self, size_mb: usize) -> Self {
+        self.bulk_buffer_mb = size_mb;
+        self
+    }
+
+    pub fn mmap_blocks(mut self, enabled: bool) -> Self {
+        self.mmap_blocks = enabled;
+        self
+    }
+
+    pub fn progress_interval(mut self, secs: u64) -> Self {
+        self.progress_interval = secs;
+        self
+    }
+
+    pub fn tx_cache_size(mut self, size: usize) -> Self {
+        self.tx_cache_size = size;
+        self
+    }
+
+    /// The size of the on-disk transaction cache (used with `--light`), 0 disabling it.
+    pub fn tx_cache_disk_mb(mut self, size: usize) -> Self {
+        self.tx_cache_disk_mb = size;
+        self
+    }
+
+    pub fn max_history(mut self, max_history: usize) -> Self {
+        self.max_history = max_history;
+        self
+    }
+
+    pub fn auto_cache_memory(mut self, budget_mb: usize) -> Self {
+        self.auto_cache_memory = Some(budget_mb);
+        self
+    }
+
+    pub fn extended_db_enabled(mut self, enabled: bool) -> Self {
+        self.extended_db_enabled = enabled;
+        self
+    }
+
+    pub fn prevout_enabled(mut self, enabled: bool) -> Self {
+        self.prevout_enabled = enabled;
+        self
+    }
+
+    pub fn prevout_budget(mut self, budget: usize) -> Self {
+        self.prevout_budget = budget;
+        self
+    }
+
+    pub fn cache_merkle_proofs(mut self, enabled: bool) -> Self {
+        self.cache_merkle_proofs = enabled;
+        self
+    }
+
+    pub fn index_opreturn(mut self, enabled: bool) -> Self {
+        self.index_opreturn = enabled;
+        self
+    }
+
+    pub fn address_rpc(mut self, enabled: bool) -> Self {
+        self.address_rpc = enabled;
+        self
+    }
+
+    pub fn internal_api(mut self, enabled: bool) -> Self {
+        self.internal_api = enabled;
+        self
+    }
+
+    pub fn export_utxos(mut self, path: PathBuf) -> Self {
+        self.export_utxos = Some(path);
+        self
+    }
+
+    pub fn snapshot(mut self, dir: PathBuf) -> Self {
+        self.snapshot = Some(dir);
+        self
+    }
+
+    pub fn verify(mut self, enabled: bool) -> Self {
+        self.verify = enabled;
+        self
+    }
+
+    pub fn mempool_file(mut self, path: PathBuf) -> Self {
+        self.mempool_file = Some(path);
+        self
+    }
+
+    /// The # of tracked mempool transactions, the lowest fee rate ones being evicted beyond it
+    /// (0 meaning no limit).
+    pub fn mempool_max_txs(mut self, max_txs: usize) -> Self {
+        self.mempool_max_txs = max_txs;
+        self
+    }
+
+    /// The retries of the blocks fetched for REST queries, when the daemon fails transiently (e.g.
+    /// while it's still writing the block): unknown blocks are never retried.
+    pub fn block_fetch_retries(mut self, retries: usize) -> Self {
+        self.block_fetch_retries = retries;
+        self
+    }
+
+    /// Cross-checks one in `sampling` of the scripts' confirmed histories and unspent outputs
+    /// against the daemon, to detect a corrupted index (0 disables it).
+    pub fn verification_sampling(mut self, sampling: usize) -> Self {
+        self.verification_sampling = sampling;
+        self
+    }
+
+    pub fn compact_on_startup(mut self, enabled: bool) -> Self {
+        self.compact_on_startup = enabled;
+        self
+    }
+
+    pub fn auto_compact_interval_hours(mut self, hours: u64) -> Self {
+        self.auto_compact_interval_hours = Some(hours);
+        self
+    }
+
+    pub fn auto_compact_window(mut self, window: (u32, u32)) -> Self {
+        self.auto_compact_window = Some(window);
+        self
+    }
+
+    pub fn auto_reindex(mut self, enabled: bool) -> Self {
+        self.auto_reindex = enabled;
+        self
+    }
+
+    pub fn no_index(mut self, enabled: bool) -> Self {
+        self.no_index = enabled;
+        self
+    }
+
+    pub fn serve_during_sync(mut self, enabled: bool) -> Self {
+        self.serve_during_sync = enabled;
+        self
+    }
+
+    pub fn ignore_tip_mismatch(mut self, enabled: bool) -> Self {
+        self.ignore_tip_mismatch = enabled;
+        self
+    }
+
+    pub fn no_wait_sync(mut self, enabled: bool) -> Self {
+        self.no_wait_sync = enabled;
+        self
+    }
+
+    /// The # of blocks the daemon may still have to download, once done waiting for its sync.
+    pub fn wait_sync_max_gap(mut self, blocks: usize) -> Self {
+        self.wait_sync_max_gap = Some(blocks);
+        self
+    }
+
+    /// Experimental: a config per network, to serve them from a single process (for now, exactly
+    /// two of them). Each one has its own DB subdirectory, daemon and listeners, at the network's
+    /// defaults: the options that would be shared by all of them can't be set.
+    pub fn build_instances(self, networks: &[NetworkParams]) -> Result<Vec<Config>> {
+        if networks.len() != 2 {
+            bail!("--network-instance must be given twice (only 2 networks are supported)");
+        }
+        if networks[0] == networks[1] {
+            bail!("--network-instance {} is given twice", networks[0].name);
+        }
+        let shared = vec![
+            ("--daemon-rpc-addr", !self.daemon_rpc_addrs.is_empty()),
+            ("--cookie-file", self.cookie_file.is_some()),
+            ("--zmq-block-addr", self.zmq_block_addr.is_some()),
+            ("--zmq-tx-addr", self.zmq_tx_addr.is_some()),
+            ("--electrum-rpc-addr", self.electrum_rpc_addr.is_some()),
+            (
+                "--http-addr",
+                self.http_addr.is_some() || self.http_unix_socket.is_some(),
+            ),
+            ("--monitoring-addr", self.monitoring_addr.is_some()),
+            ("--address-network", self.address_network.is_some()),
+            ("--export-utxos", self.export_utxos.is_some()),
+            ("--snapshot", self.snapshot.is_some()),
+            ("--mempool-file", self.mempool_file.is_some()),
+        ];
+        if let Some((flag, _)) = shared.into_iter().find(|(_, given)| *given) {
+            bail!("{} can't be used with --network-instance", flag);
+        }
+        networks
+            .iter()
+            .map(|params| self.clone().network(*params).build())
+            .collect()
+    }
+
+    pub fn build(self) -> Result<Config> {
+        let params = self.network_params;
+        let local_addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+
+        let daemon_rpc_addrs = if self.daemon_rpc_addrs.is_empty() {
+            vec![local_addr(params.daemon_port)]
+        } else {
+            self.daemon_rpc_addrs
+        };
+        let electrum_rpc_addr = self
+            .electrum_rpc_addr
+            .unwrap_or(local_addr(params.electrum_port));
+        let http_addr = self.http_addr.unwrap_or(local_addr(params.http_port));
+        let monitoring_addr = self
+            .monitoring_addr
+            .unwrap_or(local_addr(params.monitoring_port));
+        // no Electrum RPC server is run, so `electrum_rpc_addr` isn't listened on
+        let mut listeners = vec![("--monitoring-addr", monitoring_addr)];
+        match self.http_unix_socket {
+            Some(ref path) if self.http_addr.is_some() => {
+                bail!("--http-addr is given as both {} and {:?}", http_addr, path)
+            }
+            Some(ref path) if path.file_name().is_none() => {
+                bail!("--http-addr unix:{:?} isn't a socket file path", path)
+            }
+            Some(_) => (),
+            None => listeners.push(("--http-addr", http_addr)),
+        }
+        check_listeners(&listeners)?;
+
+        let mut daemon_dir = match self.daemon_dir {
+            Some(dir) => dir,
+            None => home_dir().chain_err(|| "no homedir")?.join(".viacoin"),
+        };
+        if let Some(subdir) = params.daemon_subdir {
+            daemon_dir.push(subdir);
+        }
+        if !self.cookies.is_empty() && self.cookie_file.is_some() {
+            bail!("--cookie and --cookie-file can't be used together");
+        }
+        // it holds the cookie file and the blk*.dat files to bulk index
+        let cookie_given = !self.cookies.is_empty() || self.cookie_file.is_some();
+        if (!cookie_given || !self.jsonrpc_import) && !daemon_dir.is_dir() {
+            bail!("daemon directory {:?} not found", daemon_dir);
+        }
+        if self.cookies.len() > 1 && self.cookies.len() != daemon_rpc_addrs.len() {
+            bail!(
+                "{} cookies given for {} daemon RPC addresses",
+                self.cookies.len(),
+                daemon_rpc_addrs.len()
+            );
+        }
+
+        if let Some(ref origin) = self.cors {
+            // it's sent as is in the Access-Control-Allow-Origin header
+            if origin.is_empty() || !origin.bytes().all(|b| b > b' ' && b < 0x7f) {
+                bail!("invalid CORS origin: {:?}", origin);
+            }
+        }
+        for &(credentials, flag) in &[
+            (&self.http_auth, "--http-auth"),
+            (&self.monitoring_auth, "--monitoring-auth"),
+        ] {
+            if let Some(ref credentials) = *credentials {
+                // the user name can't have a colon, unlike the password
+                match credentials.expose().find(':') {
+                    Some(colon) if colon > 0 => (),
+                    _ => bail!("{} must be given as 'user:password'", flag),
+                }
+            }
+        }
+        if self.http_auth.is_some() && self.admin_token.is_some() {
+            // both are sent in the Authorization header
+            bail!("--http-auth can't be used with --admin-token-file");
+        }
+        if let Some(ref token) = self.admin_token {
+            let token = token.expose();
+            // it's sent in the Authorization header
+            if token.len() < ADMIN_TOKEN_MIN_LEN || !token.bytes().all(|b| b > b' ' && b < 0x7f) {
+                bail!(
+                    "the admin token must have at least {} (visible ASCII) characters",
+                    ADMIN_TOKEN_MIN_LEN
+                );
+            }
+        }
+
+        let auto_cache_sizes = match self.auto_cache_memory {
+            Some(budget_mb) => {
+                if budget_mb < AUTO_CACHE_MIN_MB {
+                    bail!(
+                        "--auto-cache-memory must be at least {} MB",
+                        AUTO_CACHE_MIN_MB
+                    );
+                }
+                if let Some(total_mb) = physical_memory_mb() {
+                    if budget_mb > total_mb {
+                        bail!(
+                            "--auto-cache-memory of {} MB exceeds the system's memory ({} MB)",
+                            budget_mb,
+                            total_mb
+                        );
+                    }
+                }
+                Some(CacheSizes::split(budget_mb, !self.extended_db_enabled))
+            }
+            None => None,
+        };
+
+        if self.export_utxos.is_some() && !self.extended_db_enabled {
+            bail!("exporting the UTXO set requires the extended DB (can't be used with --light)");
+        }
+        if self.export_utxos.is_some() && (self.snapshot.is_some() || self.verify) {
+            bail!("--export-utxos can't be used with --snapshot nor --verify");
+        }
+        if self.no_index {
+            // the DB is never written to
+            for (enabled, flag) in &[
+                (self.cache_merkle_proofs, "--cache-merkle-proofs"),
+                (self.compact_on_startup, "--compact-on-startup"),
+                (
+                    self.auto_compact_interval_hours.is_some(),
+                    "--auto-compact-interval-hours",
+                ),
+                (self.auto_reindex, "--auto-reindex"),
+                (self.tx_cache_disk_mb > 0, "--tx-cache-disk-mb"),
+                (self.serve_during_sync, "--serve-during-sync"),
+            ] {
+                if *enabled {
+                    bail!("{} can't be used with --no-index", flag);
+                }
+            }
+        }
+
+        if self.serve_during_sync {
+            // the blocks are indexed (and served) one batch at a time, not from the blk*.dat files
+            if !self.jsonrpc_import {
+                bail!("--serve-during-sync requires --jsonrpc-import");
+            }
+            if self.compact_on_startup {
+                bail!("--serve-during-sync can't be used with --compact-on-startup");
+            }
+        }
+
+        if self.index_batch_bytes == Some(0) {
+            bail!("--index-batch-bytes must be positive");
+        }
+
+        if self.auto_compact_interval_hours == Some(0) {
+            bail!("--auto-compact-interval-hours must be positive");
+        }
+        if self.auto_compact_window.is_some() && self.auto_compact_interval_hours.is_none() {
+            bail!("--auto-compact-window requires --auto-compact-interval-hours");
+        }
+
+        if self.no_wait_sync && self.wait_sync_max_gap.is_some() {
+            bail!("--wait-sync-max-gap can't be used with --no-wait-sync");
+        }
+
+        if self.rate_limit > 0 && self.rate_limit_burst == 0 {
+            bail!("--rate-limit-burst must allow at least one request");
+        }
+
+        if self.daemon_rpc_connections == 0 {
+            bail!("at least one daemon RPC connection is needed");
+        }
+
+        if self.daemon_timeout == 0 {
+            bail!("the daemon timeout must be positive");
+        }
+
+        if self.daemon_batch_size == 0 {
+            bail!("the daemon batch size must be positive");
+        }
+
+        if self.rest_queue_depth == 0 {
+            bail!("the REST queue depth must be positive");
+        }
+
+        if self.prevout_budget == 0 {
+            bail!("the prevout budget must be positive (see --disable-prevout)");
+        }
+
+        if self.progress_interval == 0 {
+            bail!("the progress interval must be positive");
+        }
+
+        if self.prefix_search_limit == 0 || self.prefix_search_scan_limit < self.prefix_search_limit
+        {
+            bail!("the prefix search scan limit must be at least its (positive) results limit");
+        }
+
+        if self.log_max_size == 0 || self.log_files == 0 {
+            bail!("log files must have a non-zero size and count");
+        }
+
+        Ok(Config {
+            verbosity: self.verbosity,
+            timestamp: self.timestamp,
+            log_file: self.log_file,
+            log_max_size: self.log_max_size,
+            log_files: self.log_files,
+            log_format: self.log_format,
+            access_log_level: self.access_log_level,
+            network_params: params,
+            network_type: params.network,
+            address_network: self.address_network.unwrap_or(params.network),
+            db_path: self.db_dir.join(params.name),
+            db_options: DBOptions {
+                cache_mb: auto_cache_sizes.map_or(self.db_options.cache_mb, |s| s.db_cache_mb),
+                ..self.db_options
+            },
+            daemon_dir,
+            daemon_rpc_addrs,
+            daemon_rpc_connections: self.daemon_rpc_connections,
+            daemon_warmup_timeout: self.daemon_warmup_timeout,
+            daemon_timeout: self.daemon_timeout,
+            daemon_retries: self.daemon_retries,
+            daemon_batch_size: self.daemon_batch_size,
+            daemon_dedup: self.daemon_dedup,
+            zmq_block_addr: self.zmq_block_addr,
+            zmq_tx_addr: self.zmq_tx_addr,
+            cookies: self.cookies,
+            cookie_file: self.cookie_file,
+            electrum_rpc_addr,
+            http_addr,
+            http_unix_socket: self.http_unix_socket,
+            cors: self.cors,
+            trusted_proxies: self.trusted_proxies,
+            admin_token: self
+                .admin_token
+                .map(|token| AdminToken(token.expose().clone())),
+            http_auth: self
+                .http_auth
+                .map(|credentials| HttpAuth(credentials.expose().clone())),
+            rest_keepalive: self.rest_keepalive,
+            rest_keepalive_timeout: self.rest_keepalive_timeout,
+            rest_workers: match self.rest_workers {
+                0 => num_cpus::get(),
+                threads => threads,
+            },
+            rest_queue_depth: self.rest_queue_depth,
+            rest_backpressure: self.rest_backpressure,
+            rate_limit: self.rate_limit,
+            rate_limit_burst: self.rate_limit_burst,
+            rate_limit_loopback: self.rate_limit_loopback,
+            reject_during_compaction: self.reject_during_compaction,
+            prefix_search_limit: self.prefix_search_limit,
+            prefix_search_scan_limit: self.prefix_search_scan_limit,
+            monitoring_addr,
+            monitoring_auth: self
+                .monitoring_auth
+                .map(|credentials| HttpAuth(credentials.expose().clone())),
+            ready_max_lag: self.ready_max_lag,
+            jsonrpc_import: self.jsonrpc_import,
+            index_batch_size: self.index_batch_size,
+            index_batch_bytes: self.index_batch_bytes,
+            slow_batch_threshold: self.slow_batch_threshold,
+            large_batch_threshold: self.large_batch_threshold,
+            bulk_index_threads: match self.bulk_index_threads {
+                0 => num_cpus::get(),
+                threads => threads,
+            },
+            bulk_queue_depth: self.bulk_queue_depth,
+            bulk_buffer_mb: self.bulk_buffer_mb,
+            mmap_blocks: self.mmap_blocks,
+            progress_interval: self.progress_interval,
+            tx_cache_size: auto_cache_sizes
+                .and_then(|s| s.tx_cache_size)
+                .unwrap_or(self.tx_cache_size),
+            tx_cache_disk_mb: self.tx_cache_disk_mb,
+            history_cache_size: auto_cache_sizes
+                .map_or(HISTORY_CACHE_SIZE, |s| s.history_cache_size),
+            auto_cache_sizes,
+            max_history: self.max_history,
+            extended_db_enabled: self.extended_db_enabled,
+            prevout_enabled: self.prevout_enabled,
+            prevout_budget: self.prevout_budget,
+            cache_merkle_proofs: self.cache_merkle_proofs,
+            index_opreturn: self.index_opreturn,
+            address_rpc: self.address_rpc,
+            internal_api: self.internal_api,
+            export_utxos: self.export_utxos,
+            snapshot: self.snapshot,
+            verify: self.verify,
+            mempool_file: self.mempool_file,
+            mempool_max_txs: self.mempool_max_txs,
+            block_fetch_retries: self.block_fetch_retries,
+            verification_sampling: self.verification_sampling,
+            compact_on_startup: self.compact_on_startup,
+            auto_compact_interval_hours: self.auto_compact_interval_hours,
+            auto_compact_window: self.auto_compact_window,
+            auto_reindex: self.auto_reindex,
+            no_index: self.no_index,
+            serve_during_sync: self.serve_during_sync,
+            ignore_tip_mismatch: self.ignore_tip_mismatch,
+            no_wait_sync: self.no_wait_sync,
+            wait_sync_max_gap: self.wait_sync_max_gap,
+        })
+    }
+}
+
+fn parse_log_format(name: &str) -> Result<LogFormat> {
+    Ok(match name {
+        "text" => LogFormat::Text,
+        "json" => LogFormat::Json,
+        _ => bail!("unsupported log format: {:?}", name),
+    })
+}
+
+fn parse_backpressure(name: &str) -> Result<Backpressure> {
+    Ok(match name {
+        "queue" => Backpressure::Queue,
+        "reject" => Backpressure::Reject,
+        _ => bail!("unsupported backpressure policy: {:?}", name),
+    })
+}
+
+fn parse_log_level(name: &str) -> Result<LevelFilter> {
+    name.parse()
+        .map_err(|_| format!("unsupported access log level: {:?}", name).into())
+}
+
+fn parse_compression(name: &str) -> Result<Compression> {
+    Compression::from_name(name).chain_err(|| format!("unsupported DB compression: {:?}", name))
+}
+
+// Fails (naming both options) if two listeners would bind the same port of the same address,
+// or of any address if one of them listens on all of them (e.g. 0.0.0.0). Port 0 (any free
+// port) never collides.
+fn check_listeners(listeners: &[(&str, SocketAddr)]) -> Result<()> {
+    for (i, (name, addr)) in listeners.iter().enumerate() {
+        for (other_name, other_addr) in &listeners[i + 1..] {
+            let same_ip = addr.ip() == other_addr.ip()
+                || addr.ip().is_unspecified()
+                || other_addr.ip().is_unspecified();
+            if addr.port() != 0 && addr.port() == other_addr.port() && same_ip {
+                bail!(
+                    "{} ({}) and {} ({}) would listen on the same port",
+                    name,
+                    addr,
+                    other_name,
+                    other_addr
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether the hosts of other networks may reach a listener bound to this address: the unspecified
+// address binds all the interfaces (the public ones included), unlike the loopback, private-range
+// and link-local addresses.
+fn is_public(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local()),
+        IpAddr::V6(ref ip) => {
+            let first = ip.segments()[0];
+            // unique local (fc00::/7) and link-local (fe80::/10) addresses
+            !(ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+// The "unix:<path>" form of a listening address, for a Unix domain socket.
+fn unix_socket_path(addr: &str) -> Option<PathBuf> {
+    if addr.starts_with("unix:") {
+        Some(PathBuf::from(&addr["unix:".len()..]))
+    } else {
+        None
+    }
+}
+
+fn parse_addr(addr: &str, name: &str) -> Result<SocketAddr> {
+    addr.parse()
+        .chain_err(|| format!("invalid {} address: {:?}", name, addr))
+}
+
+// "start-end" UTC hours, e.g. "2-5" (from 2:00 to 5:00) or "22-4" (around midnight)
+fn parse_hour_window(window: &str) -> Result<(u32, u32)> {
+    let hours: Vec<&str> = window.splitn(2, '-').collect();
+    let parse = |hour: &str| hour.parse::<u32>().ok().filter(|hour| *hour < 24);
+    match (
+        hours.get(0).and_then(|h| parse(h)),
+        hours.get(1).and_then(|h| parse(h)),
+    ) {
+        (Some(start), Some(end)) if start != end => Ok((start, end)),
+        _ => bail!(
+            "invalid --auto-compact-window: {:?} (expected 'start-end' hours, e.g. '2-5')",
+            window
+        ),
+    }
+}
+
+fn parse_genesis_hash(hash: &str) -> Result<Sha256dHash> {
+    Sha256dHash::from_hex(hash).chain_err(|| format!("invalid --custom-network hash: {:?}", hash))
+}
+
+// "daemon,http,monitoring" ports, e.g. "18443,3010,24230"
+fn parse_ports(ports: &str) -> Result<(u16, u16, u16)> {
+    let ports: Vec<Option<u16>> = ports.split(',').map(|port| port.parse().ok()).collect();
+    match ports.as_slice() {
+        [Some(daemon), Some(http), Some(monitoring)] => Ok((*daemon, *http, *monitoring)),
+        _ => bail!(
+            "invalid --custom-network-ports: expected 'DAEMON,HTTP,MONITORING' ports, e.g. '18443,3010,24230'"
+        ),
+    }
+}
+
+fn parse_ip(addr: &str, name: &str) -> Result<IpAddr> {
+    addr.parse()
+        .chain_err(|| format!("invalid {} address: {:?}", name, addr))
+}
+
+struct StaticCookie {
+    value: Secret<Vec<u8>>,
+}
+
+impl CookieGetter for StaticCookie {
+    fn get(&self) -> Result<Secret<Vec<u8>>> {
+        Ok(self.value.clone())
+    }
+}
+
+// Re-read on each request, as the daemon writes a new .cookie when restarted.
+struct CookieFile {
+    path: PathBuf,
+}
+
+impl CookieGetter for CookieFile {
+    fn get(&self) -> Result<Secret<Vec<u8>>> {
+        let mut contents = fs::read(&self.path).chain_err(|| {
+            ErrorKind::Connection(format!("failed to read cookie from {:?}", self.path))
+        })?;
+        // the daemon's has no trailing newline, unlike most hand-written ones
+        let len = contents
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |pos| pos + 1);
+        contents.truncate(len);
+        Ok(Secret::new(contents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use config::{
+        check_listeners, is_public, parse_genesis_hash, parse_hour_window, parse_ports,
+        physical_memory_mb, unix_socket_path, CacheSizes, ConfigBuilder, HISTORY_CACHE_SIZE,
+    };
+    use errors::*;
+    use network::NetworkParams;
+    use rest::Backpressure;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use store::DBOptions;
+    use testutil::temp_path;
+
+    // doesn't require the daemon directory to exist
+    fn remote_daemon() -> ConfigBuilder {
+        ConfigBuilder::default()
+            .daemon_dir(PathBuf::from("/nonexistent"))
+            .jsonrpc_import(true)
+            .cookies(vec!["user:password".to_owned()])
+    }
+
+    #[test]
+    fn test_network_defaults() {
+        for (params, name, daemon_dir, daemon_port, http_port, monitoring_port) in vec![
+            (
+                NetworkParams::mainnet(),
+                "mainnet",
+                "/nonexistent",
+                5222,
+                3000,
+                4224,
+            ),
+            (
+                NetworkParams::testnet(),
+                "testnet",
+                "/nonexistent/testnet3",
+                25222,
+                3001,
+                14224,
+            ),
+            (
+                NetworkParams::regtest(),
+                "regtest",
+                "/nonexistent/regtest",
+                25222,
+                3002,
+                24224,
+            ),
+        ] {
+            let config = remote_daemon().network(params).build().unwrap();
+            assert_eq!(config.network_params, params);
+            assert_eq!(config.network_type, params.network);
+            assert_eq!(config.address_network, params.network);
+            assert_eq!(config.db_path, PathBuf::from("./db").join(name));
+            assert_eq!(config.daemon_dir, PathBuf::from(daemon_dir));
+            assert_eq!(config.daemon_rpc_addrs.len(), 1);
+            assert_eq!(config.daemon_rpc_addrs[0].port(), daemon_port);
+            assert_eq!(config.http_addr.port(), http_port);
+            assert_eq!(config.monitoring_addr.port(), monitoring_port);
+            assert_eq!(config.daemon_rpc_endpoints().len(), 1);
+            assert!(config.bulk_index_threads > 0);
+            assert_eq!(config.bulk_queue_depth, 0);
+            assert!(config.rest_workers > 0);
+            assert_eq!(config.rest_backpressure, Backpressure::Queue);
+            assert!(!config.compact_on_startup); // it's expensive
+            assert!(config.auto_compact_interval_hours.is_none());
+            assert!(!config.auto_reindex); // it's destructive
+        }
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let config = remote_daemon()
+            .admin_token("0123456789abcdef".to_owned())
+            .build()
+            .unwrap();
+        let token = config.admin_token.as_ref().unwrap();
+        assert!(token.matches("0123456789abcdef"));
+        assert!(!token.matches("0123456789abcdeF"));
+        assert!(!token.matches("0123456789abcde"));
+        assert!(!token.matches(""));
+        assert!(!format!("{:?}", config).contains("0123456789abcdef"));
+        let config = remote_daemon().build().unwrap();
+        assert!(!format!("{:?}", config).contains("user:password"));
+        assert!(remote_daemon().build().unwrap().admin_token.is_none());
+    }
+
+    #[test]
+    fn test_http_auth() {
+        let config = remote_daemon()
+            .http_auth("user:pass:word".to_owned())
+            .monitoring_auth("prometheus:secret".to_owned())
+            .build()
+            .unwrap();
+        let auth = config.http_auth.as_ref().unwrap();
+        let basic = |credentials: &str| format!("Basic {}", base64::encode(credentials));
+        assert!(auth.matches(Some(&basic("user:pass:word"))));
+        assert!(auth.matches(Some(&format!(
+            "basic  {}",
+            base64::encode("user:pass:word")
+        ))));
+        assert!(!auth.matches(Some(&basic("user:pass:wore"))));
+        assert!(!auth.matches(Some(&basic("user:pass"))));
+        assert!(!auth.matches(Some("Basic not base64")));
+        assert!(!auth.matches(Some("Bearer user:pass:word")));
+        assert!(!auth.matches(Some("Basic")));
+        assert!(!auth.matches(None));
+        let auth = config.monitoring_auth.as_ref().unwrap();
+        assert!(auth.matches(Some(&basic("prometheus:secret"))));
+        assert!(!auth.matches(Some(&basic("user:pass:word"))));
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("pass:word") && !debug.contains("secret"));
+
+        let config = remote_daemon().build().unwrap();
+        assert!(config.http_auth.is_none() && config.monitoring_auth.is_none());
+    }
+
+    #[test]
+    fn test_auto_cache_memory() {
+        let config = remote_daemon().tx_cache_size(123).build().unwrap();
+        assert_eq!(config.auto_cache_sizes, None);
+        assert_eq!(config.db_options.cache_mb, 0);
+        assert_eq!(config.tx_cache_size, 123);
+        assert_eq!(config.history_cache_size, HISTORY_CACHE_SIZE);
+
+        // overrides the individual sizes
+        let config = remote_daemon()
+            .db_options(DBOptions {
+                cache_mb: 4096,
+                ..DBOptions::default()
+            })
+            .tx_cache_size(123)
+            .auto_cache_memory(100)
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.auto_cache_sizes,
+            Some(CacheSizes {
+                budget_mb: 100,
+                db_cache_mb: 80,
+                history_cache_size: 5120,
+                tx_cache_size: None,
+            })
+        );
+        assert_eq!(config.db_options.cache_mb, 80);
+        assert_eq!(config.tx_cache_size, 123); // unused without --light
+        assert_eq!(config.history_cache_size, 5120);
+
+        let config = remote_daemon()
+            .extended_db_enabled(false)
+            .auto_cache_memory(100)
+            .build()
+            .unwrap();
+        assert_eq!(config.db_options.cache_mb, 50);
+        assert_eq!(config.tx_cache_size, 30_720);
+        assert_eq!(config.history_cache_size, 5120);
+
+        assert!(remote_daemon().auto_cache_memory(1).build().is_err());
+        if let Some(total_mb) = physical_memory_mb() {
+            let budget = remote_daemon().auto_cache_memory(total_mb + 1);
+            assert!(budget.build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_cookie_secrets() {
+        let builder = remote_daemon()
+            .cookies(vec!["user:s3cr3t".to_owned()])
+            .admin_token("0123456789abcdef".to_owned());
+        for debug in vec![
+            format!("{:?}", builder),
+            format!("{:?}", builder.clone().build().unwrap()),
+        ] {
+            assert!(!debug.contains("s3cr3t"), "{}", debug);
+            assert!(!debug.contains("0123456789abcdef"), "{}", debug);
+        }
+        let config = builder.build().unwrap();
+        assert_eq!(config.cookies[0].expose(), "user:s3cr3t");
+        let cookie = config.daemon_rpc_endpoints()[0].1.get().unwrap();
+        assert_eq!(&cookie.expose()[..], b"user:s3cr3t");
+        assert_eq!(format!("{} {:?}", cookie, cookie), "<redacted> <redacted>");
+
+        // re-read on each request (without the trailing newline)
+        let path = temp_path("cookie");
+        fs::write(&path, "user:s3cr3t\n").unwrap();
+        let config = remote_daemon()
+            .cookies(vec![])
+            .cookie_file(path.clone())
+            .build()
+            .unwrap();
+        let getter = config.daemon_rpc_endpoints()[0].1.clone();
+        assert_eq!(&getter.get().unwrap().expose()[..], b"user:s3cr3t");
+        fs::write(&path, "user:rotated").unwrap();
+        assert_eq!(&getter.get().unwrap().expose()[..], b"user:rotated");
+        assert!(!format!("{:?}", config).contains("rotated"));
+        fs::remove_file(&path).unwrap();
+        match getter.get() {
+            Err(Error(ErrorKind::Connection(msg), _)) => assert!(msg.contains("failed to read")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // either one
+        assert!(remote_daemon().cookie_file(path).build().is_err());
+    }
+
+    #[test]
+    fn test_listener_collisions() {
+        let addr = |s: &str| s.parse().unwrap();
+        let err = remote_daemon()
+            .http_addr(addr("127.0.0.1:3000"))
+            .monitoring_addr(addr("127.0.0.1:3000"))
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "--monitoring-addr (127.0.0.1:3000) and --http-addr (127.0.0.1:3000) would listen on the same port"
+        );
+        // nothing listens on the Electrum RPC address
+        assert!(remote_daemon()
+            .http_addr(addr("127.0.0.1:3000"))
+            .electrum_rpc_addr(addr("127.0.0.1:3000"))
+            .build()
+            .is_ok());
+        assert!(check_listeners(&[
+            ("--http-addr", addr("0.0.0.0:3000")),
+            ("--monitoring-addr", addr("127.0.0.1:3000")),
+        ])
+        .is_err());
+        assert!(check_listeners(&[
+            ("--http-addr", addr("127.0.0.1:3000")),
+            ("--monitoring-addr", addr("127.0.0.2:3000")),
+        ])
+        .is_ok());
+        // any free port
+        assert!(check_listeners(&[
+            ("--http-addr", addr("127.0.0.1:0")),
+            ("--monitoring-addr", addr("127.0.0.1:0")),
+        ])
+        .is_ok());
+        // the network defaults don't collide
+        for params in NetworkParams::all() {
+            assert!(remote_daemon().network(params).build().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_http_unix_socket() {
+        assert_eq!(
+            unix_socket_path("unix:/run/electrs/rest.sock"),
+            Some(PathBuf::from("/run/electrs/rest.sock"))
+        );
+        assert_eq!(unix_socket_path("127.0.0.1:3000"), None);
+
+        let addr = |s: &str| s.parse().unwrap();
+        let path = PathBuf::from("/run/electrs/rest.sock");
+        let config = remote_daemon()
+            .http_unix_socket(path.clone())
+            .build()
+            .unwrap();
+        assert_eq!(config.http_unix_socket, Some(path.clone()));
+        // the TCP port is left free
+        assert!(remote_daemon()
+            .http_unix_socket(path.clone())
+            .monitoring_addr(addr("127.0.0.1:3000"))
+            .build()
+            .is_ok());
+        let err = remote_daemon()
+            .http_unix_socket(path.clone())
+            .http_addr(addr("127.0.0.1:3000"))
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("given as both"));
+        assert!(remote_daemon()
+            .http_unix_socket(PathBuf::from("/"))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_auto_compaction() {
+        assert_eq!(parse_hour_window("2-5").unwrap(), (2, 5));
+        assert_eq!(parse_hour_window("22-4").unwrap(), (22, 4));
+        assert_eq!(parse_hour_window("0-23").unwrap(), (0, 23));
+        for window in &["", "2", "2-", "-5", "5-5", "2-24", "a-b", "2-5-7"] {
+            assert!(parse_hour_window(window).is_err(), "{}", window);
+        }
+
+        let config = remote_daemon()
+            .auto_compact_interval_hours(24)
+            .auto_compact_window((2, 5))
+            .build()
+            .unwrap();
+        assert_eq!(config.auto_compact_interval_hours, Some(24));
+        assert_eq!(config.auto_compact_window, Some((2, 5)));
+        assert!(remote_daemon()
+            .auto_compact_interval_hours(0)
+            .build()
+            .is_err());
+        assert!(remote_daemon().auto_compact_window((2, 5)).build().is_err());
+        assert!(remote_daemon()
+            .auto_compact_interval_hours(24)
+            .no_index(true)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_public_listeners() {
+        for (ip, public) in &[
+            ("127.0.0.1", false),
+            ("127.1.2.3", false),
+            ("10.0.0.1", false),
+            ("172.16.5.4", false),
+            ("192.168.1.2", false),
+            ("169.254.0.1", false),
+            ("::1", false),
+            ("fd12:3456::1", false),
+            ("fe80::1", false),
+            ("0.0.0.0", true),
+            ("::", true),
+            ("172.32.0.1", true),
+            ("8.8.8.8", true),
+            ("2001:db8::1", true),
+        ] {
+            assert_eq!(is_public(&ip.parse().unwrap()), *public, "{}", ip);
+        }
+
+        let addr = |s: &str| s.parse().unwrap();
+        let config = remote_daemon().build().unwrap();
+        assert!(config.public_listeners().is_empty()); // the defaults are local
+        let config = remote_daemon()
+            .http_addr(addr("0.0.0.0:3000"))
+            .build()
+            .unwrap();
+        assert_eq!(config.public_listeners().len(), 1);
+        let config = remote_daemon()
+            .http_unix_socket(PathBuf::from("/run/electrs/rest.sock"))
+            .build()
+            .unwrap();
+        assert!(config.public_listeners().is_empty());
+        let config = remote_daemon()
+            .http_addr(addr("0.0.0.0:3000"))
+            .electrum_rpc_addr(addr("192.168.1.2:50001"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.public_listeners(),
+            vec![("--http-addr", addr("0.0.0.0:3000"))]
+        );
+        // no Electrum RPC server is run
+        let config = remote_daemon()
+            .electrum_rpc_addr(addr("0.0.0.0:50001"))
+            .build()
+            .unwrap();
+        assert!(config.public_listeners().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        assert!(remote_daemon()
+            .http_addr(addr)
+            .monitoring_addr(addr)
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .daemon_rpc_addrs(vec![addr, "127.0.0.1:1235".parse().unwrap()])
+            .cookies(vec!["a:b".to_owned(), "c:d".to_owned(), "e:f".to_owned()])
+            .build()
+            .is_err());
+        // the cookie file and blk*.dat files are read from the daemon directory
+        assert!(remote_daemon().jsonrpc_import(false).build().is_err());
+        assert!(remote_daemon().cookies(vec![]).build().is_err());
+        assert!(remote_daemon().log_files(0).build().is_err());
+        assert!(remote_daemon().daemon_rpc_connections(0).build().is_err());
+        assert!(remote_daemon().daemon_batch_size(0).build().is_err());
+        assert!(remote_daemon().rest_queue_depth(0).build().is_err());
+        assert!(remote_daemon().prevout_budget(0).build().is_err());
+        assert!(remote_daemon().index_batch_bytes(0).build().is_err());
+        assert!(remote_daemon().prefix_search_limit(0).build().is_err());
+        assert!(remote_daemon()
+            .prefix_search_limit(100)
+            .prefix_search_scan_limit(10)
+            .build()
+            .is_err());
+        assert!(remote_daemon().log_max_size(0).build().is_err());
+        assert!(remote_daemon().cors("".to_owned()).build().is_err());
+        assert!(remote_daemon()
+            .cors("https://a.example, https://b.example".to_owned())
+            .build()
+            .is_err());
+        assert!(remote_daemon().cors("*".to_owned()).build().is_ok());
+        assert!(remote_daemon()
+            .admin_token("short".to_owned())
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .admin_token("0123456789 abcdef".to_owned())
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .http_auth("user".to_owned())
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .monitoring_auth(":password".to_owned())
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .http_auth("user:password".to_owned())
+            .admin_token("0123456789abcdef".to_owned())
+            .build()
+            .is_err());
+        let export = PathBuf::from("utxos.json");
+        assert!(remote_daemon()
+            .export_utxos(export.clone())
+            .extended_db_enabled(false)
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .export_utxos(export.clone())
+            .verify(true)
+            .build()
+            .is_err());
+        assert!(remote_daemon().export_utxos(export).build().is_ok());
+        assert!(remote_daemon()
+            .no_index(true)
+            .cache_merkle_proofs(true)
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .no_index(true)
+            .auto_reindex(true)
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .no_index(true)
+            .tx_cache_disk_mb(100)
+            .build()
+            .is_err());
+        assert!(remote_daemon().no_index(true).build().is_ok());
+        assert!(remote_daemon()
+            .serve_during_sync(true)
+            .no_index(true)
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .serve_during_sync(true)
+            .jsonrpc_import(false)
+            .daemon_dir(env::temp_dir())
+            .build()
+            .is_err());
+        assert!(remote_daemon()
+            .serve_during_sync(true)
+            .compact_on_startup(true)
+            .build()
+            .is_err());
+        assert!(remote_daemon().serve_during_sync(true).build().is_ok());
+        assert!(remote_daemon()
+            .rate_limit(10)
+            .rate_limit_burst(0)
+            .build()
+            .is_err());
+        assert!(remote_daemon().rate_limit_burst(0).build().is_ok());
+        assert!(remote_daemon()
+            .no_wait_sync(true)
+            .wait_sync_max_gap(10)
+            .build()
+            .is_err());
+        assert!(remote_daemon().wait_sync_max_gap(10).build().is_ok());
+        assert!(remote_daemon().daemon_timeout(0).build().is_err());
+        assert!(remote_daemon().progress_interval(0).build().is_err());
+        assert!(remote_daemon().daemon_retries(0).build().is_ok());
+        assert!(remote_daemon()
+            .jsonrpc_import(false)
+            .daemon_dir(env::temp_dir())
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_network_instances() {
+        let networks = [NetworkParams::mainnet(), NetworkParams::testnet()];
+        let configs = remote_daemon().build_instances(&networks).unwrap();
+        assert_eq!(configs.len(), 2);
+        for (config, params) in configs.iter().zip(networks.iter()) {
+            assert_eq!(config.network_params, *params);
+            assert_eq!(config.db_path, PathBuf::from("./db").join(params.name));
+            assert_eq!(config.daemon_rpc_addrs[0].port(), params.daemon_port);
+            assert_eq!(config.http_addr.port(), params.http_port);
+            assert_eq!(config.monitoring_addr.port(), params.monitoring_port);
+        }
+        assert_eq!(
+            configs[1].daemon_dir,
+            PathBuf::from("/nonexistent/testnet3")
+        );
+
+        assert!(remote_daemon().build_instances(&networks[..1]).is_err());
+        let same = [NetworkParams::testnet(), NetworkParams::testnet()];
+        assert!(remote_daemon().build_instances(&same).is_err());
+        // a single address can't serve both networks
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        assert!(remote_daemon()
+            .http_addr(addr)
+            .build_instances(&networks)
+            .is_err());
+        assert!(remote_daemon()
+            .daemon_rpc_addrs(vec![addr])
+            .build_instances(&networks)
+            .is_err());
+    }
+
+    #[test]
+    fn test_custom_network() {
+        let hash = "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee";
+        let genesis_hash = parse_genesis_hash(hash).unwrap();
+        assert_eq!(genesis_hash.be_hex_string(), hash);
+        assert!(parse_genesis_hash("00").is_err());
+        assert_eq!(
+            parse_ports("18443,3010,24230").unwrap(),
+            (18443, 3010, 24230)
+        );
+        for ports in &[
+            "",
+            "18443",
+            "18443,3010",
+            "18443,3010,24230,1",
+            "a,b,c",
+            "1,2,70000",
+        ] {
+            assert!(parse_ports(ports).is_err(), "{}", ports);
+        }
+
+        let params = NetworkParams::custom(
+            NetworkParams::regtest(),
+            genesis_hash,
+            Some((18443, 3010, 24230)),
+        );
+        let config = remote_daemon().network(params).build().unwrap();
+        assert_eq!(config.network_params.genesis_hash, genesis_hash);
+        // kept apart from the base network's DB, but using its daemon directory
+        assert_eq!(config.db_path, PathBuf::from("./db/custom"));
+        assert_eq!(config.daemon_dir, PathBuf::from("/nonexistent/regtest"));
+        assert_eq!(config.daemon_rpc_addrs[0].port(), 18443);
+        assert_eq!(config.http_addr.port(), 3010);
+        assert_eq!(config.monitoring_addr.port(), 24230);
     }
 }
@@ -2,22 +2,26 @@ use base64;
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::network::constants::Network;
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin::util::hash::Sha256dHash;
 use glob;
 use hex;
+use log::Level;
 use serde_json::{from_str, from_value, Value};
 use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use metrics::{HistogramOpts, HistogramVec, Metrics};
+use metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics, SyncState};
+use network::NetworkParams;
 use signal::Waiter;
-use util::HeaderList;
+use util::{HeaderList, Secret};
 
 use errors::*;
 
@@ -30,6 +34,35 @@ fn parse_hash(value: &Value) -> Result<Sha256dHash> {
     .chain_err(|| format!("non-hex value: {}", value))?)
 }
 
+// Why the daemon can't be indexed as `network` (None if it can): it must be on the network's
+// chain, as reported by `getblockchaininfo`, starting at its genesis block.
+fn network_mismatch(
+    network: &NetworkParams,
+    chain: &str,
+    genesis_hash: &Sha256dHash,
+) -> Option<String> {
+    if chain != network.daemon_chain {
+        return Some(format!(
+            "viacoind is on the {:?} chain, while indexing {} (see --network)",
+            chain, network.name
+        ));
+    }
+    if *genesis_hash != network.genesis_hash {
+        return Some(format!(
+            "viacoind's genesis block is {}, while {}'s is {} (see {})",
+            genesis_hash,
+            network.name,
+            network.genesis_hash,
+            if network.is_custom() {
+                "--custom-network"
+            } else {
+                "--network"
+            }
+        ));
+    }
+    None
+}
+
 fn header_from_value(value: Value) -> Result<BlockHeader> {
     let header_hex = value
         .as_str()
@@ -58,14 +91,24 @@ fn parse_error_code(err: &Value) -> Option<i64> {
     err.as_object()?.get("code")?.as_i64()
 }
 
+fn is_pruned_error(err: &Value) -> bool {
+    err.get("message")
+        .and_then(|message| message.as_str())
+        .map_or(false, |message| message.contains("pruned data"))
+}
+
 fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Result<Value> {
     if let Some(reply_obj) = reply.as_object_mut() {
         if let Some(err) = reply_obj.get("error") {
             if !err.is_null() {
                 if let Some(code) = parse_error_code(&err) {
                     match code {
-                        // RPC_IN_WARMUP -> retry by later reconnection
-                        -28 => bail!(ErrorKind::Connection(err.to_string())),
+                        // RPC_IN_WARMUP (e.g. "Loading block index...") -> retry later
+                        -28 => bail!(ErrorKind::Warmup(err.to_string())),
+                        // RPC_MISC_ERROR, e.g. "Block not available (pruned data)"
+                        -1 if is_pruned_error(&err) => {
+                            bail!(ErrorKind::PrunedBlock(err.to_string()))
+                        }
                         _ => (),
                     }
                 }
@@ -92,7 +135,29 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
     bail!("non-object reply: {:?}", reply);
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The form in which `getblock` returns a block (from the cheapest to the most expensive one),
+/// so callers can request only the data they need.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockVerbosity {
+    /// The hex-encoded serialized block.
+    Raw,
+    /// The header fields and block stats (size, weight, nTx), with the block's txids.
+    Summary,
+    /// Same as `Summary`, with the decoded transactions instead of their txids.
+    Full,
+}
+
+impl BlockVerbosity {
+    fn to_value(self) -> Value {
+        json!(match self {
+            BlockVerbosity::Raw => 0,
+            BlockVerbosity::Summary => 1,
+            BlockVerbosity::Full => 2,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockchainInfo {
     pub chain: String,
     pub blocks: u32,
@@ -103,6 +168,13 @@ pub struct BlockchainInfo {
     pub initialblockdownload: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MempoolInfo {
+    pub size: u64,          // # of transactions
+    pub bytes: u64,         // their total virtual size
+    pub mempoolminfee: f64, // in BTC/kB, rising above the relay fee once the mempool is full
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct NetworkInfo {
     version: u64,
@@ -116,7 +188,7 @@ pub struct MempoolEntry {
 }
 
 impl MempoolEntry {
-    fn new(fee: u64, vsize: u32) -> MempoolEntry {
+    pub fn new(fee: u64, vsize: u32) -> MempoolEntry {
         MempoolEntry {
             fee,
             vsize,
@@ -138,37 +210,153 @@ impl MempoolEntry {
 }
 
 pub trait CookieGetter: Send + Sync {
-    fn get(&self) -> Result<Vec<u8>>;
+    fn get(&self) -> Result<Secret<Vec<u8>>>;
 }
 
-struct Connection {
-    tx: TcpStream,
-    rx: Lines<BufReader<TcpStream>>,
-    cookie_getter: Arc<CookieGetter>,
+// Headers holding credentials, redacted from the logs and error messages (e.g. when a proxy echoes
+// the request's headers back).
+const SECRET_HEADERS: &[&str] = &["authorization", "proxy-authorization"];
+
+fn is_secret_header(name: &str) -> bool {
+    SECRET_HEADERS.contains(&name.trim().to_lowercase().as_str())
+}
+
+fn redact_headers(headers: &HashMap<String, String>) -> HashMap<&str, &str> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_secret_header(name) {
+                "<redacted>"
+            } else {
+                value.as_str()
+            };
+            (name.as_str(), value)
+        })
+        .collect()
+}
+
+const RETRY_MIN_DELAY: u64 = 250; // milliseconds before retrying a failed request (doubled on each retry)
+const RETRY_MAX_DELAY: u64 = 10_000; // milliseconds
+
+// Exponential backoff, with a random jitter (so that the connections to a restarted daemon don't
+// all retry at once): a random delay between half and all of the backed-off one.
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = (RETRY_MIN_DELAY << attempt.min(16)).min(RETRY_MAX_DELAY);
+    let random = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| u64::from(elapsed.subsec_nanos()));
+    Duration::from_millis(delay / 2 + random % (delay / 2 + 1))
+}
+
+// Takes the first warm-up error out of the results, as the daemon warms up as a whole.
+fn warmup_error(values: &mut Vec<Result<Value>>) -> Option<Error> {
+    let index = values.iter().position(|value| match *value {
+        Err(Error(ErrorKind::Warmup(_), _)) => true,
+        _ => false,
+    })?;
+    values.swap_remove(index).err()
+}
+
+const SYNC_POLL_INTERVAL: u64 = 3; // seconds between checks of the daemon's initial block download
+const ENDPOINT_MAX_FAILURES: usize = 3; // consecutive failures before switching to the next endpoint
+const ENDPOINT_COOLDOWN: u64 = 60; // seconds before retrying an endpoint that was switched from
+
+struct Endpoint {
     addr: SocketAddr,
-    signal: Waiter,
+    cookie_getter: Arc<CookieGetter>,
+    failures: usize,            // consecutive connection/request failures
+    failed_at: Option<Instant>, // when it was marked as failed (after too many failures)
 }
 
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(conn) => return Ok(conn),
-            Err(err) => {
-                warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
-                continue;
-            }
+// The daemon RPC endpoints, in order of preference (shared by all the reconnected daemons).
+struct Endpoints {
+    endpoints: Vec<Endpoint>,
+}
+
+impl Endpoints {
+    fn new(endpoints: Vec<(SocketAddr, Arc<CookieGetter>)>) -> Endpoints {
+        assert!(!endpoints.is_empty(), "no daemon RPC endpoints");
+        Endpoints {
+            endpoints: endpoints
+                .into_iter()
+                .map(|(addr, cookie_getter)| Endpoint {
+                    addr,
+                    cookie_getter,
+                    failures: 0,
+                    failed_at: None,
+                })
+                .collect(),
         }
     }
+
+    // The first endpoint which isn't cooling down after being marked as failed.
+    fn available(&self) -> Option<usize> {
+        let cooldown = Duration::from_secs(ENDPOINT_COOLDOWN);
+        self.endpoints.iter().position(|e| {
+            e.failed_at
+                .map_or(true, |failed_at| failed_at.elapsed() >= cooldown)
+        })
+    }
+
+    // The first available endpoint. If all of them are cooling down, the one which failed first.
+    fn select(&self) -> usize {
+        self.available().unwrap_or_else(|| {
+            (0..self.endpoints.len())
+                .min_by_key(|i| self.endpoints[*i].failed_at)
+                .unwrap()
+        })
+    }
+
+    // Returns true if the endpoint was marked as failed and another one is available (so it should
+    // be switched to right away). Once all of them are cooling down, the caller backs off.
+    fn failed(&mut self, index: usize) -> bool {
+        if self.endpoints.len() == 1 {
+            return false; // nothing to switch to
+        }
+        let endpoint = &mut self.endpoints[index];
+        endpoint.failures += 1;
+        // an endpoint retried after its cooldown is marked as failed again on its first failure
+        if endpoint.failed_at.is_none() && endpoint.failures < ENDPOINT_MAX_FAILURES {
+            return false;
+        }
+        log_fields!(
+            Level::Warn,
+            { peer: endpoint.addr.to_string() },
+            "daemon at {} failed, switching endpoint",
+            endpoint.addr
+        );
+        endpoint.failures = 0;
+        endpoint.failed_at = Some(Instant::now());
+        self.available().is_some()
+    }
+
+    fn succeeded(&mut self, index: usize) {
+        let endpoint = &mut self.endpoints[index];
+        endpoint.failures = 0;
+        endpoint.failed_at = None;
+    }
+}
+
+struct Connection {
+    tx: TcpStream,
+    rx: Lines<BufReader<TcpStream>>,
+    cookie_getter: Arc<CookieGetter>,
+    endpoint: usize, // index in Endpoints
 }
 
 impl Connection {
+    // `timeout` bounds connecting, and each read and write (so that a stuck daemon is retried).
     fn new(
         addr: SocketAddr,
         cookie_getter: Arc<CookieGetter>,
-        signal: Waiter,
+        endpoint: usize,
+        timeout: Duration,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
+        let conn = TcpStream::connect_timeout(&addr, timeout)
+            .chain_err(|| format!("failed to connect {}", addr))?;
+        conn.set_read_timeout(Some(timeout))
+            .and_then(|()| conn.set_write_timeout(Some(timeout)))
+            .chain_err(|| format!("failed to set timeouts on {:?}", conn))?;
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
@@ -177,20 +365,15 @@ impl Connection {
             tx: conn,
             rx: reader.lines(),
             cookie_getter,
-            addr,
-            signal,
+            endpoint,
         })
     }
 
-    fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
-    }
-
     fn send(&mut self, request: &str) -> Result<()> {
         let cookie = &self.cookie_getter.get()?;
         let msg = format!(
             "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
-            base64::encode(cookie),
+            base64::encode(cookie.expose()),
             request.len(),
             request,
         );
@@ -209,16 +392,24 @@ impl Connection {
             .chain_err(|| {
                 ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
             })?
-            .chain_err(|| "failed to read status")?;
+            .chain_err(|| ErrorKind::Connection("failed to read status".to_owned()))?;
         let mut headers = HashMap::new();
         for line in iter {
             let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
             if line.is_empty() {
                 in_header = false; // next line should contain the actual response.
+                if status == "HTTP/1.1 401 Unauthorized" {
+                    // without contents, e.g. after a daemon restart changed its cookie
+                    bail!(ErrorKind::Unauthorized(
+                        "daemon RPC unauthorized".to_owned()
+                    ));
+                }
             } else if in_header {
                 let parts: Vec<&str> = line.splitn(2, ": ").collect();
                 if parts.len() == 2 {
                     headers.insert(parts[0].to_owned(), parts[1].to_owned());
+                } else if is_secret_header(line.split(':').next().unwrap_or("")) {
+                    warn!("invalid header: <redacted>");
                 } else {
                     warn!("invalid header: {:?}", line);
                 }
@@ -232,7 +423,7 @@ impl Connection {
             contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_owned()))?;
         let contents_length: &str = headers
             .get("Content-Length")
-            .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
+            .chain_err(|| format!("Content-Length is missing: {:?}", redact_headers(&headers)))?;
         let contents_length: usize = contents_length
             .parse()
             .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
@@ -255,13 +446,191 @@ impl Connection {
             bail!(
                 "request failed {:?}: {:?} = {:?}",
                 status,
-                headers,
+                redact_headers(&headers),
                 contents
             );
         })
     }
 }
 
+// The daemon connections, each one used by a single request at a time (so that concurrent requests
+// don't wait for each other). They are established on first use, and re-established after failures.
+struct ConnectionPool {
+    slots: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize, // the slot to wait for when all of them are in use (round-robin)
+    in_use: Gauge,
+}
+
+impl ConnectionPool {
+    fn new(size: usize, in_use: Gauge) -> ConnectionPool {
+        ConnectionPool {
+            slots: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            in_use,
+        }
+    }
+
+    // A free slot (preferring the first ones), waiting for one if all of them are in use.
+    fn acquire(&self) -> PooledConnection {
+        let slot = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.try_lock().ok())
+            .next()
+            .unwrap_or_else(|| {
+                let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+                self.slots[index].lock().unwrap()
+            });
+        self.in_use.inc();
+        PooledConnection {
+            slot,
+            in_use: &self.in_use,
+        }
+    }
+}
+
+// Released back to the pool when dropped.
+struct PooledConnection<'a> {
+    slot: MutexGuard<'a, Option<Connection>>,
+    in_use: &'a Gauge,
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = Option<Connection>;
+
+    fn deref(&self) -> &Option<Connection> {
+        &self.slot
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut Option<Connection> {
+        &mut self.slot
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        self.in_use.dec();
+    }
+}
+
+// An error for each caller sharing a request (as errors can't be cloned), keeping the kinds they
+// may be matched on.
+fn share_error(err: &Error) -> Error {
+    let kind = match *err.kind() {
+        ErrorKind::Connection(ref msg) => ErrorKind::Connection(msg.clone()),
+        ErrorKind::Warmup(ref msg) => ErrorKind::Warmup(msg.clone()),
+        ErrorKind::Unauthorized(ref msg) => ErrorKind::Unauthorized(msg.clone()),
+        ErrorKind::PrunedBlock(ref msg) => ErrorKind::PrunedBlock(msg.clone()),
+        ErrorKind::Interrupt(signal) => ErrorKind::Interrupt(signal),
+        _ => ErrorKind::Msg(err.to_string()),
+    };
+    Error::from(kind)
+}
+
+fn share_result(result: &Result<Value>) -> Result<Value> {
+    match *result {
+        Ok(ref value) => Ok(value.clone()),
+        Err(ref err) => Err(share_error(err)),
+    }
+}
+
+// A request sent on behalf of all the callers waiting for its result.
+struct Call {
+    result: Mutex<Option<Result<Value>>>,
+    done: Condvar,
+}
+
+impl Call {
+    fn wait(&self) -> Result<Value> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+        share_result(result.as_ref().unwrap())
+    }
+}
+
+// The requests being sent, by method and params: an identical request made meanwhile waits for the
+// same result instead of being sent again ("single flight"). The results, and failures, are only
+// shared while in flight, never cached.
+struct InFlight {
+    calls: Mutex<HashMap<String, Arc<Call>>>,
+    deduplicated: CounterVec,
+}
+
+impl InFlight {
+    fn new(deduplicated: CounterVec) -> InFlight {
+        InFlight {
+            calls: Mutex::new(HashMap::new()),
+            deduplicated,
+        }
+    }
+
+    fn request<F>(&self, method: &str, params: &Value, send: F) -> Result<Value>
+    where
+        F: FnOnce() -> Result<Value>,
+    {
+        let key = format!("{}{}", method, params);
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(call) => Some(call.clone()),
+                None => {
+                    calls.insert(
+                        key.clone(),
+                        Arc::new(Call {
+                            result: Mutex::new(None),
+                            done: Condvar::new(),
+                        }),
+                    );
+                    None
+                }
+            }
+        };
+        if let Some(call) = call {
+            self.deduplicated.with_label_values(&[method]).inc();
+            return call.wait();
+        }
+        let mut sender = Sender {
+            in_flight: self,
+            key: Some(key),
+        };
+        let result = send();
+        sender.complete(share_result(&result));
+        result
+    }
+}
+
+// Completes the call (if not yet) when dropped, so that its waiters don't hang if the request
+// panics.
+struct Sender<'a> {
+    in_flight: &'a InFlight,
+    key: Option<String>, // taken once completed
+}
+
+impl<'a> Sender<'a> {
+    fn complete(&mut self, result: Result<Value>) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        // removed first, so that the next identical request is sent again
+        let call = self.in_flight.calls.lock().unwrap().remove(&key);
+        if let Some(call) = call {
+            *call.result.lock().unwrap() = Some(result);
+            call.done.notify_all();
+        }
+    }
+}
+
+impl<'a> Drop for Sender<'a> {
+    fn drop(&mut self) {
+        self.complete(Err("daemon request failed".into()));
+    }
+}
+
 struct Counter {
     value: Mutex<u64>,
 }
@@ -282,33 +651,72 @@ impl Counter {
 
 pub struct Daemon {
     daemon_dir: PathBuf,
-    network: Network,
-    conn: Mutex<Connection>,
-    message_id: Counter, // for monotonic JSONRPC 'id'
+    network: NetworkParams,
+    endpoints: Arc<Mutex<Endpoints>>,
+    pool: ConnectionPool,
+    warmup_timeout: Duration, // how long warm-up errors are retried for
+    timeout: Duration,        // for connecting, and each read or write
+    max_retries: Option<u32>, // of connection failures (including timeouts), None meaning forever
+    batch_size: usize,        // max # of requests per batch (for batch_requests())
+    in_flight: Option<Arc<InFlight>>, // with --daemon-dedup
+    message_id: Counter,      // for monotonic JSONRPC 'id'
     signal: Waiter,
 
     // monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    batch_sizes: HistogramVec,
+    connections: Gauge,
+    reconnects: CounterVec,
+    retries: CounterVec,
+    sync: Arc<SyncState>,
+}
+
+/// The daemon RPC connection and retry settings (see `Config::daemon_options()`).
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    pub connections: usize,       // max # of concurrent connections
+    pub warmup_timeout: Duration, // how long warm-up errors are retried for
+    pub timeout: Duration,        // for connecting, and each read or write
+    pub max_retries: Option<u32>, // of connection failures (including timeouts), None meaning forever
+    pub batch_size: usize,        // max # of requests per batch
+    pub dedup: bool,              // whether identical in-flight requests share their result
 }
 
 impl Daemon {
     pub fn new(
         daemon_dir: &PathBuf,
-        daemon_rpc_addr: SocketAddr,
-        cookie_getter: Arc<CookieGetter>,
-        network: Network,
+        daemon_rpc_endpoints: Vec<(SocketAddr, Arc<CookieGetter>)>,
+        options: DaemonOptions,
+        network: NetworkParams,
         signal: Waiter,
         metrics: &Metrics,
     ) -> Result<Daemon> {
+        let endpoints = Arc::new(Mutex::new(Endpoints::new(daemon_rpc_endpoints)));
+        let in_use = metrics.gauge(MetricOpts::new(
+            "daemon_rpc_connections_in_use",
+            "# of daemon RPC connections in use",
+        ));
         let daemon = Daemon {
             daemon_dir: daemon_dir.clone(),
             network,
-            conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
-                cookie_getter,
-                signal.clone(),
-            )?),
+            pool: ConnectionPool::new(options.connections, in_use.clone()),
+            endpoints,
+            warmup_timeout: options.warmup_timeout,
+            timeout: options.timeout,
+            max_retries: options.max_retries,
+            batch_size: options.batch_size,
+            in_flight: if options.dedup {
+                Some(Arc::new(InFlight::new(metrics.counter_vec(
+                    MetricOpts::new(
+                        "daemon_rpc_deduplicated",
+                        "# of daemon RPC requests sharing the result of an identical in-flight one",
+                    ),
+                    &["method"],
+                ))))
+            } else {
+                None
+            },
             message_id: Counter::new(),
             signal: signal.clone(),
             latency: metrics.histogram_vec(
@@ -319,7 +727,29 @@ impl Daemon {
                 HistogramOpts::new("daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            batch_sizes: metrics.histogram_vec(
+                HistogramOpts::new(
+                    "daemon_batch_size",
+                    "# of requests per batched daemon RPC call",
+                )
+                .buckets(vec![1., 2., 5., 10., 20., 50., 100., 200., 500., 1000.]),
+                &["method"],
+            ),
+            connections: in_use,
+            reconnects: metrics.counter_vec(
+                MetricOpts::new(
+                    "daemon_rpc_reconnects",
+                    "# of daemon RPC reconnections (after failures or to switch endpoint)",
+                ),
+                &["endpoint"],
+            ),
+            retries: metrics.counter_vec(
+                MetricOpts::new("daemon_rpc_retries", "# of retried daemon RPC requests"),
+                &["reason"],
+            ),
+            sync: metrics.sync_state(),
         };
+        *daemon.pool.acquire() = Some(daemon.connect()?);
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
         if network_info.version < 00_16_00_00 {
@@ -330,29 +760,105 @@ impl Daemon {
         }
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
+        let genesis_hash = daemon.getblockhash(0)?;
+        if let Some(mismatch) = network_mismatch(&network, &blockchain_info.chain, &genesis_hash) {
+            bail!("{}: refusing to index another chain", mismatch)
+        }
         if blockchain_info.pruned == true {
-            bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
+            // indexed blocks are never fetched again (unless running with --light)
+            warn!("bitcoind is pruned: indexing fails if a block is pruned before being indexed");
         }
+        Ok(daemon)
+    }
+
+    /// Waits until the daemon is done with its initial block download (or has at most `max_gap`
+    /// blocks left to download, if given), logging its progress meanwhile: indexing before that
+    /// would chase a moving tip, and serve a partial chain as if it were confirmed.
+    pub fn wait_for_sync(&self, max_gap: Option<usize>) -> Result<()> {
         loop {
-            if daemon.getblockchaininfo()?.initialblockdownload == false {
-                break;
+            let info = self.getblockchaininfo()?;
+            let gap = info.headers.saturating_sub(info.blocks) as usize;
+            if !info.initialblockdownload || max_gap.map_or(false, |max_gap| gap <= max_gap) {
+                self.sync.set_daemon_syncing(false);
+                return Ok(());
             }
-            warn!("wait until bitcoind is synced (i.e. initialblockdownload = false)");
-            signal.wait(Duration::from_secs(3))?;
+            self.sync.set_daemon_syncing(true);
+            info!(
+                "waiting for viacoind sync: {:.1}% ({} of {} blocks)",
+                info.verificationprogress * 100.0,
+                info.blocks,
+                info.headers
+            );
+            self.signal.wait(Duration::from_secs(SYNC_POLL_INTERVAL))?;
         }
-        Ok(daemon)
     }
 
     pub fn reconnect(&self) -> Result<Daemon> {
-        Ok(Daemon {
+        let daemon = Daemon {
             daemon_dir: self.daemon_dir.clone(),
             network: self.network,
-            conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
+            endpoints: self.endpoints.clone(),
+            pool: ConnectionPool::new(self.pool.slots.len(), self.connections.clone()),
+            warmup_timeout: self.warmup_timeout,
+            timeout: self.timeout,
+            max_retries: self.max_retries,
+            batch_size: self.batch_size,
+            in_flight: self.in_flight.clone(), // shared with this one
             message_id: Counter::new(),
             signal: self.signal.clone(),
             latency: self.latency.clone(),
             size: self.size.clone(),
-        })
+            batch_sizes: self.batch_sizes.clone(),
+            connections: self.connections.clone(),
+            reconnects: self.reconnects.clone(),
+            retries: self.retries.clone(),
+            sync: self.sync.clone(),
+        };
+        *daemon.pool.acquire() = Some(daemon.connect()?);
+        Ok(daemon)
+    }
+
+    // Connects to the preferred endpoint, switching to the next ones if it keeps failing.
+    fn connect(&self) -> Result<Connection> {
+        let mut attempt = 0;
+        let mut failures = 0;
+        loop {
+            let (index, addr, cookie_getter) = {
+                let endpoints = self.endpoints.lock().unwrap();
+                let index = endpoints.select();
+                let endpoint = &endpoints.endpoints[index];
+                (index, endpoint.addr, endpoint.cookie_getter.clone())
+            };
+            match Connection::new(addr, cookie_getter, index, self.timeout) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => {
+                    log_fields!(
+                        Level::Warn,
+                        { peer: addr.to_string() },
+                        "failed to connect daemon at {}: {}",
+                        addr,
+                        err
+                    );
+                    if self.max_retries.map_or(false, |max| failures >= max) {
+                        bail!(ErrorKind::Connection(format!(
+                            "failed to connect daemon after {} retries",
+                            failures
+                        )));
+                    }
+                    failures += 1;
+                    log_fields!(
+                        Level::Debug,
+                        { retry: failures },
+                        "reconnecting to daemon (retry {})",
+                        failures
+                    );
+                    if !self.endpoints.lock().unwrap().failed(index) {
+                        self.signal.wait(retry_delay(attempt))?;
+                        attempt += 1;
+                    }
+                }
+            }
+        }
     }
 
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
@@ -369,11 +875,10 @@ impl Daemon {
     }
 
     pub fn magic(&self) -> u32 {
-        self.network.magic()
+        self.network.magic
     }
 
-    fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
-        let mut conn = self.conn.lock().unwrap();
+    fn call_jsonrpc(&self, conn: &mut Connection, method: &str, request: &Value) -> Result<Value> {
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
         conn.send(&request)?;
@@ -389,53 +894,194 @@ impl Daemon {
         Ok(result)
     }
 
-    fn handle_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
-        let id = self.message_id.next();
+    fn handle_request_batch(
+        &self,
+        conn: &mut Connection,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Result<Value>>> {
+        let ids: Vec<u64> = params_list.iter().map(|_| self.message_id.next()).collect();
         let reqs = params_list
             .iter()
-            .map(|params| json!({"method": method, "params": params, "id": id}))
+            .zip(ids.iter())
+            .map(|(params, id)| json!({"method": method, "params": params, "id": id}))
             .collect();
-        let mut results = vec![];
-        let mut replies = self.call_jsonrpc(method, &reqs)?;
-        if let Some(replies_vec) = replies.as_array_mut() {
-            for reply in replies_vec {
-                results.push(parse_jsonrpc_reply(reply.take(), method, id)?)
-            }
-            return Ok(results);
-        }
-        bail!("non-array replies: {:?}", replies);
+        let replies = match self.call_jsonrpc(conn, method, &reqs)? {
+            Value::Array(replies) => replies,
+            replies => bail!("non-array replies: {:?}", replies),
+        };
+        // the replies may be in any order, so they are matched to the requests by id
+        let mut replies_by_id: HashMap<u64, Value> = replies
+            .into_iter()
+            .filter_map(|reply| Some((reply.get("id")?.as_u64()?, reply)))
+            .collect();
+        Ok(ids
+            .into_iter()
+            .map(|id| match replies_by_id.remove(&id) {
+                Some(reply) => parse_jsonrpc_reply(reply, method, id),
+                None => bail!("no {} reply for id {}", method, id),
+            })
+            .collect())
     }
 
-    fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+    // Retries (with a backoff) after connection failures and timeouts (up to `max_retries` times),
+    // after warm-up errors until the warm-up timeout, and once after an authorization failure (as
+    // the cookie is re-read on each request). Other errors (e.g. RPC ones) are returned right away.
+    fn retry_request_batch(
+        &self,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Result<Value>>> {
+        let mut attempt = 0;
+        let mut connection_retries = 0;
+        let mut warmup_since: Option<Instant> = None;
+        let mut reauthorized = false;
         loop {
-            match self.handle_request_batch(method, params_list) {
-                Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
-                    let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
-                    continue;
+            let mut conn = self.pool.acquire();
+            // switch back to a preferred endpoint once its cooldown is over
+            let preferred = self.endpoints.lock().unwrap().select();
+            if let Some(endpoint) = conn.as_ref().map(|conn| conn.endpoint) {
+                if endpoint != preferred {
+                    self.count_reconnect(endpoint);
+                    *conn = None;
+                }
+            }
+            if conn.is_none() {
+                *conn = Some(self.connect()?);
+            }
+            let endpoint = conn.as_ref().unwrap().endpoint;
+            let result = self
+                .handle_request_batch(conn.as_mut().unwrap(), method, params_list)
+                .and_then(|mut values| match warmup_error(&mut values) {
+                    Some(err) => Err(err), // the whole batch is retried
+                    None => Ok(values),
+                });
+            let reason = match result {
+                Err(Error(ErrorKind::Connection(ref msg), _))
+                    if self
+                        .max_retries
+                        .map_or(true, |max| connection_retries < max) =>
+                {
+                    connection_retries += 1;
+                    log_fields!(
+                        Level::Warn,
+                        { method: method },
+                        "reconnecting to bitcoind: {}",
+                        msg
+                    );
+                    log_fields!(
+                        Level::Debug,
+                        { method: method, retry: connection_retries },
+                        "retrying {} (retry {})",
+                        method,
+                        connection_retries
+                    );
+                    Some("connection")
+                }
+                Err(Error(ErrorKind::Unauthorized(ref msg), _)) if !reauthorized => {
+                    warn!("{}, reconnecting with the current cookie", msg);
+                    Some("unauthorized")
+                }
+                Err(Error(ErrorKind::Warmup(ref msg), _)) => {
+                    let since = *warmup_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() < self.warmup_timeout {
+                        log_fields!(
+                            Level::Warn,
+                            { method: method },
+                            "waiting for bitcoind to warm up: {}",
+                            msg
+                        );
+                        Some("warmup")
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            let reason = match reason {
+                Some(reason) => reason,
+                None => {
+                    if let Err(Error(ErrorKind::Connection(_), _)) = result {
+                        // out of retries: not reused, nor counted as a success
+                        self.endpoints.lock().unwrap().failed(endpoint);
+                        *conn = None;
+                    } else {
+                        self.endpoints.lock().unwrap().succeeded(endpoint);
+                        self.sync.daemon_succeeded();
+                    }
+                    return result;
+                }
+            };
+            self.retries.with_label_values(&[reason]).inc();
+            let mut wait = true;
+            if reason != "warmup" {
+                // reconnected on the next attempt (re-reading the cookie)
+                self.count_reconnect(endpoint);
+                *conn = None;
+            }
+            match reason {
+                // no need to wait before trying another endpoint, nor after a cookie change
+                "connection" => wait = !self.endpoints.lock().unwrap().failed(endpoint),
+                "unauthorized" => {
+                    reauthorized = true;
+                    wait = false;
                 }
-                result => return result,
+                _ => (),
+            }
+            drop(conn); // not kept while waiting
+            if wait {
+                self.signal.wait(retry_delay(attempt))?;
+                attempt += 1;
             }
         }
     }
 
+    fn count_reconnect(&self, endpoint: usize) {
+        let addr = self.endpoints.lock().unwrap().endpoints[endpoint].addr;
+        self.reconnects
+            .with_label_values(&[&addr.to_string()])
+            .inc();
+    }
+
     fn request(&self, method: &str, params: Value) -> Result<Value> {
-        let mut values = self.retry_request_batch(method, &[params])?;
-        assert_eq!(values.len(), 1);
-        Ok(values.remove(0))
+        let send = || {
+            let mut values = self.retry_request_batch(method, slice::from_ref(&params))?;
+            assert_eq!(values.len(), 1);
+            values.remove(0)
+        };
+        match self.in_flight {
+            Some(ref in_flight) => in_flight.request(method, &params, send),
+            None => send(),
+        }
     }
 
+    // Fails if any of the requests fails.
     fn requests(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
-        self.retry_request_batch(method, params_list)
+        self.retry_request_batch(method, params_list)?
+            .into_iter()
+            .collect()
+    }
+
+    // Sends the requests in batches of at most `batch_size`, returning the result of each one
+    // (so that a failed request doesn't fail the others).
+    fn batch_requests(&self, method: &str, params_list: &[Value]) -> Result<Vec<Result<Value>>> {
+        let mut results = Vec::with_capacity(params_list.len());
+        for batch in params_list.chunks(self.batch_size) {
+            self.batch_sizes
+                .with_label_values(&[method])
+                .observe(batch.len() as f64);
+            results.extend(self.retry_request_batch(method, batch)?);
+        }
+        Ok(results)
     }
 
     // bitcoind JSONRPC API:
 
     pub fn getblockchaininfo(&self) -> Result<BlockchainInfo> {
         let info: Value = self.request("getblockchaininfo", json!([]))?;
-        Ok(from_value(info).chain_err(|| "invalid blockchain info")?)
+        let info: BlockchainInfo = from_value(info).chain_err(|| "invalid blockchain info")?;
+        self.sync.set_daemon_height(info.blocks as usize);
+        Ok(info)
     }
 
     fn getnetworkinfo(&self) -> Result<NetworkInfo> {
@@ -443,10 +1089,25 @@ impl Daemon {
         Ok(from_value(info).chain_err(|| "invalid network info")?)
     }
 
+    pub fn getmempoolinfo(&self) -> Result<MempoolInfo> {
+        let info: Value = self.request("getmempoolinfo", json!([]))?;
+        Ok(from_value(info).chain_err(|| "invalid mempool info")?)
+    }
+
     pub fn getbestblockhash(&self) -> Result<Sha256dHash> {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
 
+    pub fn getblockhash(&self, height: usize) -> Result<Sha256dHash> {
+        parse_hash(&self.request("getblockhash", json!([height]))?)
+            .chain_err(|| "invalid blockhash")
+    }
+
+    /// Whether `blockhash` is the block at `height` of the daemon's best chain.
+    pub fn is_at_height(&self, blockhash: &Sha256dHash, height: usize) -> Result<bool> {
+        Ok(self.getblockhash(height)? == *blockhash)
+    }
+
     pub fn getblockheader(&self, blockhash: &Sha256dHash) -> Result<BlockHeader> {
         header_from_value(self.request(
             "getblockheader",
@@ -469,22 +1130,38 @@ impl Daemon {
     }
 
     pub fn getblock(&self, blockhash: &Sha256dHash) -> Result<Block> {
-        let block = block_from_value(self.request(
-            "getblock",
-            json!([blockhash.be_hex_string(), /*verbose=*/ false]),
-        )?)?;
+        let block = block_from_value(self.getblock_raw(blockhash, BlockVerbosity::Raw)?)?;
         assert_eq!(block.bitcoin_hash(), *blockhash);
         Ok(block)
     }
 
-    pub fn getblock_raw(&self, blockhash: &Sha256dHash, verbose: u32) -> Result<Value> {
-        self.request("getblock", json!([blockhash.be_hex_string(), verbose]))
+    pub fn getblock_raw(
+        &self,
+        blockhash: &Sha256dHash,
+        verbosity: BlockVerbosity,
+    ) -> Result<Value> {
+        self.request(
+            "getblock",
+            json!([blockhash.be_hex_string(), verbosity.to_value()]),
+        )
+    }
+
+    /// The txids of the block, in its order.
+    pub fn getblocktxids(&self, blockhash: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
+        let block = self.getblock_raw(blockhash, BlockVerbosity::Summary)?;
+        let txids = block.get("tx").chain_err(|| "block missing txids")?;
+        let mut result = vec![];
+        for txid in txids.as_array().chain_err(|| "invalid block txids")? {
+            result.push(parse_hash(txid).chain_err(|| "invalid txid")?);
+        }
+        Ok(result)
     }
 
     pub fn getblocks(&self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        // the index needs the full blocks, which are the most compact in their serialized form
         let params_list: Vec<Value> = blockhashes
             .iter()
-            .map(|hash| json!([hash.be_hex_string(), /*verbose=*/ false]))
+            .map(|hash| json!([hash.be_hex_string(), BlockVerbosity::Raw.to_value()]))
             .collect();
         let values = self.requests("getblock", &params_list)?;
         let mut blocks = vec![];
@@ -523,19 +1200,72 @@ impl Daemon {
         Ok(self.request("getrawtransaction", args)?)
     }
 
-    pub fn gettransactions(&self, txhashes: &[&Sha256dHash]) -> Result<Vec<Transaction>> {
-        let params_list: Vec<Value> = txhashes
+    /// Fetches the transactions in batches, with a result for each one (e.g. an error for a
+    /// transaction that was evicted from the mempool, without failing the others).
+    pub fn gettransactions(&self, txhashes: &[&Sha256dHash]) -> Result<Vec<Result<Transaction>>> {
+        let txs: Vec<(&Sha256dHash, Option<&Sha256dHash>)> =
+            txhashes.iter().map(|txhash| (*txhash, None)).collect();
+        self.gettransactions_in_blocks(&txs)
+    }
+
+    /// Like `gettransactions()`, with the blocks of the confirmed transactions (as needed without
+    /// `-txindex`).
+    pub fn gettransactions_in_blocks(
+        &self,
+        txs: &[(&Sha256dHash, Option<&Sha256dHash>)],
+    ) -> Result<Vec<Result<Transaction>>> {
+        let params_list: Vec<Value> = txs
             .iter()
-            .map(|txhash| json!([txhash.be_hex_string(), /*verbose=*/ false]))
+            .map(|&(txhash, blockhash)| match blockhash {
+                Some(blockhash) => json!([
+                    txhash.be_hex_string(),
+                    /*verbose=*/ false,
+                    blockhash.be_hex_string()
+                ]),
+                None => json!([txhash.be_hex_string(), /*verbose=*/ false]),
+            })
             .collect();
+        let values = self.batch_requests("getrawtransaction", &params_list)?;
+        assert_eq!(txs.len(), values.len());
+        let batches = (txs.len() + self.batch_size - 1) / self.batch_size;
+        log_fields!(
+            Level::Debug,
+            { method: "getrawtransaction", txs: txs.len(), batches: batches },
+            "fetched {} transactions in {} batches",
+            txs.len(),
+            batches
+        );
+        Ok(values
+            .into_iter()
+            .map(|value| value.and_then(tx_from_value))
+            .collect())
+    }
 
-        let values = self.requests("getrawtransaction", &params_list)?;
-        let mut txs = vec![];
-        for value in values {
-            txs.push(tx_from_value(value)?);
+    /// The values (in satoshis) of these outputs if they're in the daemon's UTXO set, ignoring
+    /// the mempool (i.e. None if spent by a confirmed transaction, or unknown).
+    pub fn gettxouts(&self, outpoints: &[(Sha256dHash, usize)]) -> Result<Vec<Option<u64>>> {
+        let params_list: Vec<Value> = outpoints
+            .iter()
+            .map(|(txid, vout)| {
+                json!([txid.be_hex_string(), vout, /*include_mempool=*/ false])
+            })
+            .collect();
+        let mut result = vec![];
+        for value in self.requests("gettxout", &params_list)? {
+            result.push(match value {
+                Value::Null => None,
+                txout => Some(
+                    (txout
+                        .get("value")
+                        .chain_err(|| "missing value")?
+                        .as_f64()
+                        .chain_err(|| "non-float value")?
+                        * 100_000_000f64)
+                        .round() as u64,
+                ),
+            });
         }
-        assert_eq!(txhashes.len(), txs.len());
-        Ok(txs)
+        Ok(result)
     }
 
     pub fn getmempooltxids(&self) -> Result<HashSet<Sha256dHash>> {
@@ -563,6 +1293,17 @@ impl Daemon {
         Ok(MempoolEntry::new(fee, vsize))
     }
 
+    /// Returns the estimated fee rate (in BTC/kB) for each confirmation target,
+    /// or None if the daemon doesn't have enough data for that target.
+    pub fn estimatesmartfee(&self, targets: &[u16]) -> Result<Vec<Option<f64>>> {
+        let params_list: Vec<Value> = targets.iter().map(|target| json!([target])).collect();
+        Ok(self
+            .requests("estimatesmartfee", &params_list)?
+            .iter()
+            .map(|estimate| estimate.get("feerate").and_then(|rate| rate.as_f64()))
+            .collect())
+    }
+
     pub fn broadcast(&self, tx: &Transaction) -> Result<Sha256dHash> {
         let tx = hex::encode(serialize(tx));
         let txid = self.request("sendrawtransaction", json!([tx]))?;
@@ -618,7 +1359,7 @@ impl Daemon {
         let null_hash = Sha256dHash::default();
         let mut blockhash = *bestblockhash;
         while blockhash != null_hash {
-            if indexed_headers.header_by_blockhash(&blockhash).is_some() {
+            if indexed_headers.header_by_hash(&blockhash).is_some() {
                 break;
             }
             let header = self
@@ -632,3 +1373,699 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::util::hash::Sha256dHash;
+    use daemon::{
+        network_mismatch, retry_delay, CookieGetter, Daemon, DaemonOptions, Endpoints, InFlight,
+        ENDPOINT_MAX_FAILURES, RETRY_MAX_DELAY, RETRY_MIN_DELAY, SYNC_POLL_INTERVAL,
+    };
+    use errors::*;
+    use hex;
+    use metrics::{MetricOpts, Metrics};
+    use network::NetworkParams;
+    use serde_json::{from_slice, Value};
+    use signal::Waiter;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use util::Secret;
+
+    const BLOCKHASH: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    struct TestCookie;
+
+    const COOKIE: &str = "user:password";
+
+    impl CookieGetter for TestCookie {
+        fn get(&self) -> Result<Secret<Vec<u8>>> {
+            Ok(Secret::new(COOKIE.as_bytes().to_vec()))
+        }
+    }
+
+    fn test_tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Sha256dHash::default(),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        }
+    }
+
+    // The mock daemon's failures, each one counting down to 0 (as requests fail).
+    #[derive(Default)]
+    struct Failures {
+        unauthorized: AtomicUsize, // HTTP requests getting a 401 (as if the cookie changed)
+        warmup: AtomicUsize,       // getbestblockhash calls getting a warm-up error
+        ibd: AtomicUsize,          // getblockchaininfo calls reporting an initial block download
+        timeout: AtomicUsize,      // requests left unanswered (until the connection times out)
+        forbidden: AtomicUsize, // HTTP requests getting a 403, echoing their headers (as a proxy)
+    }
+
+    fn fail(count: &AtomicUsize) -> bool {
+        let mut value = count.load(Ordering::SeqCst);
+        while value > 0 {
+            match count.compare_exchange(value, value - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(current) => value = current,
+            }
+        }
+        false
+    }
+
+    fn reply(request: &Value, failures: &Failures, delay: Duration) -> Value {
+        let result = match request["method"].as_str().unwrap() {
+            "getblock" => {
+                let error = json!({"code": -1, "message": "Block not available (pruned data)"});
+                return json!({"result": null, "error": error, "id": request["id"]});
+            }
+            "getnetworkinfo" => json!({"version": 16_00_00, "subversion": "/mock/"}),
+            "getmempoolinfo" => json!({
+                "size": 2,
+                "bytes": 450,
+                "usage": 2048,
+                "maxmempool": 300_000_000,
+                "mempoolminfee": 0.00002,
+                "minrelaytxfee": 0.00001,
+            }),
+            "getblockchaininfo" if fail(&failures.ibd) => json!({
+                "chain": "regtest",
+                "blocks": 90,
+                "headers": 100,
+                "bestblockhash": BLOCKHASH,
+                "pruned": false,
+                "verificationprogress": 0.632,
+                "initialblockdownload": true,
+            }),
+            "getblockchaininfo" => json!({
+                "chain": "regtest",
+                "blocks": 0,
+                "headers": 0,
+                "bestblockhash": BLOCKHASH,
+                "pruned": false,
+                "verificationprogress": 1.0,
+                "initialblockdownload": false,
+            }),
+            "getbestblockhash" if fail(&failures.warmup) => {
+                let error = json!({"code": -28, "message": "Loading block index..."});
+                return json!({"result": null, "error": error, "id": request["id"]});
+            }
+            "getbestblockhash" => {
+                thread::sleep(delay);
+                json!(BLOCKHASH)
+            }
+            "getblockhash" if request["params"][0] == 0 => json!(BLOCKHASH),
+            "getblockhash" => {
+                let error = json!({"code": -8, "message": "Block height out of range"});
+                return json!({"result": null, "error": error, "id": request["id"]});
+            }
+            "getrawtransaction" if request["params"][0] == test_tx().txid().be_hex_string() => {
+                json!(hex::encode(serialize(&test_tx())))
+            }
+            "getrawtransaction" => {
+                let error =
+                    json!({"code": -5, "message": "No such mempool or blockchain transaction"});
+                return json!({"result": null, "error": error, "id": request["id"]});
+            }
+            method => panic!("unexpected method: {}", method),
+        };
+        json!({"result": result, "error": null, "id": request["id"]})
+    }
+
+    // serves the JSONRPC batches of a connection, until disconnected
+    fn serve(stream: TcpStream, failures: &Failures, delay: Duration) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut content_length = 0;
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return; // disconnected
+                }
+                let line = line.trim_right();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("Content-Length: ") {
+                    content_length = line["Content-Length: ".len()..].parse().unwrap();
+                }
+                if line.contains(": ") {
+                    headers.push(line.to_owned());
+                }
+            }
+            let mut content = vec![0u8; content_length];
+            reader.read_exact(&mut content).unwrap();
+            if fail(&failures.timeout) {
+                thread::sleep(Duration::from_millis(MOCK_TIMEOUT_MS * 2));
+                return;
+            }
+            if fail(&failures.unauthorized) {
+                write!(
+                    writer,
+                    "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n"
+                )
+                .unwrap();
+                continue;
+            }
+            if fail(&failures.forbidden) {
+                let body = "forbidden";
+                write!(
+                    writer,
+                    "HTTP/1.1 403 Forbidden\r\n{}\r\nContent-Length: {}\r\n\r\n{}\n",
+                    headers.join("\r\n"),
+                    body.len() + 1,
+                    body
+                )
+                .unwrap();
+                continue;
+            }
+            let requests: Vec<Value> = from_slice(&content).unwrap();
+            // replied in reverse order (as the replies are matched to the requests by id)
+            let replies: Vec<Value> = requests
+                .iter()
+                .rev()
+                .map(|request| reply(request, failures, delay))
+                .collect();
+            let replies = json!(replies).to_string();
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}\n",
+                replies.len() + 1,
+                replies
+            )
+            .unwrap();
+        }
+    }
+
+    // a mock daemon, serving any number of connections (delaying its getbestblockhash replies)
+    fn mock_daemon_with(failures: Failures, delay: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let failures = Arc::new(failures);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let failures = failures.clone();
+                thread::spawn(move || serve(stream.unwrap(), &failures, delay));
+            }
+        });
+        addr
+    }
+
+    fn mock_daemon() -> SocketAddr {
+        mock_daemon_with(Failures::default(), Duration::from_secs(0))
+    }
+
+    fn new_daemon(
+        addrs: Vec<SocketAddr>,
+        connections: usize,
+        warmup_timeout: u64,
+    ) -> Result<Daemon> {
+        new_daemon_with(
+            addrs,
+            connections,
+            warmup_timeout,
+            Duration::from_secs(60),
+            None,
+        )
+    }
+
+    fn test_options() -> DaemonOptions {
+        DaemonOptions {
+            connections: 1,
+            warmup_timeout: Duration::from_secs(60),
+            timeout: Duration::from_secs(60),
+            max_retries: None,
+            batch_size: 2,
+            dedup: false,
+        }
+    }
+
+    fn new_daemon_with(
+        addrs: Vec<SocketAddr>,
+        connections: usize,
+        warmup_timeout: u64,
+        timeout: Duration,
+        max_retries: Option<u32>,
+    ) -> Result<Daemon> {
+        let cookie_getter: Arc<CookieGetter> = Arc::new(TestCookie);
+        Daemon::new(
+            &PathBuf::from("/nonexistent"),
+            addrs
+                .into_iter()
+                .map(|addr| (addr, cookie_getter.clone()))
+                .collect(),
+            DaemonOptions {
+                connections,
+                warmup_timeout: Duration::from_secs(warmup_timeout),
+                timeout,
+                max_retries,
+                batch_size: 2, // small batches, to test batching
+                dedup: false,  // requests not deduplicated
+            },
+            mock_network(),
+            Waiter::new(),
+            &Metrics::new("127.0.0.1:0".parse().unwrap()),
+        )
+    }
+
+    // The mock daemon's chain: a regtest one, starting (and ending) at BLOCKHASH.
+    fn mock_network() -> NetworkParams {
+        NetworkParams {
+            genesis_hash: Sha256dHash::from_hex(BLOCKHASH).unwrap(),
+            ..NetworkParams::regtest()
+        }
+    }
+
+    const MOCK_TIMEOUT_MS: u64 = 200;
+
+    fn test_daemon(addrs: Vec<SocketAddr>) -> Daemon {
+        new_daemon(addrs, 1, 60).unwrap()
+    }
+
+    #[test]
+    fn test_pruned_block() {
+        let daemon = test_daemon(vec![mock_daemon()]);
+        let blockhash = Sha256dHash::from_hex(BLOCKHASH).unwrap();
+        match daemon.getblocks(&[blockhash]) {
+            Err(Error(ErrorKind::PrunedBlock(msg), _)) => assert!(msg.contains("pruned data")),
+            result => panic!("unexpected result: {:?}", result.map(|blocks| blocks.len())),
+        }
+        let err = daemon.getblocks(&[blockhash]).unwrap_err();
+        assert!(err.to_string().contains("--jsonrpc-import"));
+    }
+
+    #[test]
+    fn test_is_at_height() {
+        let daemon = test_daemon(vec![mock_daemon()]);
+        let tip = daemon.getbestblockhash().unwrap();
+        assert!(daemon.is_at_height(&tip, 0).unwrap());
+        // the same height, but another block (e.g. of a daemon switched to another chain)
+        let other = Sha256dHash::from_data(b"other");
+        assert!(!daemon.is_at_height(&other, 0).unwrap());
+        assert!(daemon.is_at_height(&tip, 1).is_err());
+    }
+
+    #[test]
+    fn test_getmempoolinfo() {
+        let daemon = test_daemon(vec![mock_daemon()]);
+        let info = daemon.getmempoolinfo().unwrap();
+        assert_eq!((info.size, info.bytes), (2, 450));
+        assert_eq!(info.mempoolminfee, 0.00002);
+    }
+
+    #[test]
+    fn test_other_chain() {
+        let cookie_getter: Arc<CookieGetter> = Arc::new(TestCookie);
+        // the mock daemon is on regtest
+        let err = Daemon::new(
+            &PathBuf::from("/nonexistent"),
+            vec![(mock_daemon(), cookie_getter)],
+            test_options(),
+            NetworkParams::testnet(),
+            Waiter::new(),
+            &Metrics::new("127.0.0.1:0".parse().unwrap()),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("\"regtest\" chain"));
+    }
+
+    #[test]
+    fn test_failover() {
+        // nothing listens on the first endpoint
+        let failed_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let daemon = test_daemon(vec![failed_addr, mock_daemon()]);
+        assert_eq!(
+            daemon.getbestblockhash().unwrap().be_hex_string(),
+            BLOCKHASH
+        );
+        // the first endpoint is cooling down, so the second one keeps being used
+        let conn = daemon.pool.acquire();
+        assert_eq!(conn.as_ref().unwrap().endpoint, 1);
+        drop(conn);
+        assert_eq!(daemon.endpoints.lock().unwrap().select(), 1);
+    }
+
+    #[test]
+    fn test_endpoints_cooldown() {
+        let cookie_getter: Arc<CookieGetter> = Arc::new(TestCookie);
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        let mut endpoints = Endpoints::new(
+            addrs
+                .into_iter()
+                .map(|addr| (addr, cookie_getter.clone()))
+                .collect(),
+        );
+        for _ in 1..ENDPOINT_MAX_FAILURES {
+            assert!(!endpoints.failed(0));
+        }
+        // switched to the second endpoint right away
+        assert!(endpoints.failed(0));
+        assert_eq!(endpoints.select(), 1);
+        for _ in 1..ENDPOINT_MAX_FAILURES {
+            assert!(!endpoints.failed(1));
+        }
+        // both are cooling down: back off before retrying the one which failed first
+        assert!(!endpoints.failed(1));
+        assert_eq!(endpoints.select(), 0);
+        assert!(!endpoints.failed(0));
+        assert_eq!(endpoints.select(), 1);
+        endpoints.succeeded(1);
+        assert_eq!(endpoints.select(), 1);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        for attempt in 0..40 {
+            let delay = retry_delay(attempt);
+            let max = (RETRY_MIN_DELAY << attempt.min(16)).min(RETRY_MAX_DELAY);
+            assert!(delay >= Duration::from_millis(max / 2), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(max), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_network_mismatch() {
+        let genesis_hash = Sha256dHash::from_hex(BLOCKHASH).unwrap();
+        let network = mock_network();
+        assert_eq!(network_mismatch(&network, "regtest", &genesis_hash), None);
+        let mismatch = network_mismatch(&network, "main", &genesis_hash).unwrap();
+        assert!(mismatch.contains("\"main\" chain, while indexing regtest"));
+        let mismatch = network_mismatch(&network, "regtest", &Sha256dHash::default()).unwrap();
+        assert!(mismatch.contains(&format!("while regtest's is {}", BLOCKHASH)));
+        assert!(mismatch.contains("--network"));
+        let custom = NetworkParams::custom(NetworkParams::regtest(), genesis_hash, None);
+        assert_eq!(network_mismatch(&custom, "regtest", &genesis_hash), None);
+        let mismatch = network_mismatch(&custom, "regtest", &Sha256dHash::default()).unwrap();
+        assert!(mismatch.contains("--custom-network"));
+
+        // the mock daemon is on a regtest chain starting at BLOCKHASH
+        let addr = mock_daemon();
+        let new_daemon = |network| {
+            let cookie_getter: Arc<CookieGetter> = Arc::new(TestCookie);
+            Daemon::new(
+                &PathBuf::from("/nonexistent"),
+                vec![(addr, cookie_getter)],
+                test_options(),
+                network,
+                Waiter::new(),
+                &Metrics::new("127.0.0.1:0".parse().unwrap()),
+            )
+        };
+        for (network, expected) in vec![
+            (
+                NetworkParams::testnet(),
+                "\"regtest\" chain, while indexing testnet",
+            ),
+            (
+                NetworkParams {
+                    genesis_hash: Sha256dHash::default(),
+                    ..mock_network()
+                },
+                "refusing to index another chain",
+            ),
+            (
+                NetworkParams::custom(NetworkParams::regtest(), Sha256dHash::default(), None),
+                "--custom-network",
+            ),
+        ] {
+            match new_daemon(network) {
+                Err(err) => assert!(err.to_string().contains(expected), "{}", err),
+                Ok(_) => panic!("another chain's daemon was accepted: {}", expected),
+            }
+        }
+        assert!(new_daemon(custom).is_ok());
+        assert!(new_daemon(mock_network()).is_ok());
+    }
+
+    #[test]
+    fn test_warmup() {
+        let failures = Failures {
+            warmup: AtomicUsize::new(2),
+            ..Default::default()
+        };
+        let daemon = new_daemon(
+            vec![mock_daemon_with(failures, Duration::from_secs(0))],
+            1,
+            60,
+        )
+        .unwrap();
+        assert_eq!(
+            daemon.getbestblockhash().unwrap().be_hex_string(),
+            BLOCKHASH
+        );
+        assert_eq!(daemon.retries.with_label_values(&["warmup"]).get(), 2);
+
+        // not retried past the warm-up timeout
+        let failures = Failures {
+            warmup: AtomicUsize::new(1),
+            ..Default::default()
+        };
+        let daemon = new_daemon(
+            vec![mock_daemon_with(failures, Duration::from_secs(0))],
+            1,
+            0,
+        )
+        .unwrap();
+        match daemon.getbestblockhash() {
+            Err(Error(ErrorKind::Warmup(msg), _)) => assert!(msg.contains("Loading block index")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(daemon.getbestblockhash().is_ok());
+    }
+
+    #[test]
+    fn test_wait_for_sync() {
+        let failures = Failures {
+            ibd: AtomicUsize::new(4), // including Daemon::new()'s call
+            ..Default::default()
+        };
+        let daemon = test_daemon(vec![mock_daemon_with(failures, Duration::from_secs(0))]);
+        assert!(daemon.getblockchaininfo().unwrap().initialblockdownload);
+
+        // close enough to the daemon's headers
+        daemon.wait_for_sync(Some(10)).unwrap();
+
+        // waits for the initial block download to be over
+        let start = Instant::now();
+        daemon.wait_for_sync(None).unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(SYNC_POLL_INTERVAL));
+        assert!(!daemon.getblockchaininfo().unwrap().initialblockdownload);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let timeout = Duration::from_millis(MOCK_TIMEOUT_MS);
+        let failures = Failures {
+            timeout: AtomicUsize::new(1),
+            ..Default::default()
+        };
+        let addr = mock_daemon_with(failures, Duration::from_secs(0));
+        let daemon = new_daemon_with(vec![addr], 1, 60, timeout, Some(1)).unwrap();
+        // Daemon::new()'s first request timed out, and was retried
+        assert_eq!(daemon.retries.with_label_values(&["connection"]).get(), 1);
+        assert_eq!(
+            daemon.getbestblockhash().unwrap().be_hex_string(),
+            BLOCKHASH
+        );
+
+        // failing once the retries are exhausted
+        let failures = Failures {
+            timeout: AtomicUsize::new(2),
+            ..Default::default()
+        };
+        let addr = mock_daemon_with(failures, Duration::from_secs(0));
+        match new_daemon_with(vec![addr], 1, 60, timeout, Some(1)) {
+            Err(Error(ErrorKind::Connection(_), _)) => (),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("the retries should be exhausted"),
+        }
+
+        // RPC errors aren't retried
+        let daemon = new_daemon_with(vec![mock_daemon()], 1, 60, timeout, Some(1)).unwrap();
+        assert!(daemon.getblockhash(1).is_err());
+        assert_eq!(daemon.retries.with_label_values(&["connection"]).get(), 0);
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        // retried once, with the current cookie
+        let failures = Failures {
+            unauthorized: AtomicUsize::new(1),
+            ..Default::default()
+        };
+        let daemon = new_daemon(
+            vec![mock_daemon_with(failures, Duration::from_secs(0))],
+            1,
+            60,
+        )
+        .unwrap();
+        assert_eq!(daemon.retries.with_label_values(&["unauthorized"]).get(), 1);
+        assert!(daemon.getbestblockhash().is_ok());
+
+        let failures = Failures {
+            unauthorized: AtomicUsize::new(2),
+            ..Default::default()
+        };
+        match new_daemon(
+            vec![mock_daemon_with(failures, Duration::from_secs(0))],
+            1,
+            60,
+        ) {
+            Err(Error(ErrorKind::Unauthorized(_), _)) => (),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_redacted_cookie() {
+        let secrets = vec![COOKIE.to_owned(), base64::encode(COOKIE)];
+        let check = |result: Result<Daemon>| -> String {
+            let err = result.err().expect("the daemon should have failed");
+            let msg = format!("{} {:?}", err.display_chain(), err);
+            for secret in &secrets {
+                assert!(!msg.contains(&secret[..]), "cookie leaked: {}", msg);
+            }
+            msg
+        };
+
+        // a proxy echoing the request's headers back
+        let failures = Failures {
+            forbidden: AtomicUsize::new(1),
+            ..Default::default()
+        };
+        let addr = mock_daemon_with(failures, Duration::from_secs(0));
+        let msg = check(new_daemon(vec![addr], 1, 60));
+        assert!(msg.contains("403 Forbidden"), "{}", msg);
+        assert!(msg.contains("\"Authorization\": \"<redacted>\""), "{}", msg);
+
+        // the daemon refusing the cookie
+        let failures = Failures {
+            unauthorized: AtomicUsize::new(2),
+            ..Default::default()
+        };
+        let addr = mock_daemon_with(failures, Duration::from_secs(0));
+        check(new_daemon(vec![addr], 1, 60));
+
+        // nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let timeout = Duration::from_millis(MOCK_TIMEOUT_MS);
+        check(new_daemon_with(vec![addr], 1, 60, timeout, Some(0)));
+    }
+
+    #[test]
+    fn test_connection_pool() {
+        let addr = mock_daemon_with(Failures::default(), Duration::from_millis(200));
+        let daemon = Arc::new(new_daemon(vec![addr], 4, 60).unwrap());
+        let start = Instant::now();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let daemon = daemon.clone();
+                thread::spawn(move || daemon.getbestblockhash().unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // the requests were sent concurrently, each over its own connection
+        assert!(start.elapsed() < Duration::from_millis(4 * 200));
+        let connected = daemon
+            .pool
+            .slots
+            .iter()
+            .filter(|slot| slot.lock().unwrap().is_some())
+            .count();
+        assert_eq!(connected, 4);
+        assert_eq!(daemon.connections.get(), 0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let opts = MetricOpts::new("deduplicated", "# of deduplicated requests");
+        let in_flight = Arc::new(InFlight::new(metrics.counter_vec(opts, &["method"])));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let request = |params: Value, result: Result<Value>| {
+            let (in_flight, sent) = (in_flight.clone(), sent.clone());
+            thread::spawn(move || {
+                in_flight.request("getblock", &params, || {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(200));
+                    result
+                })
+            })
+        };
+        let pruned = || Err(ErrorKind::PrunedBlock("pruned data".to_owned()).into());
+
+        // sent once, the failure being returned to all the callers (with its kind)
+        let threads: Vec<_> = (0..4).map(|_| request(json!(["a"]), pruned())).collect();
+        for thread in threads {
+            match thread.join().unwrap() {
+                Err(Error(ErrorKind::PrunedBlock(ref msg), _)) => assert_eq!(msg, "pruned data"),
+                result => panic!("unexpected result: {:?}", result.map(|_| ())),
+            }
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        let deduplicated = in_flight.deduplicated.with_label_values(&["getblock"]);
+        assert_eq!(deduplicated.get(), 3);
+
+        // the failure isn't cached, and other params are sent apart
+        let threads = vec![
+            request(json!(["a"]), Ok(json!("block a"))),
+            request(json!(["b"]), Ok(json!("block b"))),
+        ];
+        let results: Vec<Value> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap().unwrap())
+            .collect();
+        assert_eq!(results, vec![json!("block a"), json!("block b")]);
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+        assert_eq!(deduplicated.get(), 3);
+        assert!(in_flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_gettransactions() {
+        let daemon = test_daemon(vec![mock_daemon()]);
+        let txid = test_tx().txid();
+        let missing = Sha256dHash::default();
+        // sent in 2 batches, the missing transaction not failing the others
+        let txs = daemon.gettransactions(&[&txid, &missing, &txid]).unwrap();
+        assert_eq!(txs.len(), 3);
+        assert_eq!(txs[0].as_ref().unwrap().txid(), txid);
+        assert!(txs[1].as_ref().unwrap_err().to_string().contains("No such"));
+        assert_eq!(txs[2].as_ref().unwrap().txid(), txid);
+        assert!(daemon.gettransactions(&[]).unwrap().is_empty());
+    }
+}
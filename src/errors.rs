@@ -11,6 +11,42 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        Warmup(msg: String) {
+            description("Daemon warming up")
+            display("Daemon warming up: {}", msg)
+        }
+
+        Unauthorized(msg: String) {
+            description("Unauthorized by the daemon")
+            display("Unauthorized by the daemon: {} (is the cookie up-to-date?)", msg)
+        }
+
+        PrunedBlock(msg: String) {
+            description("Block pruned by the daemon")
+            display("Block pruned by the daemon ({}): disable its pruning ('-prune=0'), or index \
+                     from a full node using --jsonrpc-import", msg)
+        }
+
+        BlockUnavailable(msg: String) {
+            description("Block temporarily unavailable")
+            display("Block temporarily unavailable: {}", msg)
+        }
+
+        Syncing(best_height: usize) {
+            description("still syncing")
+            display("still syncing, best height is {}", best_height)
+        }
+
+        HistoryTooLarge(limit: usize) {
+            description("history too large")
+            display("history too large: more than {} confirmed funding transactions", limit)
+        }
+
+        IncompatibleDB(msg: String) {
+            description("Incompatible database")
+            display("Incompatible database: {} (reindex it, e.g. with --auto-reindex)", msg)
+        }
+
         Interrupt(signal: Signal) {
             description("Interruption by external signal")
             display("Iterrupted by SIG{:?}", signal)
@@ -0,0 +1,166 @@
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::Sha256dHash;
+use hex;
+use serde_json;
+use std::io::Write;
+
+use index::{RawTxRow, SpendRow, TxRow};
+use store::{DBStore, ReadStore, Row};
+use util::HeaderEntry;
+
+use errors::*;
+
+const PROGRESS_INTERVAL: usize = 1_000_000; // # of scanned transactions between progress logs
+
+/// A line of the UTXO snapshot (newline-delimited JSON). Its fields are part of the snapshot
+/// format, so they must not be renamed nor removed.
+#[derive(Serialize)]
+struct UtxoRecord {
+    txid: Sha256dHash, // hex, in the usual (reversed) byte order
+    vout: u32,
+    value: u64,           // in satoshis
+    scriptpubkey: String, // hex
+    height: u32,
+}
+
+// Whether the output is spent by a transaction confirmed in the best chain
+// (spending rows of orphaned blocks are not deleted).
+fn is_spent<F>(store: &ReadStore, txid: &Sha256dHash, vout: u32, header_at: &F) -> bool
+where
+    F: Fn(usize) -> Option<HeaderEntry>,
+{
+    let key = SpendRow::filter(txid, vout);
+    let value = match store.get(&key) {
+        Some(value) => value,
+        None => return false,
+    };
+    let row = SpendRow::from_row(&Row { key, value });
+    header_at(row.height as usize).map_or(false, |header| *header.hash() == row.blockhash)
+}
+
+/// Writes the unspent outputs of the transactions confirmed in the best chain (as seen by
+/// `header_at`), returning their count and total value. Requires the extended DB (for the raw
+/// transactions and spending rows), and must not run while the index is updated.
+pub fn export_utxos<W, F>(store: &DBStore, header_at: F, mut writer: W) -> Result<(usize, u64)>
+where
+    W: Write,
+    F: Fn(usize) -> Option<HeaderEntry>,
+{
+    let (mut scanned, mut count, mut total) = (0, 0, 0);
+    for row in store.iter_scan(b"T") {
+        let tx_row = TxRow::from_row(&row);
+        scanned += 1;
+        if scanned % PROGRESS_INTERVAL == 0 {
+            info!("exported {} UTXOs, scanned {} transactions", count, scanned);
+        }
+        // the rows of orphaned blocks are not deleted
+        match header_at(tx_row.height as usize) {
+            Some(ref header) if *header.hash() == tx_row.blockhash => (),
+            _ => continue,
+        }
+        let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
+        let key = RawTxRow::filter_full(&txid);
+        let value = store
+            .get(&key)
+            .chain_err(|| format!("missing raw tx {}", txid))?;
+        let rawtx = RawTxRow::from_row(&Row { key, value }).rawtx;
+        let tx: Transaction =
+            deserialize(&rawtx).chain_err(|| format!("failed to parse tx {}", txid))?;
+        for (vout, output) in tx.output.iter().enumerate() {
+            if is_spent(store, &txid, vout as u32, &header_at) {
+                continue;
+            }
+            let record = UtxoRecord {
+                txid,
+                vout: vout as u32,
+                value: output.value,
+                scriptpubkey: hex::encode(&output.script_pubkey[..]),
+                height: tx_row.height,
+            };
+            let mut line = serde_json::to_string(&record).unwrap();
+            line.push('\n');
+            writer
+                .write_all(line.as_bytes())
+                .chain_err(|| "failed to write UTXO")?;
+            count += 1;
+            total += output.value;
+        }
+    }
+    writer.flush().chain_err(|| "failed to flush UTXOs")?;
+    Ok((count, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use export::export_utxos;
+    use index::index_block;
+    use std::fs;
+    use store::{DBOptions, DBStore, WriteStore};
+    use testutil::{make_block, make_tx, temp_path};
+    use util::HeaderList;
+
+    fn export(store: &DBStore, headers: &HeaderList) -> (Vec<String>, usize, u64) {
+        let mut output = vec![];
+        let header_at = |height| headers.header_by_height(height).cloned();
+        let (count, total) = export_utxos(store, header_at, &mut output).unwrap();
+        let lines = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_owned())
+            .collect();
+        (lines, count, total)
+    }
+
+    #[test]
+    fn test_export_utxos() {
+        let dir = temp_path("export");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let mut headers = HeaderList::empty();
+
+        let coinbase = make_tx(&[(Sha256dHash::default(), u32::max_value())], &[1000, 2000]);
+        let spending = make_tx(&[(coinbase.txid(), 0)], &[900]);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![spending.clone()]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            let entries = headers.order(vec![block.header.clone()]);
+            headers.apply(entries);
+        }
+
+        let (mut lines, count, total) = export(&store, &headers);
+        assert_eq!((count, total), (2, 2900));
+        let mut expected = vec![
+            format!(
+                r#"{{"txid":"{}","vout":1,"value":2000,"scriptpubkey":"51","height":0}}"#,
+                coinbase.txid().be_hex_string()
+            ),
+            format!(
+                r#"{{"txid":"{}","vout":0,"value":900,"scriptpubkey":"51","height":1}}"#,
+                spending.txid().be_hex_string()
+            ),
+        ];
+        lines.sort();
+        expected.sort();
+        assert_eq!(lines, expected);
+
+        // a reorg orphans the spending block, so its output is gone and the spent one is back
+        let block1b = make_block(block0.bitcoin_hash(), 2, vec![]);
+        store.write(index_block(&block1b, 1, true, false, Network::Regtest));
+        let entries = headers.order(vec![block1b.header.clone()]);
+        headers.apply(entries);
+        let (_, count, total) = export(&store, &headers);
+        assert_eq!((count, total), (2, 3000));
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,6 +15,8 @@ impl ReadStore for FakeStore {
 impl WriteStore for FakeStore {
     fn write(&self, _rows: Vec<Row>) {}
     fn flush(&self) {}
+    fn delete(&self, _keys: Vec<Bytes>) {}
+    fn delete_prefix(&self, _prefix: &[u8]) {}
 }
 
 #[cfg(test)]
@@ -1,19 +1,31 @@
+use address::script_to_address;
 use bincode;
 use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
 use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::constants::Network;
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin::util::hash::Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use log::Level;
 use std::collections::{HashMap, HashSet};
+use std::io;
 use std::iter::FromIterator;
-use std::sync::RwLock;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use daemon::Daemon;
-use metrics::{Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use export::export_utxos;
+use metrics::{
+    Counter, Gauge, Histogram, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+    SyncState,
+};
 use signal::Waiter;
-use store::{ReadStore, Row, WriteStore};
+use store::{opreturn_indexed, DBStore, ReadStore, Row, WriteStore};
 use util::{
     full_hash, hash_prefix, spawn_thread, BlockMeta, Bytes, FullHash, HashPrefix, HeaderEntry,
     HeaderList, HeaderMap, SyncChannel, HASH_PREFIX_LEN,
@@ -23,6 +35,8 @@ use config::Config;
 
 use errors::*;
 
+const MIGRATION_BATCH_SIZE: usize = 100_000; // # of rows written per batch by the DB migrations
+
 #[derive(Serialize, Deserialize)]
 pub struct TxInKey {
     pub code: u8,
@@ -202,6 +216,372 @@ impl RawTxRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SpendKey {
+    code: u8,
+    prev_txid: FullHash,
+    prev_vout: u32,
+}
+
+/// Maps an output to the input spending it (part of the extended DB).
+/// Rows of orphaned blocks are deleted on rollback, unless the block was pruned (whose rows are
+/// kept), so the block hash must still be checked against the current chain.
+pub struct SpendRow {
+    pub key: SpendKey,
+    pub txid: Sha256dHash, // value
+    pub vin: u32,
+    pub height: u32,
+    pub blockhash: Sha256dHash,
+}
+
+impl SpendRow {
+    pub fn new(
+        txid: &Sha256dHash,
+        vin: u32,
+        input: &TxIn,
+        height: u32,
+        blockhash: &Sha256dHash,
+    ) -> SpendRow {
+        SpendRow {
+            key: SpendKey {
+                code: b'S',
+                prev_txid: full_hash(&input.previous_output.txid[..]),
+                prev_vout: input.previous_output.vout,
+            },
+            txid: *txid,
+            vin,
+            height,
+            blockhash: *blockhash,
+        }
+    }
+
+    pub fn filter(txid: &Sha256dHash, vout: u32) -> Bytes {
+        bincode::serialize(&SpendKey {
+            code: b'S',
+            prev_txid: full_hash(&txid[..]),
+            prev_vout: vout,
+        })
+        .unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&(&self.txid, self.vin, self.height, &self.blockhash))
+                .unwrap(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> SpendRow {
+        let (txid, vin, height, blockhash): (Sha256dHash, u32, u32, Sha256dHash) =
+            bincode::deserialize(&row.value).expect("failed to parse spend row");
+        SpendRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse SpendKey"),
+            txid,
+            vin,
+            height,
+            blockhash,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxoKey {
+    code: u8,
+    txid: FullHash,
+    vout: u32,
+}
+
+/// A transaction's output (part of the extended DB), so that a previous output can be read
+/// without loading its whole transaction. Rows of orphaned blocks are deleted on rollback.
+pub struct TxoRow {
+    pub key: TxoKey,
+    pub txout: TxOut, // value
+}
+
+impl TxoRow {
+    pub fn new(txid: &Sha256dHash, vout: u32, txout: &TxOut) -> TxoRow {
+        TxoRow {
+            key: TxoKey {
+                code: b'o',
+                txid: full_hash(&txid[..]),
+                vout,
+            },
+            txout: txout.clone(),
+        }
+    }
+
+    pub fn filter(txid: &Sha256dHash, vout: u32) -> Bytes {
+        bincode::serialize(&TxoKey {
+            code: b'o',
+            txid: full_hash(&txid[..]),
+            vout,
+        })
+        .unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: serialize(&self.txout),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> TxoRow {
+        TxoRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse TxoKey"),
+            txout: deserialize(&row.value).expect("failed to parse txo row"),
+        }
+    }
+}
+
+/// An address (in its string encoding) seen in an indexed output, for searching addresses by
+/// prefix (part of the extended DB). The key is not bincode-serialized, so that it sorts by the
+/// address string. Rows of orphaned blocks are deleted on rollback, unless the address is still
+/// funded by the best chain (see `orphaned_addresses()`).
+pub struct AddressRow {
+    pub address: String,
+}
+
+impl AddressRow {
+    pub fn new(address: String) -> AddressRow {
+        AddressRow { address }
+    }
+
+    pub fn filter_prefix(prefix: &str) -> Bytes {
+        [b"a", prefix.as_bytes()].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: AddressRow::filter_prefix(&self.address),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: &Row) -> AddressRow {
+        AddressRow {
+            address: String::from_utf8(row.key[1..].to_vec()).expect("failed to parse address"),
+        }
+    }
+}
+
+/// The SHA256 of an OP_RETURN output's payload (its pushed data), mapped to its transaction (with
+/// `--index-opreturn`). The key is not bincode-serialized, so that it can be searched by a prefix
+/// of the hash. The rows of orphaned blocks are deleted with their transactions' ones.
+pub struct OpReturnRow {
+    pub payload_hash: FullHash,
+    pub txid: Sha256dHash,
+}
+
+impl OpReturnRow {
+    pub fn new(payload: &[u8], txid: &Sha256dHash) -> OpReturnRow {
+        OpReturnRow {
+            payload_hash: compute_script_hash(payload),
+            txid: *txid,
+        }
+    }
+
+    pub fn filter_prefix(hash_prefix: &[u8]) -> Bytes {
+        [b"R", hash_prefix].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: [
+                &OpReturnRow::filter_prefix(&self.payload_hash)[..],
+                &self.txid[..],
+            ]
+            .concat(),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: &Row) -> OpReturnRow {
+        OpReturnRow {
+            payload_hash: full_hash(&row.key[1..33]),
+            txid: deserialize(&row.key[33..]).expect("failed to parse txid"),
+        }
+    }
+}
+
+/// A transaction's cached merkle proof (with `--cache-merkle-proofs`), written lazily when it's
+/// first requested. The key starts with the block hash, so that the proofs of orphaned blocks
+/// can be deleted by prefix on reorg.
+pub struct MerkleProofRow {
+    pub blockhash: Sha256dHash,
+    pub txid: Sha256dHash,
+    pub merkle: Vec<Sha256dHash>, // value
+    pub pos: usize,
+}
+
+impl MerkleProofRow {
+    pub fn new(
+        blockhash: &Sha256dHash,
+        txid: &Sha256dHash,
+        merkle: Vec<Sha256dHash>,
+        pos: usize,
+    ) -> MerkleProofRow {
+        MerkleProofRow {
+            blockhash: *blockhash,
+            txid: *txid,
+            merkle,
+            pos,
+        }
+    }
+
+    pub fn filter_block(blockhash: &Sha256dHash) -> Bytes {
+        [b"p", &blockhash[..]].concat()
+    }
+
+    pub fn filter(blockhash: &Sha256dHash, txid: &Sha256dHash) -> Bytes {
+        [&MerkleProofRow::filter_block(blockhash)[..], &txid[..]].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: MerkleProofRow::filter(&self.blockhash, &self.txid),
+            value: bincode::serialize(&(&self.merkle, self.pos as u32)).unwrap(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> MerkleProofRow {
+        let (merkle, pos): (Vec<Sha256dHash>, u32) =
+            bincode::deserialize(&row.value).expect("failed to parse merkle proof row");
+        MerkleProofRow {
+            blockhash: deserialize(&row.key[1..33]).expect("failed to parse blockhash"),
+            txid: deserialize(&row.key[33..]).expect("failed to parse txid"),
+            merkle,
+            pos: pos as usize,
+        }
+    }
+}
+
+/// The size of the best chain's UTXO set: the count and total value of its outputs (as found by
+/// `export::export_utxos()`), as of the `tip` block. Kept in the 'U' row with the extended DB (whose
+/// raw transactions have the spent outputs' values), updated along with the indexed and orphaned
+/// blocks so that it doesn't have to be scanned. It's missing until computed by a full scan.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct UtxoSetStats {
+    pub tip: Sha256dHash,
+    pub count: u64,
+    pub value: u64, // in satoshis
+}
+
+impl UtxoSetStats {
+    pub fn key() -> Bytes {
+        b"U".to_vec()
+    }
+
+    pub fn read(store: &ReadStore) -> Option<UtxoSetStats> {
+        store
+            .get(&UtxoSetStats::key())
+            .map(|value| bincode::deserialize(&value).expect("failed to parse UTXO set stats"))
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: UtxoSetStats::key(),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+
+    /// Adds the block's outputs and removes the ones its inputs spend (whose values are looked up
+    /// by `prevout_value`), the block extending the stats' tip.
+    pub fn connect_block<F>(&mut self, block: &Block, mut prevout_value: F) -> Result<()>
+    where
+        F: FnMut(&OutPoint) -> Result<u64>,
+    {
+        if self.tip != block.header.prev_blockhash {
+            bail!(
+                "block {} doesn't extend the UTXO set stats' tip {}",
+                block.bitcoin_hash(),
+                self.tip
+            );
+        }
+        for txn in &block.txdata {
+            self.count += txn.output.len() as u64;
+            self.value += txn.output.iter().map(|output| output.value).sum::<u64>();
+        }
+        for outpoint in spent_outpoints(block) {
+            self.count -= 1;
+            self.value -= prevout_value(outpoint)?;
+        }
+        self.tip = block.bitcoin_hash();
+        Ok(())
+    }
+
+    /// Rolls back `connect_block()` for the stats' tip, unless the block was already rolled back
+    /// (i.e. the stats are at its parent), so that an interrupted rollback can be resumed.
+    pub fn disconnect_block<F>(&mut self, block: &Block, mut prevout_value: F) -> Result<()>
+    where
+        F: FnMut(&OutPoint) -> Result<u64>,
+    {
+        let blockhash = block.bitcoin_hash();
+        if self.tip == block.header.prev_blockhash {
+            return Ok(());
+        }
+        if self.tip != blockhash {
+            bail!(
+                "block {} isn't the UTXO set stats' tip {}",
+                blockhash,
+                self.tip
+            );
+        }
+        for outpoint in spent_outpoints(block) {
+            self.count += 1;
+            self.value += prevout_value(outpoint)?;
+        }
+        for txn in &block.txdata {
+            self.count -= txn.output.len() as u64;
+            self.value -= txn.output.iter().map(|output| output.value).sum::<u64>();
+        }
+        self.tip = block.header.prev_blockhash;
+        Ok(())
+    }
+}
+
+// The outputs spent by the block's inputs (i.e. excluding the coinbase ones).
+fn spent_outpoints<'a>(block: &'a Block) -> impl Iterator<Item = &'a OutPoint> {
+    let null_hash = Sha256dHash::default();
+    block
+        .txdata
+        .iter()
+        .flat_map(|txn| txn.input.iter())
+        .map(|input| &input.previous_output)
+        .filter(move |outpoint| outpoint.txid != null_hash)
+}
+
+/// The deepest reorg handled so far (as the # of orphaned blocks), kept in the 'D' row so that it
+/// survives restarts.
+pub fn max_reorg_depth_row(depth: u32) -> Row {
+    Row {
+        key: b"D".to_vec(),
+        value: bincode::serialize(&depth).unwrap(),
+    }
+}
+
+/// 0 until a reorg is handled.
+pub fn read_max_reorg_depth(store: &ReadStore) -> u32 {
+    store.get(b"D").map_or(0, |value| {
+        bincode::deserialize(&value).expect("failed to parse max reorg depth")
+    })
+}
+
+/// The value of an output of a transaction of the extended DB.
+pub fn stored_output_value(store: &ReadStore, outpoint: &OutPoint) -> Result<u64> {
+    let key = RawTxRow::filter_full(&outpoint.txid);
+    let value = store
+        .get(&key)
+        .chain_err(|| format!("missing raw tx {}", outpoint.txid))?;
+    let txn: Transaction = deserialize(&RawTxRow::from_row(&Row { key, value }).rawtx)
+        .chain_err(|| format!("failed to parse tx {}", outpoint.txid))?;
+    match txn.output.get(outpoint.vout as usize) {
+        Some(output) => Ok(output.value),
+        None => bail!("missing output {}:{}", outpoint.txid, outpoint.vout),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlockKey {
     code: u8,
@@ -237,15 +617,141 @@ pub fn index_transaction(
     rows.push(TxRow::new(&txid, height, blockhash).to_row());
 }
 
-pub fn index_block(block: &Block, height: u32, extended_db_enabled: bool) -> Vec<Row> {
+pub fn index_spends(txn: &Transaction, height: u32, blockhash: &Sha256dHash, rows: &mut Vec<Row>) {
+    let null_hash = Sha256dHash::default();
+    let txid: Sha256dHash = txn.txid();
+    for (vin, input) in txn.input.iter().enumerate() {
+        if input.previous_output.txid == null_hash {
+            continue;
+        }
+        rows.push(SpendRow::new(&txid, vin as u32, &input, height, blockhash).to_row());
+    }
+}
+
+pub fn index_txos(txn: &Transaction, rows: &mut Vec<Row>) {
+    let txid = txn.txid();
+    for (vout, output) in txn.output.iter().enumerate() {
+        rows.push(TxoRow::new(&txid, vout as u32, output).to_row());
+    }
+}
+
+// Only outputs are indexed, since an address must have been funded before spending.
+pub fn index_addresses(txn: &Transaction, network: Network, rows: &mut Vec<Row>) {
+    for output in &txn.output {
+        if let Some(address) = script_to_address(&output.script_pubkey, &network) {
+            rows.push(AddressRow::new(address).to_row());
+        }
+    }
+}
+
+/// The data pushed by an OP_RETURN output (concatenated), or `None` for the other outputs.
+pub fn opreturn_payload(output: &TxOut) -> Option<Bytes> {
+    if !output.script_pubkey.is_op_return() {
+        return None;
+    }
+    let mut payload = vec![];
+    for instruction in output.script_pubkey.iter(/*enforce_minimal=*/ false) {
+        match instruction {
+            Instruction::PushBytes(data) => payload.extend_from_slice(data),
+            Instruction::Op(_) => (),
+            Instruction::Error(_) => break, // e.g. a truncated push
+        }
+    }
+    Some(payload)
+}
+
+pub fn index_opreturns(txn: &Transaction, rows: &mut Vec<Row>) {
+    let txid = txn.txid();
+    for output in &txn.output {
+        match opreturn_payload(output) {
+            Some(ref payload) if !payload.is_empty() => {
+                rows.push(OpReturnRow::new(payload, &txid).to_row())
+            }
+            _ => (),
+        }
+    }
+}
+
+// Calls `f` with each transaction of the extended DB (and its confirmation), for DB migrations,
+// writing the rows it adds to `sink` in batches. Returns the number of written rows.
+fn for_each_raw_tx<F>(store: &DBStore, sink: &WriteStore, mut f: F) -> usize
+where
+    F: FnMut(&Transaction, &TxRow, &mut Vec<Row>),
+{
+    let (mut rows, mut written, mut count) = (vec![], 0, 0);
+    for row in store.iter_scan(b"t") {
+        let txn: Transaction =
+            deserialize(&RawTxRow::from_row(&row).rawtx).expect("failed to parse transaction");
+        let key = TxRow::filter_full(&txn.txid());
+        let value = store.get(&key).expect("missing transaction row");
+        f(&txn, &TxRow::from_row(&Row { key, value }), &mut rows);
+        if rows.len() >= MIGRATION_BATCH_SIZE {
+            written += rows.len();
+            sink.write(mem::replace(&mut rows, vec![]));
+        }
+        count += 1;
+        if count % 100_000 == 0 {
+            info!("migrated {} transactions", count);
+        }
+    }
+    written += rows.len();
+    sink.write(rows);
+    written
+}
+
+/// DB migration to version 2: builds the spending index.
+pub fn spends_migration(store: &DBStore, sink: &WriteStore, _network: Network) -> usize {
+    for_each_raw_tx(store, sink, |txn, txrow, rows| {
+        index_spends(txn, txrow.height, &txrow.blockhash, rows)
+    })
+}
+
+/// DB migration to version 3: builds the address index.
+pub fn addresses_migration(store: &DBStore, sink: &WriteStore, network: Network) -> usize {
+    let count = for_each_raw_tx(store, sink, |txn, _, rows| {
+        index_addresses(txn, network, rows)
+    });
+    log_address_index(store); // written to the migrated DB
+    count
+}
+
+/// Logs the address index's cost (its number of addresses and size on disk), once built by the
+/// initial indexing or the DB migration: it reads the whole index.
+pub fn log_address_index(store: &DBStore) {
+    let (count, size) = store.prefix_disk_size(&AddressRow::filter_prefix(""));
+    info!(
+        "address search index: {} addresses, {:.1} MB on disk",
+        count,
+        size as f64 / 1e6
+    );
+}
+
+/// DB migration to version 4: builds the outputs' rows.
+pub fn txos_migration(store: &DBStore, sink: &WriteStore, _network: Network) -> usize {
+    for_each_raw_tx(store, sink, |txn, _, rows| index_txos(txn, rows))
+}
+
+pub fn index_block(
+    block: &Block,
+    height: u32,
+    extended_db_enabled: bool,
+    index_opreturn: bool,
+    network: Network,
+) -> Vec<Row> {
     let blockhash = block.bitcoin_hash();
     let mut rows = vec![];
     for txn in &block.txdata {
         index_transaction(&txn, height, &blockhash, &mut rows);
+        if index_opreturn {
+            index_opreturns(&txn, &mut rows);
+        }
 
-        // Persist raw transaction to txstore
+        // Persist raw transaction to txstore, its outputs, the outputs it spends and its addresses
         if extended_db_enabled {
             rows.push(RawTxRow::new(&txn.txid(), serialize(txn)).to_row()); // @TODO avoid re-serialization
+            index_txos(&txn, &mut rows);
+            index_spends(&txn, height, &blockhash, &mut rows);
+            index_addresses(&txn, network, &mut rows);
         }
     }
     let blockhash = block.bitcoin_hash();
@@ -288,6 +794,49 @@ pub fn index_block(block: &Block, height: u32, extended_db_enabled: bool) -> Vec
     rows
 }
 
+/// The keys of the funding, spending and confirmation rows of an orphaned block's transactions
+/// (and, with the extended DB, of their outputs and of the outputs they spend), deleted so that
+/// they're no longer part of the histories (unless confirmed again). Its other rows (its header,
+/// metadata, txids and raw transactions) are kept, to serve orphaned blocks.
+pub fn orphaned_keys(block: &Block, extended_db_enabled: bool) -> Vec<Bytes> {
+    let blockhash = block.bitcoin_hash();
+    let mut rows = vec![];
+    for txn in &block.txdata {
+        index_transaction(&txn, /*height=*/ 0, &blockhash, &mut rows); // the keys don't depend on it
+        index_opreturns(&txn, &mut rows); // deleting them is a no-op without --index-opreturn
+        if extended_db_enabled {
+            // the new chain's outputs and spends are indexed after the rollback
+            index_txos(&txn, &mut rows);
+            index_spends(&txn, /*height=*/ 0, &blockhash, &mut rows);
+        }
+    }
+    rows.into_iter().map(|row| row.key).collect()
+}
+
+/// The keys of the address rows of an orphaned block's outputs, whose scripts aren't funded by
+/// any indexed transaction once its `orphaned_keys()` are deleted. The funding rows are found by
+/// a prefix of the script hash, so a colliding one keeps the address (as do a pruned orphaned
+/// block's rows).
+pub fn orphaned_addresses(store: &ReadStore, block: &Block, network: Network) -> Vec<Bytes> {
+    let mut keys = vec![];
+    for txn in &block.txdata {
+        for output in &txn.output {
+            let address = match script_to_address(&output.script_pubkey, &network) {
+                Some(address) => address,
+                None => continue,
+            };
+            let script_hash = compute_script_hash(&output.script_pubkey[..]);
+            if store
+                .scan_limit(&TxOutRow::filter(&script_hash[..]), 1)
+                .is_empty()
+            {
+                keys.push(AddressRow::new(address).to_row().key);
+            }
+        }
+    }
+    keys
+}
+
 pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
     // Store last indexed block (i.e. all previous blocks were indexed)
     Row {
@@ -305,6 +854,13 @@ pub fn read_indexed_blockhashes(store: &ReadStore) -> HashSet<Sha256dHash> {
     result
 }
 
+/// The header of an indexed block (including orphaned ones, whose rows are kept).
+pub fn read_block_header(store: &ReadStore, blockhash: &Sha256dHash) -> Option<BlockHeader> {
+    store
+        .get(&[b"B", &blockhash[..]].concat())
+        .map(|value| deserialize(&value).unwrap())
+}
+
 fn read_indexed_headers(store: &ReadStore) -> HeaderList {
     let latest_blockhash: Sha256dHash = match store.get(b"L") {
         // latest blockheader persisted in the DB.
@@ -351,9 +907,17 @@ fn read_indexed_headers(store: &ReadStore) -> HeaderList {
 struct Stats {
     blocks: Counter,
     txns: Counter,
+    outputs: Counter,
     vsize: Counter,
     height: Gauge,
     duration: HistogramVec,
+    block_duration: HistogramVec, // per block, by phase
+    reorgs: Counter,
+    reorg_depth: Histogram,
+    max_reorg_depth: Gauge,
+    utxo_set_count: Gauge,
+    utxo_set_value: Gauge,
+    sync: Arc<SyncState>,
 }
 
 impl Stats {
@@ -361,6 +925,10 @@ impl Stats {
         Stats {
             blocks: metrics.counter(MetricOpts::new("index_blocks", "# of indexed blocks")),
             txns: metrics.counter(MetricOpts::new("index_txns", "# of indexed transactions")),
+            outputs: metrics.counter(MetricOpts::new(
+                "index_outputs",
+                "# of indexed transaction outputs",
+            )),
             vsize: metrics.counter(MetricOpts::new("index_vsize", "# of indexed vbytes")),
             height: metrics.gauge(MetricOpts::new(
                 "index_height",
@@ -370,6 +938,32 @@ impl Stats {
                 HistogramOpts::new("index_duration", "indexing duration (in seconds)"),
                 &["step"],
             ),
+            block_duration: metrics.histogram_vec(
+                HistogramOpts::new(
+                    "index_block_duration",
+                    "Per-block indexing duration (in seconds), by phase",
+                )
+                .buckets(vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1., 5., 10.]),
+                &["phase"],
+            ),
+            reorgs: metrics.counter(MetricOpts::new("index_reorgs", "# of handled reorgs")),
+            reorg_depth: metrics.histogram(
+                HistogramOpts::new("index_reorg_depth", "# of blocks orphaned by a reorg")
+                    .buckets(vec![1., 2., 3., 5., 10., 20., 50., 100.]),
+            ),
+            max_reorg_depth: metrics.gauge(MetricOpts::new(
+                "index_max_reorg_depth",
+                "# of blocks orphaned by the deepest reorg handled (also before restarts)",
+            )),
+            utxo_set_count: metrics.gauge(MetricOpts::new(
+                "utxo_set_count",
+                "# of unspent outputs in the best chain",
+            )),
+            utxo_set_value: metrics.gauge(MetricOpts::new(
+                "utxo_set_total_value_sats",
+                "Total value of the unspent outputs in the best chain (in satoshis)",
+            )),
+            sync: metrics.sync_state(),
         }
     }
 
@@ -377,14 +971,114 @@ impl Stats {
         self.blocks.inc();
         self.txns.inc_by(block.txdata.len() as i64);
         for tx in &block.txdata {
+            self.outputs.inc_by(tx.output.len() as i64);
             self.vsize.inc_by(tx.get_weight() as i64 / 4);
         }
+        self.set_height(height);
+    }
+
+    fn set_height(&self, height: usize) {
         self.height.set(height as i64);
+        self.sync.set_index_height(height);
+    }
+
+    fn set_utxo_set(&self, stats: &UtxoSetStats) {
+        self.utxo_set_count.set(stats.count as i64);
+        self.utxo_set_value.set(stats.value as i64);
     }
 
     fn start_timer(&self, step: &str) -> HistogramTimer {
         self.duration.with_label_values(&[step]).start_timer()
     }
+
+    fn start_block_timer(&self, phase: &str) -> HistogramTimer {
+        self.block_duration
+            .with_label_values(&[phase])
+            .start_timer()
+    }
+
+    // The rows are written per batch, each of its blocks being attributed an equal share.
+    fn observe_batch_write(&self, elapsed: Duration, blocks: usize) {
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let histogram = self.block_duration.with_label_values(&["write"]);
+        for _ in 0..blocks {
+            histogram.observe(secs / blocks as f64);
+        }
+    }
+}
+
+// Assembles the fetched blocks into the batches indexed (and written) together: with a byte
+// budget, of the blocks totalling at most `max_bytes` serialized bytes (a larger block making its
+// own batch), and otherwise of each fetch's blocks.
+struct BatchBuilder {
+    max_blocks: usize, // per fetch
+    max_bytes: Option<usize>,
+    blocks: Vec<Block>,
+    bytes: usize,      // of `blocks`
+    block_size: usize, // the last fetched blocks' average, 0 before the first fetch
+}
+
+impl BatchBuilder {
+    fn new(max_blocks: usize, max_bytes: Option<usize>) -> BatchBuilder {
+        BatchBuilder {
+            max_blocks,
+            max_bytes,
+            blocks: vec![],
+            bytes: 0,
+            block_size: 0,
+        }
+    }
+
+    // With a byte budget, the # of blocks expected to fill the current batch (the next blocks
+    // having the size of the last fetched ones), so that few more are held in memory.
+    fn fetch_count(&self) -> usize {
+        match self.max_bytes {
+            Some(max_bytes) if self.block_size > 0 => (max_bytes.saturating_sub(self.bytes)
+                / self.block_size)
+                .min(self.max_blocks)
+                .max(1),
+            Some(_) => 1,
+            None => self.max_blocks,
+        }
+    }
+
+    // Returns the batches completed by these blocks.
+    fn push(&mut self, blocks: Vec<Block>) -> Vec<Vec<Block>> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None if blocks.is_empty() => return vec![],
+            None => return vec![blocks],
+        };
+        let mut batches = vec![];
+        let (count, mut fetched) = (blocks.len(), 0);
+        for block in blocks {
+            let size = serialize(&block).len();
+            if !self.blocks.is_empty() && self.bytes + size > max_bytes {
+                batches.push(mem::replace(&mut self.blocks, vec![]));
+                self.bytes = 0;
+            }
+            self.blocks.push(block);
+            self.bytes += size;
+            fetched += size;
+        }
+        if self.bytes >= max_bytes {
+            batches.push(mem::replace(&mut self.blocks, vec![]));
+            self.bytes = 0;
+        }
+        if count > 0 {
+            self.block_size = fetched / count;
+        }
+        batches
+    }
+
+    // The last, incomplete batch.
+    fn finish(self) -> Option<Vec<Block>> {
+        if self.blocks.is_empty() {
+            None
+        } else {
+            Some(self.blocks)
+        }
+    }
 }
 
 pub struct Index {
@@ -392,8 +1086,15 @@ pub struct Index {
     headers: RwLock<HeaderList>,
     daemon: Daemon,
     stats: Stats,
+    max_reorg_depth: AtomicUsize,
     batch_size: usize,
+    batch_bytes: Option<usize>,
+    slow_batch_threshold: Duration,
+    large_batch_threshold: usize, // # of rows
+    ignore_tip_mismatch: bool,
     extended_db_enabled: bool,
+    index_opreturn: bool,
+    network: Network,
 }
 
 impl Index {
@@ -406,18 +1107,37 @@ impl Index {
         let stats = Stats::new(metrics);
         let headers = read_indexed_headers(store);
         stats.height.set((headers.len() as i64) - 1);
+        stats.sync.set_index_height(headers.len().saturating_sub(1));
+        if let Some(ref utxo_stats) = UtxoSetStats::read(store) {
+            stats.set_utxo_set(utxo_stats);
+        }
+        let max_reorg_depth = read_max_reorg_depth(store);
+        stats.max_reorg_depth.set(i64::from(max_reorg_depth));
         Ok(Index {
             headers: RwLock::new(headers),
             daemon: daemon.reconnect()?,
             stats,
+            max_reorg_depth: AtomicUsize::new(max_reorg_depth as usize),
             batch_size: config.index_batch_size,
+            batch_bytes: config.index_batch_bytes,
+            slow_batch_threshold: Duration::from_millis(config.slow_batch_threshold),
+            large_batch_threshold: config.large_batch_threshold,
+            ignore_tip_mismatch: config.ignore_tip_mismatch,
             extended_db_enabled: config.extended_db_enabled,
+            index_opreturn: opreturn_indexed(store),
+            network: config.network_type,
         })
     }
 
     pub fn reload(&self, store: &ReadStore) {
         let mut headers = self.headers.write().unwrap();
         *headers = read_indexed_headers(store);
+        self.stats.set_height(headers.len().saturating_sub(1));
+    }
+
+    /// The # of blocks orphaned by the deepest reorg handled so far (0 if none).
+    pub fn max_reorg_depth(&self) -> usize {
+        self.max_reorg_depth.load(Ordering::SeqCst)
     }
 
     pub fn best_height(&self) -> usize {
@@ -426,13 +1146,17 @@ impl Index {
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
         let headers = self.headers.read().unwrap();
-        headers.header_by_blockhash(headers.tip()).cloned()
+        headers.header_by_hash(headers.tip()).cloned()
     }
 
     pub fn best_header_hash(&self) -> Sha256dHash {
         self.headers.read().unwrap().tip().clone()
     }
 
+    pub fn block_locator(&self) -> Vec<Sha256dHash> {
+        self.headers.read().unwrap().locator()
+    }
+
     pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
         self.headers
             .read()
@@ -441,44 +1165,268 @@ impl Index {
             .cloned()
     }
 
-    pub fn get_header_by_hash(&self, hash: &Sha256dHash) -> Option<HeaderEntry> {
+    /// See `HeaderList::confirmations`, checked against a consistent tip.
+    pub fn get_confirmed_header(
+        &self,
+        height: usize,
+        blockhash: &Sha256dHash,
+    ) -> Option<(HeaderEntry, usize)> {
         self.headers
             .read()
             .unwrap()
-            .header_by_blockhash(hash)
-            .cloned()
+            .confirmations(height, blockhash)
+            .map(|(header, confirmations)| (header.clone(), confirmations))
     }
 
-    pub fn update(&self, store: &WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
+    pub fn get_header_by_hash(&self, hash: &Sha256dHash) -> Option<HeaderEntry> {
+        self.headers.read().unwrap().header_by_hash(hash).cloned()
+    }
+
+    /// The last block whose median time past is at most `time` (see `HeaderList::header_by_time`),
+    /// with that median time.
+    pub fn get_header_by_time(&self, time: u32) -> Option<(HeaderEntry, u32)> {
+        let headers = self.headers.read().unwrap();
+        headers.header_by_time(time).map(|entry| {
+            let mtp = headers.median_time_past(entry.height()).unwrap();
+            (entry.clone(), mtp)
+        })
+    }
+
+    /// Loads the UTXO set stats (see `UtxoSetStats`), computing them by a full scan of the DB if
+    /// they're missing or stale (e.g. after the initial indexing), unless it's read-only. Must not
+    /// run while the index is updated.
+    pub fn init_utxo_stats(&self, store: &DBStore) -> Result<()> {
+        let tip = self.best_header_hash();
+        let stats = match UtxoSetStats::read(store) {
+            Some(ref stats) if stats.tip == tip => *stats,
+            _ if store.is_read_only() => return Ok(()),
+            _ => {
+                info!("computing the UTXO set stats at {}", tip);
+                let header_at = |height| self.get_header(height);
+                let (count, value) = export_utxos(store, header_at, io::sink())?;
+                let stats = UtxoSetStats {
+                    tip,
+                    count: count as u64,
+                    value,
+                };
+                store.write(vec![stats.to_row()]);
+                store.flush();
+                stats
+            }
+        };
+        info!(
+            "UTXO set: {} outputs, holding {} satoshis",
+            stats.count, stats.value
+        );
+        self.stats.set_utxo_set(&stats);
+        Ok(())
+    }
+
+    // The UTXO set stats can't be updated (e.g. an orphaned block is pruned), so they're deleted
+    // (to be computed again on the next startup).
+    fn drop_utxo_stats(&self, store: &WriteStore, err: &Error) {
+        warn!(
+            "failed to update the UTXO set stats (computed again on restart): {}",
+            err
+        );
+        store.delete(vec![UtxoSetStats::key()]);
+    }
+
+    // Deletes the orphaned blocks' rows from the histories, rolling back the UTXO set stats (if
+    // any). If it's interrupted, it's done again on the next update (as the last indexed block is
+    // still the orphaned tip).
+    fn rollback(
+        &self,
+        store: &DBStore,
+        daemon: &Daemon,
+        orphaned: &[Sha256dHash],
+        utxo_stats: &mut Option<UtxoSetStats>,
+    ) -> Result<()> {
+        if orphaned.is_empty() {
+            return Ok(());
+        }
+        let timer = self.stats.start_timer("rollback");
+        for blockhash in orphaned.iter().rev() {
+            // the daemon keeps the orphaned blocks (unless pruned)
+            let block = match daemon.getblocks(&[*blockhash]) {
+                Ok(mut blocks) => blocks.remove(0),
+                Err(err @ Error(ErrorKind::PrunedBlock(_), _)) => {
+                    warn!("orphaned block {} is pruned, its rows are kept", blockhash);
+                    if utxo_stats.take().is_some() {
+                        self.drop_utxo_stats(store, &err);
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(mut stats) = utxo_stats.take() {
+                // the orphaned outputs are still in the DB
+                match stats
+                    .disconnect_block(&block, |outpoint| stored_output_value(store, outpoint))
+                {
+                    Ok(()) => {
+                        store.write(vec![stats.to_row()]);
+                        *utxo_stats = Some(stats);
+                    }
+                    Err(err) => self.drop_utxo_stats(store, &err),
+                }
+            }
+            log_fields!(
+                Level::Debug,
+                { blockhash: blockhash, txs: block.txdata.len() },
+                "rolling back {} transactions of block {}",
+                block.txdata.len(),
+                blockhash
+            );
+            store.delete(orphaned_keys(&block, self.extended_db_enabled));
+            if self.extended_db_enabled {
+                store.delete(orphaned_addresses(store, &block, self.network));
+            }
+        }
+        store.flush();
+        timer.observe_duration();
+        Ok(())
+    }
+
+    // The new tip's height is derived from its ancestors (following their prev_blockhash): the
+    // daemon has to agree on it, otherwise it switched chains silently (e.g. a misconfigured proxy
+    // forwarding the requests to another daemon).
+    fn verify_tip(&self, daemon: &Daemon, tip: &HeaderEntry) -> Result<()> {
+        if daemon.is_at_height(tip.hash(), tip.height())? {
+            return Ok(());
+        }
+        if daemon.getbestblockhash()? != *tip.hash() {
+            return Ok(()); // a new block (or reorg) meanwhile, handled by the next update
+        }
+        let msg = format!(
+            "the daemon's tip {} isn't its block at height {} (was its chain switched?)",
+            tip.hash(),
+            tip.height()
+        );
+        error!("CRITICAL: {}", msg);
+        if self.ignore_tip_mismatch {
+            return Ok(());
+        }
+        bail!(msg)
+    }
+
+    pub fn update(&self, store: &DBStore, waiter: &Waiter) -> Result<Sha256dHash> {
+        self.update_until(store, waiter, || false)
+    }
+
+    /// Like `update()`, but stopping after the current batch once `stop()` returns true (e.g. to
+    /// pause the indexing), returning the tip indexed so far: the next update indexes the rest.
+    pub fn update_until<F>(&self, store: &DBStore, waiter: &Waiter, stop: F) -> Result<Sha256dHash>
+    where
+        F: Fn() -> bool,
+    {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers: Vec<HeaderEntry> = {
+        let (new_headers, orphaned): (Vec<HeaderEntry>, Vec<Sha256dHash>) = {
             let indexed_headers = self.headers.read().unwrap();
-            indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
+            let new_headers =
+                indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?);
+            // the new chain doesn't extend our tip, so the blocks above the fork are orphaned
+            let fork_height = match new_headers.first() {
+                Some(first) => first.height(),
+                None => indexed_headers
+                    .header_by_hash(&tip)
+                    .map_or(indexed_headers.len(), |h| h.height() + 1),
+            };
+            let depth = indexed_headers.len() - fork_height;
+            if depth > 0 {
+                log_fields!(
+                    Level::Warn,
+                    { depth: depth, replaced: indexed_headers.tip(), tip: tip },
+                    "reorg of {} blocks detected: {} replaced by {}",
+                    depth,
+                    indexed_headers.tip(),
+                    tip
+                );
+                self.stats.reorgs.inc();
+                self.stats.reorg_depth.observe(depth as f64);
+                if depth > self.max_reorg_depth() {
+                    store.write(vec![max_reorg_depth_row(depth as u32)]);
+                    self.max_reorg_depth.store(depth, Ordering::SeqCst);
+                    self.stats.max_reorg_depth.set(depth as i64);
+                }
+            }
+            let orphaned = (fork_height..indexed_headers.len())
+                .filter_map(|height| indexed_headers.header_by_height(height))
+                .map(|header| *header.hash())
+                .collect();
+            (new_headers, orphaned)
         };
         new_headers.last().map(|tip| {
-            info!("{:?} ({} left to index)", tip, new_headers.len());
+            log_fields!(
+                Level::Info,
+                { height: tip.height(), blockhash: tip.hash(), left: new_headers.len() },
+                "{:?} ({} left to index)",
+                tip,
+                new_headers.len()
+            );
         });
+        if let Some(entry) = new_headers.last() {
+            self.verify_tip(&daemon, entry)?;
+        }
         let height_map = HashMap::<Sha256dHash, usize>::from_iter(
             new_headers.iter().map(|h| (*h.hash(), h.height())),
         );
+        let mut utxo_stats = if self.extended_db_enabled {
+            UtxoSetStats::read(store)
+        } else {
+            None
+        };
+        // before indexing the new blocks, which may confirm some of the orphaned transactions again
+        self.rollback(store, &daemon, &orphaned, &mut utxo_stats)?;
 
         let chan = SyncChannel::new(1);
         let sender = chan.sender();
         let blockhashes: Vec<Sha256dHash> = new_headers.iter().map(|h| *h.hash()).collect();
-        let batch_size = self.batch_size;
+        let mut batches = BatchBuilder::new(self.batch_size, self.batch_bytes);
         let fetcher = spawn_thread("fetcher", move || {
-            for chunk in blockhashes.chunks(batch_size) {
-                sender
-                    .send(daemon.getblocks(&chunk))
-                    .expect("failed sending blocks to be indexed");
+            let mut fetched = 0;
+            while fetched < blockhashes.len() {
+                let count = batches.fetch_count().min(blockhashes.len() - fetched);
+                let chunk = &blockhashes[fetched..fetched + count];
+                fetched += count;
+                let completed = match daemon.getblocks(chunk) {
+                    Ok(blocks) => batches.push(blocks),
+                    Err(err) => {
+                        let _ = sender.send(Err(err));
+                        return;
+                    }
+                };
+                for batch in completed {
+                    if sender.send(Ok(batch)).is_err() {
+                        return; // indexing was interrupted
+                    }
+                }
             }
-            sender
-                .send(Ok(vec![]))
-                .expect("failed sending explicit end of stream");
+            if let Some(batch) = batches.finish() {
+                if sender.send(Ok(batch)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Ok(vec![])); // explicit end of stream
         });
+        let mut interrupted = Ok(());
+        let mut stopped = false;
+        let mut applied = 0; // # of new headers applied, once their blocks' rows are written
         loop {
-            waiter.poll()?;
+            if let Err(err) = waiter.poll() {
+                info!("stopping indexing, flushing the current batch");
+                interrupted = Err(err);
+                break;
+            }
+            if applied < new_headers.len() && stop() {
+                info!(
+                    "stopping indexing, {} blocks left",
+                    new_headers.len() - applied
+                );
+                stopped = true;
+                break;
+            }
             let timer = self.stats.start_timer("fetch");
             let batch = chan
                 .receiver()
@@ -489,7 +1437,12 @@ impl Index {
                 break;
             }
 
+            let started = Instant::now();
+            let mut heights = (usize::max_value(), 0); // of the batch's first and last blocks
+            let mut tx_count = 0;
             let mut rows = vec![];
+            // the outputs of the batch's blocks, which aren't written yet
+            let mut batch_outputs = HashMap::<(Sha256dHash, u32), u64>::new();
             for block in &batch {
                 let blockhash = block.bitcoin_hash();
                 let height = *height_map
@@ -497,23 +1450,358 @@ impl Index {
                     .expect(&format!("missing header for block {}", blockhash));
 
                 let timer = self.stats.start_timer("index");
-                let mut block_rows = index_block(block, height as u32, self.extended_db_enabled);
+                let block_timer = self.stats.start_block_timer("rows");
+                let mut block_rows = index_block(
+                    block,
+                    height as u32,
+                    self.extended_db_enabled,
+                    self.index_opreturn,
+                    self.network,
+                );
                 block_rows.push(last_indexed_block(&blockhash));
                 rows.extend(block_rows);
                 timer.observe_duration();
+                block_timer.observe_duration();
+                if let Some(mut stats) = utxo_stats.take() {
+                    let _timer = self.stats.start_block_timer("utxo_stats");
+                    for txn in &block.txdata {
+                        let txid = txn.txid();
+                        for (vout, output) in txn.output.iter().enumerate() {
+                            batch_outputs.insert((txid, vout as u32), output.value);
+                        }
+                    }
+                    match stats.connect_block(block, |outpoint| {
+                        match batch_outputs.get(&(outpoint.txid, outpoint.vout)) {
+                            Some(value) => Ok(*value),
+                            None => stored_output_value(store, outpoint),
+                        }
+                    }) {
+                        Ok(()) => utxo_stats = Some(stats),
+                        Err(err) => self.drop_utxo_stats(store, &err),
+                    }
+                }
                 self.stats.update(block, height);
+                heights = (heights.0.min(height), heights.1.max(height));
+                tx_count += block.txdata.len();
             }
+            if let Some(ref stats) = utxo_stats {
+                rows.push(stats.to_row()); // written along with the blocks
+            }
+            let row_count = rows.len();
             let timer = self.stats.start_timer("write");
+            let write_started = Instant::now();
             store.write(rows);
             timer.observe_duration();
+            self.stats
+                .observe_batch_write(write_started.elapsed(), batch.len());
+            // the queries are served up to the new blocks (e.g. during the initial sync)
+            let batch_headers = new_headers[applied..applied + batch.len()].to_vec();
+            self.headers.write().unwrap().apply(batch_headers);
+            applied += batch.len();
+
+            // the heavy batches are logged, to help identifying the problematic chain segments
+            let elapsed = started.elapsed();
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1e3;
+            let (level, prefix) =
+                if elapsed > self.slow_batch_threshold || row_count > self.large_batch_threshold {
+                    (Level::Info, "heavy batch: ")
+                } else {
+                    (Level::Debug, "")
+                };
+            log_fields!(
+                level,
+                {
+                    first_height: heights.0,
+                    last_height: heights.1,
+                    blocks: batch.len(),
+                    txs: tx_count,
+                    rows: row_count,
+                    secs: secs,
+                },
+                "{}indexed blocks {}-{} ({} blocks, {} txs, {} rows) in {:.3}s",
+                prefix,
+                heights.0,
+                heights.1,
+                batch.len(),
+                tx_count,
+                row_count,
+                secs,
+            );
         }
         let timer = self.stats.start_timer("flush");
         store.flush(); // make sure no row is left behind
         timer.observe_duration();
 
+        drop(chan); // unblock the fetcher in case indexing was interrupted
         fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(new_headers);
+        interrupted?;
+        if new_headers.is_empty() && tip != *self.headers.read().unwrap().tip() {
+            // the daemon rolled back to an already indexed block
+            store.write(vec![last_indexed_block(&tip)]);
+            store.flush();
+            self.headers.write().unwrap().rewind(&tip);
+            self.stats.set_height(self.best_height());
+        }
+        let tip = if stopped {
+            *self.headers.read().unwrap().tip()
+        } else {
+            assert_eq!(applied, new_headers.len());
+            tip
+        };
         assert_eq!(tip, *self.headers.read().unwrap().tip());
+        if let Some(ref stats) = utxo_stats {
+            self.stats.set_utxo_set(stats);
+        }
+        // only after the headers are updated, so the orphaned blocks' proofs aren't cached again
+        for blockhash in &orphaned {
+            store.delete_prefix(&MerkleProofRow::filter_block(blockhash));
+        }
         Ok(tip)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use address::script_to_address;
+    use bitcoin::blockdata::block::Block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use export::export_utxos;
+    use index::{
+        compute_script_hash, index_block, max_reorg_depth_row, orphaned_addresses, orphaned_keys,
+        read_max_reorg_depth, stored_output_value, AddressRow, BatchBuilder, RawTxRow, SpendRow,
+        TxOutRow, TxoRow, UtxoSetStats,
+    };
+    use std::fs;
+    use std::io;
+    use store::{DBOptions, DBStore, ReadStore, WriteStore};
+    use testutil::{make_block, make_tx, temp_path};
+    use util::{hash_prefix, HeaderList};
+
+    #[test]
+    fn test_batch_bytes() {
+        // blocks growing in size, like the chain's
+        let blocks: Vec<Block> = (1..21)
+            .map(|n| {
+                let txdata = (0..n)
+                    .map(|vout| make_tx(&[(Sha256dHash::default(), vout)], &[1]))
+                    .collect();
+                make_block(Sha256dHash::default(), n, txdata)
+            })
+            .collect();
+        let sizes: Vec<usize> = blocks.iter().map(|block| serialize(block).len()).collect();
+        let max_bytes = sizes[10] * 3;
+        let mut builder = BatchBuilder::new(5, Some(max_bytes));
+        assert_eq!(builder.fetch_count(), 1); // nothing to estimate from yet
+        let mut batches = vec![];
+        let mut remaining = blocks.clone();
+        while !remaining.is_empty() {
+            let count = builder.fetch_count().min(remaining.len());
+            assert!(count >= 1 && count <= 5);
+            let rest = remaining.split_off(count);
+            batches.extend(builder.push(remaining));
+            remaining = rest;
+        }
+        batches.extend(builder.finish());
+        // all the blocks, in order, in batches within the budget
+        let batched: Vec<Sha256dHash> = batches
+            .iter()
+            .flat_map(|batch| batch.iter().map(|block| block.bitcoin_hash()))
+            .collect();
+        let expected: Vec<Sha256dHash> = blocks.iter().map(|block| block.bitcoin_hash()).collect();
+        assert_eq!(batched, expected);
+        for batch in &batches {
+            let size: usize = batch.iter().map(|block| serialize(block).len()).sum();
+            assert!(size <= max_bytes, "{} > {}", size, max_bytes);
+        }
+        // fewer blocks per batch as they grow
+        assert!(batches.len() > 2);
+        assert!(batches[0].len() > batches[batches.len() - 1].len());
+
+        // a block over the budget makes its own batch
+        let mut builder = BatchBuilder::new(5, Some(sizes[0]));
+        let batches = builder.push(blocks[..3].to_vec());
+        let lengths: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+        assert_eq!(lengths, vec![1, 1, 1]);
+        assert!(builder.finish().is_none());
+
+        // without a budget, each fetch is a batch
+        let mut builder = BatchBuilder::new(5, None);
+        assert_eq!(builder.fetch_count(), 5);
+        assert_eq!(builder.push(blocks[..5].to_vec()).len(), 1);
+        assert!(builder.push(vec![]).is_empty());
+        assert!(builder.finish().is_none());
+    }
+
+    #[test]
+    fn test_max_reorg_depth() {
+        let dir = temp_path("reorg-depth");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        assert_eq!(read_max_reorg_depth(&store), 0);
+        store.write(vec![max_reorg_depth_row(3)]);
+        store.flush();
+        drop(store);
+        // persisted across restarts
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        assert_eq!(read_max_reorg_depth(&store), 3);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index_nonstandard_scripts() {
+        let dir = temp_path("nonstandard");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        // scripts without an address are indexed by their hash too
+        let op_return = Script::from(vec![0x6a, 0x04, 0xde, 0xad, 0xbe, 0xef]);
+        let mut multisig = vec![0x51]; // bare 1-of-2 multisig
+        for key in &[0x02, 0x03] {
+            multisig.push(0x21);
+            multisig.extend_from_slice(&[*key; 33]);
+        }
+        multisig.extend_from_slice(&[0x52, 0xae]);
+        let multisig = Script::from(multisig);
+
+        let mut tx = make_tx(&[(Sha256dHash::default(), 0)], &[0, 1000]);
+        tx.output[0].script_pubkey = op_return.clone();
+        tx.output[1].script_pubkey = multisig.clone();
+        let block = make_block(Sha256dHash::default(), 0, vec![tx.clone()]);
+        store.write(index_block(&block, 0, true, false, Network::Regtest));
+
+        for script in &[op_return, multisig] {
+            let rows = store.scan(&TxOutRow::filter(&compute_script_hash(&script[..])));
+            let txid_prefixes: Vec<_> = rows
+                .iter()
+                .map(|row| TxOutRow::from_row(row).txid_prefix)
+                .collect();
+            assert_eq!(txid_prefixes, vec![hash_prefix(&tx.txid()[..])]);
+        }
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_orphaned_rows() {
+        let dir = temp_path("orphaned");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let p2pkh = |pubkey_hash: u8| {
+            let mut script = vec![0x76, 0xa9, 0x14];
+            script.extend_from_slice(&[pubkey_hash; 20]);
+            script.extend_from_slice(&[0x88, 0xac]);
+            Script::from(script)
+        };
+        let address = |script: &Script| {
+            let address = script_to_address(script, &Network::Regtest).unwrap();
+            AddressRow::new(address).to_row().key
+        };
+
+        // the orphaned block pays again to an address funded by the best chain, and to a new one
+        let mut coinbase = make_tx(&[(Sha256dHash::default(), u32::max_value())], &[1000]);
+        coinbase.output[0].script_pubkey = p2pkh(1);
+        let mut spending = make_tx(&[(coinbase.txid(), 0)], &[600, 400]);
+        spending.output[0].script_pubkey = p2pkh(1);
+        spending.output[1].script_pubkey = p2pkh(2);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![spending.clone()]);
+        store.write(index_block(&block0, 0, true, false, Network::Regtest));
+        store.write(index_block(&block1, 1, true, false, Network::Regtest));
+        assert!(store.get(&address(&p2pkh(2))).is_some());
+
+        store.delete(orphaned_keys(&block1, true));
+        store.delete(orphaned_addresses(&store, &block1, Network::Regtest));
+        assert!(store.get(&SpendRow::filter(&coinbase.txid(), 0)).is_none());
+        assert!(store.get(&TxoRow::filter(&spending.txid(), 0)).is_none());
+        assert!(store.get(&address(&p2pkh(2))).is_none());
+        // still funded by the best chain
+        assert!(store.get(&address(&p2pkh(1))).is_some());
+        assert!(store.get(&TxoRow::filter(&coinbase.txid(), 0)).is_some());
+        // kept to serve the orphaned block
+        assert!(store
+            .get(&RawTxRow::filter_full(&spending.txid()))
+            .is_some());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_utxo_set_stats() {
+        let dir = temp_path("utxo-stats");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let mut headers = HeaderList::empty();
+        let mut stats = UtxoSetStats::default();
+        // compared to a full scan of the best chain's outputs
+        let scan = |headers: &HeaderList| {
+            let header_at = |height| headers.header_by_height(height).cloned();
+            export_utxos(&store, header_at, io::sink()).unwrap()
+        };
+        let index_at = |block: &Block, height: usize, headers: &mut HeaderList| {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            let entries = headers.order(vec![block.header.clone()]);
+            headers.apply(entries);
+        };
+
+        let null_outpoint = (Sha256dHash::default(), u32::max_value());
+        let coinbase = make_tx(&[null_outpoint], &[1000, 2000]);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        // spending an output of the previous block, and one of the same block
+        let spending = make_tx(&[(coinbase.txid(), 0)], &[900, 50]);
+        let chained = make_tx(&[(spending.txid(), 1)], &[40]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![spending, chained]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            index_at(block, height, &mut headers);
+            stats
+                .connect_block(block, |outpoint| stored_output_value(&store, outpoint))
+                .unwrap();
+        }
+        assert_eq!((stats.count, stats.value), (3, 2940));
+        assert_eq!(scan(&headers), (3, 2940));
+        assert_eq!(stats.tip, block1.bitcoin_hash());
+
+        // the blocks must extend the tip
+        let mut unchanged = stats;
+        assert!(unchanged
+            .connect_block(&block1, |outpoint| stored_output_value(&store, outpoint))
+            .is_err());
+        assert_eq!(unchanged, stats);
+
+        // a reorg orphans block1 (destroying its outputs and restoring the one it spent), and
+        // its replacement spends another output
+        stats
+            .disconnect_block(&block1, |outpoint| stored_output_value(&store, outpoint))
+            .unwrap();
+        assert_eq!((stats.count, stats.value), (2, 3000));
+        assert_eq!(stats.tip, block0.bitcoin_hash());
+        let rolled_back = stats;
+        stats
+            .disconnect_block(&block1, |outpoint| stored_output_value(&store, outpoint))
+            .unwrap(); // already rolled back
+        assert_eq!(stats, rolled_back);
+
+        let replacement = make_tx(&[(coinbase.txid(), 1)], &[1500, 400]);
+        let block1b = make_block(block0.bitcoin_hash(), 2, vec![replacement]);
+        let block2b = make_block(block1b.bitcoin_hash(), 3, vec![]);
+        for (height, block) in vec![&block1b, &block2b].into_iter().enumerate() {
+            index_at(block, height + 1, &mut headers);
+            stats
+                .connect_block(block, |outpoint| stored_output_value(&store, outpoint))
+                .unwrap();
+        }
+        assert_eq!((stats.count, stats.value), (3, 2900));
+        assert_eq!(scan(&headers), (3, 2900));
+
+        // persisted as the 'U' row
+        store.write(vec![stats.to_row()]);
+        assert_eq!(UtxoSetStats::read(&store), Some(stats));
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
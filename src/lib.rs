@@ -3,10 +3,10 @@
 extern crate base64;
 extern crate bincode;
 extern crate bitcoin;
-extern crate viacoin_bech32;
 extern crate chan_signal;
 extern crate crypto;
 extern crate dirs;
+extern crate futures;
 extern crate glob;
 extern crate hex;
 extern crate hyper;
@@ -23,7 +23,9 @@ extern crate stderrlog;
 extern crate sysconf;
 extern crate time;
 extern crate tiny_http;
+extern crate tokio_uds;
 extern crate url;
+extern crate viacoin_bech32;
 
 #[macro_use]
 extern crate chan;
@@ -40,17 +42,28 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+#[macro_use]
+pub mod logger; // first, for its macros
+
+pub mod address;
 pub mod app;
 pub mod bulk;
 pub mod config;
 pub mod daemon;
 pub mod errors;
+pub mod export;
 pub mod fake;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod network;
 pub mod query;
 pub mod rest;
 pub mod signal;
 pub mod store;
+#[cfg(test)]
+mod testutil;
+pub mod txcache;
 pub mod util;
+pub mod verify;
+pub mod zmq;
@@ -0,0 +1,361 @@
+use log::{self, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use time;
+
+use errors::*;
+
+/// Logs like `log!`, attaching `{key: value, ...}` fields to the record: the JSON log format emits
+/// them as keys (next to the message), while the plain-text one only shows the message.
+macro_rules! log_fields {
+    (target: $target:expr, $level:expr, { $($key:ident: $value:expr),* $(,)* }, $($arg:tt)+) => {{
+        let level = $level;
+        if log_enabled!(target: $target, level) {
+            if ::logger::fields_enabled() {
+                ::logger::set_fields(vec![$((stringify!($key), json!($value))),*]);
+            }
+            log!(target: $target, level, $($arg)+);
+        }
+    }};
+    ($level:expr, { $($key:ident: $value:expr),* $(,)* }, $($arg:tt)+) => {
+        log_fields!(target: module_path!(), $level, { $($key: $value),* }, $($arg)+)
+    };
+}
+
+/// The target of the REST access log records, filtered by their own level (see
+/// `FileLogger::access_level`) instead of the verbosity.
+pub const ACCESS_LOG_TARGET: &str = "electrs::access";
+
+// Whether the installed logger emits the fields (so that they're not collected otherwise).
+static FIELDS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // the fields of the thread's next log record (taken by the logger)
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = RefCell::new(vec![]);
+}
+
+pub fn fields_enabled() -> bool {
+    FIELDS_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_fields(fields: Vec<(&'static str, Value)>) {
+    FIELDS.with(|current| *current.borrow_mut() = fields);
+}
+
+fn take_fields() -> Vec<(&'static str, Value)> {
+    FIELDS.with(|current| current.replace(vec![]))
+}
+
+/// The format of the log lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text, // as `stderrlog` formats them
+    Json, // an object per line, with the timestamp, level, target, message and fields
+}
+
+/// An append-only log file, rotated once it would grow beyond `max_size` bytes:
+/// `path` is renamed to `path.1` (shifting older files up to `path.<max_files - 1>`).
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize, // including the current one
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> Result<RotatingFile> {
+        assert!(max_files > 0, "at least one log file must be kept");
+        let file = open_append(path).chain_err(|| format!("failed to open log file {:?}", path))?;
+        let size = file
+            .metadata()
+            .chain_err(|| format!("failed to stat log file {:?}", path))?
+            .len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Writes `line` at once, so it is never split across files.
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 1 {
+            let oldest = self.rotated_path(self.max_files - 1);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.max_files - 1).rev() {
+                let path = self.rotated_path(index);
+                if path.exists() {
+                    fs::rename(&path, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes log records into a `RotatingFile` (or to stderr, for the JSON format), using the same
+/// verbosity levels as `stderrlog`, and its line format for plain text. Records are formatted
+/// before taking the lock, then written by a single call.
+pub struct FileLogger {
+    level: LevelFilter,
+    access_level: LevelFilter,
+    timestamp: bool,
+    format: LogFormat,
+    file: Option<Mutex<RotatingFile>>, // None for stderr
+}
+
+impl FileLogger {
+    pub fn new(
+        file: Option<RotatingFile>,
+        verbosity: usize,
+        timestamp: bool,
+        format: LogFormat,
+    ) -> FileLogger {
+        let level = match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        FileLogger {
+            level,
+            access_level: LevelFilter::Off,
+            timestamp,
+            format,
+            file: file.map(Mutex::new),
+        }
+    }
+
+    /// Sets the level of the access log records (`Off` by default).
+    pub fn access_level(mut self, level: LevelFilter) -> FileLogger {
+        self.access_level = level;
+        self
+    }
+
+    /// Installs this logger globally (can be done only once per process).
+    pub fn init(self) -> Result<()> {
+        let level = cmp::max(self.level, self.access_level);
+        let format = self.format;
+        log::set_boxed_logger(Box::new(self)).chain_err(|| "logger already initialized")?;
+        log::set_max_level(level);
+        FIELDS_ENABLED.store(format == LogFormat::Json, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let (level, args) = (record.level(), record.args());
+        if self.format == LogFormat::Json {
+            // always timestamped, and the fields can't replace the record's own keys
+            let now = time::now();
+            let mut object: Map<String, Value> = take_fields()
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect();
+            object.insert(
+                "timestamp".to_owned(),
+                json!(format!(
+                    "{}.{:03}{}",
+                    time::strftime("%Y-%m-%dT%H:%M:%S", &now).unwrap(),
+                    now.tm_nsec / 1_000_000,
+                    time::strftime("%z", &now).unwrap()
+                )),
+            );
+            object.insert("level".to_owned(), json!(level.to_string()));
+            object.insert("target".to_owned(), json!(record.target()));
+            object.insert("message".to_owned(), json!(args.to_string()));
+            format!("{}\n", Value::Object(object))
+        } else if self.timestamp {
+            let now = time::now();
+            format!(
+                "{}.{:03}{} - {} - {}\n",
+                time::strftime("%Y-%m-%dT%H:%M:%S", &now).unwrap(),
+                now.tm_nsec / 1_000_000,
+                time::strftime("%z", &now).unwrap(),
+                level,
+                args
+            )
+        } else {
+            format!("{} - {}\n", level, args)
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target() == ACCESS_LOG_TARGET {
+            metadata.level() <= self.access_level
+        } else {
+            metadata.level() <= self.level
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        match self.file {
+            Some(ref file) => {
+                if let Err(e) = file.lock().unwrap().write_line(line.as_bytes()) {
+                    eprintln!("failed to write log file: {}", e);
+                }
+            }
+            None => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match self.file {
+            Some(ref file) => {
+                let _ = file.lock().unwrap().flush();
+            }
+            None => {
+                let _ = io::stderr().flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use logger::{set_fields, FileLogger, LogFormat, RotatingFile, ACCESS_LOG_TARGET};
+    use serde_json::{self, Value};
+    use std::fs;
+    use testutil::temp_path;
+
+    #[test]
+    fn test_rotation() {
+        let dir = temp_path("logger");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("electrs.log");
+
+        let mut file = RotatingFile::open(&path, 10, 3).unwrap();
+        for line in &["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        // the oldest line was dropped, and each file holds a single (whole) line
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("electrs.log"), "line 4\n");
+        assert_eq!(read("electrs.log.1"), "line 3\n");
+        assert_eq!(read("electrs.log.2"), "line 2\n");
+        assert!(!dir.join("electrs.log.3").exists());
+
+        // appends to the existing file when reopened
+        let mut file = RotatingFile::open(&path, 100, 3).unwrap();
+        file.write_line(b"line 5\n").unwrap();
+        assert_eq!(read("electrs.log"), "line 4\nline 5\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_formats() {
+        let dir = temp_path("log-format");
+        fs::create_dir_all(&dir).unwrap();
+        let log = |format, name| {
+            let path = dir.join(name);
+            let file = RotatingFile::open(&path, 1000, 1).unwrap();
+            let logger = FileLogger::new(Some(file), 2, false, format);
+            set_fields(vec![("height", json!(100)), ("level", json!("ignored"))]);
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("indexed {} blocks", 10))
+                    .level(Level::Info)
+                    .target("electrs::index")
+                    .build(),
+            );
+            // only the next record gets the fields
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("done"))
+                    .level(Level::Debug) // filtered out
+                    .target("electrs::index")
+                    .build(),
+            );
+            logger.flush();
+            fs::read_to_string(path).unwrap()
+        };
+
+        assert_eq!(
+            log(LogFormat::Text, "text.log"),
+            "INFO - indexed 10 blocks\n"
+        );
+
+        let lines = log(LogFormat::Json, "json.log");
+        assert_eq!(lines.lines().count(), 1);
+        let line: Value = serde_json::from_str(&lines).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "electrs::index");
+        assert_eq!(line["message"], "indexed 10 blocks");
+        assert_eq!(line["height"], 100);
+        assert!(line["timestamp"].as_str().unwrap().contains('T'));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_access_level() {
+        let enabled = |logger: &FileLogger, target, level| {
+            logger.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+        let logger = FileLogger::new(None, 0, false, LogFormat::Text);
+        assert!(!enabled(&logger, ACCESS_LOG_TARGET, Level::Error));
+
+        // independent of the verbosity, both ways
+        let logger = logger.access_level(LevelFilter::Info);
+        assert!(enabled(&logger, ACCESS_LOG_TARGET, Level::Info));
+        assert!(!enabled(&logger, ACCESS_LOG_TARGET, Level::Debug));
+        assert!(!enabled(&logger, "electrs::rest", Level::Info));
+        assert!(enabled(&logger, "electrs::rest", Level::Error));
+
+        let logger =
+            FileLogger::new(None, 4, false, LogFormat::Text).access_level(LevelFilter::Off);
+        assert!(!enabled(&logger, ACCESS_LOG_TARGET, Level::Error));
+        assert!(enabled(&logger, "electrs::rest", Level::Trace));
+    }
+}
@@ -1,20 +1,32 @@
+use bincode;
 use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
 use hex;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use log::Level;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
 use std::iter::FromIterator;
 use std::ops::Bound;
+use std::path::Path;
 use std::sync::Mutex;
+use time;
 
 use daemon::{Daemon, MempoolEntry};
-use index::index_transaction;
-use metrics::{Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use index::{index_transaction, TxRow};
+use metrics::{
+    CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+};
 use store::{ReadStore, Row};
 use util::Bytes;
 
 use errors::*;
 
 const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
+const MAX_REPLACEMENTS: usize = 100_000; // # of observed replacements to remember
+const PACKAGE_MAX_TXS: usize = 1000; // # of ancestors or descendants walked for the package totals
+const MEMPOOL_FILE_VERSION: u32 = 2; // of the --mempool-file format (1 had no first-seen times)
 pub const MEMPOOL_HEIGHT: u32 = u32::max_value(); // special "marker" for mempool transactions
 
 struct MempoolStore {
@@ -28,13 +40,16 @@ impl MempoolStore {
         }
     }
 
-    fn add(&mut self, tx: &Transaction) {
+    // Returns the size of the added rows (in bytes).
+    fn add(&mut self, tx: &Transaction) -> usize {
         let mut rows = vec![];
         index_transaction(tx, MEMPOOL_HEIGHT, &Sha256dHash::default(), &mut rows);
+        let size = rows.iter().map(|row| row.key.len() + row.value.len()).sum();
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert(vec![]).push(value);
         }
+        size
     }
 
     fn remove(&mut self, tx: &Transaction) {
@@ -94,12 +109,34 @@ impl ReadStore for MempoolStore {
 struct Item {
     tx: Transaction,     // stored for faster retrieval and index removal
     entry: MempoolEntry, // caches mempool fee rates
+    size: usize,         // of the serialized transaction and its index rows (in bytes)
+    first_seen: u64,     // when it was first tracked (in seconds since the Unix epoch)
+}
+
+/// A tracked transaction, as persisted across restarts (see `Tracker::save()`).
+#[derive(Serialize, Deserialize)]
+struct SavedTx {
+    rawtx: Bytes,
+    fee: u64,        // in satoshis
+    vsize: u32,      // in virtual bytes
+    first_seen: u64, // in seconds since the Unix epoch
+}
+
+/// A tracked transaction, as persisted by the version 1 format (without its first-seen time).
+#[derive(Deserialize)]
+struct SavedTxV1 {
+    rawtx: Bytes,
+    fee: u64,
+    vsize: u32,
 }
 
 struct Stats {
     count: Gauge,
+    memory: Gauge,
+    evicted: CounterVec,
     update: HistogramVec,
     vsize: GaugeVec,
+    total_vsize: Gauge,
     max_fee_rate: Mutex<f32>,
 }
 
@@ -119,6 +156,7 @@ impl Stats {
             }
             vsize += e.vsize();
         }
+        self.total_vsize.set(vsize as i64);
         let mut max_fee_rate = self.max_fee_rate.lock().unwrap();
         loop {
             bands.push((fee_rate, vsize));
@@ -138,24 +176,220 @@ impl Stats {
     }
 }
 
+type OutPoint = (Sha256dHash, u32);
+
+/// Why a transaction is no longer in the daemon's mempool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Removal {
+    Confirmed, // in a block (already indexed)
+    Replaced,  // by a transaction spending one of its inputs (e.g. RBF)
+    Dropped,   // evicted (the daemon's mempool being full) or expired by the daemon
+}
+
+impl Removal {
+    fn label(&self) -> &'static str {
+        match self {
+            Removal::Confirmed => "confirmed",
+            Removal::Replaced => "replaced",
+            Removal::Dropped => "dropped",
+        }
+    }
+}
+
+/// Keeps track of observed RBF replacements (replaced txid -> replacing txid).
+/// Only replacements seen while this process was running are known.
+struct Replacements {
+    map: HashMap<Sha256dHash, Sha256dHash>,
+    order: VecDeque<Sha256dHash>, // for evicting the oldest replacements
+}
+
+impl Replacements {
+    fn new() -> Replacements {
+        Replacements {
+            map: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, replaced: Sha256dHash, replacement: Sha256dHash) {
+        if self.map.insert(replaced, replacement).is_none() {
+            self.order.push_back(replaced);
+        }
+        while self.order.len() > MAX_REPLACEMENTS {
+            let oldest = self.order.pop_front().unwrap();
+            self.map.remove(&oldest);
+        }
+    }
+
+    fn chain(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        let mut result = vec![];
+        let mut txid = txid;
+        while let Some(next) = self.map.get(txid) {
+            if result.contains(next) || result.len() >= MAX_REPLACEMENTS {
+                break;
+            }
+            result.push(*next);
+            txid = next;
+        }
+        result
+    }
+}
+
+/// Keeps track of the mempool transactions seen spending an output before being replaced by a
+/// conflicting spend (outpoint -> replaced txids, oldest first), e.g. to notice the double-spends
+/// of unconfirmed payments. Only the conflicts seen while this process was running are known.
+struct SpendConflicts {
+    map: HashMap<OutPoint, Vec<Sha256dHash>>,
+    order: VecDeque<OutPoint>, // for evicting the oldest conflicts
+}
+
+impl SpendConflicts {
+    fn new() -> SpendConflicts {
+        SpendConflicts {
+            map: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, outpoint: OutPoint, replaced: Sha256dHash) {
+        let replaced_spends = self.map.entry(outpoint).or_insert_with(|| vec![]);
+        if replaced_spends.is_empty() {
+            self.order.push_back(outpoint);
+        }
+        if !replaced_spends.contains(&replaced) {
+            replaced_spends.push(replaced);
+        }
+        while self.order.len() > MAX_REPLACEMENTS {
+            let oldest = self.order.pop_front().unwrap();
+            self.map.remove(&oldest);
+        }
+    }
+
+    fn get(&self, outpoint: &OutPoint) -> Vec<Sha256dHash> {
+        self.map.get(outpoint).cloned().unwrap_or_default()
+    }
+}
+
+/// A mempool transaction, with its in-mempool parents and children. The graph follows the mempool:
+/// a transaction spending a not yet seen one is linked to it once it arrives, and confirmed
+/// transactions leave the packages of the remaining ones.
+pub struct TxGraph {
+    pub fee: u64,                  // in satoshis
+    pub vsize: u32,                // in virtual bytes
+    pub depends: Vec<Sha256dHash>, // the mempool transactions it spends from
+    pub spentby: Vec<Sha256dHash>, // the mempool transactions spending its outputs
+    pub ancestors: PackageStats,
+    pub descendants: PackageStats,
+    pub ancestor_txids: Vec<Sha256dHash>,   // excluding itself
+    pub descendant_txids: Vec<Sha256dHash>, // excluding itself
+    pub effective_fee_rate: f32,            // in sat/vbyte
+    pub first_seen: u64,                    // in seconds since the Unix epoch
+}
+
+/// The totals of a mempool transaction along with all its in-mempool ancestors (or descendants),
+/// including the transaction itself (like bitcoind's `ancestorcount`, `ancestorsize` and
+/// `ancestorfees`). Miners select a transaction at its ancestors' fee rate, while its descendants'
+/// one is the fee rate its children bump it to (CPFP).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PackageStats {
+    pub count: usize,
+    pub fee: u64,   // in satoshis
+    pub vsize: u64, // in virtual bytes
+}
+
+impl PackageStats {
+    pub fn fee_rate(&self) -> f32 {
+        self.fee as f32 / self.vsize as f32
+    }
+}
+
+/// The mempool's totals (as of its last refresh).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MempoolSummary {
+    pub count: usize,   // # of transactions
+    pub vsize: u64,     // in virtual bytes
+    pub total_fee: u64, // in satoshis
+}
+
+/// A mempool transaction's position by fee rate, among all the mempool transactions. It is an
+/// approximation: the ranking is a snapshot of the last mempool refresh.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FeeRank {
+    pub rank: usize, // 1 for the highest fee rate (transactions paying the same share a rank)
+    pub count: usize, // # of mempool transactions
+    pub percentile: f32, // % of the mempool transactions paying a lower fee rate
+}
+
+// `fee_rates` must be sorted (in increasing order), and include `fee_rate`.
+fn fee_rank(fee_rates: &[f32], fee_rate: f32) -> FeeRank {
+    let count = fee_rates.len();
+    let lower = fee_rates
+        .iter()
+        .take_while(|rate| **rate < fee_rate)
+        .count();
+    let higher = fee_rates
+        .iter()
+        .rev()
+        .take_while(|rate| **rate > fee_rate)
+        .count();
+    FeeRank {
+        rank: higher + 1,
+        count,
+        percentile: 100.0 * lower as f32 / count as f32,
+    }
+}
+
+fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|txin| txin.sequence < 0xffff_fffe)
+}
+
 pub struct Tracker {
     items: HashMap<Sha256dHash, Item>,
     index: MempoolStore,
+    spends: HashMap<OutPoint, Sha256dHash>, // outpoint -> spending mempool txid
+    max_txs: usize,                         // 0 meaning no limit
+    evicted: HashSet<Sha256dHash>,          // still in the daemon's mempool
+    memory: usize,                          // the items' size (in bytes)
+    vsize: u64,                             // the items' total (in virtual bytes)
+    total_fee: u64,                         // the items' total (in satoshis)
+    replacements: Replacements,
+    conflicts: SpendConflicts,
     histogram: Vec<(f32, u32)>,
+    fee_rates: Mutex<Option<Vec<f32>>>, // sorted, computed on the first fee_rank() after a refresh
     stats: Stats,
 }
 
 impl Tracker {
-    pub fn new(metrics: &Metrics) -> Tracker {
+    pub fn new(max_txs: usize, metrics: &Metrics) -> Tracker {
         Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
+            spends: HashMap::new(),
+            max_txs,
+            evicted: HashSet::new(),
+            memory: 0,
+            vsize: 0,
+            total_fee: 0,
+            replacements: Replacements::new(),
+            conflicts: SpendConflicts::new(),
             histogram: vec![],
+            fee_rates: Mutex::new(None),
             stats: Stats {
                 count: metrics.gauge(MetricOpts::new(
                     "mempool_count",
                     "# of mempool transactions",
                 )),
+                memory: metrics.gauge(MetricOpts::new(
+                    "mempool_memory_bytes",
+                    "Estimated size of the mempool transactions and their index (in bytes)",
+                )),
+                evicted: metrics.counter_vec(
+                    MetricOpts::new(
+                        "mempool_evicted",
+                        "# of mempool transactions evicted without being confirmed",
+                    ),
+                    &["reason"],
+                ),
                 update: metrics.histogram_vec(
                     HistogramOpts::new("mempool_update", "Time to update mempool (in seconds)"),
                     &["step"],
@@ -167,6 +401,10 @@ impl Tracker {
                     ),
                     &["fee_rate"],
                 ),
+                total_vsize: metrics.gauge(MetricOpts::new(
+                    "mempool_total_vsize",
+                    "Total vsize of mempool transactions",
+                )),
                 max_fee_rate: Mutex::new(1.0),
             },
         }
@@ -176,6 +414,160 @@ impl Tracker {
         self.items.get(txid).map(|stats| stats.tx.clone())
     }
 
+    pub fn has_txn(&self, txid: &Sha256dHash) -> bool {
+        self.items.contains_key(txid)
+    }
+
+    /// Returns the txids that (transitively) replaced the given transaction, oldest first.
+    pub fn get_replacements(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        self.replacements.chain(txid)
+    }
+
+    /// Returns the mempool transaction spending this output, with the spending input's index.
+    pub fn get_spend(&self, txid: &Sha256dHash, vout: u32) -> Option<(Sha256dHash, u32)> {
+        let spender = self.spends.get(&(*txid, vout))?;
+        let vin = self.items.get(spender)?.tx.input.iter().position(|txin| {
+            txin.previous_output.txid == *txid && txin.previous_output.vout == vout
+        })?;
+        Some((*spender, vin as u32))
+    }
+
+    /// Returns the txids of the mempool transactions that spent this output before being replaced
+    /// by a conflicting spend, oldest first.
+    pub fn get_replaced_spends(&self, txid: &Sha256dHash, vout: u32) -> Vec<Sha256dHash> {
+        self.conflicts.get(&(*txid, vout))
+    }
+
+    /// The transaction's fee and vsize, with those of its unconfirmed ancestors and descendants.
+    pub fn get_graph(&self, txid: &Sha256dHash) -> Option<TxGraph> {
+        let item = self.items.get(txid)?;
+        let ancestors = self.related(txid, |_, item| self.parents(item));
+        let descendants = self.related(txid, |txid, item| self.children(txid, item));
+        Some(TxGraph {
+            fee: item.entry.fee(),
+            vsize: item.entry.vsize(),
+            depends: self.parents(item),
+            spentby: self.children(txid, item),
+            ancestors: self.package(&ancestors),
+            descendants: self.package(&descendants),
+            effective_fee_rate: self.effective_fee_rate(&descendants),
+            ancestor_txids: ancestors[1..].to_vec(),
+            descendant_txids: descendants[1..].to_vec(),
+            first_seen: item.first_seen,
+        })
+    }
+
+    fn parents(&self, item: &Item) -> Vec<Sha256dHash> {
+        let mut parents = vec![];
+        for txin in &item.tx.input {
+            let parent = txin.previous_output.txid;
+            if self.items.contains_key(&parent) && !parents.contains(&parent) {
+                parents.push(parent);
+            }
+        }
+        parents
+    }
+
+    fn children(&self, txid: &Sha256dHash, item: &Item) -> Vec<Sha256dHash> {
+        let mut children = vec![];
+        for vout in 0..item.tx.output.len() as u32 {
+            if let Some(child) = self.spends.get(&(*txid, vout)) {
+                if !children.contains(child) {
+                    children.push(*child);
+                }
+            }
+        }
+        children
+    }
+
+    // The transaction (first) and the ones transitively reachable through `related` (i.e. its
+    // ancestors or descendants), each one listed once, walking at most PACKAGE_MAX_TXS of them.
+    fn related<F>(&self, txid: &Sha256dHash, related: F) -> Vec<Sha256dHash>
+    where
+        F: Fn(&Sha256dHash, &Item) -> Vec<Sha256dHash>,
+    {
+        let mut seen = HashSet::new();
+        let mut txids = vec![];
+        let mut pending = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            if txids.len() >= PACKAGE_MAX_TXS || !seen.insert(txid) {
+                continue;
+            }
+            if let Some(item) = self.items.get(&txid) {
+                txids.push(txid);
+                pending.extend(related(&txid, item));
+            }
+        }
+        txids
+    }
+
+    fn package(&self, txids: &[Sha256dHash]) -> PackageStats {
+        let mut stats = PackageStats {
+            count: 0,
+            fee: 0,
+            vsize: 0,
+        };
+        for item in txids.iter().filter_map(|txid| self.items.get(txid)) {
+            stats.count += 1;
+            stats.fee += item.entry.fee();
+            stats.vsize += u64::from(item.entry.vsize());
+        }
+        stats
+    }
+
+    // An approximation of the fee rate miners (selecting the transactions by their ancestors' fee
+    // rate) include the transaction at, given its descendants (itself first): it's mined along
+    // with the best of their ancestor packages, which include it. A package can't pay more than
+    // its last transaction's own fee rate though, as its ancestors would be mined before it
+    // otherwise (e.g. a high fee parent doesn't bump a low fee child).
+    fn effective_fee_rate(&self, descendants: &[Sha256dHash]) -> f32 {
+        descendants
+            .iter()
+            .filter_map(|txid| {
+                let item = self.items.get(txid)?;
+                let package = self.package(&self.related(txid, |_, item| self.parents(item)));
+                Some(package.fee_rate().min(item.entry.fee_per_vbyte()))
+            })
+            .fold(0.0, f32::max)
+    }
+
+    pub fn fee_rank(&self, txid: &Sha256dHash) -> Option<FeeRank> {
+        let fee_rate = self.items.get(txid)?.entry.fee_per_vbyte();
+        let mut fee_rates = self.fee_rates.lock().unwrap();
+        let fee_rates = fee_rates.get_or_insert_with(|| {
+            let mut fee_rates: Vec<f32> = self
+                .items
+                .values()
+                .map(|item| item.entry.fee_per_vbyte())
+                .collect();
+            fee_rates.sort_unstable_by(|r1, r2| r1.partial_cmp(r2).unwrap());
+            fee_rates
+        });
+        Some(fee_rank(fee_rates, fee_rate))
+    }
+
+    /// Whether the transaction spends an output of another mempool transaction (i.e. Electrum's
+    /// height -1, rather than 0).
+    pub fn has_unconfirmed_inputs(&self, txid: &Sha256dHash) -> bool {
+        self.items
+            .get(txid)
+            .map_or(false, |item| !self.parents(item).is_empty())
+    }
+
+    /// Returns the transaction's fee (in satoshis) and vsize, as reported by the daemon.
+    pub fn get_fee(&self, txid: &Sha256dHash) -> Option<(u64, u32)> {
+        let entry = &self.items.get(txid)?.entry;
+        Some((entry.fee(), entry.vsize()))
+    }
+
+    pub fn summary(&self) -> MempoolSummary {
+        MempoolSummary {
+            count: self.items.len(),
+            vsize: self.vsize,
+            total_fee: self.total_fee,
+        }
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -187,7 +579,9 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
+    /// Syncs the tracked transactions with the daemon's mempool. The ones it no longer has are
+    /// looked up in `store` (the confirmed index, already updated) to tell the confirmed ones.
+    pub fn update(&mut self, daemon: &Daemon, store: &ReadStore) -> Result<()> {
         let timer = self.stats.start_timer("fetch");
         let new_txids = daemon
             .getmempooltxids()
@@ -195,53 +589,205 @@ impl Tracker {
         let old_txids = HashSet::from_iter(self.items.keys().cloned());
         timer.observe_duration();
 
+        // the evicted transactions are fetched again once there's room for them
+        self.evicted.retain(|txid| new_txids.contains(txid));
+        let full = self.max_txs > 0 && new_txids.intersection(&old_txids).count() >= self.max_txs;
+        let evicted = &self.evicted;
+
         let timer = self.stats.start_timer("add");
-        let txids_iter = new_txids.difference(&old_txids);
+        let txids_iter = new_txids
+            .difference(&old_txids)
+            .filter(|txid| !(full && evicted.contains(txid)));
         let entries: Vec<(&Sha256dHash, MempoolEntry)> = txids_iter
             .filter_map(|txid| {
                 match daemon.getmempoolentry(txid) {
                     Ok(entry) => Some((txid, entry)),
                     Err(err) => {
-                        warn!("no mempool entry {}: {}", txid, err); // e.g. new block or RBF
+                        // e.g. new block or RBF
+                        log_fields!(
+                            Level::Warn,
+                            { txid: txid },
+                            "no mempool entry {}: {}",
+                            txid,
+                            err
+                        );
                         None // ignore this transaction for now
                     }
                 }
             })
             .collect();
-        if entries.is_empty() {
-            return Ok(());
-        }
         let txids: Vec<&Sha256dHash> = entries.iter().map(|(txid, _)| *txid).collect();
-        let txs = match daemon.gettransactions(&txids) {
+        let fetched = if txids.is_empty() {
+            Ok(vec![])
+        } else {
+            daemon.gettransactions(&txids)
+        };
+        let txs = match fetched {
             Ok(txs) => txs,
             Err(err) => {
-                warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
-                return Ok(()); // keep the mempool until next update()
+                // e.g. new block or RBF (added on the next update if still in the mempool)
+                log_fields!(
+                    Level::Warn,
+                    { txs: txids.len() },
+                    "failed to get transactions {:?}: {}",
+                    txids,
+                    err
+                );
+                vec![]
             }
         };
+        let now = unix_time();
         for ((txid, entry), tx) in entries.into_iter().zip(txs.into_iter()) {
-            assert_eq!(tx.txid(), *txid);
-            self.add(txid, tx, entry);
+            match tx {
+                Ok(tx) => {
+                    assert_eq!(tx.txid(), *txid);
+                    self.add(txid, tx, entry, now);
+                }
+                // e.g. new block or RBF (added on the next update if still in the mempool)
+                Err(err) => log_fields!(
+                    Level::Warn,
+                    { txid: txid },
+                    "failed to get transaction {}: {}",
+                    txid,
+                    err
+                ),
+            }
         }
         timer.observe_duration();
 
         let timer = self.stats.start_timer("remove");
-        for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
-        }
+        let missing: Vec<Sha256dHash> = old_txids.difference(&new_txids).cloned().collect();
+        self.remove_missing(&missing, store);
+        timer.observe_duration();
+
+        let timer = self.stats.start_timer("evict");
+        self.evict();
         timer.observe_duration();
 
         let timer = self.stats.start_timer("fees");
         self.update_fee_histogram();
         timer.observe_duration();
 
-        self.stats.count.set(self.items.len() as i64);
+        self.update_stats();
         Ok(())
     }
 
-    fn add(&mut self, txid: &Sha256dHash, tx: Transaction, entry: MempoolEntry) {
-        self.index.add(&tx);
-        self.items.insert(*txid, Item { tx, entry });
+    /// Writes the tracked transactions (with their fees) to `path`, replacing it atomically, and
+    /// returns their count.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let txs: Vec<SavedTx> = self
+            .items
+            .values()
+            .map(|item| SavedTx {
+                rawtx: serialize(&item.tx),
+                fee: item.entry.fee(),
+                vsize: item.entry.vsize(),
+                first_seen: item.first_seen,
+            })
+            .collect();
+        let data = bincode::serialize(&(MEMPOOL_FILE_VERSION, &txs)).unwrap();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).chain_err(|| format!("failed to write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, path).chain_err(|| format!("failed to rename {:?}", tmp_path))?;
+        Ok(txs.len())
+    }
+
+    /// Adds the transactions written by `save()` that are still in the daemon's mempool (i.e. in
+    /// `mempool_txids`), without fetching them. Returns the # of added and discarded transactions
+    /// (none if `path` doesn't exist, e.g. on the first run). Their first-seen times are kept,
+    /// unless saved by an older version (without them), when they're reset to now.
+    pub fn load(
+        &mut self,
+        path: &Path,
+        mempool_txids: &HashSet<Sha256dHash>,
+    ) -> Result<(usize, usize)> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(err) => bail!("failed to read {:?}: {}", path, err),
+        };
+        let version: u32 =
+            bincode::deserialize(&data).chain_err(|| format!("failed to parse {:?}", path))?;
+        let txs: Vec<SavedTx> = match version {
+            MEMPOOL_FILE_VERSION => {
+                let (_, txs): (u32, Vec<SavedTx>) = bincode::deserialize(&data)
+                    .chain_err(|| format!("failed to parse {:?}", path))?;
+                txs
+            }
+            1 => {
+                let (_, txs): (u32, Vec<SavedTxV1>) = bincode::deserialize(&data)
+                    .chain_err(|| format!("failed to parse {:?}", path))?;
+                info!("{:?} has no first-seen times, resetting them", path);
+                let now = unix_time();
+                txs.into_iter()
+                    .map(|saved| SavedTx {
+                        rawtx: saved.rawtx,
+                        fee: saved.fee,
+                        vsize: saved.vsize,
+                        first_seen: now,
+                    })
+                    .collect()
+            }
+            _ => bail!("unsupported mempool file version {} in {:?}", version, path),
+        };
+        let (mut added, mut discarded) = (0, 0);
+        for saved in txs {
+            let tx: Transaction =
+                deserialize(&saved.rawtx).chain_err(|| format!("invalid tx in {:?}", path))?;
+            let txid = tx.txid();
+            if !mempool_txids.contains(&txid) {
+                discarded += 1; // e.g. confirmed or evicted while stopped
+                continue;
+            }
+            if !self.items.contains_key(&txid) {
+                let entry = MempoolEntry::new(saved.fee, saved.vsize);
+                self.add(&txid, tx, entry, saved.first_seen);
+                added += 1;
+            }
+        }
+        self.evict();
+        self.update_fee_histogram();
+        self.update_stats();
+        Ok((added, discarded))
+    }
+
+    fn add(&mut self, txid: &Sha256dHash, tx: Transaction, entry: MempoolEntry, first_seen: u64) {
+        for txin in &tx.input {
+            let outpoint = (txin.previous_output.txid, txin.previous_output.vout);
+            if let Some(spender) = self.spends.insert(outpoint, *txid) {
+                if spender != *txid {
+                    self.conflicts.add(outpoint, spender);
+                }
+                let replaced_rbf = self
+                    .items
+                    .get(&spender)
+                    .map_or(false, |item| signals_rbf(&item.tx));
+                if spender != *txid && replaced_rbf {
+                    log_fields!(
+                        Level::Debug,
+                        { txid: spender, replaced_by: txid },
+                        "{} replaced by {}",
+                        spender,
+                        txid
+                    );
+                    self.replacements.add(spender, *txid);
+                }
+            }
+        }
+        let size = serialize(&tx).len() + self.index.add(&tx);
+        self.memory += size;
+        self.vsize += u64::from(entry.vsize());
+        self.total_fee += entry.fee();
+        self.evicted.remove(txid);
+        self.items.insert(
+            *txid,
+            Item {
+                tx,
+                entry,
+                size,
+                first_seen,
+            },
+        );
     }
 
     fn remove(&mut self, txid: &Sha256dHash) {
@@ -249,7 +795,106 @@ impl Tracker {
             .items
             .remove(txid)
             .expect(&format!("missing mempool tx {}", txid));
+        for txin in &stats.tx.input {
+            let outpoint = (txin.previous_output.txid, txin.previous_output.vout);
+            if self.spends.get(&outpoint) == Some(txid) {
+                self.spends.remove(&outpoint);
+            }
+        }
         self.index.remove(&stats.tx);
+        self.memory -= stats.size;
+        self.vsize -= u64::from(stats.entry.vsize());
+        self.total_fee -= stats.entry.fee();
+    }
+
+    // Removes the transactions the daemon no longer has (after adding the new ones, so that their
+    // replacements are known), returning why they're gone. Only the confirmed ones are indexed.
+    fn remove_missing(
+        &mut self,
+        txids: &[Sha256dHash],
+        store: &ReadStore,
+    ) -> Vec<(Sha256dHash, Removal)> {
+        let removals: Vec<(Sha256dHash, Removal)> = txids
+            .iter()
+            .map(|txid| (*txid, self.removal(txid, store)))
+            .collect();
+        for (txid, removal) in &removals {
+            self.remove(txid);
+            if *removal != Removal::Confirmed {
+                log_fields!(
+                    Level::Debug,
+                    { txid: txid, reason: removal.label() },
+                    "{} {} from the mempool",
+                    txid,
+                    removal.label()
+                );
+                self.stats
+                    .evicted
+                    .with_label_values(&[removal.label()])
+                    .inc();
+            }
+        }
+        removals
+    }
+
+    fn removal(&self, txid: &Sha256dHash, store: &ReadStore) -> Removal {
+        if store.get(&TxRow::filter_full(txid)).is_some() {
+            return Removal::Confirmed;
+        }
+        let item = &self.items[txid];
+        let replaced = item.tx.input.iter().any(|txin| {
+            let outpoint = (txin.previous_output.txid, txin.previous_output.vout);
+            self.spends
+                .get(&outpoint)
+                .map_or(false, |spender| spender != txid)
+        });
+        if replaced {
+            Removal::Replaced
+        } else {
+            Removal::Dropped
+        }
+    }
+
+    // Drops the transactions with the lowest descendant fee rate (along with their descendants,
+    // which can't be mined without them) until at most `max_txs` are left, like the daemon does
+    // once its mempool is full. The fee rates are computed once, before evicting any transaction.
+    fn evict(&mut self) {
+        if self.max_txs == 0 || self.items.len() <= self.max_txs {
+            return;
+        }
+        let mut scores: Vec<(f32, Sha256dHash)> = self
+            .items
+            .keys()
+            .map(|txid| {
+                let descendants = self.related(txid, |txid, item| self.children(txid, item));
+                (self.package(&descendants).fee_rate(), *txid)
+            })
+            .collect();
+        scores.sort_unstable_by(|(r1, _), (r2, _)| r1.partial_cmp(r2).unwrap());
+        let mut evicted = 0;
+        for (_, txid) in scores {
+            if self.items.len() <= self.max_txs {
+                break;
+            }
+            if !self.items.contains_key(&txid) {
+                continue; // already evicted, as a descendant
+            }
+            for txid in self.related(&txid, |txid, item| self.children(txid, item)) {
+                self.remove(&txid);
+                self.evicted.insert(txid);
+                evicted += 1;
+            }
+        }
+        debug!("evicted {} mempool transactions", evicted);
+        self.stats
+            .evicted
+            .with_label_values(&["limit"])
+            .inc_by(evicted as i64);
+    }
+
+    fn update_stats(&self) {
+        self.stats.count.set(self.items.len() as i64);
+        self.stats.memory.set(self.memory as i64);
     }
 
     fn update_fee_histogram(&mut self) {
@@ -259,9 +904,14 @@ impl Tracker {
         });
         self.histogram = electrum_fees(&entries);
         self.stats.update(&entries);
+        *self.fee_rates.lock().unwrap() = None; // recomputed lazily
     }
 }
 
+fn unix_time() -> u64 {
+    time::get_time().sec as u64
+}
+
 fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
     let mut histogram = vec![];
     let mut bin_size = 0;
@@ -280,3 +930,358 @@ fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+#[cfg(test)]
+mod tests {
+    use bincode;
+    use bitcoin::blockdata::transaction::Transaction;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::util::hash::Sha256dHash;
+    use daemon::MempoolEntry;
+    use index::{compute_script_hash, TxOutRow, TxRow};
+    use mempool::{
+        fee_rank, FeeRank, MempoolStore, MempoolSummary, PackageStats, Removal, Tracker,
+    };
+    use metrics::Metrics;
+    use std::collections::HashSet;
+    use std::fs;
+    use store::ReadStore;
+    use testutil::{make_tx, temp_path};
+    use util::Bytes;
+
+    // spending the first output of each parent, with `outputs` outputs
+    fn spend(parents: &[Sha256dHash], outputs: usize) -> Transaction {
+        let inputs: Vec<(Sha256dHash, u32)> = parents.iter().map(|parent| (*parent, 0)).collect();
+        let values: Vec<u64> = (0..outputs as u64).collect();
+        make_tx(&inputs, &values)
+    }
+
+    fn add(tracker: &mut Tracker, tx: &Transaction, fee: u64, vsize: u32) -> Sha256dHash {
+        let txid = tx.txid();
+        tracker.add(
+            &txid,
+            tx.clone(),
+            MempoolEntry::new(fee, vsize),
+            1_500_000_000,
+        );
+        txid
+    }
+
+    #[test]
+    fn test_package_fee_rates() {
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        // a low fee parent, bumped by its child (CPFP), itself spent by a grandchild
+        let confirmed = Sha256dHash::default();
+        let parent = add(&mut tracker, &spend(&[confirmed], 2), 100, 100);
+        let child = add(&mut tracker, &spend(&[parent], 1), 1900, 100);
+        let grandchild = add(&mut tracker, &spend(&[child], 1), 300, 100);
+
+        let graph = tracker.get_graph(&parent).unwrap();
+        assert_eq!(graph.fee / u64::from(graph.vsize), 1);
+        let package = |count, fee, vsize| PackageStats { count, fee, vsize };
+        assert_eq!(graph.ancestors, package(1, 100, 100));
+        assert_eq!(graph.descendants, package(3, 2300, 300));
+
+        let graph = tracker.get_graph(&child).unwrap();
+        assert_eq!(graph.ancestors, package(2, 2000, 200));
+        assert_eq!(graph.ancestors.fee_rate(), 10.0);
+        assert_eq!(graph.descendants, package(2, 2200, 200));
+
+        let graph = tracker.get_graph(&grandchild).unwrap();
+        assert_eq!(graph.ancestors, package(3, 2300, 300));
+        assert_eq!(graph.descendants, package(1, 300, 100));
+
+        // a diamond: the common ancestor is only counted once
+        let other = add(&mut tracker, &spend(&[confirmed], 3), 200, 100);
+        let mut spend_both = spend(&[parent, other], 1);
+        spend_both.input[0].previous_output.vout = 1;
+        let both = add(&mut tracker, &spend_both, 700, 100);
+        assert_eq!(
+            tracker.get_graph(&both).unwrap().ancestors,
+            package(3, 1000, 300)
+        );
+        assert_eq!(
+            tracker.get_graph(&parent).unwrap().descendants,
+            package(4, 3000, 400)
+        );
+    }
+
+    #[test]
+    fn test_effective_fee_rate() {
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let confirmed = Sha256dHash::default();
+        // a child arriving before its parent is linked to it once the parent is added
+        let parent_tx = spend(&[confirmed], 1);
+        let child = add(&mut tracker, &spend(&[parent_tx.txid()], 1), 1900, 100);
+        let graph = tracker.get_graph(&child).unwrap();
+        assert_eq!(graph.ancestors.count, 1);
+        assert_eq!(graph.effective_fee_rate, 19.0);
+        assert!(!tracker.has_unconfirmed_inputs(&child));
+        let parent = add(&mut tracker, &parent_tx, 100, 100);
+        assert!(tracker.has_unconfirmed_inputs(&child));
+        assert!(!tracker.has_unconfirmed_inputs(&parent));
+        let graph = tracker.get_graph(&child).unwrap();
+        assert_eq!(graph.ancestor_txids, vec![parent]);
+        assert_eq!(graph.effective_fee_rate, 10.0);
+        // bumped by its child (CPFP)
+        let graph = tracker.get_graph(&parent).unwrap();
+        assert_eq!(graph.descendant_txids, vec![child]);
+        assert_eq!(graph.effective_fee_rate, 10.0);
+
+        // a high fee parent doesn't bump its low fee child
+        let rich = add(&mut tracker, &spend(&[confirmed], 2), 1000, 100);
+        let mut poor_tx = spend(&[rich], 1);
+        poor_tx.input[0].previous_output.vout = 1;
+        let poor = add(&mut tracker, &poor_tx, 100, 100);
+        assert_eq!(tracker.get_graph(&rich).unwrap().effective_fee_rate, 10.0);
+        assert_eq!(tracker.get_graph(&poor).unwrap().effective_fee_rate, 1.0);
+
+        // once the parent is confirmed, the child is on its own
+        tracker.remove(&parent);
+        let graph = tracker.get_graph(&child).unwrap();
+        assert_eq!(
+            graph.ancestors,
+            PackageStats {
+                count: 1,
+                fee: 1900,
+                vsize: 100
+            }
+        );
+        assert!(graph.ancestor_txids.is_empty());
+        assert_eq!(graph.effective_fee_rate, 19.0);
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut tracker = Tracker::new(3, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let tx = |vout| {
+            let mut tx = spend(&[Sha256dHash::default()], 1);
+            tx.input[0].previous_output.vout = vout;
+            tx
+        };
+        // a low fee parent bumped by its child (paying 5.5 sat/vbyte together)
+        let parent = add(&mut tracker, &tx(0), 100, 100);
+        let child = add(&mut tracker, &spend(&[parent], 1), 1000, 100);
+        let cheap_tx = tx(1);
+        let cheap = add(&mut tracker, &cheap_tx, 300, 100);
+        let other = add(&mut tracker, &tx(2), 800, 100);
+        let memory = tracker.memory;
+        assert!(memory > 0);
+
+        tracker.evict();
+        assert!(!tracker.has_txn(&cheap));
+        assert_eq!(
+            tracker.evicted,
+            vec![cheap].into_iter().collect::<HashSet<_>>()
+        );
+        assert!(tracker.memory < memory);
+
+        // the parent is evicted along with its child (which can't be mined without it)
+        let more = add(&mut tracker, &tx(3), 600, 100);
+        tracker.evict();
+        assert!(!tracker.has_txn(&parent));
+        assert!(!tracker.has_txn(&child));
+        assert!(tracker.has_txn(&more) && tracker.has_txn(&other));
+        assert_eq!(tracker.evicted.len(), 3);
+
+        // evicted transactions can be added back
+        add(&mut tracker, &cheap_tx, 300, 100);
+        assert!(tracker.has_txn(&cheap));
+        assert!(!tracker.evicted.contains(&cheap));
+        for txid in vec![cheap, more, other] {
+            tracker.remove(&txid);
+        }
+        assert_eq!(tracker.memory, 0);
+        assert_eq!((tracker.vsize, tracker.total_fee), (0, 0));
+        assert!(tracker.index.map.is_empty());
+
+        // unlimited
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        for vout in 0..10 {
+            add(&mut tracker, &tx(vout), 100, 100);
+        }
+        tracker.evict();
+        assert_eq!(
+            tracker.summary(),
+            MempoolSummary {
+                count: 10,
+                vsize: 1000,
+                total_fee: 1000,
+            }
+        );
+        assert!(tracker.evicted.is_empty());
+    }
+
+    #[test]
+    fn test_remove_missing() {
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let funding = Sha256dHash::default();
+        let confirmed_tx = spend(&[funding], 1);
+        let confirmed = add(&mut tracker, &confirmed_tx, 100, 100);
+        let replaced = add(&mut tracker, &spend(&[confirmed], 1), 100, 100);
+        let dropped_tx = spend(&[Sha256dHash::from_data(b"other")], 1);
+        let dropped = add(&mut tracker, &dropped_tx, 100, 100);
+        let replacement = add(&mut tracker, &spend(&[confirmed], 2), 200, 100);
+
+        // the confirmed transactions are found in the index
+        let mut index = MempoolStore::new();
+        index.add(&confirmed_tx);
+        let removals = tracker.remove_missing(&[confirmed, replaced, dropped], &index);
+        assert_eq!(
+            removals,
+            vec![
+                (confirmed, Removal::Confirmed),
+                (replaced, Removal::Replaced),
+                (dropped, Removal::Dropped),
+            ]
+        );
+        assert_eq!(tracker.summary().count, 1);
+        assert!(tracker.has_txn(&replacement));
+        // they're gone from the mempool histories
+        assert!(tracker
+            .index()
+            .scan(&TxRow::filter_full(&dropped))
+            .is_empty());
+        let script_rows = |tracker: &Tracker| {
+            let hash = compute_script_hash(dropped_tx.output[0].script_pubkey.as_bytes());
+            tracker.index().scan(&TxOutRow::filter(&hash)).len()
+        };
+        assert_eq!(script_rows(&tracker), 1); // the replacement's output
+        tracker.remove(&replacement);
+        assert_eq!(script_rows(&tracker), 0);
+        assert!(tracker.spends.is_empty());
+
+        let evicted = |reason| tracker.stats.evicted.with_label_values(&[reason]).get();
+        assert_eq!(evicted("confirmed"), 0);
+        assert_eq!(evicted("replaced"), 1);
+        assert_eq!(evicted("dropped"), 1);
+    }
+
+    #[test]
+    fn test_spend_conflicts() {
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let funding = Sha256dHash::default();
+        let payment = add(&mut tracker, &spend(&[funding], 1), 100, 100);
+        assert!(tracker.get_replaced_spends(&funding, 0).is_empty());
+
+        // double-spent by a conflicting transaction, itself replaced by another one
+        let double_spend = add(&mut tracker, &spend(&[funding], 2), 200, 100);
+        tracker.remove(&payment);
+        assert_eq!(tracker.get_replaced_spends(&funding, 0), vec![payment]);
+        let again = add(&mut tracker, &spend(&[funding], 3), 300, 100);
+        tracker.remove(&double_spend);
+        assert_eq!(
+            tracker.get_replaced_spends(&funding, 0),
+            vec![payment, double_spend]
+        );
+        assert_eq!(tracker.spends[&(funding, 0)], again);
+        assert!(tracker.get_replaced_spends(&funding, 1).is_empty());
+    }
+
+    #[test]
+    fn test_remove_replaced() {
+        let mut tracker = Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let funding = Sha256dHash::default();
+        let original = add(&mut tracker, &spend(&[funding], 1), 100, 100);
+        assert_eq!(tracker.get_spend(&funding, 0), Some((original, 0)));
+
+        // the replacement is added before the original is removed (as by Tracker::update)
+        let replacement = add(&mut tracker, &spend(&[funding], 2), 200, 100);
+        tracker.remove(&original);
+        assert_eq!(tracker.get_spend(&funding, 0), Some((replacement, 0)));
+        assert!(tracker.get_spend(&funding, 1).is_none());
+        assert!(tracker
+            .index()
+            .scan(&TxRow::filter_full(&original))
+            .is_empty());
+        assert_eq!(
+            tracker
+                .index()
+                .scan(&TxRow::filter_full(&replacement))
+                .len(),
+            1
+        );
+
+        tracker.remove(&replacement);
+        assert!(tracker.get_spend(&funding, 0).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("mempool");
+        let new_tracker = || Tracker::new(0, &Metrics::new("127.0.0.1:0".parse().unwrap()));
+        let mut tracker = new_tracker();
+        let confirmed = Sha256dHash::default();
+        let parent = add(&mut tracker, &spend(&[confirmed], 1), 100, 100);
+        let child = add(&mut tracker, &spend(&[parent], 1), 1900, 100);
+        let mut other_tx = spend(&[confirmed], 1);
+        other_tx.input[0].previous_output.vout = 1;
+        let other = add(&mut tracker, &other_tx, 500, 200);
+        assert_eq!(tracker.save(&path).unwrap(), 3);
+
+        // the transaction the daemon no longer has is discarded
+        let mut reloaded = new_tracker();
+        let mempool_txids: HashSet<Sha256dHash> = vec![parent, child].into_iter().collect();
+        assert_eq!(reloaded.load(&path, &mempool_txids).unwrap(), (2, 1));
+        assert!(!reloaded.has_txn(&other));
+        assert_eq!(reloaded.get_fee(&child), Some((1900, 100)));
+        assert_eq!(
+            reloaded.get_graph(&child).unwrap().ancestor_txids,
+            vec![parent]
+        );
+        assert_eq!(reloaded.summary().count, 2);
+        // with their first-seen times
+        assert_eq!(
+            reloaded.get_graph(&child).unwrap().first_seen,
+            1_500_000_000
+        );
+        // already tracked transactions are kept
+        assert_eq!(reloaded.load(&path, &mempool_txids).unwrap(), (0, 1));
+
+        // the first-seen times are reset when loading the previous version's format
+        let v1: Vec<(Bytes, u64, u32)> = vec![(serialize(&other_tx), 500, 200)];
+        fs::write(&path, bincode::serialize(&(1u32, v1)).unwrap()).unwrap();
+        let mut reloaded = new_tracker();
+        let mempool_txids: HashSet<Sha256dHash> = vec![other].into_iter().collect();
+        assert_eq!(reloaded.load(&path, &mempool_txids).unwrap(), (1, 0));
+        assert_eq!(reloaded.get_fee(&other), Some((500, 200)));
+        assert!(reloaded.get_graph(&other).unwrap().first_seen > 1_500_000_000);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.load(&path, &mempool_txids).unwrap(), (0, 0));
+        fs::write(&path, b"garbage").unwrap();
+        assert!(reloaded.load(&path, &mempool_txids).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fee_rank() {
+        let fee_rates = [1.0, 2.0, 2.0, 5.0, 10.0];
+        assert_eq!(
+            fee_rank(&fee_rates, 10.0),
+            FeeRank {
+                rank: 1,
+                count: 5,
+                percentile: 80.0
+            }
+        );
+        // transactions paying the same fee rate share a rank
+        assert_eq!(
+            fee_rank(&fee_rates, 2.0),
+            FeeRank {
+                rank: 3,
+                count: 5,
+                percentile: 20.0
+            }
+        );
+        assert_eq!(
+            fee_rank(&fee_rates, 1.0),
+            FeeRank {
+                rank: 5,
+                count: 5,
+                percentile: 0.0
+            }
+        );
+        assert_eq!(fee_rank(&[3.0], 3.0).rank, 1);
+    }
+}
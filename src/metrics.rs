@@ -1,18 +1,26 @@
 use page_size;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
 use prometheus::{self, Encoder};
+use serde_json;
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::fs;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sysconf;
+use time;
 use tiny_http;
 
 pub use prometheus::{
-    GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter as Counter,
-    IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
+    Gauge as FloatGauge, GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec,
+    IntCounter as Counter, IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
 };
 
+use config::{HttpAuth, HTTP_AUTH_CHALLENGE};
 use util::spawn_thread;
 
 use errors::*;
@@ -20,6 +28,7 @@ use errors::*;
 pub struct Metrics {
     reg: prometheus::Registry,
     addr: SocketAddr,
+    sync: Arc<SyncState>,
 }
 
 impl Metrics {
@@ -27,9 +36,14 @@ impl Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr,
+            sync: Arc::new(SyncState::new()),
         }
     }
 
+    pub fn sync_state(&self) -> Arc<SyncState> {
+        self.sync.clone()
+    }
+
     pub fn counter(&self, opts: prometheus::Opts) -> Counter {
         let c = Counter::with_opts(opts).unwrap();
         self.reg.register(Box::new(c.clone())).unwrap();
@@ -48,6 +62,12 @@ impl Metrics {
         g
     }
 
+    pub fn float_gauge(&self, opts: prometheus::Opts) -> FloatGauge {
+        let g = FloatGauge::with_opts(opts).unwrap();
+        self.reg.register(Box::new(g.clone())).unwrap();
+        g
+    }
+
     pub fn gauge_vec(&self, opts: prometheus::Opts, labels: &[&str]) -> GaugeVec {
         let g = GaugeVec::new(opts, labels).unwrap();
         self.reg.register(Box::new(g.clone())).unwrap();
@@ -66,31 +86,459 @@ impl Metrics {
         h
     }
 
-    pub fn start(&self) {
+    /// Serves the metrics, and the `/healthz`, `/readyz` and `/debug/state` endpoints
+    /// (ready once the index is at most `ready_max_lag` blocks behind the daemon), requiring
+    /// these credentials if any.
+    pub fn start(&self, ready_max_lag: usize, auth: Option<HttpAuth>) {
         let server = tiny_http::Server::http(self.addr).expect(&format!(
             "failed to start monitoring HTTP server at {}",
             self.addr
         ));
         start_process_exporter(&self);
         let reg = self.reg.clone();
+        let sync = self.sync.clone();
         spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
+            let request = server.recv();
+            if let Err(e) = handle_request(&reg, &sync, ready_max_lag, auth.as_ref(), request) {
                 error!("http error: {}", e);
             }
         });
     }
 }
 
+/// The rate at which a count increases (e.g. indexed blocks per second), as an exponential moving
+/// average over the intervals it's updated at: each one is weighted by `alpha`, so that the rate
+/// doesn't swing with the variations between them.
+pub struct MovingRate {
+    alpha: f64,
+    count: u64,
+    rate: Option<f64>, // per second
+}
+
+impl MovingRate {
+    pub fn new(alpha: f64, count: u64) -> MovingRate {
+        MovingRate {
+            alpha,
+            count,
+            rate: None,
+        }
+    }
+
+    /// Updates the rate with the count reached `elapsed` after the previous update, returning it.
+    pub fn update(&mut self, count: u64, elapsed: Duration) -> f64 {
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        if secs > 0.0 {
+            let current = count.saturating_sub(self.count) as f64 / secs;
+            self.rate = Some(match self.rate {
+                Some(rate) => rate + self.alpha * (current - rate),
+                None => current,
+            });
+        }
+        self.count = count;
+        self.rate()
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate.unwrap_or(0.0)
+    }
+
+    /// The estimated time until the count reaches `target` (None while it isn't increasing).
+    pub fn eta(&self, target: u64) -> Option<Duration> {
+        let rate = self.rate.filter(|rate| *rate > 0.0)?;
+        let remaining = target.saturating_sub(self.count) as f64;
+        Some(Duration::from_secs((remaining / rate).ceil() as u64))
+    }
+}
+
+// seconds since the last main loop iteration / successful daemon RPC
+const ALIVE_TIMEOUT: usize = 60;
+const DAEMON_TIMEOUT: usize = 60;
+
+// the indexing rate reflects the last minute, sampled every second at most
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const RATE_SAMPLING: Duration = Duration::from_secs(1);
+
+fn unix_time() -> usize {
+    time::get_time().sec as usize
+}
+
+/// The rate of a growing count (e.g. the indexed blocks) over a sliding window of recent samples,
+/// reflecting the recent progress rather than the lifetime average.
+struct SlidingRate {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl SlidingRate {
+    fn new() -> SlidingRate {
+        SlidingRate {
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, now: Instant, count: usize) {
+        match self.samples.back() {
+            // e.g. a reorg's rollback: the progress starts over
+            Some(&(_, last)) if count < last => self.samples.clear(),
+            Some(&(time, _)) if now.duration_since(time) < RATE_SAMPLING => return,
+            _ => (),
+        }
+        self.samples.push_back((now, count));
+        while self.samples.len() > 2 && now.duration_since(self.samples[0].0) > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Per second, or `None` without progress over the window (or if it's too recent to tell).
+    fn rate(&self, now: Instant) -> Option<f64> {
+        let (first_time, first) = *self.samples.front()?;
+        let (_, last) = *self.samples.back()?;
+        let elapsed = now.duration_since(first_time);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        if last == first || secs < 1.0 {
+            return None;
+        }
+        Some((last - first) as f64 / secs)
+    }
+}
+
+/// The indexing and daemon state, updated as they progress (so that the health endpoints don't
+/// have to query the DB or the daemon). Times are in seconds since the epoch, 0 meaning never.
+pub struct SyncState {
+    started: usize,
+    heartbeat: AtomicUsize, // last main loop iteration (after the initial sync)
+    index_height: AtomicUsize,
+    daemon_height: AtomicUsize,
+    daemon_success: AtomicUsize,    // last successful daemon RPC
+    daemon_syncing: AtomicBool,     // while waiting for the daemon's initial block download
+    serving: AtomicBool,            // the REST server is started (maybe during the initial sync)
+    index_rate: Mutex<SlidingRate>, // of the indexed blocks
+}
+
+#[derive(Serialize, Debug)]
+struct SyncStatus {
+    alive: bool,
+    ready: bool,
+    initial_sync: bool,
+    index_height: usize,
+    daemon_height: usize,
+    ready_max_lag: usize,
+    last_heartbeat: Option<usize>,
+    last_daemon_success: Option<usize>,
+    daemon_syncing: bool,
+    partial: bool, // serving the blocks indexed so far, during the initial sync
+    index_rate: Option<f64>, // blocks/s, over the last minute
+    eta: Option<usize>, // seconds left to catch up with the daemon, at this rate
+    not_ready: Vec<String>,
+}
+
+impl SyncState {
+    fn new() -> SyncState {
+        SyncState {
+            started: unix_time(),
+            heartbeat: AtomicUsize::new(0),
+            index_height: AtomicUsize::new(0),
+            daemon_height: AtomicUsize::new(0),
+            daemon_success: AtomicUsize::new(0),
+            daemon_syncing: AtomicBool::new(false),
+            serving: AtomicBool::new(false),
+            index_rate: Mutex::new(SlidingRate::new()),
+        }
+    }
+
+    /// Called on each main loop iteration, once the initial sync is over.
+    pub fn heartbeat(&self) {
+        self.heartbeat.store(unix_time(), Ordering::Relaxed);
+    }
+
+    /// Whether the main loop is running, i.e. the initial sync is over.
+    pub fn initial_sync_done(&self) -> bool {
+        self.heartbeat.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn set_index_height(&self, height: usize) {
+        self.index_height.store(height, Ordering::Relaxed);
+        let mut rate = self.index_rate.lock().unwrap();
+        rate.record(Instant::now(), height);
+    }
+
+    /// The recent indexing rate (in blocks/s) and the estimated time left to catch up with the
+    /// daemon (in seconds), if it's indexing.
+    pub fn index_progress(&self) -> (Option<f64>, Option<usize>) {
+        let rate = self.index_rate.lock().unwrap().rate(Instant::now());
+        let left = self
+            .daemon_height
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.index_height.load(Ordering::Relaxed));
+        (rate, rate.map(|rate| (left as f64 / rate).ceil() as usize))
+    }
+
+    pub fn set_daemon_height(&self, height: usize) {
+        self.daemon_height.store(height, Ordering::Relaxed);
+    }
+
+    pub fn daemon_succeeded(&self) {
+        self.daemon_success.store(unix_time(), Ordering::Relaxed);
+    }
+
+    pub fn set_daemon_syncing(&self, syncing: bool) {
+        self.daemon_syncing.store(syncing, Ordering::Relaxed);
+    }
+
+    /// In seconds, since the process started.
+    pub fn uptime(&self) -> usize {
+        unix_time().saturating_sub(self.started)
+    }
+
+    /// Called once the REST server is started.
+    pub fn set_serving(&self) {
+        self.serving.store(true, Ordering::Relaxed);
+    }
+
+    fn status(&self, ready_max_lag: usize, now: usize) -> SyncStatus {
+        let since = |time: usize| if time > 0 { Some(time) } else { None };
+        let last_heartbeat = since(self.heartbeat.load(Ordering::Relaxed));
+        let last_daemon_success = since(self.daemon_success.load(Ordering::Relaxed));
+        let index_height = self.index_height.load(Ordering::Relaxed);
+        let daemon_height = self.daemon_height.load(Ordering::Relaxed);
+        let daemon_syncing = self.daemon_syncing.load(Ordering::Relaxed);
+        let (index_rate, eta) = self.index_progress();
+
+        let mut not_ready = vec![];
+        // the main loop isn't running during the initial sync, so it can't be stuck yet
+        let alive = match last_heartbeat {
+            None => {
+                not_ready.push("initial sync in progress".to_owned());
+                true
+            }
+            Some(time) => now.saturating_sub(time) <= ALIVE_TIMEOUT,
+        };
+        if !alive {
+            not_ready.push(format!("main loop stalled for over {}s", ALIVE_TIMEOUT));
+        }
+        if daemon_syncing {
+            not_ready.push("daemon initial block download in progress".to_owned());
+        }
+        if daemon_height.saturating_sub(index_height) > ready_max_lag {
+            not_ready.push(format!(
+                "index is {} blocks behind the daemon",
+                daemon_height - index_height
+            ));
+        }
+        if last_daemon_success.map_or(true, |time| now.saturating_sub(time) > DAEMON_TIMEOUT) {
+            not_ready.push(format!("no successful daemon RPC in {}s", DAEMON_TIMEOUT));
+        }
+        SyncStatus {
+            alive,
+            ready: not_ready.is_empty(),
+            initial_sync: last_heartbeat.is_none(),
+            index_height,
+            daemon_height,
+            ready_max_lag,
+            last_heartbeat,
+            last_daemon_success,
+            daemon_syncing,
+            partial: last_heartbeat.is_none() && self.serving.load(Ordering::Relaxed),
+            index_rate,
+            eta,
+            not_ready,
+        }
+    }
+}
+
+type Response = tiny_http::Response<io::Cursor<Vec<u8>>>;
+
+fn json_response(status: &SyncStatus, ok: bool) -> Response {
+    let body = serde_json::to_vec(status).unwrap();
+    tiny_http::Response::from_data(body)
+        .with_status_code(if ok { 200 } else { 503 })
+        .with_header(
+            "Content-Type: application/json"
+                .parse::<tiny_http::Header>()
+                .unwrap(),
+        )
+}
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+fn unauthorized_response() -> Response {
+    tiny_http::Response::from_string("Authentication required")
+        .with_status_code(401)
+        .with_header(
+            format!("WWW-Authenticate: {}", HTTP_AUTH_CHALLENGE)
+                .parse::<tiny_http::Header>()
+                .unwrap(),
+        )
+}
+
+fn authorization(request: &tiny_http::Request) -> Option<&str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str())
+}
+
+// Whether the client prefers the OpenMetrics format (over Prometheus' text format).
+fn accepts_openmetrics(request: &tiny_http::Request) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Accept"))
+        .flat_map(|header| header.value.as_str().split(','))
+        .any(|media_range| {
+            let mut params = media_range.split(';').map(|param| param.trim());
+            let media_type = params.next().unwrap_or("");
+            let rejected = params.any(|param| {
+                param.starts_with("q=") && param[2..].parse::<f32>().ok() == Some(0.0)
+            });
+            media_type.eq_ignore_ascii_case("application/openmetrics-text") && !rejected
+        })
+}
+
+// Escapes label values and help texts.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        (if value > 0.0 { "+Inf" } else { "-Inf" }).to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn format_labels(metric: &Metric, extra: Option<(&str, f64)>) -> String {
+    let mut labels: Vec<String> = metric
+        .get_label()
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.get_name(), escape(label.get_value())))
+        .collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, format_value(value)));
+    }
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Encodes the metrics in the OpenMetrics text format (unsupported by the prometheus crate).
+fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let metric_type = family.get_field_type();
+        let mut name = family.get_name();
+        let type_name = match metric_type {
+            MetricType::COUNTER => {
+                // the family is named without the samples' suffix
+                if name.ends_with("_total") {
+                    name = &name[..name.len() - "_total".len()];
+                }
+                "counter"
+            }
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        writeln!(out, "# TYPE {} {}", name, type_name).unwrap();
+        writeln!(out, "# HELP {} {}", name, escape(family.get_help())).unwrap();
+        for metric in family.get_metric() {
+            let labels = format_labels(metric, None);
+            let mut sample = |suffix: &str, labels: &str, value: f64| {
+                writeln!(out, "{}{}{} {}", name, suffix, labels, format_value(value)).unwrap();
+            };
+            match metric_type {
+                MetricType::COUNTER => sample("_total", &labels, metric.get_counter().get_value()),
+                MetricType::GAUGE => sample("", &labels, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => sample("", &labels, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let count = histogram.get_sample_count() as f64;
+                    let mut has_inf = false;
+                    for bucket in histogram.get_bucket() {
+                        let bound = bucket.get_upper_bound();
+                        has_inf |= bound.is_infinite();
+                        let labels = format_labels(metric, Some(("le", bound)));
+                        sample("_bucket", &labels, bucket.get_cumulative_count() as f64);
+                    }
+                    if !has_inf {
+                        // required by OpenMetrics (but implicit in the prometheus crate)
+                        let labels = format_labels(metric, Some(("le", ::std::f64::INFINITY)));
+                        sample("_bucket", &labels, count);
+                    }
+                    sample("_count", &labels, count);
+                    sample("_sum", &labels, histogram.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let labels =
+                            format_labels(metric, Some(("quantile", quantile.get_quantile())));
+                        sample("", &labels, quantile.get_value());
+                    }
+                    sample("_count", &labels, summary.get_sample_count() as f64);
+                    sample("_sum", &labels, summary.get_sample_sum());
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
 fn handle_request(
     reg: &prometheus::Registry,
+    sync: &SyncState,
+    ready_max_lag: usize,
+    auth: Option<&HttpAuth>,
     request: io::Result<tiny_http::Request>,
 ) -> io::Result<()> {
     let request = request?;
-    let mut buffer = vec![];
-    prometheus::TextEncoder::new()
-        .encode(&reg.gather(), &mut buffer)
-        .unwrap();
-    let response = tiny_http::Response::from_data(buffer);
+    if !auth.map_or(true, |auth| auth.matches(authorization(&request))) {
+        return request.respond(unauthorized_response());
+    }
+    let response = match request.url() {
+        "/healthz" | "/readyz" | "/debug/state" => {
+            let status = sync.status(ready_max_lag, unix_time());
+            let ok = match request.url() {
+                "/healthz" => status.alive,
+                "/readyz" => status.ready,
+                _ => true,
+            };
+            json_response(&status, ok)
+        }
+        _ if accepts_openmetrics(&request) => {
+            tiny_http::Response::from_data(encode_openmetrics(&reg.gather())).with_header(
+                format!("Content-Type: {}", OPENMETRICS_CONTENT_TYPE)
+                    .parse::<tiny_http::Header>()
+                    .unwrap(),
+            )
+        }
+        _ => {
+            let mut buffer = vec![];
+            let encoder = prometheus::TextEncoder::new();
+            encoder.encode(&reg.gather(), &mut buffer).unwrap();
+            tiny_http::Response::from_data(buffer).with_header(
+                format!("Content-Type: {}", encoder.format_type())
+                    .parse::<tiny_http::Header>()
+                    .unwrap(),
+            )
+        }
+    };
     request.respond(response)
 }
 
@@ -153,3 +601,149 @@ fn start_process_exporter(metrics: &Metrics) {
         thread::sleep(Duration::from_secs(5));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use metrics::{
+        encode_openmetrics, unauthorized_response, CounterVec, Gauge, HistogramOpts, HistogramVec,
+        MetricOpts, Metrics, MovingRate, SlidingRate, SyncState, ALIVE_TIMEOUT, DAEMON_TIMEOUT,
+    };
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_unauthorized_response() {
+        let response = unauthorized_response();
+        assert_eq!(response.status_code().0, 401);
+        assert!(response.headers().iter().any(|header| {
+            header.field.equiv("WWW-Authenticate") && header.value.as_str().starts_with("Basic ")
+        }));
+    }
+
+    #[test]
+    fn test_moving_rate() {
+        let mut rate = MovingRate::new(0.5, 1000);
+        assert_eq!(rate.rate(), 0.0);
+        assert_eq!(rate.eta(2000), None);
+        // the first interval sets the rate, the next ones are averaged in
+        assert_eq!(rate.update(1100, Duration::from_secs(10)), 10.0);
+        assert_eq!(rate.update(1400, Duration::from_secs(10)), 20.0);
+        assert_eq!(rate.update(1400, Duration::from_millis(500)), 10.0);
+        assert_eq!(rate.eta(2000), Some(Duration::from_secs(60)));
+        assert_eq!(rate.eta(1000), Some(Duration::from_secs(0)));
+        // an empty interval is skipped
+        assert_eq!(rate.update(1400, Duration::from_secs(0)), 10.0);
+        assert_eq!(rate.update(1400, Duration::from_secs(10)), 5.0);
+    }
+
+    #[test]
+    fn test_sliding_rate() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut rate = SlidingRate::new();
+        assert_eq!(rate.rate(start), None);
+        rate.record(at(0), 100);
+        assert_eq!(rate.rate(at(0)), None);
+        rate.record(at(10), 200);
+        assert_eq!(rate.rate(at(10)), Some(10.0));
+        // sampled every second at most
+        rate.record(at(10) + Duration::from_millis(500), 300);
+        assert_eq!(rate.samples.len(), 2);
+        // only the last minute counts
+        rate.record(at(70), 1300);
+        assert_eq!(rate.rate(at(70)), Some(1100.0 / 60.0));
+        // the rate decreases while stalled
+        assert_eq!(rate.rate(at(100)), Some(1100.0 / 90.0));
+        // a rollback starts over
+        rate.record(at(71), 1299);
+        assert_eq!(rate.rate(at(71)), None);
+    }
+
+    #[test]
+    fn test_sync_status() {
+        let sync = SyncState::new();
+        sync.set_index_height(90);
+        sync.set_daemon_height(100);
+        sync.daemon_succeeded();
+        let now = sync
+            .daemon_success
+            .load(::std::sync::atomic::Ordering::Relaxed);
+
+        // alive (but not ready) during the initial sync
+        let status = sync.status(2, now);
+        assert!(status.alive && status.initial_sync && !status.ready);
+        assert_eq!(status.not_ready.len(), 2);
+        assert!(!status.partial);
+        assert!(status.index_rate.is_none() && status.eta.is_none()); // no progress yet
+
+        // serving the blocks indexed so far (--serve-during-sync)
+        sync.set_serving();
+        let status = sync.status(2, now);
+        assert!(status.initial_sync && status.partial && !status.ready);
+
+        sync.heartbeat();
+        sync.set_index_height(98);
+        let status = sync.status(2, now);
+        assert!(status.alive && !status.initial_sync && status.ready && !status.partial);
+
+        // the daemon is (back) in its initial block download
+        sync.set_daemon_syncing(true);
+        let status = sync.status(2, now);
+        assert!(status.alive && status.daemon_syncing && !status.ready);
+        sync.set_daemon_syncing(false);
+
+        // lagging behind the daemon
+        sync.set_daemon_height(101);
+        assert!(!sync.status(2, now).ready);
+        sync.set_index_height(101);
+        assert!(sync.status(2, now).ready);
+
+        // unreachable daemon
+        let status = sync.status(2, now + DAEMON_TIMEOUT + 1);
+        assert!(status.alive && !status.ready);
+
+        // stalled main loop
+        let status = sync.status(2, now + ALIVE_TIMEOUT.max(DAEMON_TIMEOUT) + 10);
+        assert!(!status.alive && !status.ready);
+    }
+
+    #[test]
+    fn test_openmetrics() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let requests: CounterVec = metrics.counter_vec(
+            MetricOpts::new("requests_total", "# of requests"),
+            &["method"],
+        );
+        requests.with_label_values(&["GET \"/\"\n"]).inc_by(3);
+        let height: Gauge = metrics.gauge(MetricOpts::new("height", "Index height\\tip"));
+        height.set(42);
+        let latency: HistogramVec = metrics.histogram_vec(
+            HistogramOpts::new("latency", "Latency [seconds]").buckets(vec![0.5, 1.0]),
+            &["step"],
+        );
+        latency.with_label_values(&["fetch"]).observe(0.25);
+        latency.with_label_values(&["fetch"]).observe(2.0);
+
+        let text = encode_openmetrics(&metrics.reg.gather());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "# TYPE height gauge",
+                "# HELP height Index height\\\\tip",
+                "height 42",
+                "# TYPE latency histogram",
+                "# HELP latency Latency [seconds]",
+                "latency_bucket{step=\"fetch\",le=\"0.5\"} 1",
+                "latency_bucket{step=\"fetch\",le=\"1\"} 1",
+                "latency_bucket{step=\"fetch\",le=\"+Inf\"} 2",
+                "latency_count{step=\"fetch\"} 2",
+                "latency_sum{step=\"fetch\"} 2.25",
+                "# TYPE requests counter",
+                "# HELP requests # of requests",
+                "requests_total{method=\"GET \\\"/\\\"\\n\"} 3",
+                "# EOF",
+            ]
+        );
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
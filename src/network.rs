@@ -0,0 +1,238 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+
+use address::AddressParams;
+use errors::*;
+
+/// The parameters of a Viacoin network, selected once by `--network` (see `Config::from_args()`),
+/// so that the rest of the code doesn't match on `Network` to find them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkParams {
+    pub name: &'static str, // as given to `--network`, also naming the DB subdirectory
+    pub network: Network,   // for the library (which only knows mainnet, testnet and regtest)
+    pub daemon_chain: &'static str, // as reported by the daemon's `getblockchaininfo`
+    pub magic: u32,         // prefixing the P2P messages and the blocks in the blk*.dat files
+    pub daemon_port: u16,
+    pub electrum_port: u16,
+    pub http_port: u16,
+    pub monitoring_port: u16,
+    pub daemon_subdir: Option<&'static str>, // of the daemon's data directory
+    pub address: AddressParams,
+    pub genesis_hash: Sha256dHash,
+    pub target_block_interval: u32, // in seconds
+}
+
+impl NetworkParams {
+    pub fn mainnet() -> NetworkParams {
+        NetworkParams {
+            name: "mainnet",
+            network: Network::Bitcoin,
+            daemon_chain: "main",
+            magic: Network::Bitcoin.magic(),
+            daemon_port: 5222,
+            electrum_port: 50001,
+            http_port: 3000,
+            monitoring_port: 4224,
+            daemon_subdir: None,
+            address: AddressParams::for_network(&Network::Bitcoin),
+            genesis_hash: genesis_block(Network::Bitcoin).bitcoin_hash(),
+            target_block_interval: 24, // on all networks (Bitcoin's is 10 minutes)
+        }
+    }
+
+    pub fn testnet() -> NetworkParams {
+        NetworkParams {
+            name: "testnet",
+            network: Network::Testnet,
+            daemon_chain: "test",
+            magic: Network::Testnet.magic(),
+            daemon_port: 25222,
+            electrum_port: 60001,
+            http_port: 3001,
+            monitoring_port: 14224,
+            daemon_subdir: Some("testnet3"),
+            address: AddressParams::for_network(&Network::Testnet),
+            genesis_hash: genesis_block(Network::Testnet).bitcoin_hash(),
+            target_block_interval: 24,
+        }
+    }
+
+    pub fn regtest() -> NetworkParams {
+        NetworkParams {
+            name: "regtest",
+            network: Network::Regtest,
+            daemon_chain: "regtest",
+            magic: Network::Regtest.magic(),
+            daemon_port: 25222,
+            electrum_port: 60401,
+            http_port: 3002,
+            monitoring_port: 24224,
+            daemon_subdir: Some("regtest"),
+            address: AddressParams::for_network(&Network::Regtest),
+            genesis_hash: genesis_block(Network::Regtest).bitcoin_hash(),
+            target_block_interval: 24,
+        }
+    }
+
+    /// A custom test network (--custom-network), e.g. a private test chain: the base network's
+    /// parameters (its addresses, P2P magic and daemon chain), but its own genesis block (which the
+    /// daemon must have, see `Daemon::new()`) and default daemon, HTTP and monitoring ports (the
+    /// base network's, unless given). Its database is kept apart, under the "custom" name.
+    pub fn custom(
+        base: NetworkParams,
+        genesis_hash: Sha256dHash,
+        ports: Option<(u16, u16, u16)>,
+    ) -> NetworkParams {
+        let (daemon_port, http_port, monitoring_port) =
+            ports.unwrap_or((base.daemon_port, base.http_port, base.monitoring_port));
+        NetworkParams {
+            name: "custom",
+            genesis_hash,
+            daemon_port,
+            http_port,
+            monitoring_port,
+            ..base
+        }
+    }
+
+    /// Whether it's a custom network (see `custom()`).
+    pub fn is_custom(&self) -> bool {
+        self.name == "custom"
+    }
+
+    pub fn all() -> Vec<NetworkParams> {
+        vec![
+            NetworkParams::mainnet(),
+            NetworkParams::testnet(),
+            NetworkParams::regtest(),
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Result<NetworkParams> {
+        match NetworkParams::all()
+            .into_iter()
+            .find(|params| params.name == name)
+        {
+            Some(params) => Ok(params),
+            None => bail!("unsupported Viacoin network: {:?}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address::AddressParams;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use network::NetworkParams;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_network_params() {
+        let cases = vec![
+            (
+                "mainnet",
+                Network::Bitcoin,
+                "main",
+                (5222, 50001, 3000, 4224),
+                None,
+                (71, 33, "via"),
+            ),
+            (
+                "testnet",
+                Network::Testnet,
+                "test",
+                (25222, 60001, 3001, 14224),
+                Some("testnet3"),
+                (127, 196, "tvia"),
+            ),
+            (
+                "regtest",
+                Network::Regtest,
+                "regtest",
+                (25222, 60401, 3002, 24224),
+                Some("regtest"),
+                (111, 196, "bcrt"),
+            ),
+        ];
+        let all = NetworkParams::all();
+        assert_eq!(all.len(), cases.len()); // a new network must be added to this table
+        for (params, case) in all.iter().zip(cases) {
+            let (name, network, chain, ports, subdir, (pubkey_prefix, script_prefix, hrp)) = case;
+            assert_eq!(params.name, name);
+            assert_eq!(params.network, network);
+            assert_eq!(params.daemon_chain, chain);
+            assert_eq!(
+                (
+                    params.daemon_port,
+                    params.electrum_port,
+                    params.http_port,
+                    params.monitoring_port
+                ),
+                ports
+            );
+            assert_eq!(params.daemon_subdir, subdir);
+            assert_eq!(params.address.pubkey_prefix, pubkey_prefix);
+            assert_eq!(params.address.script_prefix, script_prefix);
+            assert_eq!(params.address.bech_hrp, hrp);
+            assert_eq!(params.address, AddressParams::for_network(&network));
+            assert_eq!(params.target_block_interval, 24);
+            assert_eq!(NetworkParams::from_name(name).unwrap(), *params);
+        }
+        for params in &all {
+            assert_eq!(params.magic, params.network.magic());
+            assert_eq!(
+                params.genesis_hash,
+                genesis_block(params.network).bitcoin_hash()
+            );
+        }
+        assert!(NetworkParams::from_name("bitcoin").is_err());
+
+        // each network has its own chain, and their default listeners don't collide
+        let fields: Vec<fn(&NetworkParams) -> String> = vec![
+            |p| p.name.to_string(),
+            |p| p.daemon_chain.to_string(),
+            |p| format!("{:x}", p.magic),
+            |p| format!("{:?}", p.daemon_subdir),
+            |p| p.genesis_hash.be_hex_string(),
+            |p| p.electrum_port.to_string(),
+            |p| p.http_port.to_string(),
+            |p| p.monitoring_port.to_string(),
+        ];
+        for field in fields {
+            let values: HashSet<String> = all.iter().map(field).collect();
+            assert_eq!(values.len(), all.len());
+        }
+        assert!(all.iter().all(|params| !params.is_custom()));
+    }
+
+    #[test]
+    fn test_custom_network() {
+        let genesis_hash = Sha256dHash::from_data(b"custom genesis");
+        let base = NetworkParams::testnet();
+        let params = NetworkParams::custom(base, genesis_hash, None);
+        assert!(params.is_custom());
+        assert_eq!(params.name, "custom");
+        assert_eq!(params.genesis_hash, genesis_hash);
+        // the rest is the base network's
+        assert_eq!(
+            NetworkParams {
+                name: base.name,
+                genesis_hash: base.genesis_hash,
+                ..params
+            },
+            base
+        );
+        assert!(NetworkParams::from_name("custom").is_err());
+
+        let params = NetworkParams::custom(base, genesis_hash, Some((1, 2, 3)));
+        assert_eq!(
+            (params.daemon_port, params.http_port, params.monitoring_port),
+            (1, 2, 3)
+        );
+        assert_eq!(params.electrum_port, base.electrum_port);
+        assert_eq!(params.daemon_chain, base.daemon_chain);
+    }
+}
@@ -1,24 +1,47 @@
 use bincode;
 use bitcoin::blockdata::block::Block;
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
-use std::collections::{BTreeMap, HashMap};
-use std::sync::{Arc, RwLock};
+use lru_cache::LruCache;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use app::App;
-use index::{compute_script_hash, RawTxRow, TxInRow, TxOutRow, TxRow};
-use mempool::Tracker;
-use metrics::{HistogramOpts, HistogramVec, Metrics};
+use config::Config;
+use daemon::{BlockVerbosity, BlockchainInfo};
+use index::{
+    compute_script_hash, read_block_header, AddressRow, MerkleProofRow, OpReturnRow, RawTxRow,
+    SpendRow, TxInRow, TxOutRow, TxRow, TxoRow,
+};
+use mempool::{FeeRank, MempoolSummary, Tracker, TxGraph, MEMPOOL_HEIGHT};
+use metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics, SyncState};
 use serde_json::Value;
-use store::{ReadStore, Row};
+use store::{opreturn_indexed, ReadStore, Row, WriteStore};
+use txcache::DiskTxCache;
 use util::{
-    BlockHeaderMeta, BlockMeta, BlockStatus, Bytes, HashPrefix, HeaderEntry, TransactionStatus,
+    full_hash, hash_prefix, script_type, spawn_thread, BlockHeaderMeta, BlockMeta, BlockStatus,
+    Bytes, FullHash, HashPrefix, HeaderEntry, TransactionStatus,
 };
+use verify::{Sample, Verifier};
 
 use errors::*;
 
 const FUNDING_TXN_LIMIT: usize = 100;
+const BLOCK_FEES_CACHE_SIZE: usize = 100; // # of blocks
+const BLOCK_OUTPUT_TYPES_CACHE_SIZE: usize = 100; // # of blocks
+const RAW_BLOCK_CACHE_SIZE: usize = 10; // # of blocks (a few MB each, at most)
+const BLOCK_FETCH_RETRY_DELAY_MS: u64 = 200; // between the retries of a block fetch
+const BLOCK_META_CACHE_SIZE: usize = 100; // # of blocks
+const FEE_ESTIMATES_TTL: u64 = 30; // seconds
+const FEE_ESTIMATES_TARGETS: [u16; 6] = [1, 2, 3, 6, 10, 25];
+const MEMPOOL_MIN_FEE_TTL: u64 = 10; // seconds
+const BLOCKCHAIN_INFO_TTL: u64 = 10; // seconds
+
+type FeeEstimates = BTreeMap<u16, f32>; // confirmation target => fee rate [sat/vbyte]
 
 #[derive(Clone)]
 pub struct FundingOutput {
@@ -42,6 +65,54 @@ impl From<OutPoint> for FundingOutput {
 }
 type OutPoint = (Sha256dHash, usize); // (txid, output_index)
 
+// A confirmed output funding a script, and whether it's spent by a confirmed transaction.
+#[derive(Clone, Debug, PartialEq)]
+struct ConfirmedOutput {
+    outpoint: OutPoint,
+    value: u64,
+    spent: bool,
+}
+
+/// The aggregated funding and spending of a script (over its confirmed or mempool transactions).
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone)]
+pub struct ScriptStats {
+    pub funded_txo_count: usize,
+    pub funded_txo_sum: u64,
+    pub spent_txo_count: usize,
+    pub spent_txo_sum: u64,
+    pub tx_count: usize,
+}
+
+impl ScriptStats {
+    fn fund(&mut self, value: u64) {
+        self.funded_txo_count += 1;
+        self.funded_txo_sum += value;
+    }
+
+    fn spend(&mut self, value: u64) {
+        self.spent_txo_count += 1;
+        self.spent_txo_sum += value;
+    }
+}
+
+/// A script's balance (in satoshis): of its confirmed unspent outputs, and the mempool's change to
+/// it, which is negative when the mempool transactions spend more of its outputs (confirmed or
+/// not) than they fund.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
+impl Balance {
+    fn new(chain_stats: &ScriptStats, mempool_stats: &ScriptStats) -> Balance {
+        Balance {
+            confirmed: chain_stats.funded_txo_sum - chain_stats.spent_txo_sum,
+            unconfirmed: mempool_stats.funded_txo_sum as i64 - mempool_stats.spent_txo_sum as i64,
+        }
+    }
+}
+
 pub struct SpendingInput {
     pub txn: Option<TxnHeight>,
     pub txn_id: Sha256dHash,
@@ -51,6 +122,13 @@ pub struct SpendingInput {
     pub value: u64,
 }
 
+/// The input spending an output, with its transaction's confirmation status.
+pub struct OutputSpend {
+    pub txid: Sha256dHash,
+    pub vin: u32,
+    pub status: TransactionStatus,
+}
+
 pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
@@ -63,6 +141,14 @@ fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>))
 }
 
 impl Status {
+    /// Drops the mempool part, so that balances, history and unspent outputs are confirmed-only.
+    pub fn confirmed_only(self) -> Status {
+        Status {
+            confirmed: self.confirmed,
+            mempool: (vec![], vec![]),
+        }
+    }
+
     fn funding(&self) -> impl Iterator<Item = &FundingOutput> {
         self.confirmed.0.iter().chain(self.mempool.0.iter())
     }
@@ -111,25 +197,158 @@ impl Status {
         txns
     }
 
+    /// The unspent outputs, as Electrum's `listunspent`: the ones spent by a mempool transaction
+    /// are dropped and the ones it creates are added. They are ordered by height (the mempool ones
+    /// last), then by outpoint. A transaction that is still in the mempool after its confirmation
+    /// (until the mempool is updated) is only counted as confirmed.
     pub fn unspent(&self) -> Vec<&FundingOutput> {
         let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
         for f in self.funding() {
-            outputs_map.insert((f.txn_id, f.output_index), f);
-        }
-        for s in self.spending() {
-            if let None = outputs_map.remove(&s.funding_output) {
-                warn!("failed to remove {:?}", s.funding_output);
-            }
+            outputs_map.entry((f.txn_id, f.output_index)).or_insert(f);
         }
+        let spent: HashSet<OutPoint> = self.spending().map(|s| s.funding_output).collect();
         let mut outputs = outputs_map
             .into_iter()
+            .filter(|(outpoint, _)| !spent.contains(outpoint))
             .map(|item| item.1) // a reference to unspent output
             .collect::<Vec<&FundingOutput>>();
-        outputs.sort_unstable_by_key(|out| out.height);
+        outputs.sort_unstable_by_key(|out| (out.height, out.txn_id, out.output_index));
         outputs
     }
 }
 
+pub type History = Vec<(u32, Sha256dHash)>; // (height, txid), ordered
+
+/// A mempool transaction of a script's history, and whether it spends an output of another mempool
+/// transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MempoolTx {
+    pub txid: Sha256dHash,
+    pub unconfirmed_inputs: bool,
+}
+
+// The mempool transactions with confirmed inputs first, then the ones spending unconfirmed outputs,
+// each by txid (in hex).
+fn sort_mempool_txs(txs: &mut Vec<MempoolTx>) {
+    txs.sort_by_key(|tx| (tx.unconfirmed_inputs, tx.txid.be_hex_string()));
+}
+
+/// The count and total value of a block's outputs of some script type.
+#[derive(Serialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct OutputTypeStats {
+    pub count: usize,
+    pub value: u64, // in satoshis
+}
+
+pub type OutputTypes = BTreeMap<&'static str, OutputTypeStats>; // by script type
+
+// The outputs that aren't of a standard type are counted as "nonstandard".
+fn output_types(block: &Block) -> OutputTypes {
+    let mut output_types = OutputTypes::new();
+    for txout in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+        let stats = output_types
+            .entry(script_type(&txout.script_pubkey))
+            .or_insert_with(OutputTypeStats::default);
+        stats.count += 1;
+        stats.value += txout.value;
+    }
+    output_types
+}
+
+/// Electrs' view of the chain, compared to the daemon's (whose fields are `None` if it can't be
+/// reached): it is still returned while syncing.
+#[derive(Serialize, Debug)]
+pub struct ChainStatus {
+    pub version: &'static str,
+    pub tip_height: usize,
+    pub tip_hash: Sha256dHash,
+    pub daemon_height: Option<u32>,
+    pub daemon_hash: Option<String>,
+    pub daemon_initial_block_download: Option<bool>,
+    pub initial_sync_done: bool, // the index caught up with the daemon once, since startup
+    pub synced: bool,            // ... and has the daemon's tip, which isn't downloading blocks
+    pub mempool_count: usize,
+}
+
+/// A cache's usage, with its lookups since startup (from its metrics).
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// Orders each height's txids by `sort_by_position` (i.e. by their position in the block), so a
+// history is listed in the blockchain order. Only the heights with several txids are sorted.
+fn order_by_position<F>(history: &mut History, mut sort_by_position: F) -> Result<()>
+where
+    F: FnMut(u32, &mut Vec<Sha256dHash>) -> Result<()>,
+{
+    let mut start = 0;
+    while start < history.len() {
+        let height = history[start].0;
+        let end = history[start..]
+            .iter()
+            .position(|(h, _)| *h != height)
+            .map_or(history.len(), |offset| start + offset);
+        if end - start > 1 {
+            let mut txids: Vec<Sha256dHash> =
+                history[start..end].iter().map(|(_, txid)| *txid).collect();
+            sort_by_position(height, &mut txids)?;
+            for (entry, txid) in history[start..end].iter_mut().zip(txids) {
+                entry.1 = txid;
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+// The confirmed history of a script, as of the given tip.
+struct CachedHistory {
+    tip: Sha256dHash,
+    confirmed: History,
+    outputs: Vec<ConfirmedOutput>, // confirmed funding outputs
+}
+
+impl CachedHistory {
+    fn new(tip: Sha256dHash, confirmed: History, outputs: Vec<ConfirmedOutput>) -> CachedHistory {
+        CachedHistory {
+            tip,
+            confirmed,
+            outputs,
+        }
+    }
+
+    // Returns false if the history wasn't extended (e.g. after a reorg).
+    fn update(
+        &mut self,
+        tip: Sha256dHash,
+        confirmed: History,
+        outputs: Vec<ConfirmedOutput>,
+    ) -> bool {
+        let extended = confirmed.starts_with(&self.confirmed);
+        *self = CachedHistory::new(tip, confirmed, outputs);
+        extended
+    }
+
+    // Recomputed along with the history (after each block), so reorgs are accounted for.
+    fn chain_stats(&self) -> ScriptStats {
+        let mut stats = ScriptStats {
+            tx_count: self.confirmed.len(),
+            ..ScriptStats::default()
+        };
+        for output in &self.outputs {
+            stats.fund(output.value);
+            if output.spent {
+                stats.spend(output.value);
+            }
+        }
+        stats
+    }
+}
+
 #[derive(Clone)]
 pub struct TxnHeight {
     pub txn: Transaction,
@@ -155,6 +374,103 @@ fn rawtxrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<RawTxRow> {
     Some(RawTxRow::from_row(&Row { key, value }))
 }
 
+// The DB isn't written to during maintenance, so the fetched transactions (and the evictions that
+// they'd cause) are only kept in memory while paused.
+fn cache_txs_on_disk(
+    disk: Option<&DiskTxCache>,
+    paused: bool,
+    store: &ReadStore,
+    write_store: &WriteStore,
+    txs: &[&Transaction],
+) {
+    if let Some(disk) = disk {
+        if !paused {
+            disk.insert(store, write_store, txs);
+        }
+    }
+}
+
+fn txo_by_outpoint(store: &ReadStore, outpoint: &OutPoint) -> Option<TxOut> {
+    let key = TxoRow::filter(&outpoint.0, outpoint.1 as u32);
+    let value = store.get(&key)?;
+    Some(TxoRow::from_row(&Row { key, value }).txout)
+}
+
+// The previous outputs are read by `read_txo` when possible (see `TxoRow`), the other ones from
+// their whole transactions: at most `budget` of them are loaded (each one possibly fetched from
+// the daemon), so that a transaction spending thousands of them can't trigger as many fetches.
+// The outputs not found (or beyond the budget) are left unknown.
+fn lookup_txos<R, L>(
+    outpoints: &[OutPoint],
+    budget: usize,
+    read_txo: R,
+    load_txns: L,
+) -> Vec<Option<TxOut>>
+where
+    R: Fn(&OutPoint) -> Option<TxOut>,
+    L: FnOnce(&[Sha256dHash]) -> Vec<Result<Transaction>>,
+{
+    let mut results: Vec<Option<TxOut>> = outpoints.iter().map(|o| read_txo(o)).collect();
+    // each missing transaction is loaded once, with the txids in order (for DB locality)
+    let mut missing: BTreeMap<Sha256dHash, Vec<usize>> = BTreeMap::new();
+    for (i, outpoint) in outpoints.iter().enumerate() {
+        if results[i].is_none() {
+            missing.entry(outpoint.0).or_insert_with(Vec::new).push(i);
+        }
+    }
+    if missing.len() > budget {
+        debug!(
+            "prevout budget exceeded: {} transactions to load, only {} loaded",
+            missing.len(),
+            budget
+        );
+    }
+    let txids: Vec<Sha256dHash> = missing.keys().take(budget).cloned().collect();
+    for (txid, tx) in txids.iter().zip(load_txns(&txids)) {
+        // e.g. a mempool transaction's parent, not indexed yet: its fee is left unknown
+        let tx = match tx {
+            Ok(tx) => tx,
+            Err(err) => {
+                debug!("prevout tx {} not found: {}", txid, err);
+                continue;
+            }
+        };
+        for &i in &missing[txid] {
+            results[i] = tx.output.get(outpoints[i].1).cloned();
+        }
+    }
+    results
+}
+
+// the spend recorded in `store`, ignoring rows of orphaned blocks (left behind by pruned ones)
+fn spend_by_outpoint<F>(store: &ReadStore, outpoint: &OutPoint, header_at: F) -> Option<OutputSpend>
+where
+    F: Fn(usize) -> Option<HeaderEntry>,
+{
+    let key = SpendRow::filter(&outpoint.0, outpoint.1 as u32);
+    let value = store.get(&key)?;
+    let row = SpendRow::from_row(&Row { key, value });
+    let header = header_at(row.height as usize)?;
+    if *header.hash() != row.blockhash {
+        return None;
+    }
+    Some(OutputSpend {
+        txid: row.txid,
+        vin: row.vin,
+        status: TransactionStatus::confirmed(&header),
+    })
+}
+
+// the spend found by the mempool tracker (as the spending txid and input index)
+fn mempool_spend(spend: Option<(Sha256dHash, u32)>) -> Option<OutputSpend> {
+    let (txid, vin) = spend?;
+    Some(OutputSpend {
+        txid,
+        vin,
+        status: TransactionStatus::unconfirmed(),
+    })
+}
+
 fn txrows_by_prefix(store: &ReadStore, txid_prefix: &HashPrefix) -> Vec<TxRow> {
     store
         .scan(&TxRow::filter_prefix(&txid_prefix))
@@ -172,6 +488,110 @@ fn txids_by_script_hash(store: &ReadStore, script_hash: &[u8]) -> Vec<HashPrefix
         .collect()
 }
 
+// Fails if the script has more than `max_history` confirmed funding transactions (0 meaning no
+// limit), without loading them: at most `max_history + 1` of its funding rows are read.
+fn check_history_size(store: &ReadStore, script_hash: &[u8], max_history: usize) -> Result<()> {
+    if max_history == 0 {
+        return Ok(());
+    }
+    let rows = store.scan_limit(&TxOutRow::filter(script_hash), max_history + 1);
+    if rows.len() > max_history {
+        bail!(ErrorKind::HistoryTooLarge(max_history));
+    }
+    Ok(())
+}
+
+// Unlike txids_by_script_hash(), the result isn't limited.
+fn all_txids_by_script_hash(store: &ReadStore, script_hash: &[u8]) -> Vec<HashPrefix> {
+    store
+        .scan(&TxOutRow::filter(script_hash))
+        .iter()
+        .map(|row| TxOutRow::from_row(row).txid_prefix)
+        .collect()
+}
+
+// The confirmed history of a script and its funding outputs (each one along with whether it's
+// spent), where `load` loads the transactions matching the given txid prefixes.
+fn confirmed_history<L>(
+    store: &ReadStore,
+    script_hash: &[u8],
+    load: L,
+) -> Result<(History, Vec<ConfirmedOutput>)>
+where
+    L: Fn(&ReadStore, Vec<HashPrefix>) -> Result<Vec<TxnHeight>>,
+{
+    let mut history = BTreeSet::new();
+    let mut outputs = vec![];
+    for txid_prefix in all_txids_by_script_hash(store, script_hash) {
+        for t in load(store, vec![txid_prefix])? {
+            let txid = t.txn.txid();
+            for (vout, output) in t.txn.output.iter().enumerate() {
+                if compute_script_hash(&output.script_pubkey[..]) != script_hash {
+                    continue; // or a txid prefix collision
+                }
+                history.insert((t.height, txid));
+                let spending = load(store, txids_by_funding_output(store, &txid, vout))?
+                    .into_iter()
+                    .find(|s| {
+                        s.txn.input.iter().any(|input| {
+                            input.previous_output.txid == txid
+                                && input.previous_output.vout == vout as u32
+                        })
+                    });
+                if let Some(ref s) = spending {
+                    history.insert((s.height, s.txn.txid()));
+                }
+                outputs.push(ConfirmedOutput {
+                    outpoint: (txid, vout),
+                    value: output.value,
+                    spent: spending.is_some(),
+                });
+            }
+        }
+    }
+    Ok((history.into_iter().collect(), outputs))
+}
+
+// The transactions funding the script at the lowest height of the best chain (usually a single
+// one), found from their tx rows: only these transactions are loaded, skipping the rows of txid
+// prefix collisions and of orphaned blocks.
+fn first_funding_txs<F, L>(
+    store: &ReadStore,
+    script_hash: &[u8],
+    header_at: F,
+    load: L,
+) -> Result<Vec<TxnHeight>>
+where
+    F: Fn(usize) -> Option<HeaderEntry>,
+    L: Fn(&ReadStore, Vec<HashPrefix>) -> Result<Vec<TxnHeight>>,
+{
+    let mut candidates: Vec<(u32, Sha256dHash)> = all_txids_by_script_hash(store, script_hash)
+        .iter()
+        .flat_map(|txid_prefix| txrows_by_prefix(store, txid_prefix))
+        .filter(|row| {
+            header_at(row.height as usize).map_or(false, |header| *header.hash() == row.blockhash)
+        })
+        .map(|row| (row.height, deserialize(&row.key.txid).unwrap()))
+        .collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+    let mut funding: Vec<TxnHeight> = vec![];
+    for (height, txid) in candidates {
+        if funding.first().map_or(false, |first| first.height < height) {
+            break;
+        }
+        let txns = load(store, vec![hash_prefix(&txid[..])])?;
+        funding.extend(txns.into_iter().filter(|t| {
+            t.txn.txid() == txid
+                && t.txn
+                    .output
+                    .iter()
+                    .any(|output| compute_script_hash(&output.script_pubkey[..]) == script_hash)
+        }));
+    }
+    Ok(funding)
+}
+
 fn txids_by_funding_output(
     store: &ReadStore,
     txn_id: &Sha256dHash,
@@ -198,31 +618,363 @@ pub fn get_block_txids(store: &ReadStore, blockhash: &Sha256dHash) -> Option<Vec
     Some(txids)
 }
 
+// The end of a (height-ordered) confirmed history, from `min_height`.
+fn recent_history(confirmed: &History, min_height: u32) -> &[(u32, Sha256dHash)] {
+    let start = confirmed
+        .iter()
+        .rposition(|(height, _)| *height < min_height)
+        .map_or(0, |index| index + 1);
+    &confirmed[start..]
+}
+
+// Returns up to `limit` entries of the (ascending) confirmed history, newest first, following
+// `after_txid` (None if it's not part of the history). Each height's txids are ordered by
+// `sort_by_position` (only for the heights included in the page), so pages are stable.
+fn history_page<F>(
+    confirmed: &History,
+    after_txid: Option<&Sha256dHash>,
+    limit: usize,
+    mut sort_by_position: F,
+) -> Result<Option<History>>
+where
+    F: FnMut(u32, &mut Vec<Sha256dHash>) -> Result<()>,
+{
+    // the entries before `end` are yet to be paged (starting from the cursor's height)
+    let mut end = match after_txid {
+        Some(after_txid) => {
+            let index = match confirmed.iter().position(|(_, txid)| txid == after_txid) {
+                Some(index) => index,
+                None => return Ok(None),
+            };
+            let height = confirmed[index].0;
+            confirmed[index..]
+                .iter()
+                .position(|(h, _)| *h != height)
+                .map_or(confirmed.len(), |offset| index + offset)
+        }
+        None => confirmed.len(),
+    };
+    let mut skip_until = after_txid;
+    let mut page = vec![];
+    while end > 0 && page.len() < limit {
+        let height = confirmed[end - 1].0;
+        let start = confirmed[..end]
+            .iter()
+            .rposition(|(h, _)| *h != height)
+            .map_or(0, |index| index + 1);
+        let mut txids: Vec<Sha256dHash> = confirmed[start..end]
+            .iter()
+            .map(|(_, txid)| *txid)
+            .collect();
+        sort_by_position(height, &mut txids)?;
+        let mut txids = txids.into_iter().rev();
+        if let Some(after_txid) = skip_until.take() {
+            txids.position(|txid| txid == *after_txid);
+        }
+        for txid in txids.take(limit - page.len()) {
+            page.push((height, txid));
+        }
+        end = start;
+    }
+    Ok(Some(page))
+}
+
+/// Times the DB reads of the query path.
+struct TimedReadStore<'a> {
+    store: &'a ReadStore,
+    latency: &'a HistogramVec,
+}
+
+impl<'a> ReadStore for TimedReadStore<'a> {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let _timer = self.latency.with_label_values(&["get"]).start_timer();
+        self.store.get(key)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        let _timer = self.latency.with_label_values(&["scan"]).start_timer();
+        self.store.scan(prefix)
+    }
+
+    fn scan_limit(&self, prefix: &[u8], limit: usize) -> Vec<Row> {
+        let _timer = self.latency.with_label_values(&["scan"]).start_timer();
+        self.store.scan_limit(prefix, limit)
+    }
+}
+
+// Up to `limit` of the indexed addresses starting with `prefix` (sorted), reading at most
+// `scan_limit` of them. Bech32 addresses are indexed in lowercase, so they are matched
+// case-insensitively.
+fn search_addresses(
+    store: &ReadStore,
+    prefix: &str,
+    limit: usize,
+    scan_limit: usize,
+) -> Option<Vec<String>> {
+    let mut prefixes = vec![prefix.to_string()];
+    let lowercase = prefix.to_lowercase();
+    if lowercase != prefix {
+        prefixes.push(lowercase);
+    }
+    let mut addresses: Vec<String> = vec![];
+    for prefix in prefixes {
+        let rows = store.scan_limit(&AddressRow::filter_prefix(&prefix), scan_limit + 1);
+        if addresses.len() + rows.len() > scan_limit {
+            return None; // too broad
+        }
+        addresses.extend(rows.iter().map(|row| AddressRow::from_row(row).address));
+    }
+    addresses.sort();
+    addresses.truncate(limit);
+    Some(addresses)
+}
+
+// Up to `limit` of the confirmed transactions with an OP_RETURN payload whose SHA256 starts with
+// `hash_prefix` (sorted by the hash), reading at most `scan_limit` index rows. The rows of orphaned
+// blocks may be kept (e.g. pruned ones), so the transactions are only returned if their block is in
+// the best chain (as seen by `in_best_chain`).
+fn search_opreturn<F>(
+    store: &ReadStore,
+    hash_prefix: &[u8],
+    limit: usize,
+    scan_limit: usize,
+    in_best_chain: &F,
+) -> Option<Vec<Sha256dHash>>
+where
+    F: Fn(&Sha256dHash) -> bool,
+{
+    let rows = store.scan_limit(&OpReturnRow::filter_prefix(hash_prefix), scan_limit + 1);
+    if rows.len() > scan_limit {
+        return None; // too broad
+    }
+    let mut txids: Vec<Sha256dHash> = vec![];
+    for row in rows {
+        let txid = OpReturnRow::from_row(&row).txid;
+        if txids.contains(&txid) {
+            continue; // a transaction may have several matching payloads
+        }
+        if txrow_by_txid(store, &txid).map_or(false, |row| in_best_chain(&row.blockhash)) {
+            txids.push(txid);
+        }
+    }
+    txids.truncate(limit);
+    Some(txids)
+}
+
+// The header of an indexed block, from the best chain (as seen by `best_header`) or from the DB for
+// orphaned blocks, whose height is found by following their ancestors back to the best chain.
+fn header_by_hash<F>(store: &ReadStore, hash: &Sha256dHash, best_header: &F) -> Option<HeaderEntry>
+where
+    F: Fn(&Sha256dHash) -> Option<HeaderEntry>,
+{
+    if let Some(entry) = best_header(hash) {
+        return Some(entry);
+    }
+    let header = read_block_header(store, hash)?;
+    let mut ancestor = header.prev_blockhash;
+    let mut depth = 1;
+    loop {
+        if let Some(entry) = best_header(&ancestor) {
+            return Some(HeaderEntry::new(entry.height() + depth, header));
+        }
+        ancestor = read_block_header(store, &ancestor)?.prev_blockhash;
+        depth += 1;
+    }
+}
+
+// Fetching a block fails transiently while the daemon is unreachable or warming up, or is still
+// writing the block to disk, unlike when it doesn't know the block (or pruned it).
+fn is_transient_block_error(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::Connection(_) | ErrorKind::Warmup(_) => true,
+        ErrorKind::Msg(msg) => {
+            msg.contains("Can't read block from disk") || msg.contains("Block not found on disk")
+        }
+        _ => false,
+    }
+}
+
+// Retries `fetch` up to `retries` times on transient failures, failing with `BlockUnavailable` once
+// they're exhausted. The other failures (e.g. an unknown block) are returned at once.
+fn fetch_block_with_retries<T, F>(
+    blockhash: &Sha256dHash,
+    retries: usize,
+    delay: Duration,
+    fetch: F,
+) -> Result<T>
+where
+    F: Fn() -> Result<T>,
+{
+    let mut attempt = 0;
+    loop {
+        match fetch() {
+            Err(ref err) if is_transient_block_error(err) => {
+                if attempt >= retries {
+                    bail!(ErrorKind::BlockUnavailable(format!(
+                        "{}: {}",
+                        blockhash, err
+                    )));
+                }
+                attempt += 1;
+                debug!(
+                    "retrying block {} ({}/{}): {}",
+                    blockhash, attempt, retries, err
+                );
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
 pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
     extended_db_enabled: bool,
+    cache_merkle_proofs: bool,
+    opreturn_index: bool, // the DB was created with --index-opreturn
+    max_history: usize,   // # of confirmed funding transactions, 0 meaning no limit
+    block_fetch_retries: usize,
+    fee_estimates: Mutex<Option<(Instant, FeeEstimates)>>,
+    mempool_min_fee: Mutex<Option<(Instant, f32)>>, // [sat/vbyte]
+    blockchain_info: Mutex<Option<(Instant, BlockchainInfo)>>, // the daemon's
+    sync: Arc<SyncState>,
+    history_cache: Mutex<LruCache<FullHash, CachedHistory>>,
+    block_fees_cache: Mutex<LruCache<Sha256dHash, Arc<Vec<u64>>>>,
+    block_output_types_cache: Mutex<LruCache<Sha256dHash, Arc<OutputTypes>>>,
+    raw_block_cache: Mutex<LruCache<Sha256dHash, Arc<Bytes>>>,
+    block_meta_cache: Mutex<LruCache<Sha256dHash, BlockMeta>>, // of the recent blocks
+    tx_cache: Mutex<LruCache<Sha256dHash, Transaction>>, // fetched from the daemon (with --light)
+    tx_disk_cache: Option<DiskTxCache>,                  // behind tx_cache, kept across restarts
+    updates: Mutex<u64>,                                 // # of completed index & mempool updates
+    updated: Condvar,
+    verifier: Option<Verifier>, // with --verification-sampling
 
     // monitoring
     latency: HistogramVec,
+    db_latency: HistogramVec,
+    history_cache_lookups: CounterVec,
+    history_too_large: CounterVec,
+    merkle_proof_cache_lookups: CounterVec,
+    tx_cache_lookups: CounterVec,
+    tx_cache_entries: Gauge,
+    tx_cache_capacity: Gauge,
+    tx_daemon_lookups: CounterVec,
 }
 
 impl Query {
-    pub fn new(app: Arc<App>, extended_db_enabled: bool, metrics: &Metrics) -> Arc<Query> {
+    pub fn new(
+        app: Arc<App>,
+        verifier: Option<Verifier>,
+        metrics: &Metrics,
+        config: &Config,
+    ) -> Arc<Query> {
         let latency_buckets = vec![
             1e-4, 2e-4, 5e-4, 1e-3, 2e-3, 5e-3, 1e-2, 2e-2, 5e-2, 0.1, 0.2, 0.5, 1., 2., 5., 10.,
             20., 50., 100.,
         ];
-        Arc::new(Query {
+        let opreturn_index = opreturn_indexed(app.read_store());
+        let tx_disk_cache = if !config.extended_db_enabled && config.tx_cache_disk_mb > 0 {
+            let cache = DiskTxCache::open(
+                app.read_store(),
+                config.tx_cache_disk_mb as u64 * 1024 * 1024,
+                metrics,
+            );
+            let (entries, size) = cache.usage();
+            info!(
+                "on-disk tx cache: {} transactions, {:.1} MB",
+                entries,
+                size as f64 / 1e6
+            );
+            Some(cache)
+        } else {
+            None
+        };
+        let query = Arc::new(Query {
             app,
-            extended_db_enabled,
-            tracker: RwLock::new(Tracker::new(metrics)),
+            extended_db_enabled: config.extended_db_enabled,
+            cache_merkle_proofs: config.cache_merkle_proofs,
+            opreturn_index,
+            max_history: config.max_history,
+            block_fetch_retries: config.block_fetch_retries,
+            tracker: RwLock::new(Tracker::new(config.mempool_max_txs, metrics)),
+            fee_estimates: Mutex::new(None),
+            mempool_min_fee: Mutex::new(None),
+            blockchain_info: Mutex::new(None),
+            sync: metrics.sync_state(),
+            history_cache: Mutex::new(LruCache::new(config.history_cache_size)),
+            block_fees_cache: Mutex::new(LruCache::new(BLOCK_FEES_CACHE_SIZE)),
+            block_output_types_cache: Mutex::new(LruCache::new(BLOCK_OUTPUT_TYPES_CACHE_SIZE)),
+            raw_block_cache: Mutex::new(LruCache::new(RAW_BLOCK_CACHE_SIZE)),
+            block_meta_cache: Mutex::new(LruCache::new(BLOCK_META_CACHE_SIZE)),
+            tx_cache: Mutex::new(LruCache::new(config.tx_cache_size)),
+            tx_disk_cache,
+            updates: Mutex::new(0),
+            updated: Condvar::new(),
+            verifier,
             latency: metrics.histogram_vec(
                 HistogramOpts::new("query_latency", "Query latency (in seconds)")
+                    .buckets(latency_buckets.clone()),
+                &["type"],
+            ),
+            db_latency: metrics.histogram_vec(
+                HistogramOpts::new("query_db_latency", "Query DB reads latency (in seconds)")
                     .buckets(latency_buckets),
+                &["op"],
+            ),
+            history_cache_lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_history_cache",
+                    "# of history cache lookups (hit, extended after a new block, or miss)",
+                ),
                 &["type"],
             ),
-        })
+            history_too_large: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_history_too_large",
+                    "# of queries refused as the script's history exceeds --max-history",
+                ),
+                &["type"],
+            ),
+            merkle_proof_cache_lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_merkle_proof_cache",
+                    "# of merkle proof cache lookups (hit or miss)",
+                ),
+                &["type"],
+            ),
+            tx_cache_lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_tx_cache",
+                    "# of transaction cache lookups with --light (hit or miss)",
+                ),
+                &["type"],
+            ),
+            tx_cache_entries: metrics.gauge(MetricOpts::new(
+                "query_tx_cache_entries",
+                "# of cached transactions",
+            )),
+            tx_cache_capacity: metrics.gauge(MetricOpts::new(
+                "query_tx_cache_capacity",
+                "Maximum # of cached transactions",
+            )),
+            tx_daemon_lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_tx_daemon",
+                    "# of transactions fetched from the daemon with --light (hit, or miss if it failed)",
+                ),
+                &["type"],
+            ),
+        });
+        query.tx_cache_capacity.set(config.tx_cache_size as i64);
+        query
+    }
+
+    fn read_store(&self) -> TimedReadStore {
+        TimedReadStore {
+            store: self.app.read_store(),
+            latency: &self.db_latency,
+        }
     }
 
     fn load_txns_by_prefix(
@@ -234,25 +986,34 @@ impl Query {
             bail!("Too many txs");
         }
 
-        let mut txns = vec![];
         let _timer = self
             .latency
             .with_label_values(&["load_txns_by_prefix"])
             .start_timer();
+        let mut tx_rows = vec![];
         for txid_prefix in prefixes {
-            for tx_row in txrows_by_prefix(store, &txid_prefix) {
-                let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
-                let txn = self
-                    .load_txn(&txid, Some(&tx_row.blockhash))
-                    .chain_err(|| "cannot locate tx")?;
-                txns.push(TxnHeight {
-                    txn,
+            tx_rows.extend(txrows_by_prefix(store, &txid_prefix));
+        }
+        let txids: Vec<(Sha256dHash, Option<Sha256dHash>)> = tx_rows
+            .iter()
+            .map(|tx_row| {
+                (
+                    deserialize(&tx_row.key.txid).unwrap(),
+                    Some(tx_row.blockhash),
+                )
+            })
+            .collect();
+        tx_rows
+            .into_iter()
+            .zip(self.load_txns(&txids))
+            .map(|(tx_row, txn)| {
+                Ok(TxnHeight {
+                    txn: txn.chain_err(|| "cannot locate tx")?,
                     height: tx_row.height,
                     blockhash: tx_row.blockhash,
                 })
-            }
-        }
-        Ok(txns)
+            })
+            .collect()
     }
 
     fn find_spending_input(
@@ -314,6 +1075,15 @@ impl Query {
         result
     }
 
+    // Counts the queries refused by check_history_size(), by type.
+    fn check_history_size(&self, store: &ReadStore, script_hash: &[u8], query: &str) -> Result<()> {
+        let result = check_history_size(store, script_hash, self.max_history);
+        if result.is_err() {
+            self.history_too_large.with_label_values(&[query]).inc();
+        }
+        result
+    }
+
     fn confirmed_status(
         &self,
         script_hash: &[u8],
@@ -322,16 +1092,24 @@ impl Query {
             .latency
             .with_label_values(&["confirmed_status"])
             .start_timer();
+        self.check_synced()?;
         let mut funding = vec![];
         let mut spending = vec![];
-        let read_store = self.app.read_store();
+        let read_store = &self.read_store();
+        // the rows of the blocks being indexed are ignored until their headers are
+        let max_height = self.get_best_height() as u32;
+        self.check_history_size(read_store, script_hash, "status")?;
         let txid_prefixes = txids_by_script_hash(read_store, script_hash);
         for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
-            funding.extend(self.find_funding_outputs(&t, script_hash));
+            if t.height <= max_height {
+                funding.extend(self.find_funding_outputs(&t, script_hash));
+            }
         }
         for funding_output in &funding {
             if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
-                spending.push(spent);
+                if spent.height <= max_height {
+                    spending.push(spent);
+                }
             }
         }
         Ok((funding, spending))
@@ -371,101 +1149,544 @@ impl Query {
         Ok(Status { confirmed, mempool })
     }
 
-    pub fn find_spending_by_outpoint(&self, outpoint: OutPoint) -> Result<Option<SpendingInput>> {
+    // The confirmed history is read from the DB with a single scan of the script's funding
+    // rows, along with the funding outputs (needed to find their spending mempool transactions).
+    // The rows above `max_height` (of the blocks being indexed) are ignored.
+    fn confirmed_history(
+        &self,
+        script_hash: &[u8],
+        max_height: u32,
+    ) -> Result<(History, Vec<ConfirmedOutput>)> {
         let _timer = self
             .latency
-            .with_label_values(&["find_spending_by_outpoint"])
+            .with_label_values(&["confirmed_history"])
             .start_timer();
-        let funding_output = FundingOutput::from(outpoint);
-        let read_store = self.app.read_store();
-        let tracker = self.tracker.read().unwrap();
-        Ok(
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
-                Some(spent)
-            } else if let Some(spent) =
-                self.find_spending_input(tracker.index(), &funding_output)?
-            {
-                Some(spent)
-            } else {
-                None
-            },
-        )
+        self.check_synced()?;
+        let read_store = &self.read_store();
+        self.check_history_size(read_store, script_hash, "history")?;
+        let (mut history, outputs) =
+            confirmed_history(read_store, script_hash, |store, prefixes| {
+                Ok(self
+                    .load_txns_by_prefix(store, prefixes)?
+                    .into_iter()
+                    .filter(|t| t.height <= max_height)
+                    .collect())
+            })?;
+        order_by_position(&mut history, |height, txids| {
+            self.sort_by_position(height, txids)
+        })?;
+        Ok((history, outputs))
     }
 
-    pub fn find_spending_for_funding_tx(
+    fn mempool_history(
         &self,
-        tx: Transaction,
-    ) -> Result<Vec<Option<SpendingInput>>> {
+        script_hash: &[u8],
+        confirmed_outputs: &[ConfirmedOutput],
+    ) -> Result<(Vec<MempoolTx>, ScriptStats)> {
         let _timer = self
             .latency
-            .with_label_values(&["find_spending_for_funding_tx"])
+            .with_label_values(&["mempool_history"])
             .start_timer();
-        let txid = tx.txid();
-        let mut spends = vec![];
-        for (output_index, output) in tx.output.iter().enumerate() {
-            let spend = if !output.script_pubkey.is_provably_unspendable() {
-                self.find_spending_by_outpoint((txid, output_index))?
-            } else {
-                None
-            };
-            spends.push(spend)
+        let tracker = self.tracker.read().unwrap();
+        let mut history = BTreeSet::new();
+        let mut stats = ScriptStats::default();
+        // confirmed outputs already spent by a confirmed transaction can't be spent in mempool
+        let mut outputs: Vec<FundingOutput> = confirmed_outputs
+            .iter()
+            .filter(|output| !output.spent)
+            .map(|output| FundingOutput {
+                value: output.value,
+                ..FundingOutput::from(output.outpoint)
+            })
+            .collect();
+        for txid_prefix in all_txids_by_script_hash(tracker.index(), script_hash) {
+            for t in self.load_txns_by_prefix(tracker.index(), vec![txid_prefix])? {
+                let funding = self.find_funding_outputs(&t, script_hash);
+                if !funding.is_empty() {
+                    history.insert(t.txn.txid());
+                    for funding_output in &funding {
+                        stats.fund(funding_output.value);
+                    }
+                    outputs.extend(funding);
+                }
+            }
         }
-        Ok(spends)
+        for funding_output in &outputs {
+            if let Some(spent) = self.find_spending_input(tracker.index(), funding_output)? {
+                history.insert(spent.txn_id);
+                stats.spend(spent.value);
+            }
+        }
+        stats.tx_count = history.len();
+        let mut txs: Vec<MempoolTx> = history
+            .into_iter()
+            .map(|txid| MempoolTx {
+                txid,
+                unconfirmed_inputs: tracker.has_unconfirmed_inputs(&txid),
+            })
+            .collect();
+        sort_mempool_txs(&mut txs);
+        Ok((txs, stats))
     }
 
-    fn lookup_confirmed_blockhash(
-        &self,
-        tx_hash: &Sha256dHash,
-        block_height: Option<u32>,
-    ) -> Result<Option<Sha256dHash>> {
+    // Calls `f` with the script's confirmed history (cached until the next block) and mempool
+    // one, along with the mempool stats.
+    fn with_history<T, F>(&self, script_hash: &[u8], f: F) -> Result<T>
+    where
+        F: FnOnce(&CachedHistory, &[MempoolTx], &ScriptStats) -> T,
+    {
+        let (tip, max_height) = self
+            .app
+            .index()
+            .best_header()
+            .map_or((Sha256dHash::default(), 0), |header| {
+                (*header.hash(), header.height() as u32)
+            });
+        let key = full_hash(script_hash);
+        // the cache isn't locked while the DB is read, so concurrent lookups don't block each other
+        let cached = self.history_cache.lock().unwrap().remove(&key);
+        let history = match cached {
+            Some(mut history) => {
+                if history.tip == tip {
+                    self.history_cache_lookups.with_label_values(&["hit"]).inc();
+                } else {
+                    let (confirmed, outputs) = self.confirmed_history(script_hash, max_height)?;
+                    let label = if history.update(tip, confirmed, outputs) {
+                        "extend"
+                    } else {
+                        "miss"
+                    };
+                    self.history_cache_lookups.with_label_values(&[label]).inc();
+                }
+                history
+            }
+            None => {
+                self.history_cache_lookups
+                    .with_label_values(&["miss"])
+                    .inc();
+                let (confirmed, outputs) = self.confirmed_history(script_hash, max_height)?;
+                CachedHistory::new(tip, confirmed, outputs)
+            }
+        };
+        if let Some(ref verifier) = self.verifier {
+            if verifier.sampled() {
+                let unspent: Vec<(Sha256dHash, usize, u64)> = history
+                    .outputs
+                    .iter()
+                    .filter(|out| !out.spent)
+                    .map(|out| (out.outpoint.0, out.outpoint.1, out.value))
+                    .collect();
+                verifier.check(Sample::new(key, tip, &history.confirmed, &unspent));
+            }
+        }
+        let (mempool, mempool_stats) = self.mempool_history(script_hash, &history.outputs)?;
+        let result = f(&history, &mempool, &mempool_stats);
+        self.history_cache.lock().unwrap().insert(key, history);
+        Ok(result)
+    }
+
+    /// Returns the script's (confirmed, mempool) funding and spending stats. Unlike status(),
+    /// the history is only limited by `max_history`.
+    pub fn get_script_stats(&self, script_hash: &[u8]) -> Result<(ScriptStats, ScriptStats)> {
         let _timer = self
             .latency
-            .with_label_values(&["lookup_confirmed_blockhash"])
+            .with_label_values(&["get_script_stats"])
             .start_timer();
-        let blockhash = if self.tracker.read().unwrap().get_txn(&tx_hash).is_some() {
-            None // found in mempool (as unconfirmed transaction)
-        } else {
-            // Lookup in confirmed transactions' index
-            let height = match block_height {
-                Some(height) => height,
-                None => {
-                    txrow_by_txid(self.app.read_store(), &tx_hash)
-                        .chain_err(|| format!("not indexed tx {}", tx_hash))?
-                        .height
-                }
-            };
-            let header = self
-                .app
-                .index()
-                .get_header(height as usize)
-                .chain_err(|| format!("missing header at height {}", height))?;
-            Some(*header.hash())
-        };
-        Ok(blockhash)
+        self.with_history(script_hash, |history, _, mempool_stats| {
+            (history.chain_stats(), mempool_stats.clone())
+        })
     }
 
-    // Load transaction by txid
-    pub fn load_txn(
-        &self,
+    /// Returns the script's confirmed and unconfirmed balance, from its stats (so it's only
+    /// limited by `max_history`).
+    pub fn get_balance(&self, script_hash: &[u8]) -> Result<Balance> {
+        let (chain_stats, mempool_stats) = self.get_script_stats(script_hash)?;
+        Ok(Balance::new(&chain_stats, &mempool_stats))
+    }
+
+    /// Returns the (height, txid) of every transaction funding or spending the given script,
+    /// ordered by height then position in the block (mempool ones, having MEMPOOL_HEIGHT, are
+    /// last, the ones with confirmed inputs first).
+    /// Unlike status(), the history is only limited by `max_history`, and transactions aren't
+    /// kept in memory.
+    pub fn history_txids(&self, script_hash: &[u8]) -> Result<History> {
+        let _timer = self
+            .latency
+            .with_label_values(&["history_txids"])
+            .start_timer();
+        self.with_history(script_hash, |history, mempool, _| {
+            let mut txids = history.confirmed.clone();
+            txids.extend(mempool.iter().map(|tx| (MEMPOOL_HEIGHT, tx.txid)));
+            txids
+        })
+    }
+
+    /// Returns a page of the script's history, newest first: its mempool transactions (only on
+    /// the first page, when `after_txid` is None), then up to `limit` confirmed ones following
+    /// `after_txid`. Returns None if `after_txid` isn't part of the confirmed history.
+    pub fn get_history(
+        &self,
+        script_hash: &[u8],
+        after_txid: Option<&Sha256dHash>,
+        limit: usize,
+    ) -> Result<Option<History>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_history"])
+            .start_timer();
+        let (confirmed, mempool) = self.with_history(script_hash, |history, mempool, _| {
+            (history.confirmed.clone(), mempool.to_vec())
+        })?;
+        let page = history_page(&confirmed, after_txid, limit, |height, txids| {
+            self.sort_by_position(height, txids)
+        })?;
+        Ok(page.map(|page| match after_txid {
+            Some(_) => page,
+            None => mempool
+                .into_iter()
+                .rev()
+                .map(|tx| (MEMPOOL_HEIGHT, tx.txid))
+                .chain(page)
+                .collect(),
+        }))
+    }
+
+    /// Returns the script's transactions confirmed in the last `blocks` blocks and its mempool
+    /// ones, newest first (like the first page of `get_history()`). Only the end of the cached
+    /// history (ordered by height) is walked.
+    pub fn get_recent_history(&self, script_hash: &[u8], blocks: usize) -> Result<History> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_recent_history"])
+            .start_timer();
+        let min_height = (self.get_best_height() + 1).saturating_sub(blocks) as u32;
+        self.with_history(script_hash, |history, mempool, _| {
+            mempool
+                .iter()
+                .rev()
+                .map(|tx| (MEMPOOL_HEIGHT, tx.txid))
+                .chain(
+                    recent_history(&history.confirmed, min_height)
+                        .iter()
+                        .rev()
+                        .cloned(),
+                )
+                .collect()
+        })
+    }
+
+    // Orders the given txids (confirmed at this height) by their position in the block.
+    fn sort_by_position(&self, height: u32, txids: &mut Vec<Sha256dHash>) -> Result<()> {
+        if txids.len() < 2 {
+            return Ok(());
+        }
+        let header = self
+            .app
+            .index()
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        let block_txids = self.get_block_txids(header.hash())?;
+        let positions: HashMap<&Sha256dHash, usize> = block_txids
+            .iter()
+            .enumerate()
+            .map(|(position, txid)| (txid, position))
+            .collect();
+        // txids of orphaned blocks (not deleted from the index) are placed last
+        txids.sort_by_key(|txid| positions.get(txid).cloned().unwrap_or(usize::max_value()));
+        Ok(())
+    }
+
+    /// Looks up the input spending the given output: in the spending index (or by scanning the
+    /// spending transactions in light mode), then in the mempool.
+    pub fn lookup_spend(&self, outpoint: OutPoint) -> Result<Option<OutputSpend>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["lookup_spend"])
+            .start_timer();
+        let confirmed = self.lookup_confirmed_spend(outpoint)?;
+        Ok(confirmed.or_else(|| {
+            let (txid, vout) = outpoint;
+            let tracker = self.tracker.read().unwrap();
+            mempool_spend(tracker.get_spend(&txid, vout as u32))
+        }))
+    }
+
+    /// The txids of the mempool transactions seen spending this output before being replaced by
+    /// a conflicting spend (oldest first): e.g. an unconfirmed payment that was double-spent.
+    pub fn lookup_replaced_spends(&self, outpoint: OutPoint) -> Vec<Sha256dHash> {
+        let (txid, vout) = outpoint;
+        self.tracker
+            .read()
+            .unwrap()
+            .get_replaced_spends(&txid, vout as u32)
+    }
+
+    pub fn lookup_confirmed_spend(&self, outpoint: OutPoint) -> Result<Option<OutputSpend>> {
+        let header_at = |height| self.app.index().get_header(height);
+        Ok(if self.extended_db_enabled {
+            spend_by_outpoint(&self.read_store(), &outpoint, &header_at)
+        } else {
+            // there's no spending index in light mode
+            let funding_output = FundingOutput::from(outpoint);
+            self.find_spending_input(&self.read_store(), &funding_output)?
+                .and_then(|spend| {
+                    let txn = spend.txn?;
+                    Some(OutputSpend {
+                        txid: spend.txn_id,
+                        vin: spend.input_index as u32,
+                        status: TransactionStatus {
+                            confirmed: true,
+                            block_height: Some(txn.height as usize),
+                            block_hash: Some(txn.blockhash),
+                        },
+                    })
+                })
+        })
+    }
+
+    pub fn lookup_spends(&self, tx: Transaction) -> Result<Vec<Option<OutputSpend>>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["lookup_spends"])
+            .start_timer();
+        let txid = tx.txid();
+        let mut spends = vec![];
+        for (output_index, output) in tx.output.iter().enumerate() {
+            let spend = if !output.script_pubkey.is_provably_unspendable() {
+                self.lookup_spend((txid, output_index))?
+            } else {
+                None
+            };
+            spends.push(spend)
+        }
+        Ok(spends)
+    }
+
+    fn lookup_confirmed_blockhash(
+        &self,
+        tx_hash: &Sha256dHash,
+        block_height: Option<u32>,
+    ) -> Result<Option<Sha256dHash>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["lookup_confirmed_blockhash"])
+            .start_timer();
+        let blockhash = if self.tracker.read().unwrap().get_txn(&tx_hash).is_some() {
+            None // found in mempool (as unconfirmed transaction)
+        } else {
+            // Lookup in confirmed transactions' index
+            let height = match block_height {
+                Some(height) => height,
+                None => {
+                    txrow_by_txid(&self.read_store(), &tx_hash)
+                        .chain_err(|| format!("not indexed tx {}", tx_hash))?
+                        .height
+                }
+            };
+            let header = self
+                .app
+                .index()
+                .get_header(height as usize)
+                .chain_err(|| format!("missing header at height {}", height))?;
+            Some(*header.hash())
+        };
+        Ok(blockhash)
+    }
+
+    // Load transaction by txid
+    pub fn load_txn(
+        &self,
         txid: &Sha256dHash,
         blockhash: Option<&Sha256dHash>,
     ) -> Result<Transaction> {
         let _timer = self.latency.with_label_values(&["load_txn"]).start_timer();
         if self.extended_db_enabled {
             // fetch from our txstore or mempool tracker
-            rawtxrow_by_txid(self.app.read_store(), txid)
+            rawtxrow_by_txid(&self.read_store(), txid)
                 .map(|row| deserialize(&row.rawtx).expect("cannot parse tx from txstore"))
                 .or_else(|| self.tracker.read().unwrap().get_txn(&txid))
                 .chain_err(|| format!("cannot find tx {}", txid))
         } else {
-            // fetch from bitcoind
-            let blockhash_from_index: Option<Sha256dHash> = match blockhash {
-                Some(_) => None,
-                None => self.lookup_confirmed_blockhash(txid, None)?,
+            if let Some(tx) = self.tx_cache.lock().unwrap().get_mut(txid) {
+                self.tx_cache_lookups.with_label_values(&["hit"]).inc();
+                return Ok(tx.clone());
+            }
+            self.tx_cache_lookups.with_label_values(&["miss"]).inc();
+            let cached = self
+                .tx_disk_cache
+                .as_ref()
+                .and_then(|disk| disk.get(self.app.read_store(), txid));
+            let tx = match cached {
+                Some(tx) => tx,
+                None => {
+                    // fetch from bitcoind
+                    let blockhash_from_index: Option<Sha256dHash> = match blockhash {
+                        Some(_) => None,
+                        None => self.lookup_confirmed_blockhash(txid, None)?,
+                    };
+                    let blockhash: Option<&Sha256dHash> =
+                        blockhash.or(blockhash_from_index.as_ref());
+                    let tx = self.app.daemon().gettransaction(txid, blockhash);
+                    let label = if tx.is_ok() { "hit" } else { "miss" };
+                    self.tx_daemon_lookups.with_label_values(&[label]).inc();
+                    let tx = tx?;
+                    cache_txs_on_disk(
+                        self.tx_disk_cache.as_ref(),
+                        self.app.is_paused(),
+                        self.app.read_store(),
+                        self.app.write_store(),
+                        &[&tx],
+                    );
+                    tx
+                }
             };
-            let blockhash: Option<&Sha256dHash> = blockhash.or(blockhash_from_index.as_ref());
-            self.app.daemon().gettransaction(txid, blockhash)
+            let mut cache = self.tx_cache.lock().unwrap();
+            cache.insert(*txid, tx.clone());
+            self.tx_cache_entries.set(cache.len() as i64);
+            Ok(tx)
+        }
+    }
+
+    /// A previous output, read from its own row with the extended DB (the mempool transactions'
+    /// outputs, and all of them without it, are read from their whole transaction).
+    pub fn lookup_txo(&self, outpoint: &OutPoint) -> Result<TxOut> {
+        if self.extended_db_enabled {
+            if let Some(txout) = txo_by_outpoint(&self.read_store(), outpoint) {
+                return Ok(txout);
+            }
+        }
+        let tx = self.load_txn(&outpoint.0, None)?;
+        tx.output
+            .get(outpoint.1)
+            .cloned()
+            .chain_err(|| format!("missing prevout {}:{}", outpoint.0, outpoint.1))
+    }
+
+    /// Several previous outputs (`None` if unknown), loading at most `budget` whole transactions
+    /// for those that aren't read from their own row.
+    pub fn lookup_txos(&self, outpoints: &[OutPoint], budget: usize) -> Vec<Option<TxOut>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["lookup_txos"])
+            .start_timer();
+        let read_store = self.read_store();
+        lookup_txos(
+            outpoints,
+            budget,
+            |outpoint| {
+                if self.extended_db_enabled {
+                    txo_by_outpoint(&read_store, outpoint)
+                } else {
+                    None
+                }
+            },
+            |txids| {
+                let requests: Vec<(Sha256dHash, Option<Sha256dHash>)> =
+                    txids.iter().map(|txid| (*txid, None)).collect();
+                self.load_txns(&requests)
+            },
+        )
+    }
+
+    // Load several transactions by txid (with their block, if known). Without the extended DB,
+    // the ones that aren't cached are fetched from bitcoind in batches. Each transaction gets
+    // its own result, so that a missing one doesn't fail the others.
+    pub fn load_txns(
+        &self,
+        txids: &[(Sha256dHash, Option<Sha256dHash>)],
+    ) -> Vec<Result<Transaction>> {
+        if self.extended_db_enabled {
+            return txids
+                .iter()
+                .map(|&(ref txid, ref blockhash)| self.load_txn(txid, blockhash.as_ref()))
+                .collect();
+        }
+        let _timer = self.latency.with_label_values(&["load_txns"]).start_timer();
+        let mut results: Vec<Option<Result<Transaction>>> = {
+            let mut cache = self.tx_cache.lock().unwrap();
+            txids
+                .iter()
+                .map(|&(ref txid, _)| cache.get_mut(txid).map(|tx| Ok(tx.clone())))
+                .collect()
+        };
+        let hits = results.iter().filter(|result| result.is_some()).count();
+        self.tx_cache_lookups
+            .with_label_values(&["hit"])
+            .inc_by(hits as i64);
+        self.tx_cache_lookups
+            .with_label_values(&["miss"])
+            .inc_by((txids.len() - hits) as i64);
+        if let Some(ref disk) = self.tx_disk_cache {
+            let mut cache = self.tx_cache.lock().unwrap();
+            for (i, &(ref txid, _)) in txids.iter().enumerate() {
+                if results[i].is_some() {
+                    continue;
+                }
+                if let Some(tx) = disk.get(self.app.read_store(), txid) {
+                    cache.insert(*txid, tx.clone());
+                    results[i] = Some(Ok(tx));
+                }
+            }
+            self.tx_cache_entries.set(cache.len() as i64);
+        }
+        let mut missing: Vec<(usize, Sha256dHash, Option<Sha256dHash>)> = vec![];
+        for (i, &(txid, blockhash)) in txids.iter().enumerate() {
+            if results[i].is_some() {
+                continue;
+            }
+            let blockhash = match blockhash {
+                Some(blockhash) => Ok(Some(blockhash)),
+                None => self.lookup_confirmed_blockhash(&txid, None),
+            };
+            match blockhash {
+                Ok(blockhash) => missing.push((i, txid, blockhash)),
+                Err(err) => results[i] = Some(Err(err)),
+            }
+        }
+        if !missing.is_empty() {
+            let requests: Vec<(&Sha256dHash, Option<&Sha256dHash>)> = missing
+                .iter()
+                .map(|&(_, ref txid, ref blockhash)| (txid, blockhash.as_ref()))
+                .collect();
+            match self.app.daemon().gettransactions_in_blocks(&requests) {
+                Ok(txs) => {
+                    {
+                        let fetched: Vec<&Transaction> =
+                            txs.iter().filter_map(|tx| tx.as_ref().ok()).collect();
+                        self.tx_daemon_lookups
+                            .with_label_values(&["hit"])
+                            .inc_by(fetched.len() as i64);
+                        self.tx_daemon_lookups
+                            .with_label_values(&["miss"])
+                            .inc_by((missing.len() - fetched.len()) as i64);
+                        cache_txs_on_disk(
+                            self.tx_disk_cache.as_ref(),
+                            self.app.is_paused(),
+                            self.app.read_store(),
+                            self.app.write_store(),
+                            &fetched,
+                        );
+                    }
+                    let mut cache = self.tx_cache.lock().unwrap();
+                    for (&(i, txid, _), tx) in missing.iter().zip(txs.into_iter()) {
+                        if let Ok(ref tx) = tx {
+                            cache.insert(txid, tx.clone());
+                        }
+                        results[i] = Some(tx);
+                    }
+                    self.tx_cache_entries.set(cache.len() as i64);
+                }
+                Err(err) => {
+                    self.tx_daemon_lookups
+                        .with_label_values(&["miss"])
+                        .inc_by(missing.len() as i64);
+                    for &(i, txid, _) in &missing {
+                        results[i] = Some(Err(format!("cannot fetch tx {}: {}", txid, err).into()));
+                    }
+                }
+            }
         }
+        results.into_iter().map(|result| result.unwrap()).collect()
     }
 
     // Load raw transaction by txid
@@ -480,7 +1701,7 @@ impl Query {
             .start_timer();
         if self.extended_db_enabled {
             // fetch from our txstore or mempool tracker
-            Ok(rawtxrow_by_txid(self.app.read_store(), txid)
+            Ok(rawtxrow_by_txid(&self.read_store(), txid)
                 .map(|row| row.rawtx)
                 .or_else(|| {
                     self.tracker
@@ -523,7 +1744,15 @@ impl Query {
 
     pub fn get_block(&self, blockhash: &Sha256dHash) -> Result<Block> {
         let _timer = self.latency.with_label_values(&["get_block"]).start_timer();
-        self.app.daemon().getblock(blockhash)
+        self.fetch_block(blockhash, || self.app.daemon().getblock(blockhash))
+    }
+
+    fn fetch_block<T, F>(&self, blockhash: &Sha256dHash, fetch: F) -> Result<T>
+    where
+        F: Fn() -> Result<T>,
+    {
+        let delay = Duration::from_millis(BLOCK_FETCH_RETRY_DELAY_MS);
+        fetch_block_with_retries(blockhash, self.block_fetch_retries, delay, fetch)
     }
 
     pub fn get_block_header_with_meta(&self, blockhash: &Sha256dHash) -> Result<BlockHeaderMeta> {
@@ -532,7 +1761,7 @@ impl Query {
             .with_label_values(&["get_block_header_with_meta"])
             .start_timer();
         Ok(BlockHeaderMeta {
-            header_entry: self.get_header_by_hash(blockhash)?,
+            header_entry: self.get_any_header_by_hash(blockhash)?,
             meta: self.get_block_meta(blockhash)?,
         })
     }
@@ -544,14 +1773,13 @@ impl Query {
             .start_timer();
         if self.extended_db_enabled {
             // fetch from our blockhash=>txids index
-            get_block_txids(self.app.read_store(), blockhash)
-                .chain_err(|| "cannot load block txids")
+            get_block_txids(&self.read_store(), blockhash).chain_err(|| "cannot load block txids")
         } else {
             // fetch from bitcoind
             let block = self
                 .app
                 .daemon()
-                .getblock_raw(blockhash, 1)
+                .getblock_raw(blockhash, BlockVerbosity::Summary)
                 .chain_err(|| "cannot load block")?;
             let txids = block
                 .get("tx")
@@ -568,6 +1796,93 @@ impl Query {
         }
     }
 
+    /// The fee of each transaction in the block (in order, excluding the coinbase), computed from
+    /// its prevouts' values. Cached per block, as its transactions never change.
+    pub fn get_block_fees(&self, blockhash: &Sha256dHash) -> Result<Arc<Vec<u64>>> {
+        if let Some(fees) = self.block_fees_cache.lock().unwrap().get_mut(blockhash) {
+            return Ok(fees.clone());
+        }
+        let _timer = self
+            .latency
+            .with_label_values(&["get_block_fees"])
+            .start_timer();
+        let txs = self
+            .get_block_txids(blockhash)?
+            .iter()
+            .skip(1)
+            .map(|txid| self.load_txn(txid, Some(blockhash)))
+            .collect::<Result<Vec<Transaction>>>()?;
+
+        // only the previous outputs are read (not their whole transactions), in order (for DB
+        // locality)
+        let mut outpoints: Vec<OutPoint> = txs
+            .iter()
+            .flat_map(|tx| tx.input.iter())
+            .map(|txin| {
+                (
+                    txin.previous_output.txid,
+                    txin.previous_output.vout as usize,
+                )
+            })
+            .collect();
+        outpoints.sort();
+        outpoints.dedup();
+        let mut prevout_values: HashMap<OutPoint, u64> = HashMap::new();
+        for outpoint in outpoints {
+            let prevout = self.lookup_txo(&outpoint)?;
+            prevout_values.insert(outpoint, prevout.value);
+        }
+
+        let fees = txs
+            .iter()
+            .map(|tx| {
+                let total_in: u64 = tx
+                    .input
+                    .iter()
+                    .map(|txin| {
+                        prevout_values[&(
+                            txin.previous_output.txid,
+                            txin.previous_output.vout as usize,
+                        )]
+                    })
+                    .sum();
+                let total_out: u64 = tx.output.iter().map(|txout| txout.value).sum();
+                total_in
+                    .checked_sub(total_out)
+                    .chain_err(|| format!("negative fee for tx {}", tx.txid()))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        let fees = Arc::new(fees);
+        self.block_fees_cache
+            .lock()
+            .unwrap()
+            .insert(*blockhash, fees.clone());
+        Ok(fees)
+    }
+
+    /// The count and total value of the block's outputs, by script type. Cached per block, as
+    /// its transactions never change.
+    pub fn get_block_output_types(&self, blockhash: &Sha256dHash) -> Result<Arc<OutputTypes>> {
+        if let Some(output_types) = self
+            .block_output_types_cache
+            .lock()
+            .unwrap()
+            .get_mut(blockhash)
+        {
+            return Ok(output_types.clone());
+        }
+        let _timer = self
+            .latency
+            .with_label_values(&["get_block_output_types"])
+            .start_timer();
+        let output_types = Arc::new(output_types(&self.get_block(blockhash)?));
+        self.block_output_types_cache
+            .lock()
+            .unwrap()
+            .insert(*blockhash, output_types.clone());
+        Ok(output_types)
+    }
+
     pub fn get_block_meta(&self, blockhash: &Sha256dHash) -> Result<BlockMeta> {
         let _timer = self
             .latency
@@ -575,13 +1890,48 @@ impl Query {
             .start_timer();
         if self.extended_db_enabled {
             // fetch from our blockhash=>txids index
-            get_block_meta(self.app.read_store(), blockhash).chain_err(|| "cannot load block meta")
+            get_block_meta(&self.read_store(), blockhash).chain_err(|| "cannot load block meta")
         } else {
             // fetch from bitcoind
-            BlockMeta::parse_getblock(self.app.daemon().getblock_raw(blockhash, 1)?)
+            BlockMeta::parse_getblock(
+                self.app
+                    .daemon()
+                    .getblock_raw(blockhash, BlockVerbosity::Summary)?,
+            )
         }
     }
 
+    /// Returns the `count` best chain blocks (or less, near genesis), newest first. Their
+    /// metadata is cached by hash (it never changes), so only the new blocks are looked up.
+    pub fn get_recent_blocks(&self, count: usize) -> Result<Vec<BlockHeaderMeta>> {
+        let tip = self.get_best_height();
+        let heights: Vec<usize> = (0..=tip).rev().take(count).collect();
+        self.get_headers(&heights)
+            .into_iter()
+            .map(|header_entry| {
+                let hash = *header_entry.hash();
+                let cached = self
+                    .block_meta_cache
+                    .lock()
+                    .unwrap()
+                    .get_mut(&hash)
+                    .cloned();
+                let meta = match cached {
+                    Some(meta) => meta,
+                    None => {
+                        let meta = self.get_block_meta(&hash)?;
+                        self.block_meta_cache
+                            .lock()
+                            .unwrap()
+                            .insert(hash, meta.clone());
+                        meta
+                    }
+                };
+                Ok(BlockHeaderMeta { header_entry, meta })
+            })
+            .collect()
+    }
+
     pub fn get_headers(&self, heights: &[usize]) -> Vec<HeaderEntry> {
         let index = self.app.index();
         heights
@@ -590,11 +1940,85 @@ impl Query {
             .collect()
     }
 
+    /// The script's first funding transaction: its earliest confirmed one (by height, then
+    /// position in the block) with its block's header, else the first of its mempool ones (by
+    /// txid) without a header, None if it was never funded. Unlike its history, only the
+    /// transactions funding it at the lowest height are loaded.
+    pub fn get_first_funding(
+        &self,
+        script_hash: &[u8],
+    ) -> Result<Option<(Sha256dHash, Option<HeaderEntry>)>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_first_funding"])
+            .start_timer();
+        let index = self.app.index();
+        let header_at = |height| index.get_header(height);
+        let funding = first_funding_txs(
+            &self.read_store(),
+            script_hash,
+            &header_at,
+            |store, prefixes| self.load_txns_by_prefix(store, prefixes),
+        )?;
+        if let Some(height) = funding.first().map(|first| first.height) {
+            let mut txids: Vec<Sha256dHash> = funding.iter().map(|t| t.txn.txid()).collect();
+            self.sort_by_position(height, &mut txids)?;
+            return Ok(Some((txids[0], header_at(height as usize))));
+        }
+        self.check_synced()?; // it may be funded by a block still to index
+        let tracker = self.tracker.read().unwrap();
+        let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
+        let txids = self
+            .load_txns_by_prefix(tracker.index(), txid_prefixes)?
+            .into_iter()
+            .filter(|t| !self.find_funding_outputs(t, script_hash).is_empty())
+            .map(|t| t.txn.txid());
+        Ok(txids.min().map(|txid| (txid, None)))
+    }
+
+    pub fn get_header_by_time(&self, time: u32) -> Option<(HeaderEntry, u32)> {
+        self.app.index().get_header_by_time(time)
+    }
+
     pub fn get_header_by_hash(&self, hash: &Sha256dHash) -> Result<HeaderEntry> {
         let header = self.app.index().get_header_by_hash(hash);
         Ok(header.chain_err(|| "no header found")?.clone())
     }
 
+    /// Like `get_header_by_hash()`, but also finds the headers of orphaned blocks (in the DB).
+    pub fn get_any_header_by_hash(&self, hash: &Sha256dHash) -> Result<HeaderEntry> {
+        let index = self.app.index();
+        let best_header = |hash: &Sha256dHash| index.get_header_by_hash(hash);
+        header_by_hash(&self.read_store(), hash, &best_header).chain_err(|| "no header found")
+    }
+
+    /// The serialized block, fetched from the daemon (as blocks aren't stored in the DB).
+    pub fn get_block_raw(&self, blockhash: &Sha256dHash) -> Result<Arc<Bytes>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_block_raw"])
+            .start_timer();
+        if let Some(raw) = self.raw_block_cache.lock().unwrap().get_mut(blockhash) {
+            return Ok(raw.clone());
+        }
+        let value = self.fetch_block(blockhash, || {
+            self.app
+                .daemon()
+                .getblock_raw(blockhash, BlockVerbosity::Raw)
+        })?;
+        let raw = ::hex::decode(value.as_str().chain_err(|| "non-string block")?)
+            .chain_err(|| "non-hex block")?;
+        if raw.len() < 80 || Sha256dHash::from_data(&raw[..80]) != *blockhash {
+            bail!("daemon returned another block than {}", blockhash);
+        }
+        let raw = Arc::new(raw);
+        self.raw_block_cache
+            .lock()
+            .unwrap()
+            .insert(*blockhash, raw.clone());
+        Ok(raw)
+    }
+
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
         let last_header = self.app.index().best_header();
         Ok(last_header.chain_err(|| "no headers indexed")?.clone())
@@ -604,17 +2028,40 @@ impl Query {
         self.app.index().best_header_hash()
     }
 
+    pub fn get_block_locator(&self) -> Vec<Sha256dHash> {
+        self.app.index().block_locator()
+    }
+
+    /// Whether the initial sync is still in progress: with `--serve-during-sync`, queries are
+    /// served meanwhile from the blocks indexed so far.
+    pub fn is_syncing(&self) -> bool {
+        !self.sync.initial_sync_done()
+    }
+
+    // The scripts' histories can't be served from a partial index, as they may have transactions
+    // in the blocks still to index.
+    fn check_synced(&self) -> Result<()> {
+        if self.is_syncing() {
+            bail!(ErrorKind::Syncing(self.get_best_height()));
+        }
+        Ok(())
+    }
+
     pub fn get_best_height(&self) -> usize {
         self.app.index().best_height()
     }
 
+    /// The # of blocks orphaned by the deepest reorg handled by this index (0 if none).
+    pub fn get_max_reorg_depth(&self) -> usize {
+        self.app.index().max_reorg_depth()
+    }
+
     pub fn get_block_status(&self, hash: &Sha256dHash) -> BlockStatus {
         let _timer = self
             .latency
             .with_label_values(&["get_block_status"])
             .start_timer();
-        // get_header_by_hash looks up the height first, then fetches the header by that.
-        // if the block is no longer the best block at this height, it'll return None.
+        // only the headers of the best chain are found by hash, so orphaned blocks return None.
         match self.app.index().get_header_by_hash(hash) {
             Some(header) => BlockStatus {
                 in_best_chain: true,
@@ -634,28 +2081,87 @@ impl Query {
     }
 
     pub fn get_tx_status(&self, tx_hash: &Sha256dHash) -> Result<TransactionStatus> {
+        Ok(match self.get_tx_confirmations(tx_hash)? {
+            Some((header, _)) => TransactionStatus::confirmed(&header),
+            None => TransactionStatus::unconfirmed(),
+        })
+    }
+
+    /// The header of the block confirming the tx, with its # of confirmations (against the
+    /// current tip), or `None` if it's unconfirmed.
+    pub fn get_tx_confirmations(
+        &self,
+        tx_hash: &Sha256dHash,
+    ) -> Result<Option<(HeaderEntry, usize)>> {
         let _timer = self
             .latency
             .with_label_values(&["get_tx_status"])
             .start_timer();
         // try fetching the height/hash of the block seen to confirm the tx
-        let (height, blockhash) = match txrow_by_txid(self.app.read_store(), &tx_hash) {
-            None => return Ok(TransactionStatus::unconfirmed()),
+        let (height, blockhash) = match txrow_by_txid(&self.read_store(), &tx_hash) {
+            None => return Ok(None),
             Some(txrow) => (txrow.height, txrow.blockhash),
         };
 
-        // fetch the block header at the recorded confirmation height
-        let header = self
+        // the block at confirmation height is not the one containing the tx (or is beyond the
+        // tip), must've reorged!
+        Ok(self
             .app
             .index()
-            .get_header(height as usize)
-            .chain_err(|| "invalid block height for tx")?;
+            .get_confirmed_header(height as usize, &blockhash))
+    }
 
-        // the block at confirmation height is not the one containing the tx, must've reorged!
-        if header.hash() != &blockhash {
-            Ok(TransactionStatus::unconfirmed())
+    /// Returns up to `limit` indexed addresses starting with `prefix`, sorted.
+    /// Bech32 addresses are indexed in lowercase, so they are matched case-insensitively.
+    /// Returns `None` if more than `scan_limit` addresses start with `prefix` (as it's too broad).
+    pub fn search_addresses(
+        &self,
+        prefix: &str,
+        limit: usize,
+        scan_limit: usize,
+    ) -> Option<Vec<String>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["search_addresses"])
+            .start_timer();
+        search_addresses(&self.read_store(), prefix, limit, scan_limit)
+    }
+
+    /// Whether the DB has the OP_RETURN index, i.e. it was created with `--index-opreturn`.
+    pub fn has_opreturn_index(&self) -> bool {
+        self.opreturn_index
+    }
+
+    /// Returns up to `limit` confirmed txids with an OP_RETURN payload whose SHA256 starts with
+    /// `hash_prefix`, or `None` if more than `scan_limit` payloads match (as it's too broad).
+    pub fn search_opreturn(
+        &self,
+        hash_prefix: &[u8],
+        limit: usize,
+        scan_limit: usize,
+    ) -> Option<Vec<Sha256dHash>> {
+        let _timer = self
+            .latency
+            .with_label_values(&["search_opreturn"])
+            .start_timer();
+        let index = self.app.index();
+        let in_best_chain = |hash: &Sha256dHash| index.get_header_by_hash(hash).is_some();
+        search_opreturn(
+            &self.read_store(),
+            hash_prefix,
+            limit,
+            scan_limit,
+            &in_best_chain,
+        )
+    }
+
+    /// Like `get_tx_status()`, but returns `None` for a tx that is neither confirmed nor in mempool.
+    pub fn find_tx_status(&self, tx_hash: &Sha256dHash) -> Result<Option<TransactionStatus>> {
+        let status = self.get_tx_status(tx_hash)?;
+        if status.confirmed || self.tracker.read().unwrap().has_txn(tx_hash) {
+            Ok(Some(status))
         } else {
-            Ok(TransactionStatus::confirmed(&header))
+            Ok(None)
         }
     }
 
@@ -668,6 +2174,34 @@ impl Query {
             .latency
             .with_label_values(&["get_merkle_proof"])
             .start_timer();
+        if !self.cache_merkle_proofs {
+            return self.compute_merkle_proof(tx_hash, block_hash);
+        }
+        let key = MerkleProofRow::filter(block_hash, tx_hash);
+        if let Some(value) = self.read_store().get(&key) {
+            self.merkle_proof_cache_lookups
+                .with_label_values(&["hit"])
+                .inc();
+            let row = MerkleProofRow::from_row(&Row { key, value });
+            return Ok((row.merkle, row.pos));
+        }
+        self.merkle_proof_cache_lookups
+            .with_label_values(&["miss"])
+            .inc();
+        let (merkle, pos) = self.compute_merkle_proof(tx_hash, block_hash)?;
+        let row = MerkleProofRow::new(block_hash, tx_hash, merkle, pos);
+        if !self.app.is_paused() {
+            // the DB isn't written to during maintenance
+            self.app.write_store().write(vec![row.to_row()]);
+        }
+        Ok((row.merkle, row.pos))
+    }
+
+    fn compute_merkle_proof(
+        &self,
+        tx_hash: &Sha256dHash,
+        block_hash: &Sha256dHash,
+    ) -> Result<(Vec<Sha256dHash>, usize)> {
         let mut txids = self
             .get_block_txids(&block_hash)
             .chain_err(|| format!("missing txids for block #{}", block_hash))?;
@@ -697,8 +2231,156 @@ impl Query {
         self.app.daemon().broadcast(txn)
     }
 
+    pub fn get_tx_replacements(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        self.tracker.read().unwrap().get_replacements(txid)
+    }
+
+    pub fn get_mempool_tx_graph(&self, txid: &Sha256dHash) -> Option<TxGraph> {
+        self.tracker.read().unwrap().get_graph(txid)
+    }
+
+    pub fn get_mempool_fee_rank(&self, txid: &Sha256dHash) -> Option<FeeRank> {
+        self.tracker.read().unwrap().fee_rank(txid)
+    }
+
+    /// Returns a mempool transaction's fee (in satoshis) and vsize.
+    pub fn get_mempool_fee(&self, txid: &Sha256dHash) -> Option<(u64, u32)> {
+        self.tracker.read().unwrap().get_fee(txid)
+    }
+
+    pub fn get_mempool_summary(&self) -> MempoolSummary {
+        self.tracker.read().unwrap().summary()
+    }
+
+    // Called after the index is updated, so waiters are notified about new blocks too.
     pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+        if self.app.is_paused() {
+            return Ok(());
+        }
+        self.tracker
+            .write()
+            .unwrap()
+            .update(self.app.daemon(), self.app.read_store())?;
+        *self.updates.lock().unwrap() += 1;
+        self.updated.notify_all();
+        Ok(())
+    }
+
+    /// Writes the tracked mempool to `path`, to be reloaded on the next start (see
+    /// `load_mempool()`). Returns the # of written transactions.
+    pub fn save_mempool(&self, path: &Path) -> Result<usize> {
+        self.tracker.read().unwrap().save(path)
+    }
+
+    /// Reloads the mempool written by `save_mempool()`, discarding the transactions the daemon
+    /// no longer has. Returns the # of reloaded and discarded transactions.
+    pub fn load_mempool(&self, path: &Path) -> Result<(usize, usize)> {
+        let txids = self.app.daemon().getmempooltxids()?;
+        self.tracker.write().unwrap().load(path, &txids)
+    }
+
+    /// The history cache's hits include the histories extended after a new block.
+    pub fn history_cache_stats(&self) -> CacheStats {
+        let (entries, capacity) = {
+            let cache = self.history_cache.lock().unwrap();
+            (cache.len(), cache.capacity())
+        };
+        let lookups = |kind| self.history_cache_lookups.with_label_values(&[kind]).get() as u64;
+        CacheStats {
+            entries,
+            capacity,
+            hits: lookups("hit") + lookups("extend"),
+            misses: lookups("miss"),
+        }
+    }
+
+    pub fn tx_cache_stats(&self) -> CacheStats {
+        let (entries, capacity) = self.tx_cache_usage();
+        let lookups = |kind| self.tx_cache_lookups.with_label_values(&[kind]).get() as u64;
+        CacheStats {
+            entries,
+            capacity,
+            hits: lookups("hit"),
+            misses: lookups("miss"),
+        }
+    }
+
+    /// The number of cached transactions (fetched from the daemon with --light) and the cache's
+    /// capacity.
+    pub fn tx_cache_usage(&self) -> (usize, usize) {
+        let cache = self.tx_cache.lock().unwrap();
+        (cache.len(), cache.capacity())
+    }
+
+    /// Resizes the transaction cache (evicting the least recently used ones if needed), e.g. to
+    /// tune it without restarting.
+    pub fn set_tx_cache_capacity(&self, capacity: usize) {
+        let mut cache = self.tx_cache.lock().unwrap();
+        cache.set_capacity(capacity);
+        self.tx_cache_entries.set(cache.len() as i64);
+        self.tx_cache_capacity.set(capacity as i64);
+    }
+
+    /// Pauses the index and mempool updates for maintenance (see `App::pause()`).
+    pub fn pause_updates(&self) {
+        self.app.pause()
+    }
+
+    pub fn resume_updates(&self) {
+        self.app.resume()
+    }
+
+    pub fn updates_paused(&self) -> bool {
+        self.app.is_paused()
+    }
+
+    /// Starts a full compaction of the DB in the background.
+    pub fn start_compaction(&self) -> Result<()> {
+        self.app.check_compaction()?;
+        let app = self.app.clone();
+        spawn_thread("compaction", move || {
+            if let Err(e) = app.compact() {
+                warn!("compaction failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.app.is_compacting()
+    }
+
+    pub fn uptime(&self) -> usize {
+        self.sync.uptime()
+    }
+
+    /// The recent indexing rate (in blocks/s) and the estimated time to catch up (in seconds).
+    pub fn index_progress(&self) -> (Option<f64>, Option<usize>) {
+        self.sync.index_progress()
+    }
+
+    pub fn db_disk_size(&self) -> u64 {
+        self.app.db_disk_size()
+    }
+
+    pub fn db_prefix_sizes(&self) -> BTreeMap<u8, (usize, u64)> {
+        self.app.db_prefix_sizes()
+    }
+
+    /// The number of updates done so far, to be passed to `wait_for_update()`.
+    pub fn update_count(&self) -> u64 {
+        *self.updates.lock().unwrap()
+    }
+
+    /// Blocks until an update is done after `seen` (or the timeout expires),
+    /// returning the current update count.
+    pub fn wait_for_update(&self, seen: u64, timeout: Duration) -> u64 {
+        let updates = self.updates.lock().unwrap();
+        if *updates != seen {
+            return *updates;
+        }
+        let (updates, _) = self.updated.wait_timeout(updates, timeout).unwrap();
+        *updates
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -706,8 +2388,115 @@ impl Query {
         self.tracker.read().unwrap().fee_histogram().clone()
     }
 
+    /// Returns the daemon's fee rate estimates, cached for a short while.
+    /// Targets the daemon has no estimate for are omitted.
+    pub fn get_fee_estimates(&self) -> Result<FeeEstimates> {
+        let mut cache = self.fee_estimates.lock().unwrap();
+        if let Some((updated, ref estimates)) = *cache {
+            if updated.elapsed() < Duration::from_secs(FEE_ESTIMATES_TTL) {
+                return Ok(estimates.clone());
+            }
+        }
+        let _timer = self
+            .latency
+            .with_label_values(&["get_fee_estimates"])
+            .start_timer();
+        // [sat/B] = 10^5 [BTC/kB]
+        let estimates: FeeEstimates = FEE_ESTIMATES_TARGETS
+            .iter()
+            .zip(self.app.daemon().estimatesmartfee(&FEE_ESTIMATES_TARGETS)?)
+            .filter_map(|(target, rate)| Some((*target, (rate? * 1e5) as f32)))
+            .collect();
+        *cache = Some((Instant::now(), estimates.clone()));
+        Ok(estimates)
+    }
+
+    /// Returns the daemon's mempool minimum fee rate (in sat/vbyte), below which transactions
+    /// aren't accepted (above the relay fee once the mempool is full), cached for a short while.
+    /// If the daemon fails, the last known one is returned.
+    pub fn get_mempool_min_fee(&self) -> Result<f32> {
+        let mut cache = self.mempool_min_fee.lock().unwrap();
+        if let Some((updated, rate)) = *cache {
+            if updated.elapsed() < Duration::from_secs(MEMPOOL_MIN_FEE_TTL) {
+                return Ok(rate);
+            }
+        }
+        match self.app.daemon().getmempoolinfo() {
+            Ok(info) => {
+                let rate = (info.mempoolminfee * 1e5) as f32; // [sat/B] = 10^5 [BTC/kB]
+                *cache = Some((Instant::now(), rate));
+                Ok(rate)
+            }
+            Err(err) => match *cache {
+                Some((_, rate)) => {
+                    warn!(
+                        "failed to get the mempool min fee (using the last one): {}",
+                        err
+                    );
+                    Ok(rate)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Returns the daemon's `getblockchaininfo`, cached for a short while. If the daemon fails,
+    /// the last known one is returned (if any).
+    fn get_blockchain_info(&self) -> Option<BlockchainInfo> {
+        let mut cache = self.blockchain_info.lock().unwrap();
+        if let Some(&(updated, ref info)) = cache.as_ref() {
+            if updated.elapsed() < Duration::from_secs(BLOCKCHAIN_INFO_TTL) {
+                return Some(info.clone());
+            }
+        }
+        match self.app.daemon().getblockchaininfo() {
+            Ok(info) => {
+                *cache = Some((Instant::now(), info.clone()));
+                Some(info)
+            }
+            Err(err) => {
+                warn!("failed to get the blockchain info: {}", err);
+                cache.as_ref().map(|(_, info)| info.clone())
+            }
+        }
+    }
+
+    pub fn get_chain_status(&self) -> ChainStatus {
+        let tip_hash = self.get_best_header_hash();
+        let daemon = self.get_blockchain_info();
+        let initial_sync_done = self.sync.initial_sync_done();
+        let synced = initial_sync_done
+            && daemon.as_ref().map_or(false, |info| {
+                !info.initialblockdownload && info.bestblockhash == tip_hash.be_hex_string()
+            });
+        ChainStatus {
+            version: env!("CARGO_PKG_VERSION"),
+            tip_height: self.get_best_height(),
+            tip_hash,
+            daemon_height: daemon.as_ref().map(|info| info.blocks),
+            daemon_hash: daemon.as_ref().map(|info| info.bestblockhash.clone()),
+            daemon_initial_block_download: daemon.as_ref().map(|info| info.initialblockdownload),
+            initial_sync_done,
+            synced,
+            mempool_count: self.get_mempool_summary().count,
+        }
+    }
+
     // Fee rate [BTC/kB] to be confirmed in `blocks` from now.
     pub fn estimate_fee(&self, blocks: usize) -> f32 {
+        // prefer the daemon's estimate for the nearest target that isn't sooner than requested
+        match self.get_fee_estimates() {
+            Ok(estimates) => {
+                if let Some((_, rate)) = estimates
+                    .iter()
+                    .find(|(target, _)| **target as usize >= blocks)
+                {
+                    return rate * 1e-5;
+                }
+            }
+            Err(e) => warn!("failed to get fee estimates: {}", e),
+        }
+        // otherwise, estimate using the mempool fee histogram
         let mut total_vsize = 0u32;
         let mut last_fee_rate = 0.0;
         let blocks_in_vbytes = (blocks * 1_000_000) as u32; // assume ~1MB blocks
@@ -721,3 +2510,931 @@ impl Query {
         last_fee_rate * 1e-5 // [BTC/kB] = 10^5 [sat/B]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::Block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxOut};
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use errors::*;
+    use index::{compute_script_hash, index_block, orphaned_keys, AddressRow, SpendRow};
+    use mempool::MEMPOOL_HEIGHT;
+    use metrics::Metrics;
+    use query::{
+        all_txids_by_script_hash, cache_txs_on_disk, check_history_size, confirmed_history,
+        fetch_block_with_retries, first_funding_txs, header_by_hash, history_page, lookup_txos,
+        mempool_spend, order_by_position, output_types, rawtxrow_by_txid, recent_history,
+        search_addresses, search_opreturn, sort_mempool_txs, spend_by_outpoint, txrow_by_txid,
+        txrows_by_prefix, Balance, CachedHistory, FundingOutput, History, MempoolTx, OutputSpend,
+        OutputTypeStats, ScriptStats, SpendingInput, Status, TxnHeight,
+    };
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::time::Duration;
+    use store::{DBOptions, DBStore, ReadStore, WriteStore};
+    use testutil::{make_block, make_tx, temp_path};
+    use txcache::DiskTxCache;
+    use util::{HashPrefix, HeaderList};
+
+    #[test]
+    fn test_sort_mempool_txs() {
+        let parent = Sha256dHash::from_data(b"unconfirmed");
+        let child = Sha256dHash::from_data(b"child"); // sorted before the parent by txid
+        let mut mempool = vec![
+            MempoolTx {
+                txid: child,
+                unconfirmed_inputs: true,
+            },
+            MempoolTx {
+                txid: parent,
+                unconfirmed_inputs: false,
+            },
+        ];
+        sort_mempool_txs(&mut mempool);
+        assert_eq!(mempool[0].txid, parent);
+        assert_eq!(mempool[1].txid, child);
+    }
+
+    #[test]
+    fn test_order_by_position() {
+        let txid = |i: u8| Sha256dHash::from_data(&[i]);
+        let mut history: History = vec![(1, txid(0)), (2, txid(1)), (2, txid(2)), (3, txid(3))];
+        let mut sorted_heights = vec![];
+        order_by_position(&mut history, |height, txids| {
+            sorted_heights.push(height);
+            txids.reverse(); // as if they were in the reverse order in the block
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sorted_heights, vec![2]); // the other heights have a single txid
+        assert_eq!(
+            history,
+            vec![(1, txid(0)), (2, txid(2)), (2, txid(1)), (3, txid(3))]
+        );
+    }
+
+    // tagged (in its input script), so that the transactions spending the same output differ
+    fn tagged_tx(prev_txid: Sha256dHash, prev_vout: u32, tag: u8) -> Transaction {
+        let mut tx = make_tx(&[(prev_txid, prev_vout)], &[1000]);
+        tx.input[0].script_sig = Script::from(vec![tag]);
+        tx
+    }
+
+    // like Query::load_txns_by_prefix(), from the extended DB
+    fn load_from_db(store: &ReadStore, prefixes: Vec<HashPrefix>) -> Result<Vec<TxnHeight>> {
+        Ok(prefixes
+            .iter()
+            .flat_map(|txid_prefix| txrows_by_prefix(store, txid_prefix))
+            .map(|row| {
+                let txid = deserialize(&row.key.txid).unwrap();
+                let rawtx = rawtxrow_by_txid(store, &txid).unwrap().rawtx;
+                TxnHeight {
+                    txn: deserialize(&rawtx).unwrap(),
+                    height: row.height,
+                    blockhash: row.blockhash,
+                }
+            })
+            .collect())
+    }
+
+    fn apply(headers: &mut HeaderList, block: &Block) {
+        let entries = headers.order(vec![block.header.clone()]);
+        headers.apply(entries);
+    }
+
+    #[test]
+    fn test_output_types() {
+        let script = |prefix: &[u8], len: usize, suffix: &[u8]| {
+            let mut bytes = prefix.to_vec();
+            bytes.extend(vec![0x11; len]);
+            bytes.extend(suffix);
+            Script::from(bytes)
+        };
+        let p2pkh = script(&[0x76, 0xa9, 0x14], 20, &[0x88, 0xac]);
+        let p2sh = script(&[0xa9, 0x14], 20, &[0x87]);
+        let p2wpkh = script(&[0x00, 0x14], 20, &[]);
+        let p2wsh = script(&[0x00, 0x20], 32, &[]);
+        let op_return = script(&[0x6a, 0x02], 2, &[]);
+
+        let mut coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        coinbase.output[0].script_pubkey = p2pkh.clone();
+        coinbase.output.push(TxOut {
+            value: 0,
+            script_pubkey: op_return,
+        });
+        let mut tx = tagged_tx(coinbase.txid(), 0, 1);
+        tx.output = vec![(p2pkh, 10), (p2sh, 20), (p2wpkh, 30), (p2wsh, 40)]
+            .into_iter()
+            .map(|(script_pubkey, value)| TxOut {
+                value,
+                script_pubkey,
+            })
+            .collect();
+        let nonstandard = tagged_tx(tx.txid(), 0, 2); // pays to OP_TRUE
+        let block = make_block(Sha256dHash::default(), 0, vec![coinbase, tx, nonstandard]);
+
+        let stats = |count, value| OutputTypeStats { count, value };
+        let expected = vec![
+            ("nonstandard", stats(1, 1000)),
+            ("op_return", stats(1, 0)),
+            ("p2pkh", stats(2, 1010)),
+            ("p2sh", stats(1, 20)),
+            ("v0_p2wpkh", stats(1, 30)),
+            ("v0_p2wsh", stats(1, 40)),
+        ];
+        assert_eq!(
+            output_types(&block).into_iter().collect::<Vec<_>>(),
+            expected
+        );
+        assert!(output_types(&make_block(Sha256dHash::default(), 0, vec![])).is_empty());
+    }
+
+    // like Query::lookup_spend(): the confirmed spend, then the mempool one
+    fn lookup(
+        store: &ReadStore,
+        mempool: Option<(Sha256dHash, u32)>,
+        headers: &HeaderList,
+        outpoint: (Sha256dHash, usize),
+    ) -> Option<OutputSpend> {
+        let header_at = |height| headers.header_by_height(height).cloned();
+        spend_by_outpoint(store, &outpoint, &header_at).or_else(|| mempool_spend(mempool))
+    }
+
+    #[test]
+    fn test_recent_history() {
+        let confirmed = generate(100, 1);
+        let max_height = confirmed.last().unwrap().0;
+        assert!(recent_history(&confirmed, max_height + 1).is_empty());
+        let last = recent_history(&confirmed, max_height);
+        assert!(!last.is_empty());
+        assert!(last.iter().all(|(height, _)| *height == max_height));
+        let recent = recent_history(&confirmed, max_height - 2);
+        assert_eq!(&confirmed[confirmed.len() - recent.len()..], recent);
+        assert!(recent.iter().all(|(height, _)| *height >= max_height - 2));
+        assert!(confirmed[..confirmed.len() - recent.len()]
+            .iter()
+            .all(|(height, _)| *height < max_height - 2));
+        assert_eq!(recent_history(&confirmed, 0), &confirmed[..]);
+        assert!(recent_history(&vec![], 10).is_empty());
+    }
+
+    #[test]
+    fn test_history_page() {
+        let confirmed = generate(100, 1);
+        // within each block, txs are positioned in descending txid order
+        let sort_by_position = |_: u32, txids: &mut Vec<Sha256dHash>| {
+            txids.sort_by(|a, b| b.cmp(a));
+            Ok(())
+        };
+
+        let mut pages: Vec<History> = vec![];
+        let mut after_txid = None;
+        loop {
+            let page = history_page(&confirmed, after_txid.as_ref(), 7, sort_by_position)
+                .unwrap()
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            after_txid = Some(page.last().unwrap().1);
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), 15); // 14 full pages and a partial one
+        assert!(pages[..14].iter().all(|page| page.len() == 7));
+
+        // newest first: descending height, then descending position
+        let mut expected = confirmed.clone();
+        expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let walked: History = pages.into_iter().flat_map(|page| page).collect();
+        assert_eq!(walked, expected);
+
+        let unknown = Sha256dHash::from_data(b"unknown");
+        assert!(
+            history_page(&confirmed, Some(&unknown), 7, sort_by_position)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_lookup_spend() {
+        let dir = temp_path("spend");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let mut headers = HeaderList::empty();
+
+        let coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        let funding = coinbase.txid();
+        let spending = tagged_tx(funding, 0, 1);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![spending.clone()]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+
+        let spend = lookup(&store, None, &headers, (funding, 0)).unwrap();
+        assert_eq!(spend.txid, spending.txid());
+        assert_eq!(spend.vin, 0);
+        assert!(spend.status.confirmed);
+        assert_eq!(spend.status.block_height, Some(1));
+        assert_eq!(spend.status.block_hash, Some(block1.bitcoin_hash()));
+        assert!(lookup(&store, None, &headers, (funding, 1)).is_none());
+        assert!(lookup(&store, None, &headers, (spending.txid(), 0)).is_none());
+
+        // a reorg orphans the spending block, so the output is unspent again
+        let block1b = make_block(block0.bitcoin_hash(), 2, vec![]);
+        store.delete(orphaned_keys(&block1, true));
+        assert!(store.get(&SpendRow::filter(&funding, 0)).is_none());
+        store.write(index_block(&block1b, 1, true, false, Network::Regtest));
+        apply(&mut headers, &block1b);
+        assert!(lookup(&store, None, &headers, (funding, 0)).is_none());
+
+        // the spending tx is back in the mempool
+        let mempool = Some((spending.txid(), 0));
+        let spend = lookup(&store, mempool, &headers, (funding, 0)).unwrap();
+        assert_eq!((spend.txid, spend.vin), (spending.txid(), 0));
+        assert!(!spend.status.confirmed);
+        assert!(spend.status.block_height.is_none());
+
+        // ... until it's confirmed again, on the new chain
+        let block2 = make_block(block1b.bitcoin_hash(), 3, vec![spending.clone()]);
+        store.write(index_block(&block2, 2, true, false, Network::Regtest));
+        apply(&mut headers, &block2);
+        let spend = lookup(&store, mempool, &headers, (funding, 0)).unwrap();
+        assert_eq!(spend.status.block_height, Some(2));
+        assert_eq!(spend.status.block_hash, Some(block2.bitcoin_hash()));
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_txs_on_disk() {
+        let dir = temp_path("paused-cache");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let disk = DiskTxCache::open(
+            &store,
+            1 << 20,
+            &Metrics::new("127.0.0.1:0".parse().unwrap()),
+        );
+        let tx = tagged_tx(Sha256dHash::default(), 0, 1);
+
+        // nothing is written to the DB while paused
+        cache_txs_on_disk(Some(&disk), true, &store, &store, &[&tx]);
+        assert!(disk.get(&store, &tx.txid()).is_none());
+        assert_eq!(disk.usage().0, 0);
+
+        cache_txs_on_disk(Some(&disk), false, &store, &store, &[&tx]);
+        assert_eq!(disk.get(&store, &tx.txid()), Some(tx));
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_header_by_hash() {
+        let dir = temp_path("header");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let mut headers = HeaderList::empty();
+
+        let block0 = make_block(Sha256dHash::default(), 0, vec![]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![]);
+        let block2 = make_block(block1.bitcoin_hash(), 2, vec![]);
+        for (height, block) in vec![&block0, &block1, &block2].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+        // a 2-blocks reorg
+        let block1b = make_block(block0.bitcoin_hash(), 3, vec![]);
+        let block2b = make_block(block1b.bitcoin_hash(), 4, vec![]);
+        let block3b = make_block(block2b.bitcoin_hash(), 5, vec![]);
+        for (height, block) in vec![&block1b, &block2b, &block3b].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32 + 1,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+
+        let best_header = |hash: &Sha256dHash| headers.header_by_hash(hash).cloned();
+        let lookup = |block: &Block| {
+            let entry = header_by_hash(&store, &block.bitcoin_hash(), &best_header).unwrap();
+            assert_eq!(*entry.hash(), block.bitcoin_hash());
+            assert_eq!(*entry.header(), block.header);
+            entry.height()
+        };
+        assert_eq!(lookup(&block0), 0);
+        assert_eq!(lookup(&block3b), 3);
+        // orphaned
+        assert!(best_header(&block2.bitcoin_hash()).is_none());
+        assert_eq!(lookup(&block1), 1);
+        assert_eq!(lookup(&block2), 2);
+        // unknown
+        let unknown = make_block(block3b.bitcoin_hash(), 6, vec![]);
+        assert!(header_by_hash(&store, &unknown.bitcoin_hash(), &best_header).is_none());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // the confirmed history of a script, as read from its funding rows
+    fn funding_history(store: &ReadStore, script: &Script) -> History {
+        let script_hash = compute_script_hash(&script[..]);
+        let mut history: History = all_txids_by_script_hash(store, &script_hash)
+            .iter()
+            .flat_map(|txid_prefix| txrows_by_prefix(store, txid_prefix))
+            .map(|row| (row.height, deserialize(&row.key.txid).unwrap()))
+            .collect();
+        history.sort();
+        history
+    }
+
+    #[test]
+    fn test_reorg_history() {
+        let dir = temp_path("reorg");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let mut headers = HeaderList::empty();
+
+        let coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        let tx_a = tagged_tx(coinbase.txid(), 0, 1);
+        let tx_b = tagged_tx(tx_a.txid(), 0, 2);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![tx_a.clone(), tx_b.clone()]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+        let script = &coinbase.output[0].script_pubkey; // used by all the outputs
+        let mut expected = vec![(0, coinbase.txid()), (1, tx_a.txid()), (1, tx_b.txid())];
+        expected.sort();
+        assert_eq!(funding_history(&store, script), expected);
+
+        // block1 is invalidated, and tx_a is confirmed again by the new chain (but not tx_b)
+        let block1b = make_block(block0.bitcoin_hash(), 2, vec![]);
+        let block2b = make_block(block1b.bitcoin_hash(), 3, vec![tx_a.clone()]);
+        store.delete(orphaned_keys(&block1, true));
+        for (height, block) in vec![&block1b, &block2b].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32 + 1,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+        let mut expected = vec![(0, coinbase.txid()), (2, tx_a.txid())];
+        expected.sort();
+        assert_eq!(funding_history(&store, script), expected);
+        assert!(txrow_by_txid(&store, &tx_b.txid()).is_none());
+        assert_eq!(
+            txrow_by_txid(&store, &tx_a.txid()).unwrap().blockhash,
+            block2b.bitcoin_hash()
+        );
+        // the orphaned block's header is kept
+        let best_header = |hash: &Sha256dHash| headers.header_by_hash(hash).cloned();
+        let entry = header_by_hash(&store, &block1.bitcoin_hash(), &best_header).unwrap();
+        assert_eq!(entry.height(), 1);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // the stats, recounted over all the transactions of the given (best chain) blocks
+    fn naive_stats(blocks: &[&Block], script: &Script) -> ScriptStats {
+        let mut values = HashMap::new(); // funding outpoint => value
+        let mut stats = ScriptStats::default();
+        for block in blocks {
+            for txn in &block.txdata {
+                let mut involved = false;
+                for input in &txn.input {
+                    let outpoint = (input.previous_output.txid, input.previous_output.vout);
+                    if let Some(value) = values.get(&outpoint) {
+                        stats.spent_txo_count += 1;
+                        stats.spent_txo_sum += value;
+                        involved = true;
+                    }
+                }
+                for (vout, output) in txn.output.iter().enumerate() {
+                    if output.script_pubkey == *script {
+                        values.insert((txn.txid(), vout as u32), output.value);
+                        stats.funded_txo_count += 1;
+                        stats.funded_txo_sum += output.value;
+                        involved = true;
+                    }
+                }
+                if involved {
+                    stats.tx_count += 1;
+                }
+            }
+        }
+        stats
+    }
+
+    #[test]
+    fn test_script_stats() {
+        let dir = temp_path("stats");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let load = load_from_db;
+
+        let coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        let script = coinbase.output[0].script_pubkey.clone();
+        let script_hash = compute_script_hash(&script[..]);
+        let other = Script::from(vec![0x52]);
+        let mut funding = tagged_tx(coinbase.txid(), 0, 1);
+        funding.output = vec![
+            TxOut {
+                value: 5000,
+                script_pubkey: script.clone(),
+            },
+            TxOut {
+                value: 3000,
+                script_pubkey: script.clone(),
+            },
+            TxOut {
+                value: 2000,
+                script_pubkey: other.clone(),
+            },
+        ];
+        let mut spend_a = tagged_tx(funding.txid(), 0, 2);
+        spend_a.output[0].script_pubkey = other.clone();
+        let mut spend_b = tagged_tx(funding.txid(), 1, 3);
+        spend_b.output[0].script_pubkey = other.clone();
+
+        // funded, then partially spent
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let block1 = make_block(block0.bitcoin_hash(), 1, vec![funding.clone(), spend_a]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+        }
+        let (history, outputs) = confirmed_history(&store, &script_hash, &load).unwrap();
+        let mut cached = CachedHistory::new(block1.bitcoin_hash(), history, outputs);
+        let stats = cached.chain_stats();
+        assert_eq!(stats, naive_stats(&[&block0, &block1], &script));
+        assert_eq!(
+            (stats.funded_txo_sum, stats.spent_txo_sum, stats.tx_count),
+            (9000, 6000, 3)
+        );
+
+        // block1 is replaced, so another output is spent instead
+        let block1b = make_block(block0.bitcoin_hash(), 2, vec![funding]);
+        let block2b = make_block(block1b.bitcoin_hash(), 3, vec![spend_b]);
+        store.delete(orphaned_keys(&block1, true));
+        for (height, block) in vec![&block1b, &block2b].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32 + 1,
+                true,
+                false,
+                Network::Regtest,
+            ));
+        }
+        let (history, outputs) = confirmed_history(&store, &script_hash, &load).unwrap();
+        assert!(!cached.update(block2b.bitcoin_hash(), history, outputs));
+        let stats = cached.chain_stats();
+        assert_eq!(stats, naive_stats(&[&block0, &block1b, &block2b], &script));
+        assert_eq!(
+            (stats.funded_txo_sum, stats.spent_txo_sum, stats.tx_count),
+            (9000, 4000, 3)
+        );
+
+        // a never used script has an empty history (rather than failing)
+        let unused = compute_script_hash(&[0x53]);
+        let (history, outputs) = confirmed_history(&store, &unused, &load).unwrap();
+        assert!(history.is_empty() && outputs.is_empty());
+        let cached = CachedHistory::new(block2b.bitcoin_hash(), history, outputs);
+        assert_eq!(cached.chain_stats(), ScriptStats::default());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_balance() {
+        let stats = |funded: &[u64], spent: &[u64]| {
+            let mut stats = ScriptStats::default();
+            for value in funded {
+                stats.fund(*value);
+            }
+            for value in spent {
+                stats.spend(*value);
+            }
+            stats
+        };
+        // confirmed: 5000 and 3000 received, 2000 spent
+        let chain_stats = stats(&[5000, 3000, 2000], &[2000]);
+        let balance = |mempool_stats| Balance::new(&chain_stats, &mempool_stats);
+        assert_eq!(
+            balance(stats(&[], &[])),
+            Balance {
+                confirmed: 8000,
+                unconfirmed: 0
+            }
+        );
+        // a pending incoming payment
+        assert_eq!(balance(stats(&[1500], &[])).unconfirmed, 1500);
+        // a pending outgoing payment, spending a confirmed output (without change)
+        assert_eq!(balance(stats(&[], &[5000])).unconfirmed, -5000);
+        // both, with the change spent again in the mempool: the confirmed balance is unchanged
+        let mixed = balance(stats(&[1500, 700, 400], &[5000, 700]));
+        assert_eq!(
+            mixed,
+            Balance {
+                confirmed: 8000,
+                unconfirmed: -3100
+            }
+        );
+    }
+
+    #[test]
+    fn test_first_funding_txs() {
+        let dir = temp_path("first-funding");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let script = Script::from(vec![0x51]); // tagged_tx()'s output
+        let script_hash = compute_script_hash(&script[..]);
+        let other = Script::from(vec![0x52]);
+
+        let mut coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        coinbase.output[0].script_pubkey = other.clone();
+        let mut unrelated = tagged_tx(coinbase.txid(), 0, 1);
+        unrelated.output[0].script_pubkey = other.clone();
+        let funding_a = tagged_tx(coinbase.txid(), 0, 2);
+        let funding_b = tagged_tx(coinbase.txid(), 0, 3);
+        let later = tagged_tx(funding_a.txid(), 0, 4);
+        let orphaned = tagged_tx(Sha256dHash::default(), u32::max_value(), 5);
+
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase]);
+        let block1 = make_block(
+            block0.bitcoin_hash(),
+            1,
+            vec![unrelated, funding_a.clone(), funding_b.clone()],
+        );
+        let block2 = make_block(block1.bitcoin_hash(), 2, vec![later]);
+        // another genesis block, whose rows are left in the DB
+        let block0b = make_block(Sha256dHash::default(), 3, vec![orphaned]);
+        let mut headers = HeaderList::empty();
+        for (height, block) in vec![&block0, &block1, &block2].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+            apply(&mut headers, block);
+        }
+        store.write(index_block(&block0b, 0, true, false, Network::Regtest));
+        let header_at = |height| headers.header_by_height(height).cloned();
+
+        let funding = first_funding_txs(&store, &script_hash, &header_at, load_from_db).unwrap();
+        let mut txids: Vec<Sha256dHash> = funding.iter().map(|t| t.txn.txid()).collect();
+        txids.sort();
+        let mut expected = vec![funding_a.txid(), funding_b.txid()];
+        expected.sort();
+        assert_eq!(txids, expected);
+        assert!(funding.iter().all(|t| t.height == 1));
+
+        // a never funded script
+        let unused = compute_script_hash(&[0x53]);
+        assert!(first_funding_txs(&store, &unused, &header_at, load_from_db)
+            .unwrap()
+            .is_empty());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_history_size() {
+        let dir = temp_path("max-history");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        // a script funded by 3 transactions (one of them funding it twice)
+        let coinbase = tagged_tx(Sha256dHash::default(), u32::max_value(), 0);
+        let script_hash = compute_script_hash(&coinbase.output[0].script_pubkey[..]);
+        let first = tagged_tx(coinbase.txid(), 0, 1);
+        let mut second = tagged_tx(first.txid(), 0, 2);
+        second.output.push(second.output[0].clone());
+        let block = make_block(Sha256dHash::default(), 0, vec![coinbase, first, second]);
+        store.write(index_block(&block, 0, true, false, Network::Regtest));
+
+        for max_history in vec![0, 3, 100] {
+            assert!(check_history_size(&store, &script_hash, max_history).is_ok());
+        }
+        match check_history_size(&store, &script_hash, 2) {
+            Err(Error(ErrorKind::HistoryTooLarge(2), _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let unused = compute_script_hash(&[0x53]);
+        assert!(check_history_size(&store, &unused, 1).is_ok());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_addresses() {
+        let dir = temp_path("search");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let addresses: Vec<String> = (0..20)
+            .map(|i| format!("Vabc{:02}", i))
+            .chain(vec!["via1qabc".to_owned(), "via1qabd".to_owned()])
+            .collect();
+        store.write(
+            addresses
+                .iter()
+                .map(|address| AddressRow::new(address.clone()).to_row())
+                .collect(),
+        );
+
+        let search =
+            |prefix, limit, scan_limit| search_addresses(&store, prefix, limit, scan_limit);
+        assert_eq!(
+            search("Vabc", 3, 20).unwrap(),
+            vec!["Vabc00", "Vabc01", "Vabc02"]
+        );
+        assert_eq!(search("Vabc1", 100, 20).unwrap().len(), 10);
+        assert!(search("Vxyz", 10, 20).unwrap().is_empty());
+        // more matching addresses than the scan limit
+        assert!(search("Vabc", 3, 19).is_none());
+        assert!(search("Vabc1", 3, 10).is_some());
+        // bech32 addresses match case-insensitively
+        assert_eq!(
+            search("VIA1QAB", 10, 10).unwrap(),
+            vec!["via1qabc", "via1qabd"]
+        );
+        assert!(search("VIA1QAB", 10, 1).is_none());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_opreturn() {
+        let dir = temp_path("opreturn");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let with_output = |tag, script: Vec<u8>| {
+            let mut txn = tagged_tx(Sha256dHash::default(), 0, tag);
+            txn.output.push(TxOut {
+                value: 0,
+                script_pubkey: Script::from(script),
+            });
+            txn
+        };
+        let txs = vec![
+            with_output(1, [&[0x6a, 0x03][..], b"hel", &[0x02], b"lo"].concat()), // split pushes
+            with_output(2, [&[0x6a, 0x05][..], b"hello"].concat()),
+            with_output(3, [&[0x6a, 0x05][..], b"world"].concat()),
+            with_output(4, vec![0x6a]), // no payload
+        ];
+        let block = make_block(Sha256dHash::default(), 0, txs.clone());
+        store.write(index_block(&block, 0, true, true, Network::Regtest));
+        let mut headers = HeaderList::empty();
+        apply(&mut headers, &block);
+
+        let hello = compute_script_hash(b"hello");
+        let in_best_chain = |hash: &Sha256dHash| headers.header_by_hash(hash).is_some();
+        let search =
+            |prefix: &[u8]| search_opreturn(&store, prefix, 10, 10, &in_best_chain).unwrap();
+        let mut expected = vec![txs[0].txid(), txs[1].txid()];
+        expected.sort_by_key(|txid| txid[..].to_vec());
+        assert_eq!(search(&hello[..2]), expected);
+        assert_eq!(search(&hello[..]), expected);
+        assert_eq!(
+            search(&compute_script_hash(b"world")[..]),
+            vec![txs[2].txid()]
+        );
+        assert!(search(&compute_script_hash(b"")[..]).is_empty());
+        assert_eq!(
+            search_opreturn(&store, &hello, 1, 10, &in_best_chain)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(search_opreturn(&store, &[], 10, 2, &in_best_chain).is_none()); // too broad
+
+        // a reorg replacing the block by one confirming only the second transaction (the orphaned
+        // block's rows being kept, as for a pruned block)
+        let block_b = make_block(Sha256dHash::default(), 1, vec![txs[1].clone()]);
+        store.write(index_block(&block_b, 0, true, true, Network::Regtest));
+        let mut reorged = HeaderList::empty();
+        apply(&mut reorged, &block_b);
+        let in_best_chain = |hash: &Sha256dHash| reorged.header_by_hash(hash).is_some();
+        assert_eq!(
+            search_opreturn(&store, &hello, 10, 10, &in_best_chain).unwrap(),
+            vec![txs[1].txid()]
+        );
+        assert!(search_opreturn(
+            &store,
+            &compute_script_hash(b"world"),
+            10,
+            10,
+            &in_best_chain
+        )
+        .unwrap()
+        .is_empty());
+
+        // the orphaned transactions aren't found anymore, once their rows are deleted
+        store.delete(orphaned_keys(&block, true));
+        assert!(search(&[]).is_empty());
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unspent_with_mempool() {
+        let txid = |n: u8| Sha256dHash::from_data(&[n]);
+        let funding = |txid, output_index, height, value| FundingOutput {
+            txn: None,
+            txn_id: txid,
+            height,
+            output_index,
+            value,
+        };
+        let spending = |txid, height, funding_output| SpendingInput {
+            txn: None,
+            txn_id: txid,
+            height,
+            input_index: 0,
+            funding_output,
+            value: 0,
+        };
+        let status = Status {
+            confirmed: (
+                vec![
+                    funding(txid(3), 1, 12, 3000),
+                    funding(txid(1), 0, 10, 1000),
+                    funding(txid(2), 0, 10, 2000),
+                    funding(txid(4), 0, 11, 4000),
+                ],
+                vec![spending(txid(4), 11, (txid(2), 0))],
+            ),
+            mempool: (
+                vec![
+                    // received unconfirmed
+                    funding(txid(5), 0, MEMPOOL_HEIGHT, 500),
+                    // confirmed, but not yet dropped from the mempool
+                    funding(txid(4), 0, MEMPOOL_HEIGHT, 4000),
+                ],
+                // spent unconfirmed
+                vec![spending(txid(6), MEMPOOL_HEIGHT, (txid(3), 1))],
+            ),
+        };
+        let unspent: Vec<(Sha256dHash, usize, u32, u64)> = status
+            .unspent()
+            .into_iter()
+            .map(|out| (out.txn_id, out.output_index, out.height, out.value))
+            .collect();
+        assert_eq!(
+            unspent,
+            vec![
+                (txid(1), 0, 10, 1000),
+                (txid(4), 0, 11, 4000),
+                (txid(5), 0, MEMPOOL_HEIGHT, 500),
+            ]
+        );
+        let unspent: Vec<Sha256dHash> = status
+            .confirmed_only()
+            .unspent()
+            .into_iter()
+            .map(|out| out.txn_id)
+            .collect();
+        assert_eq!(unspent, vec![txid(1), txid(4), txid(3)]);
+    }
+
+    #[test]
+    fn test_lookup_txos() {
+        // a transaction spending an output of 1000 distinct (fake) previous transactions
+        let prevtxs: Vec<Transaction> = (0..1000u32)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i, // distinct txids
+                input: vec![],
+                output: vec![
+                    TxOut {
+                        value: i as u64,
+                        script_pubkey: Script::new(),
+                    },
+                    TxOut {
+                        value: 1_000_000 + i as u64,
+                        script_pubkey: Script::new(),
+                    },
+                ],
+            })
+            .collect();
+        let by_txid: HashMap<Sha256dHash, &Transaction> =
+            prevtxs.iter().map(|tx| (tx.txid(), tx)).collect();
+        let outpoints: Vec<(Sha256dHash, usize)> =
+            prevtxs.iter().map(|tx| (tx.txid(), 1)).collect();
+        let loaded = Cell::new(0);
+        let load_txns = |txids: &[Sha256dHash]| -> Vec<Result<Transaction>> {
+            loaded.set(loaded.get() + txids.len());
+            txids.iter().map(|txid| Ok(by_txid[txid].clone())).collect()
+        };
+
+        // read from their own rows: no whole transaction is loaded
+        let read_txo = |outpoint: &(Sha256dHash, usize)| {
+            by_txid
+                .get(&outpoint.0)
+                .map(|tx| tx.output[outpoint.1].clone())
+        };
+        let txos = lookup_txos(&outpoints, 10, read_txo, &load_txns);
+        for (i, txo) in txos.iter().enumerate() {
+            assert_eq!(txo.as_ref().unwrap().value, 1_000_000 + i as u64);
+        }
+        assert_eq!(loaded.get(), 0);
+
+        // otherwise, only the budgeted transactions are loaded (the first ones by txid)
+        let txos = lookup_txos(&outpoints, 100, |_| None, &load_txns);
+        assert_eq!(loaded.get(), 100);
+        let mut txids: Vec<Sha256dHash> = by_txid.keys().cloned().collect();
+        txids.sort();
+        for (outpoint, txo) in outpoints.iter().zip(txos) {
+            let budgeted = txids[..100].contains(&outpoint.0);
+            assert_eq!(txo.is_some(), budgeted);
+        }
+
+        // each transaction is loaded once, missing outputs being left unknown
+        loaded.set(0);
+        let (txid0, txid1) = (prevtxs[0].txid(), prevtxs[1].txid());
+        let txos = lookup_txos(
+            &[(txid0, 0), (txid1, 2), (txid0, 1)],
+            2,
+            |_| None,
+            &load_txns,
+        );
+        assert_eq!(loaded.get(), 2);
+        assert_eq!(txos[0].as_ref().unwrap().value, 0);
+        assert!(txos[1].is_none());
+        assert_eq!(txos[2].as_ref().unwrap().value, 1_000_000);
+    }
+
+    #[test]
+    fn test_fetch_block_with_retries() {
+        fn connection() -> ErrorKind {
+            ErrorKind::Connection("refused".to_owned())
+        }
+        fn writing() -> ErrorKind {
+            ErrorKind::Msg("getblock RPC error: Can't read block from disk".to_owned())
+        }
+        fn not_found() -> ErrorKind {
+            ErrorKind::Msg("getblock RPC error: Block not found".to_owned())
+        }
+        let blockhash = Sha256dHash::default();
+        let fetch = |failures: Vec<fn() -> ErrorKind>| {
+            let attempts = Cell::new(0);
+            let result = fetch_block_with_retries(&blockhash, 2, Duration::from_millis(0), || {
+                attempts.set(attempts.get() + 1);
+                match failures.get(attempts.get() - 1) {
+                    Some(failure) => Err(Error::from(failure())),
+                    None => Ok(()),
+                }
+            });
+            (result.map_err(|e| e.to_string()), attempts.get())
+        };
+
+        assert_eq!(fetch(vec![]), (Ok(()), 1));
+        assert_eq!(fetch(vec![connection, writing]), (Ok(()), 3));
+        let (result, attempts) = fetch(vec![connection, connection, writing]);
+        assert!(result
+            .unwrap_err()
+            .starts_with("Block temporarily unavailable"));
+        assert_eq!(attempts, 3);
+
+        // unknown blocks are never retried
+        let (result, attempts) = fetch(vec![not_found]);
+        assert!(result.unwrap_err().contains("Block not found"));
+        assert_eq!(attempts, 1);
+    }
+}
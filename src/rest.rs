@@ -1,35 +1,151 @@
+use address::{address_to_script, script_to_address};
 use bitcoin::consensus::encode::{self, serialize};
 use bitcoin::network::constants::Network;
-use bitcoin::util::address::Address;
 use bitcoin::util::hash::{HexError, Sha256dHash};
 use bitcoin::{BitcoinHash, Script};
 use bitcoin::{Transaction, TxIn, TxOut};
-use config::Config;
+use config::{AdminToken, Config, HttpAuth, HTTP_AUTH_CHALLENGE};
 use errors;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Sink, Stream};
 use hex::{self, FromHexError};
+use hyper::header::{self, HeaderMap, HeaderValue};
 use hyper::rt::{self, Future};
-use hyper::service::service_fn_ok;
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Chunk, Method, Request, Response, Server, StatusCode, Uri};
 use index::compute_script_hash;
-use mempool::MEMPOOL_HEIGHT;
-use query::{FundingOutput, Query, SpendingInput, TxnHeight};
+use log::Level;
+use logger::ACCESS_LOG_TARGET;
+use mempool::{FeeRank, MempoolSummary, PackageStats, TxGraph, MEMPOOL_HEIGHT};
+use metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use query::{FundingOutput, History, OutputSpend, Query, ScriptStats, Status, TxnHeight};
 use serde::Serialize;
-use serde_json;
-use std::collections::BTreeMap;
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::num::ParseIntError;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio_uds::{UnixListener, UnixStream};
+use url::form_urlencoded;
 use util::{
-    full_hash, get_script_asm, script_to_address, BlockHeaderMeta, FullHash, TransactionStatus,
+    full_hash, get_script_asm, script_type, spawn_thread, BlockHeaderMeta, FullHash, HeaderEntry,
+    TransactionStatus, HASH_LEN,
 };
 
 const TX_LIMIT: usize = 25;
 const BLOCK_LIMIT: usize = 10;
+const SUMMARY_BLOCKS_MAX: usize = 25; // max # of blocks in /summary
+const RECENT_TXS_BLOCKS_MAX: usize = 1000; // max # of blocks in /txs/recent
+const MEMPOOL_GRAPH_LIMIT: usize = 1000; // max # of parents/children listed per mempool tx
+const ADDRESS_SEARCH_MIN_LEN: usize = 5; // shorter prefixes return no addresses
+const OPRETURN_SEARCH_MIN_LEN: usize = 2; // in bytes of the payload's hash
+const STREAM_BUFFER: usize = 16; // lines buffered before a streaming response waits for the client
+const TX_WAIT_TIMEOUT: u64 = 30; // default time (in seconds) a tx status long-poll is held
+const TX_WAIT_MAX_TIMEOUT: u64 = 60; // seconds
+const TX_WAIT_MAX_WAITERS: usize = 1000; // concurrent long-polls
+const TX_WAIT_CHECK_INTERVAL: u64 = 1; // seconds between checks for expired or disconnected waiters
+const TIP_SUBSCRIBERS_MAX: usize = 1000; // concurrent /blocks/tip/subscribe and /events streams
+const TIP_WAITERS_MAX: usize = 1000; // concurrent /blocks/tip/hash?wait=<secs> long-polls
+const EVENTS_MEMPOOL_MIN_INTERVAL: u64 = 1; // seconds between the /events mempool summaries
 
 const TTL_LONG: u32 = 157784630; // ttl for static resources (5 years)
 const TTL_SHORT: u32 = 10; // ttl for volatie resources
+const CONFIRMATIONS_MARGIN: usize = 5; // added to the deepest reorg's depth, for 6 without reorgs
 const CONF_FINAL: usize = 10; // reorgs deeper than this are considered unlikely
+const RPC_MAX_BODY: usize = 1_000_000; // bytes of a POST /rpc request
+const RPC_MAX_ADDRESSES: usize = 100; // per /rpc call
+
+// viacoind's JSON-RPC error codes
+const RPC_INVALID_REQUEST: i32 = -32600;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_INVALID_PARAMS: i32 = -32602;
+const RPC_INTERNAL_ERROR: i32 = -32603;
+const RPC_PARSE_ERROR: i32 = -32700;
+const RPC_MISC_ERROR: i32 = -1;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+const RPC_INVALID_PARAMETER: i32 = -8;
+const RPC_IN_WARMUP: i32 = -28;
+const CORS_MAX_AGE: u32 = 86400; // seconds a preflight response may be cached by browsers
+const RATE_LIMIT_CHEAP_FACTOR: f64 = 10.; // cheap requests allowed per expensive one
+const RATE_LIMIT_MAX_CLIENTS: usize = 10_000; // tracked buckets before dropping the idle ones
+
+// Routes whose responses depend on the tip or the mempool, which are never cached
+// (the other ones are cached according to the TTL set by their handler).
+const NO_STORE_ROUTES: &[&str] = &[
+    "/blocks/tip/hash",
+    "/blocks/tip/height",
+    "/blocks/tip/locator",
+    "/blocks/tip/subscribe",
+    "/blocks/:start_height",
+    "/events",
+    "/blocks/at-time/:timestamp", // the latest block before it may be the tip
+    "/block-height/:height/time", // estimated from the tip for the future heights
+    "/confirmations/recommended",
+    "/fee-estimates",
+    "/summary",
+    "/status",
+    "/address/:address",
+    "/address/:address/txs",
+    "/address/:address/txs/stream",
+    "/address/:address/txs/recent",
+    "/address/:address/utxo",
+    "/address/:address/first-funding",
+    "/address/:address/balance",
+    "/scripthash/:hash",
+    "/scripthash/:hash/txs",
+    "/script/:hex/txs",
+    "/scripthash/:hash/txs/stream",
+    "/scripthash/:hash/txs/recent",
+    "/scripthash/:hash/utxo",
+    "/scripthash/:hash/first-funding",
+    "/scripthash/:hash/balance",
+    "/tx/:txid/status",
+    "/tx/:txid/wait",
+    "/tx/:txid/replacements",
+    "/tx/:txid/outspend/:vout",
+    "/tx/:txid/outspends",
+    "/tx/:txid/cpfp",
+    "/mempool",
+    "/mempool/min-fee",
+    "/mempool/tx/:txid",
+    "/admin/status",
+    "/admin/db",
+    "/internal/status",
+    "/rpc",
+];
+
+// Routes reading (or scanning) many rows, which are rate-limited separately from the others.
+const EXPENSIVE_ROUTES: &[&str] = &[
+    "/address/:address",
+    "/address/:address/txs",
+    "/address/:address/txs/stream",
+    "/address/:address/txs/recent",
+    "/address/:address/utxo",
+    "/address/:address/balance",
+    "/scripthash/:hash",
+    "/scripthash/:hash/txs",
+    "/script/:hex/txs",
+    "/scripthash/:hash/txs/stream",
+    "/scripthash/:hash/txs/recent",
+    "/scripthash/:hash/utxo",
+    "/scripthash/:hash/balance",
+    "/tx/:txid/merkle-proof",
+    "/block/:hash/txs",
+    "/block/:hash/txs/stream",
+    "/address-prefix/:prefix",
+    "/opreturn/:hexprefix",
+    "/rpc",
+];
 
 #[derive(Serialize, Deserialize)]
 struct BlockValue {
@@ -79,7 +195,15 @@ struct TransactionValue {
     size: u32,
     weight: u32,
     fee: Option<u64>,
+    // only for unconfirmed transactions (in sat/vbyte)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feerate: Option<f32>,
     status: Option<TransactionStatus>,
+    // only for unconfirmed transactions served by /tx/:txid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee_rank: Option<FeeRank>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpfp: Option<CpfpValue>,
 }
 
 impl From<Transaction> for TransactionValue {
@@ -105,7 +229,10 @@ impl From<Transaction> for TransactionValue {
             size: bytes.len() as u32,
             weight: tx.get_weight() as u32,
             fee: None, // added later
+            feerate: None,
             status: None,
+            fee_rank: None,
+            cpfp: None,
         }
     }
 }
@@ -131,6 +258,24 @@ impl From<TxnHeight> for TransactionValue {
     }
 }
 
+// The counts of /tx/:txid's vin and vout, without serializing them (for lists of transactions).
+#[derive(Serialize, Debug, PartialEq)]
+struct TxSummaryValue {
+    vin_count: usize,
+    vout_count: usize,
+    total_out_value: u64,
+}
+
+impl<'a> From<&'a Transaction> for TxSummaryValue {
+    fn from(txn: &Transaction) -> Self {
+        TxSummaryValue {
+            vin_count: txn.input.len(),
+            vout_count: txn.output.len(),
+            total_out_value: txn.output.iter().map(|txout| txout.value).sum(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct TxInValue {
     txid: Sha256dHash,
@@ -180,36 +325,150 @@ impl From<TxOut> for TxOutValue {
         let script = txout.script_pubkey;
         let script_asm = get_script_asm(&script);
 
-        // TODO should the following something to put inside rust-elements lib?
-        let mut script_type = "";
-        if script.is_empty() {
-            script_type = "empty";
-        } else if script.is_op_return() {
-            script_type = "op_return";
-        } else if script.is_p2pk() {
-            script_type = "p2pk";
-        } else if script.is_p2pkh() {
-            script_type = "p2pkh";
-        } else if script.is_p2sh() {
-            script_type = "p2sh";
-        } else if script.is_v0_p2wpkh() {
-            script_type = "v0_p2wpkh";
-        } else if script.is_v0_p2wsh() {
-            script_type = "v0_p2wsh";
-        } else if script.is_provably_unspendable() {
-            script_type = "provably_unspendable";
-        }
-
         TxOutValue {
+            scriptpubkey_type: script_type(&script).to_string(),
             scriptpubkey: script,
             scriptpubkey_asm: script_asm,
             scriptpubkey_address: None, // added later
-            scriptpubkey_type: script_type.to_string(),
             value,
         }
     }
 }
 
+impl TxOutValue {
+    // with the address encoded for the network (from the segwit program for the v0 outputs)
+    fn new(txout: TxOut, network: &Network) -> Self {
+        let mut value = TxOutValue::from(txout);
+        value.scriptpubkey_address = script_to_address(&value.scriptpubkey, network);
+        value
+    }
+}
+
+#[derive(Serialize)]
+struct ValidateAddressValue {
+    isvalid: bool,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    address_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scripthash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scriptpubkey: Option<Script>,
+}
+
+impl ValidateAddressValue {
+    // addresses of other networks are reported as invalid, rather than failing the request
+    fn new(addr: &str, network: &Network) -> Self {
+        match address_to_script(addr, network) {
+            Ok(script) => ValidateAddressValue {
+                isvalid: true,
+                address_type: Some(script_type(&script)),
+                scripthash: Some(hex::encode(compute_script_hash(&script[..]))),
+                scriptpubkey: Some(script),
+            },
+            Err(_) => ValidateAddressValue {
+                isvalid: false,
+                address_type: None,
+                scripthash: None,
+                scriptpubkey: None,
+            },
+        }
+    }
+}
+
+/// The totals of an unconfirmed transaction's ancestors or descendants (including itself).
+#[derive(Serialize)]
+struct PackageValue {
+    count: usize,
+    fee: u64,
+    vsize: u64,
+    feerate: f32,
+}
+
+impl From<PackageStats> for PackageValue {
+    fn from(stats: PackageStats) -> Self {
+        PackageValue {
+            count: stats.count,
+            fee: stats.fee,
+            vsize: stats.vsize,
+            feerate: stats.fee_rate(),
+        }
+    }
+}
+
+/// An unconfirmed transaction's dependencies, with the fee rate its package is likely mined at.
+#[derive(Serialize)]
+struct CpfpValue {
+    effective_feerate: f32,
+    ancestors: PackageValue,
+    descendants: PackageValue,
+}
+
+impl<'a> From<&'a TxGraph> for CpfpValue {
+    fn from(graph: &TxGraph) -> Self {
+        CpfpValue {
+            effective_feerate: graph.effective_fee_rate,
+            ancestors: PackageValue::from(graph.ancestors),
+            descendants: PackageValue::from(graph.descendants),
+        }
+    }
+}
+
+/// A resource matching a `/search/:query`, with the endpoint serving it.
+#[derive(Serialize, Debug, PartialEq)]
+struct SearchMatch {
+    #[serde(rename = "type")]
+    match_type: &'static str, // "block", "tx" or "address"
+    id: String,
+    path: String,
+}
+
+impl SearchMatch {
+    fn new(match_type: &'static str, id: String) -> Self {
+        let path = format!("/{}/{}", match_type, id);
+        SearchMatch {
+            match_type,
+            id,
+            path,
+        }
+    }
+}
+
+// The resources matching an explorer search (e.g. its search box): a block height, a block hash
+// or a txid (both being checked, as they can't be told apart), or an address of the network.
+fn search_matches<H, B, T>(
+    q: &str,
+    network: &Network,
+    hash_at: H,
+    has_block: B,
+    has_tx: T,
+) -> Result<Vec<SearchMatch>, HttpError>
+where
+    H: Fn(usize) -> Option<Sha256dHash>,
+    B: Fn(&Sha256dHash) -> bool,
+    T: Fn(&Sha256dHash) -> Result<bool, HttpError>,
+{
+    let mut matches = vec![];
+    if let Ok(height) = q.parse::<usize>() {
+        if let Some(hash) = hash_at(height) {
+            matches.push(SearchMatch::new("block", hash.be_hex_string()));
+        }
+    }
+    if q.len() == 64 {
+        if let Ok(hash) = Sha256dHash::from_hex(q) {
+            if has_block(&hash) {
+                matches.push(SearchMatch::new("block", hash.be_hex_string()));
+            }
+            if has_tx(&hash)? {
+                matches.push(SearchMatch::new("tx", hash.be_hex_string()));
+            }
+        }
+    }
+    if address_to_script(q, network).is_ok() {
+        matches.push(SearchMatch::new("address", q.to_string()));
+    }
+    Ok(matches)
+}
+
 #[derive(Serialize)]
 struct UtxoValue {
     txid: Sha256dHash,
@@ -247,38 +506,58 @@ impl From<FundingOutput> for UtxoValue {
     }
 }
 
+// GET /tx/:txid/status, with the block fields null (and 0 confirmations) for an unconfirmed tx
+#[derive(Serialize)]
+struct TxStatusValue {
+    confirmed: bool,
+    block_height: Option<usize>,
+    block_hash: Option<Sha256dHash>,
+    block_time: Option<u32>,
+    confirmations: usize,
+}
+impl From<Option<(HeaderEntry, usize)>> for TxStatusValue {
+    fn from(confirmed: Option<(HeaderEntry, usize)>) -> Self {
+        match confirmed {
+            Some((header, confirmations)) => TxStatusValue {
+                confirmed: true,
+                block_height: Some(header.height()),
+                block_hash: Some(header.hash().clone()),
+                block_time: Some(header.header().time),
+                confirmations,
+            },
+            None => TxStatusValue {
+                confirmed: false,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+                confirmations: 0,
+            },
+        }
+    }
+}
+
+// unspent outputs are serialized as `{"spent": false}`
 #[derive(Serialize)]
 struct SpendingValue {
     spent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     txid: Option<Sha256dHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     vin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
+    // the mempool spends of this output that were replaced by a conflicting one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    replaced: Vec<Sha256dHash>,
 }
-impl From<SpendingInput> for SpendingValue {
-    fn from(spend: SpendingInput) -> Self {
-        let SpendingInput {
-            txn,
-            txn_id,
-            input_index,
-            ..
-        } = spend;
-        let TxnHeight {
-            height, blockhash, ..
-        } = txn.unwrap(); // we should never get a SpendingInput without a txn here
-
+impl From<OutputSpend> for SpendingValue {
+    fn from(spend: OutputSpend) -> Self {
         SpendingValue {
             spent: true,
-            txid: Some(txn_id),
-            vin: Some(input_index as u32),
-            status: Some(if height != MEMPOOL_HEIGHT {
-                TransactionStatus {
-                    confirmed: true,
-                    block_height: Some(height as usize),
-                    block_hash: Some(blockhash),
-                }
-            } else {
-                TransactionStatus::unconfirmed()
-            }),
+            txid: Some(spend.txid),
+            vin: Some(spend.vin),
+            status: Some(spend.status),
+            replaced: vec![],
         }
     }
 }
@@ -289,6 +568,7 @@ impl Default for SpendingValue {
             txid: None,
             vin: None,
             status: None,
+            replaced: vec![],
         }
     }
 }
@@ -332,20 +612,25 @@ fn attach_txs_data(txs: &mut Vec<TransactionValue>, config: &Config, query: &Arc
             // easily access the network)
             for mut vout in tx.vout.iter_mut() {
                 vout.scriptpubkey_address =
-                    script_to_address(&vout.scriptpubkey, &config.network_type);
+                    script_to_address(&vout.scriptpubkey, &config.address_network);
             }
         }
 
-        // fetch prevtxs and attach prevouts to nextins
+        // lookup prevouts (within the budget) and attach them to nextins
         if config.prevout_enabled {
-            for (prev_txid, prev_vouts) in lookups {
-                let prevtx = query.load_txn(&prev_txid, None).unwrap();
-                for (prev_out_idx, ref mut nextin) in prev_vouts {
-                    let mut prevout =
-                        TxOutValue::from(prevtx.output[prev_out_idx as usize].clone());
-                    prevout.scriptpubkey_address =
-                        script_to_address(&prevout.scriptpubkey, &config.network_type);
-                    nextin.prevout = Some(prevout);
+            let outpoints: Vec<(Sha256dHash, usize)> = lookups
+                .iter()
+                .flat_map(|(txid, vouts)| {
+                    vouts.iter().map(move |&(vout, _)| (*txid, vout as usize))
+                })
+                .collect();
+            let prevouts = query.lookup_txos(&outpoints, config.prevout_budget);
+            let nextins = lookups
+                .into_iter()
+                .flat_map(|(_, vouts)| vouts.into_iter().map(|(_, nextin)| nextin));
+            for (nextin, prevout) in nextins.zip(prevouts) {
+                if let Some(txout) = prevout {
+                    nextin.prevout = Some(TxOutValue::new(txout, &config.address_network));
                 }
             }
         }
@@ -364,204 +649,1383 @@ fn attach_txs_data(txs: &mut Vec<TransactionValue>, config: &Config, query: &Arc
                 .map(|vin| vin.clone().prevout.unwrap().value)
                 .sum();
             let total_out: u64 = tx.vout.iter().map(|vout| vout.value).sum();
-            tx.fee = Some(total_in - total_out);
+            tx.fee = total_in.checked_sub(total_out);
+        }
+    }
+
+    // the mempool tracker knows the unconfirmed txs' fees, even without their prevouts
+    for tx in txs.iter_mut() {
+        if tx.status.as_ref().map_or(true, |status| status.confirmed) {
+            continue;
+        }
+        if let Some((fee, vsize)) = query.get_mempool_fee(&tx.txid) {
+            tx.fee = Some(fee);
+            tx.feerate = Some(fee as f32 / vsize as f32);
         }
     }
 }
 
-pub fn run_server(config: &Config, query: Arc<Query>) {
-    let addr = &config.http_addr;
-    info!("REST server running on {}", addr);
+pub struct Handle {
+    tx: oneshot::Sender<()>,
+    socket: Option<PathBuf>,
+    thread: thread::JoinHandle<()>,
+}
 
-    let config = Arc::new(config.clone());
+impl Handle {
+    /// Stops accepting new connections and waits for in-flight requests to complete.
+    pub fn stop(self) {
+        self.tx.send(()).expect("failed to send shutdown signal");
+        self.thread.join().expect("REST server failed");
+        if let Some(path) = self.socket {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
 
-    let new_service = move || {
-        let query = query.clone();
-        let config = config.clone();
+/// Binds a Unix domain socket at `path`, replacing the one left behind by an unclean shutdown.
+/// The socket is made accessible to the owner's group (e.g. a reverse proxy's), but not others.
+fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
 
-        service_fn_ok(
-            move |req: Request<Body>| match handle_request(req, &query, &config) {
-                Ok(response) => response,
-                Err(e) => {
-                    warn!("{:?}", e);
-                    Response::builder()
-                        .status(e.0)
-                        .header("Content-Type", "text/plain")
-                        .body(Body::from(e.1))
-                        .unwrap()
-                }
-            },
-        )
-    };
+struct Stats {
+    latency: HistogramVec,
+    requests: CounterVec,
+    connections: Gauge,
+}
+
+impl Stats {
+    fn new(metrics: &Metrics) -> Stats {
+        Stats {
+            latency: metrics.histogram_vec(
+                HistogramOpts::new("rest_latency", "REST request latency (in seconds)").buckets(
+                    vec![
+                        1e-4, 2e-4, 5e-4, 1e-3, 2e-3, 5e-3, 1e-2, 2e-2, 5e-2, 0.1, 0.2, 0.5, 1.,
+                        2., 5., 10., 20., 50., 100.,
+                    ],
+                ),
+                &["route"],
+            ),
+            requests: metrics.counter_vec(
+                MetricOpts::new("rest_requests", "# of REST requests (by response status)"),
+                &["route", "status"],
+            ),
+            connections: metrics.gauge(MetricOpts::new(
+                "rest_connections",
+                "# of open REST connections",
+            )),
+        }
+    }
+}
 
-    let server = Server::bind(&addr)
-        .serve(new_service)
-        .map_err(|e| eprintln!("server error: {}", e));
+// counts an open connection, until its service is dropped
+struct ConnectionGuard(Gauge);
 
-    thread::spawn(move || {
-        rt::run(server);
-    });
+impl ConnectionGuard {
+    fn new(connections: Gauge) -> ConnectionGuard {
+        connections.inc();
+        ConnectionGuard(connections)
+    }
 }
 
-fn handle_request(
-    req: Request<Body>,
-    query: &Arc<Query>,
-    config: &Config,
-) -> Result<Response<Body>, HttpError> {
-    // TODO it looks hyper does not have routing and query parsing :(
-    let uri = req.uri();
-    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
-    info!("path {:?}", path);
-    match (
-        req.method(),
-        path.get(0),
-        path.get(1),
-        path.get(2),
-        path.get(3),
-    ) {
-        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None) => http_message(
-            StatusCode::OK,
-            query.get_best_header_hash().be_hex_string(),
-            TTL_SHORT,
-        ),
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
 
-        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None) => http_message(
-            StatusCode::OK,
-            query.get_best_height().to_string(),
-            TTL_SHORT,
-        ),
+// Closes kept-alive connections once reused enough, so that clients reconnect (e.g. to another
+// server behind a load balancer) instead of holding onto a connection forever.
+struct ConnectionReuse {
+    served: AtomicUsize,
+    opened: Instant,
+    max_requests: usize,
+    max_age: Duration,
+}
 
-        (&Method::GET, Some(&"blocks"), start_height, None, None) => {
-            let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
-            blocks(&query, start_height)
-        }
-        (&Method::GET, Some(&"block-height"), Some(height), None, None) => {
-            let height = height.parse::<usize>()?;
-            let headers = query.get_headers(&[height]);
-            let header = headers
-                .get(0)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
-            let ttl = ttl_by_depth(Some(height), query);
-            http_message(StatusCode::OK, header.hash().be_hex_string(), ttl)
-        }
-        (&Method::GET, Some(&"block"), Some(hash), None, None) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let blockhm = query.get_block_header_with_meta(&hash)?;
-            let block_value = BlockValue::from(blockhm);
-            json_response(block_value, TTL_LONG)
-        }
-        (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let status = query.get_block_status(&hash);
-            let ttl = ttl_by_depth(status.height, query);
-            json_response(status, ttl)
+impl ConnectionReuse {
+    fn new(max_requests: usize, max_age: Duration) -> ConnectionReuse {
+        ConnectionReuse {
+            served: AtomicUsize::new(0),
+            opened: Instant::now(),
+            max_requests,
+            max_age,
         }
-        (&Method::GET, Some(&"block"), Some(hash), Some(&"txids"), None) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let txids = query
-                .get_block_txids(&hash)
-                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
-            json_response(txids, TTL_LONG)
+    }
+
+    // Called on each response, asking the client to close the connection once it's the last one.
+    fn limit(&self, response: &mut Response<Body>) {
+        let served = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+        if served >= self.max_requests || self.opened.elapsed() >= self.max_age {
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
         }
-        (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), start_index) => {
-            let hash = Sha256dHash::from_hex(hash)?;
-            let txids = query
-                .get_block_txids(&hash)
-                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+    }
+}
 
-            let start_index = start_index
-                .map_or(0u32, |el| el.parse().unwrap_or(0))
-                .max(0u32) as usize;
-            if start_index >= txids.len() {
-                bail!(HttpError::not_found("start index out of range".to_string()));
-            } else if start_index % TX_LIMIT != 0 {
-                bail!(HttpError::from(format!(
-                    "start index must be a multipication of {}",
-                    TX_LIMIT
-                )));
-            }
+// The X-Forwarded-For entries, joining the repeated headers (in order) into a single list.
+fn forwarded_for(headers: &HeaderMap) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(","))
+    }
+}
 
-            let mut txs = txids
-                .iter()
-                .skip(start_index)
-                .take(TX_LIMIT)
-                .map(|txid| {
-                    query
-                        .load_txn(&txid, Some(&hash))
-                        .map(TransactionValue::from)
-                })
-                .collect::<Result<Vec<TransactionValue>, _>>()?;
-            attach_txs_data(&mut txs, config, query);
-            json_response(txs, TTL_LONG)
+// The address of the client, as seen by the closest proxy we don't trust: each trusted proxy
+// appends the address it was connected from to X-Forwarded-For, so it's read from the right,
+// while the connected peer is trusted. Entries before that were set by the client itself.
+fn client_addr(peer: SocketAddr, forwarded: Option<&str>, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer.ip();
+    if let Some(forwarded) = forwarded {
+        for entry in forwarded.rsplit(',') {
+            if !trusted.contains(&client) {
+                break;
+            }
+            let entry = entry.trim();
+            // may also be an 'addr:port' (or '[addr]:port') pair
+            match entry
+                .parse::<IpAddr>()
+                .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+            {
+                Ok(addr) => client = addr,
+                Err(_) => break, // e.g. "unknown", keeping the last proxy
+            }
         }
-        (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None)
-        | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None) => {
-            // @TODO create new AddressStatsValue struct?
-            let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            match query.status(&script_hash[..]) {
-                Ok(status) => json_response(
-                    json!({
-                        *script_type: script_str,
-                        "tx_count": status.history().len(),
-                        "confirmed_balance": status.confirmed_balance(),
-                        "mempool_balance": status.mempool_balance(),
-                        "total_received": status.total_received(),
-                    }),
-                    TTL_SHORT,
-                ),
+    }
+    client
+}
 
-                // if the address has too many txs, just return the address with no additional info (but no error)
-                Err(errors::Error(errors::ErrorKind::Msg(ref msg), _))
-                    if *msg == "Too many txs".to_string() =>
-                {
-                    json_response(json!({ *script_type: script_str }), TTL_SHORT)
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Cost {
+    Cheap,
+    Expensive,
+}
 
-                Err(err) => bail!(err),
-            }
+impl Cost {
+    fn of(route: &str) -> Cost {
+        if EXPENSIVE_ROUTES.contains(&route) {
+            Cost::Expensive
+        } else {
+            Cost::Cheap
         }
-        (
-            &Method::GET,
-            Some(script_type @ &"address"),
-            Some(script_str),
-            Some(&"txs"),
-            start_index,
-        )
-        | (
-            &Method::GET,
-            Some(script_type @ &"scripthash"),
-            Some(script_str),
-            Some(&"txs"),
-            start_index,
-        ) => {
-            let start_index = start_index
-                .map_or(0u32, |el| el.parse().unwrap_or(0))
-                .max(0u32) as usize;
+    }
 
-            let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let status = query.status(&script_hash[..])?;
-            let txs = status.history_txs();
+    fn label(self) -> &'static str {
+        match self {
+            Cost::Cheap => "cheap",
+            Cost::Expensive => "expensive",
+        }
+    }
+}
 
-            if txs.len() == 0 {
-                return json_response(json!([]), TTL_SHORT);
-            } else if start_index >= txs.len() {
-                bail!(HttpError::not_found("start index out of range".to_string()));
-            } else if start_index % TX_LIMIT != 0 {
-                bail!(HttpError::from(format!(
-                    "start index must be a multipication of {}",
-                    TX_LIMIT
-                )));
-            }
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
 
-            let mut txs = txs
-                .iter()
-                .skip(start_index)
-                .take(TX_LIMIT)
-                .map(|t| TransactionValue::from((*t).clone()))
-                .collect();
-            attach_txs_data(&mut txs, config, query);
+impl TokenBucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = if now > self.updated {
+            now - self.updated
+        } else {
+            Duration::from_secs(0)
+        };
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * rate).min(burst);
+        self.updated = now;
+    }
+}
 
-            json_response(txs, TTL_SHORT)
+// Token buckets per client IP (and request cost), refilled at `rate` requests per second up to
+// `burst` ones for the expensive requests (and `RATE_LIMIT_CHEAP_FACTOR` times more for the cheap
+// ones), so that a single client can't starve the others.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    limit_loopback: bool,
+    buckets: Mutex<HashMap<(IpAddr, Cost), TokenBucket>>,
+    throttled: CounterVec,
+}
+
+impl RateLimiter {
+    fn new(config: &Config, metrics: &Metrics) -> RateLimiter {
+        RateLimiter {
+            rate: f64::from(config.rate_limit),
+            burst: f64::from(config.rate_limit_burst),
+            limit_loopback: config.rate_limit_loopback,
+            buckets: Mutex::new(HashMap::new()),
+            throttled: metrics.counter_vec(
+                MetricOpts::new(
+                    "rest_throttled",
+                    "# of REST requests refused by the rate limiter (by cost)",
+                ),
+                &["cost"],
+            ),
+        }
+    }
+
+    // the refill rate and the size of the buckets
+    fn limits(&self, cost: Cost) -> (f64, f64) {
+        match cost {
+            Cost::Cheap => (
+                self.rate * RATE_LIMIT_CHEAP_FACTOR,
+                self.burst * RATE_LIMIT_CHEAP_FACTOR,
+            ),
+            Cost::Expensive => (self.rate, self.burst),
+        }
+    }
+
+    fn allow(&self, client: IpAddr, route: &str) -> bool {
+        self.allow_at(client, Cost::of(route), Instant::now())
+    }
+
+    fn allow_at(&self, client: IpAddr, cost: Cost, now: Instant) -> bool {
+        if self.rate == 0. || (client.is_loopback() && !self.limit_loopback) {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_MAX_CLIENTS {
+            // the full buckets are the same as new ones
+            buckets.retain(|&(_, cost), bucket| {
+                let (rate, burst) = self.limits(cost);
+                bucket.refill(rate, burst, now);
+                bucket.tokens < burst
+            });
+        }
+        let (rate, burst) = self.limits(cost);
+        let bucket = buckets.entry((client, cost)).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.refill(rate, burst, now);
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            self.throttled.with_label_values(&[cost.label()]).inc();
+            false
+        }
+    }
+}
+
+// Refuses the expensive requests while the DB is being fully compacted (with
+// --reject-during-compaction), logging when it starts and stops doing so.
+struct CompactionGate {
+    enabled: bool,
+    rejecting: AtomicBool,
+}
+
+impl CompactionGate {
+    fn new(enabled: bool) -> CompactionGate {
+        CompactionGate {
+            enabled,
+            rejecting: AtomicBool::new(false),
+        }
+    }
+
+    fn allow(&self, route: &str, compacting: bool) -> bool {
+        if !self.enabled || Cost::of(route) == Cost::Cheap {
+            return true;
+        }
+        if self.rejecting.swap(compacting, Ordering::Relaxed) != compacting {
+            if compacting {
+                warn!("DB compaction in progress, rejecting the expensive requests");
+            } else {
+                info!("DB compaction over, serving the expensive requests again");
+            }
+        }
+        !compacting
+    }
+}
+
+fn busy_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", "60")
+        .body(Body::from("Database compaction in progress, retry later"))
+        .unwrap()
+}
+
+fn throttled_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", "1")
+        .body(Body::from("Too many requests, retry later"))
+        .unwrap()
+}
+
+fn overloaded_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", "1")
+        .body(Body::from("Server overloaded, retry later"))
+        .unwrap()
+}
+
+// What to do with the requests beyond --rest-queue-depth, while all the workers are busy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    Queue,
+    Reject,
+}
+
+type Job = (Request<Body>, oneshot::Sender<Response<Body>>);
+
+// A fixed set of threads running the jobs sent to them, rather than a thread per job.
+struct WorkerPool<T> {
+    sender: Mutex<std::sync::mpsc::Sender<T>>,
+    queued: Arc<AtomicUsize>,
+    max_queued: Option<usize>, // None queues them all
+    queued_gauge: Gauge,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    fn start<F>(
+        name: &'static str,
+        workers: usize,
+        depth: usize,
+        backpressure: Backpressure,
+        handler: F,
+        queued_gauge: Gauge,
+        active: Gauge,
+    ) -> WorkerPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = std::sync::mpsc::channel::<T>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let queued = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            let queued = queued.clone();
+            let queued_gauge = queued_gauge.clone();
+            let active = active.clone();
+            spawn_thread(name, move || loop {
+                // stops once the server (and so the pool) is dropped
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                queued.fetch_sub(1, Ordering::SeqCst);
+                queued_gauge.dec();
+                active.inc();
+                // a panicking job doesn't take its worker down (the panic is logged by the hook)
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(job)));
+                active.dec();
+            });
+        }
+        WorkerPool {
+            sender: Mutex::new(sender),
+            queued,
+            max_queued: match backpressure {
+                Backpressure::Queue => None,
+                Backpressure::Reject => Some(depth),
+            },
+            queued_gauge,
+        }
+    }
+
+    // Gives the job back if it's rejected (with a full queue).
+    fn submit(&self, job: T) -> Result<(), T> {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.max_queued.map_or(false, |max| queued >= max) {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(job);
+        }
+        self.queued_gauge.inc();
+        self.sender
+            .lock()
+            .unwrap()
+            .send(job)
+            .expect("REST workers stopped");
+        Ok(())
+    }
+}
+
+// The requests are handled by a fixed set of --rest-workers threads, rather than on the server's
+// event loop, where a heavy one would hold up the other connections.
+struct RequestPool {
+    workers: WorkerPool<Job>,
+}
+
+impl RequestPool {
+    fn start<F>(
+        workers: usize,
+        depth: usize,
+        backpressure: Backpressure,
+        handler: F,
+        metrics: &Metrics,
+    ) -> RequestPool
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    {
+        let queued = metrics.gauge(MetricOpts::new(
+            "rest_queued",
+            "# of REST requests waiting for a worker thread",
+        ));
+        let active = metrics.gauge(MetricOpts::new(
+            "rest_workers_active",
+            "# of REST worker threads handling a request",
+        ));
+        let handle = move |(req, sender): Job| {
+            // a panicking handler fails its request
+            let response =
+                panic::catch_unwind(AssertUnwindSafe(|| handler(req))).unwrap_or_else(|_| {
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("Internal error"))
+                        .unwrap()
+                });
+            let _ = sender.send(response); // the client may be gone
+        };
+        RequestPool {
+            workers: WorkerPool::start(
+                "rest",
+                workers,
+                depth,
+                backpressure,
+                handle,
+                queued,
+                active,
+            ),
+        }
+    }
+
+    // The response to come, or None if the request is rejected (with a full queue).
+    fn submit(&self, req: Request<Body>) -> Option<oneshot::Receiver<Response<Body>>> {
+        let (sender, receiver) = oneshot::channel();
+        match self.workers.submit((req, sender)) {
+            Ok(()) => Some(receiver),
+            Err(_) => None,
+        }
+    }
+}
+
+// A streaming response's transactions, sent to the client as they're loaded.
+struct StreamJob {
+    txids: Vec<Sha256dHash>,
+    blockhash: Option<Sha256dHash>,
+    sender: mpsc::Sender<Result<Chunk, io::Error>>,
+}
+
+// The streaming responses are produced by their own set of --rest-workers threads, as a slow
+// client holds its producer for as long as it takes to consume the stream.
+struct StreamPool {
+    workers: WorkerPool<StreamJob>,
+}
+
+impl StreamPool {
+    fn start(query: Arc<Query>, config: Arc<Config>, metrics: &Metrics) -> StreamPool {
+        let queued = metrics.gauge(MetricOpts::new(
+            "rest_streams_queued",
+            "# of REST streaming responses waiting for a producer thread",
+        ));
+        let active = metrics.gauge(MetricOpts::new(
+            "rest_streams_active",
+            "# of REST streaming responses being produced",
+        ));
+        StreamPool {
+            workers: WorkerPool::start(
+                "stream",
+                config.rest_workers,
+                config.rest_queue_depth,
+                config.rest_backpressure,
+                move |job| produce_stream(job, &query, &config),
+                queued,
+                active,
+            ),
+        }
+    }
+
+    // Streams the transactions as newline-delimited JSON (see `produce_stream()`), or refuses to
+    // with a full queue.
+    fn stream(&self, txids: Vec<Sha256dHash>, blockhash: Option<Sha256dHash>) -> Response<Body> {
+        let (sender, receiver) = mpsc::channel::<Result<Chunk, io::Error>>(STREAM_BUFFER);
+        let job = StreamJob {
+            txids,
+            blockhash,
+            sender,
+        };
+        if self.workers.submit(job).is_err() {
+            return overloaded_response();
+        }
+        let body = receiver
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "stream failed"))
+            .and_then(|line| line);
+        Response::builder()
+            .header("Content-Type", "application/x-ndjson")
+            .header("Cache-Control", "no-cache")
+            .body(Body::wrap_stream(body))
+            .unwrap()
+    }
+}
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = oneshot::Canceled> + Send>;
+type RequestFuture = Box<Future<Item = Request<Body>, Error = oneshot::Canceled> + Send>;
+
+fn submit(pool: &RequestPool, req: Request<Body>) -> ResponseFuture {
+    match pool.submit(req) {
+        Some(response) => Box::new(response),
+        None => Box::new(future::ok(overloaded_response())),
+    }
+}
+
+// With --http-auth, every request needs the credentials (but the CORS preflights, sent without them).
+fn authenticated(req: &Request<Body>, auth: Option<&HttpAuth>) -> bool {
+    auth.map_or(true, |auth| {
+        auth.matches(
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok()),
+        )
+    })
+}
+
+fn unauthorized_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("Content-Type", "text/plain")
+        .header(header::WWW_AUTHENTICATE, HTTP_AUTH_CHALLENGE)
+        .body(Body::from("Authentication required"))
+        .unwrap()
+}
+
+pub fn run_server(config: &Config, query: Arc<Query>, metrics: &Metrics) -> Handle {
+    let addr = config.http_addr;
+    let keepalive = config.rest_keepalive > 0;
+    let socket = config.http_unix_socket.clone();
+    match socket {
+        Some(ref path) => info!("REST server running on unix:{}", path.display()),
+        None => info!("REST server running on {}", addr),
+    }
+
+    let config = Arc::new(config.clone());
+    let stats = Arc::new(Stats::new(metrics));
+    let limiter = Arc::new(RateLimiter::new(&config, metrics));
+    let gate = Arc::new(CompactionGate::new(config.reject_during_compaction));
+    let waiters = TxWaiters::start(query.clone(), metrics);
+    let tips = TipSubscribers::start(query.clone(), metrics);
+    let streams = StreamPool::start(query.clone(), config.clone(), metrics);
+    let pool = {
+        let query = query.clone();
+        let config = config.clone();
+        Arc::new(RequestPool::start(
+            config.rest_workers,
+            config.rest_queue_depth,
+            config.rest_backpressure,
+            move |req| {
+                let route = route_label(req.method(), req.uri().path());
+                match handle_request(req, &query, &config, &waiters, &tips, &streams) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let e = while_syncing(e, &query);
+                        log_fields!(
+                            Level::Warn,
+                            { route: route, status: e.0.as_u16() },
+                            "{:?}",
+                            e
+                        );
+                        Response::builder()
+                            .status(e.0)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(e.1))
+                            .unwrap()
+                    }
+                }
+            },
+            metrics,
+        ))
+    };
+
+    let make_service = move |peer: SocketAddr| {
+        let query = query.clone();
+        let config = config.clone();
+        let stats = stats.clone();
+        let limiter = limiter.clone();
+        let gate = gate.clone();
+        let pool = pool.clone();
+        let connection = ConnectionGuard::new(stats.connections.clone());
+        let reuse = Arc::new(ConnectionReuse::new(
+            config.rest_keepalive,
+            Duration::from_secs(config.rest_keepalive_timeout),
+        ));
+
+        service_fn(move |req: Request<Body>| {
+            let _connection = &connection;
+            let route = route_label(req.method(), req.uri().path());
+            let timer = stats.latency.with_label_values(&[route]).start_timer();
+            let started = Instant::now();
+            let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+            let client = client_addr(
+                peer,
+                forwarded_for(req.headers()).as_ref().map(|s| s.as_str()),
+                &config.trusted_proxies,
+            );
+            let cors = config.cors.clone();
+            let response: ResponseFuture = match cors.as_ref().map(|origin| origin.as_str()) {
+                Some(origin) if *req.method() == Method::OPTIONS => {
+                    Box::new(future::ok(preflight_response(origin)))
+                }
+                _ if !authenticated(&req, config.http_auth.as_ref()) => {
+                    Box::new(future::ok(unauthorized_response()))
+                }
+                _ if !limiter.allow(client, route) => Box::new(future::ok(throttled_response())),
+                _ if !gate.allow(route, query.is_compacting()) => {
+                    Box::new(future::ok(busy_response()))
+                }
+                _ if is_rpc_request(&req, &config) => {
+                    let pool = pool.clone();
+                    Box::new(read_rpc_body(req).and_then(move |req| submit(&pool, req)))
+                }
+                _ => submit(&pool, req),
+            };
+            let stats = stats.clone();
+            let reuse = reuse.clone();
+            response.map(move |mut response| {
+                set_response_headers(
+                    &mut response,
+                    route,
+                    cors.as_ref().map(|origin| origin.as_str()),
+                );
+                reuse.limit(&mut response);
+                timer.observe_duration();
+                let status = response.status().as_u16().to_string();
+                stats.requests.with_label_values(&[route, &status]).inc();
+                let elapsed = started.elapsed();
+                let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+                log_fields!(
+                    target: ACCESS_LOG_TARGET,
+                    Level::Info,
+                    {
+                        client: client.to_string(),
+                        method: method.as_str(),
+                        path: path,
+                        status: response.status().as_u16(),
+                        ms: ms,
+                    },
+                    "{} {} {} {} {}ms",
+                    client,
+                    method,
+                    path,
+                    status,
+                    ms
+                );
+                response
+            })
+        })
+    };
+
+    let (tx, rx) = oneshot::channel::<()>();
+    let server: Box<Future<Item = (), Error = ()> + Send> = match socket {
+        Some(ref path) => {
+            // Unix socket clients are local: they're seen as the loopback address, so that
+            // --trusted-proxies can be set to 127.0.0.1 for a reverse proxy forwarding over it.
+            let peer = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0);
+            let listener = bind_unix_socket(path)
+                .unwrap_or_else(|e| panic!("failed to bind {}: {}", path.display(), e));
+            Box::new(
+                Server::builder(listener.incoming())
+                    .http1_keepalive(keepalive)
+                    .serve(make_service_fn(move |_: &UnixStream| make_service(peer)))
+                    .with_graceful_shutdown(rx)
+                    .map_err(|e| eprintln!("server error: {}", e)),
+            )
+        }
+        None => Box::new(
+            Server::bind(&addr)
+                .http1_keepalive(keepalive)
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    make_service(conn.remote_addr())
+                }))
+                .with_graceful_shutdown(rx)
+                .map_err(|e| eprintln!("server error: {}", e)),
+        ),
+    };
+
+    Handle {
+        tx,
+        socket,
+        thread: thread::spawn(move || {
+            rt::run(server);
+        }),
+    }
+}
+
+// The caching and CORS policies are applied here to all the responses, rather than by each handler.
+fn set_response_headers(response: &mut Response<Body>, route: &str, cors: Option<&str>) {
+    let headers = response.headers_mut();
+    if NO_STORE_ROUTES.contains(&route) {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    if let Some(origin) = cors {
+        let origin = HeaderValue::from_str(origin).expect("invalid CORS origin");
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+}
+
+fn preflight_response(origin: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS")
+        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type")
+        .header(header::ACCESS_CONTROL_MAX_AGE, CORS_MAX_AGE.to_string())
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The route matching this request, with its parameters left out (to keep metric labels bounded).
+fn route_label(method: &Method, path: &str) -> &'static str {
+    if *method == Method::POST && path == "/rpc" {
+        return "/rpc";
+    }
+    if *method != Method::GET {
+        return "other";
+    }
+    let path: Vec<&str> = path.split('/').skip(1).collect();
+    match (
+        path.get(0),
+        path.get(1),
+        path.get(2),
+        path.get(3),
+        path.get(4),
+    ) {
+        (Some(&"blocks"), Some(&"tip"), Some(&"hash"), None, None) => "/blocks/tip/hash",
+        (Some(&"blocks"), Some(&"tip"), Some(&"height"), None, None) => "/blocks/tip/height",
+        (Some(&"blocks"), Some(&"tip"), Some(&"locator"), None, None) => "/blocks/tip/locator",
+        (Some(&"blocks"), Some(&"tip"), Some(&"subscribe"), None, None) => "/blocks/tip/subscribe",
+        (Some(&"blocks"), Some(&"at-time"), Some(_), None, None) => "/blocks/at-time/:timestamp",
+        (Some(&"blocks"), _, None, None, None) => "/blocks/:start_height",
+        (Some(&"events"), None, None, None, None) => "/events",
+        (Some(&"fee-estimates"), None, None, None, None) => "/fee-estimates",
+        (Some(&"summary"), None, None, None, None) => "/summary",
+        (Some(&"status"), None, None, None, None) => "/status",
+        (Some(&"block-height"), Some(_), None, None, None) => "/block-height/:height",
+        (Some(&"block-height"), Some(_), Some(&"time"), None, None) => "/block-height/:height/time",
+        (Some(&"block"), Some(_), None, None, None) => "/block/:hash",
+        (Some(&"block-header"), Some(_), Some(&"raw"), None, None) => "/block-header/:hash/raw",
+        (Some(&"block"), Some(_), Some(&"status"), None, None) => "/block/:hash/status",
+        (Some(&"block"), Some(_), Some(&"txids"), None, None) => "/block/:hash/txids",
+        (Some(&"block"), Some(_), Some(&"fees"), None, None) => "/block/:hash/fees",
+        (Some(&"block"), Some(_), Some(&"output-types"), None, None) => "/block/:hash/output-types",
+        (Some(&"block"), Some(_), Some(&"header"), None, None) => "/block/:hash/header",
+        (Some(&"block"), Some(_), Some(&"raw"), None, None) => "/block/:hash/raw",
+        (Some(&"block"), Some(_), Some(&"txs"), Some(&"stream"), None) => "/block/:hash/txs/stream",
+        (Some(&"block"), Some(_), Some(&"txs"), _, None) => "/block/:hash/txs",
+        (Some(&"address-prefix"), Some(_), None, None, None) => "/address-prefix/:prefix",
+        (Some(&"opreturn"), Some(_), None, None, None) => "/opreturn/:hexprefix",
+        (Some(&"validate-address"), Some(_), None, None, None) => "/validate-address/:address",
+        (Some(&"search"), Some(_), None, None, None) => "/search/:query",
+        (Some(&"address"), Some(_), None, None, None) => "/address/:address",
+        (Some(&"address"), Some(_), Some(&"txs"), Some(&"stream"), None) => {
+            "/address/:address/txs/stream"
+        }
+        (Some(&"address"), Some(_), Some(&"txs"), Some(&"recent"), None) => {
+            "/address/:address/txs/recent"
+        }
+        (Some(&"address"), Some(_), Some(&"txs"), _, None) => "/address/:address/txs",
+        (Some(&"address"), Some(_), Some(&"utxo"), None, None) => "/address/:address/utxo",
+        (Some(&"address"), Some(_), Some(&"balance"), None, None) => "/address/:address/balance",
+        (Some(&"address"), Some(_), Some(&"first-funding"), None, None) => {
+            "/address/:address/first-funding"
+        }
+        (Some(&"address"), Some(_), Some(&"scriptpubkey"), None, None) => {
+            "/address/:address/scriptpubkey"
+        }
+        (Some(&"scripthash"), Some(_), None, None, None) => "/scripthash/:hash",
+        (Some(&"scripthash"), Some(_), Some(&"txs"), Some(&"stream"), None) => {
+            "/scripthash/:hash/txs/stream"
+        }
+        (Some(&"scripthash"), Some(_), Some(&"txs"), Some(&"recent"), None) => {
+            "/scripthash/:hash/txs/recent"
+        }
+        (Some(&"scripthash"), Some(_), Some(&"txs"), _, None) => "/scripthash/:hash/txs",
+        (Some(&"scripthash"), Some(_), Some(&"utxo"), None, None) => "/scripthash/:hash/utxo",
+        (Some(&"scripthash"), Some(_), Some(&"balance"), None, None) => "/scripthash/:hash/balance",
+        (Some(&"scripthash"), Some(_), Some(&"first-funding"), None, None) => {
+            "/scripthash/:hash/first-funding"
+        }
+        (Some(&"script"), Some(_), Some(&"txs"), None, None) => "/script/:hex/txs",
+        (Some(&"tx"), Some(_), None, None, None) => "/tx/:txid",
+        (Some(&"tx"), Some(_), Some(&"hex"), None, None) => "/tx/:txid/hex",
+        (Some(&"tx"), Some(_), Some(&"summary"), None, None) => "/tx/:txid/summary",
+        (Some(&"tx"), Some(_), Some(&"status"), None, None) => "/tx/:txid/status",
+        (Some(&"tx"), Some(_), Some(&"wait"), None, None) => "/tx/:txid/wait",
+        (Some(&"tx"), Some(_), Some(&"merkle-proof"), None, None) => "/tx/:txid/merkle-proof",
+        (Some(&"tx"), Some(_), Some(&"replacements"), None, None) => "/tx/:txid/replacements",
+        (Some(&"tx"), Some(_), Some(&"cpfp"), None, None) => "/tx/:txid/cpfp",
+        (Some(&"tx"), Some(_), Some(&"outspend"), Some(_), None) => "/tx/:txid/outspend/:vout",
+        (Some(&"tx"), Some(_), Some(&"outspends"), None, None) => "/tx/:txid/outspends",
+        (Some(&"mempool"), Some(&"tx"), Some(_), None, None) => "/mempool/tx/:txid",
+        (Some(&"mempool"), Some(&"min-fee"), None, None, None) => "/mempool/min-fee",
+        (Some(&"mempool"), None, None, None, None) => "/mempool",
+        (Some(&"confirmations"), Some(&"recommended"), None, None, None) => {
+            "/confirmations/recommended"
+        }
+        (Some(&"admin"), Some(&"status"), None, None, None) => "/admin/status",
+        (Some(&"admin"), Some(&"tx-cache"), None, None, None) => "/admin/tx-cache",
+        (Some(&"admin"), Some(&"compact"), None, None, None) => "/admin/compact",
+        (Some(&"admin"), Some(&"db"), None, None, None) => "/admin/db",
+        (Some(&"internal"), Some(&"status"), None, None, None) => "/internal/status",
+        _ => "other",
+    }
+}
+
+fn handle_request(
+    req: Request<Body>,
+    query: &Arc<Query>,
+    config: &Config,
+    waiters: &TxWaiters,
+    tips: &TipSubscribers,
+    streams: &StreamPool,
+) -> Result<Response<Body>, HttpError> {
+    if is_rpc_request(&req, config) {
+        return Ok(rpc_response(req, query, config));
+    }
+    // TODO it looks hyper does not have routing and query parsing :(
+    let uri = req.uri();
+    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+    log_fields!(
+        Level::Debug,
+        { method: req.method().as_str(), path: uri.path() },
+        "path {:?}",
+        path
+    );
+    match (
+        req.method(),
+        path.get(0),
+        path.get(1),
+        path.get(2),
+        path.get(3),
+    ) {
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None) => {
+            let params = query_params(uri);
+            match params.get("wait") {
+                Some(wait) => {
+                    let timeout = wait.parse::<u64>()?.min(TX_WAIT_MAX_TIMEOUT);
+                    let last = match params.get("last") {
+                        Some(last) => Some(Sha256dHash::from_hex(last)?),
+                        None => None,
+                    };
+                    tips.wait(query, last, Duration::from_secs(timeout))
+                }
+                None => http_message(
+                    StatusCode::OK,
+                    query.get_best_header_hash().be_hex_string(),
+                    TTL_SHORT,
+                ),
+            }
+        }
+
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None) => http_message(
+            StatusCode::OK,
+            query.get_best_height().to_string(),
+            TTL_SHORT,
+        ),
+
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"subscribe"), None) => {
+            tips.subscribe(query, TipFormat::Json, None)
+        }
+        (&Method::GET, Some(&"events"), None, None, None) => {
+            let mempool_interval = match query_params(uri).get("mempool") {
+                Some(secs) => Some(Duration::from_secs(
+                    secs.parse::<u64>()?.max(EVENTS_MEMPOOL_MIN_INTERVAL),
+                )),
+                None => None,
+            };
+            tips.subscribe(query, TipFormat::Sse, mempool_interval)
+        }
+
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"locator"), None) => {
+            json_response(query.get_block_locator(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"blocks"), start_height, None, None) => {
+            let start_height = match start_height {
+                Some(height) => Some(height.parse::<usize>()?),
+                None => None,
+            };
+            blocks(&query, start_height)
+        }
+        (&Method::GET, Some(&"fee-estimates"), None, None, None) => {
+            json_response(query.get_fee_estimates()?, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"summary"), None, None, None) => {
+            let count = match query_params(uri).get("blocks") {
+                Some(count) => count.parse::<usize>()?.min(SUMMARY_BLOCKS_MAX),
+                None => BLOCK_LIMIT,
+            };
+            let blocks: Vec<BlockValue> = query
+                .get_recent_blocks(count)?
+                .into_iter()
+                .map(BlockValue::from)
+                .collect();
+            let mempool = query.get_mempool_summary();
+            json_response(
+                json!({
+                    "tip_height": query.get_best_height(),
+                    "blocks": blocks,
+                    "mempool": {
+                        "count": mempool.count,
+                        "vsize": mempool.vsize,
+                        "total_fee": mempool.total_fee,
+                        "fee_histogram": query.get_fee_histogram(),
+                    },
+                    "fee_estimates": query.get_fee_estimates()?,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"status"), None, None, None) => {
+            json_response(query.get_chain_status(), TTL_SHORT)
+        }
+        (&Method::GET, Some(&"mempool"), None, None, None) => {
+            let mempool = query.get_mempool_summary();
+            json_response(
+                json!({
+                    "count": mempool.count,
+                    "vsize": mempool.vsize,
+                    "total_fee": mempool.total_fee,
+                    "fee_histogram": query.get_fee_histogram(),
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"mempool"), Some(&"min-fee"), None, None) => json_response(
+            json!({ "mempoolminfee": query.get_mempool_min_fee()? }),
+            TTL_SHORT,
+        ),
+        (&Method::GET, Some(&"confirmations"), Some(&"recommended"), None, None) => {
+            let max_reorg_depth = query.get_max_reorg_depth();
+            json_response(
+                json!({
+                    "confirmations": recommended_confirmations(max_reorg_depth),
+                    "max_reorg_depth": max_reorg_depth,
+                    "margin": CONFIRMATIONS_MARGIN,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"block-height"), Some(height), None, None) => {
+            let height = height.parse::<usize>()?;
+            let headers = query.get_headers(&[height]);
+            let header = headers
+                .get(0)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let ttl = ttl_by_depth(Some(height), query);
+            http_message(StatusCode::OK, header.hash().be_hex_string(), ttl)
+        }
+        (&Method::GET, Some(&"block-height"), Some(height), Some(&"time"), None) => {
+            let height = height.parse::<usize>()?;
+            let tip = query.get_best_header()?;
+            if height <= tip.height() {
+                let headers = query.get_headers(&[height]);
+                let header = headers
+                    .get(0)
+                    .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                let ttl = ttl_by_depth(Some(height), query);
+                json_response(
+                    json!({ "height": height, "time": header.header().time, "estimated": false }),
+                    ttl,
+                )
+            } else {
+                // extrapolated from the tip, assuming blocks are found at the target interval
+                let interval = config.network_params.target_block_interval as u64;
+                let time = ((height - tip.height()) as u64)
+                    .checked_mul(interval)
+                    .and_then(|delay| delay.checked_add(tip.header().time as u64))
+                    .ok_or_else(|| HttpError::from("Block height too far".to_string()))?;
+                json_response(
+                    json!({ "height": height, "time": time, "estimated": true }),
+                    TTL_SHORT,
+                )
+            }
+        }
+        (&Method::GET, Some(&"blocks"), Some(&"at-time"), Some(timestamp), None) => {
+            let timestamp = timestamp.parse::<u32>()?;
+            let (entry, mediantime) = query
+                .get_header_by_time(timestamp)
+                .ok_or_else(|| HttpError::not_found("No block before this time".to_string()))?;
+            // the next block's median time is later, so the answer only changes on reorgs
+            let ttl = if entry.height() < query.get_best_height() {
+                ttl_by_depth(Some(entry.height() + 1), query)
+            } else {
+                TTL_SHORT
+            };
+            json_response(
+                json!({
+                    "height": entry.height(),
+                    "hash": entry.hash().be_hex_string(),
+                    "time": entry.header().time,
+                    "mediantime": mediantime,
+                }),
+                ttl,
+            )
+        }
+        (&Method::GET, Some(&"block"), Some(hash), None, None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let blockhm = query
+                .get_block_header_with_meta(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            // orphaned blocks are found too, so the status tells whether it's in the best chain
+            let status = query.get_block_status(&hash);
+            let ttl = ttl_by_depth(status.height, query);
+            let mut value = serde_json::to_value(BlockValue::from(blockhm))?;
+            value["status"] = serde_json::to_value(status)?;
+            json_response(value, ttl)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"header"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let header = query
+                .get_any_header_by_hash(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            http_message(
+                StatusCode::OK,
+                hex::encode(serialize(header.header())),
+                TTL_LONG,
+            )
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"raw"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let raw = query.get_block_raw(&hash).map_err(|e| match *e.kind() {
+                errors::ErrorKind::BlockUnavailable(_) => HttpError::from(e),
+                _ => HttpError::not_found("Block not found".to_string()),
+            })?;
+            binary_response(raw.to_vec(), TTL_LONG)
+        }
+        (&Method::GET, Some(&"block-header"), Some(hash), Some(&"raw"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let header = query
+                .get_any_header_by_hash(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            binary_response(serialize(header.header()), TTL_LONG)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let status = query.get_block_status(&hash);
+            let ttl = ttl_by_depth(status.height, query);
+            json_response(status, ttl)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"txids"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let txids = query
+                .get_block_txids(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            json_response(txids, TTL_LONG)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"fees"), None) => {
+            // prevouts are needed, and looking them up via the daemon is too expensive
+            if !config.prevout_enabled || !config.extended_db_enabled {
+                bail!(HttpError::from(
+                    "Block fees require the extended DB with prevouts (unavailable with --light or --disable-prevout)"
+                        .to_string()
+                ));
+            }
+            let hash = Sha256dHash::from_hex(hash)?;
+            // an unknown block is a 404, unlike a failure to get its fees (e.g. a missing prevout)
+            let fees = query.get_block_fees(&hash).map_err(|e| {
+                if query.get_block_txids(&hash).is_err() {
+                    HttpError::not_found("Block not found".to_string())
+                } else {
+                    HttpError::from(e)
+                }
+            })?;
+            json_response(&*fees, TTL_LONG)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"output-types"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let output_types = query
+                .get_block_output_types(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            json_response(&*output_types, TTL_LONG)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), Some(&"stream")) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let txids = query
+                .get_block_txids(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            Ok(streams.stream(txids, Some(hash)))
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), start_index) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let txids = query
+                .get_block_txids(&hash)
+                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+
+            let start_index = match start_index {
+                Some(index) => index.parse::<usize>()?,
+                None => 0,
+            };
+            if start_index >= txids.len() {
+                bail!(HttpError::not_found("start index out of range".to_string()));
+            } else if start_index % TX_LIMIT != 0 {
+                bail!(HttpError::from(format!(
+                    "start index must be a multipication of {}",
+                    TX_LIMIT
+                )));
+            }
+
+            let txids: Vec<(Sha256dHash, Option<Sha256dHash>)> = txids
+                .iter()
+                .skip(start_index)
+                .take(TX_LIMIT)
+                .map(|txid| (*txid, Some(hash)))
+                .collect();
+            let mut txs = query
+                .load_txns(&txids)
+                .into_iter()
+                .map(|txn| txn.map(TransactionValue::from))
+                .collect::<Result<Vec<TransactionValue>, _>>()?;
+            attach_txs_data(&mut txs, config, query);
+            json_response(txs, TTL_LONG)
+        }
+        (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None) => {
+            // addresses are only indexed in the extended DB
+            if !config.extended_db_enabled {
+                bail!(HttpError::from(
+                    "Address search is unavailable with --light".to_string()
+                ));
+            }
+            let addresses = if prefix.len() < ADDRESS_SEARCH_MIN_LEN {
+                vec![]
+            } else {
+                query
+                    .search_addresses(
+                        prefix,
+                        config.prefix_search_limit,
+                        config.prefix_search_scan_limit,
+                    )
+                    .ok_or_else(|| {
+                        HttpError::from("Prefix too broad, refine your query".to_string())
+                    })?
+            };
+            json_response(addresses, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"opreturn"), Some(hexprefix), None, None) => {
+            if !query.has_opreturn_index() {
+                bail!(HttpError::from(
+                    "OP_RETURN search requires a DB indexed with --index-opreturn".to_string()
+                ));
+            }
+            let prefix = hex::decode(hexprefix)?;
+            if prefix.len() < OPRETURN_SEARCH_MIN_LEN || prefix.len() > 32 {
+                bail!(HttpError::from(format!(
+                    "Expected a prefix of {} to 32 bytes of the payload's SHA256",
+                    OPRETURN_SEARCH_MIN_LEN
+                )));
+            }
+            let txids = query
+                .search_opreturn(
+                    &prefix,
+                    config.prefix_search_limit,
+                    config.prefix_search_scan_limit,
+                )
+                .ok_or_else(|| {
+                    HttpError::from("Prefix too broad, refine your query".to_string())
+                })?;
+            json_response(txids, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"validate-address"), Some(addr), None, None) => json_response(
+            ValidateAddressValue::new(addr, &config.address_network),
+            TTL_LONG,
+        ),
+        (&Method::GET, Some(&"search"), Some(q), None, None) => {
+            let matches = search_matches(
+                q,
+                &config.address_network,
+                |height| query.get_headers(&[height]).get(0).map(|h| *h.hash()),
+                |hash| query.get_any_header_by_hash(hash).is_ok(),
+                |txid| Ok(query.find_tx_status(txid)?.is_some()),
+            )?;
+            if matches.is_empty() {
+                bail!(HttpError::not_found(
+                    "No block, transaction or address found".to_string()
+                ));
+            }
+            // the matched heights and transactions may change with the next block
+            json_response(matches, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"address"), Some(addr), Some(&"scriptpubkey"), None) => {
+            let script = parse_address(addr, &config.address_network)?;
+            // an address always encodes the same script
+            http_message(StatusCode::OK, hex::encode(script.as_bytes()), TTL_LONG)
+        }
+        (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None)
+        | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None) => {
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let include_mempool = mempool_param(uri)?;
+            // unlike the legacy fields below, the stats aren't limited by the history's size
+            let (chain_stats, mut mempool_stats) = query.get_script_stats(&script_hash[..])?;
+            if !include_mempool {
+                mempool_stats = ScriptStats::default();
+            }
+            match script_status(query, &script_hash, include_mempool) {
+                Ok(status) => json_response(
+                    json!({
+                        *script_type: script_str,
+                        "chain_stats": chain_stats,
+                        "mempool_stats": mempool_stats,
+                        "tx_count": status.history().len(),
+                        "confirmed_balance": status.confirmed_balance(),
+                        "mempool_balance": status.mempool_balance(),
+                        "total_received": status.total_received(),
+                    }),
+                    TTL_SHORT,
+                ),
+
+                // if the address has too many txs, just return the address with its stats (but no error)
+                Err(errors::Error(errors::ErrorKind::Msg(ref msg), _))
+                    if *msg == "Too many txs".to_string() =>
+                {
+                    json_response(
+                        json!({
+                            *script_type: script_str,
+                            "chain_stats": chain_stats,
+                            "mempool_stats": mempool_stats,
+                        }),
+                        TTL_SHORT,
+                    )
+                }
+
+                Err(err) => bail!(err),
+            }
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"stream"),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"stream"),
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let mut history = query.history_txids(&script_hash[..])?;
+            if !mempool_param(uri)? {
+                history.retain(|(height, _)| *height != MEMPOOL_HEIGHT);
+            }
+            Ok(stream_history(history, streams))
+        }
+        (&Method::GET, Some(script_type @ &"address"), Some(script_str), Some(&"txs"), None)
+        | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), Some(&"txs"), None)
+        | (&Method::GET, Some(script_type @ &"script"), Some(script_str), Some(&"txs"), None) => {
+            let after_txid = match query_params(uri).get("after_txid") {
+                Some(txid) => Some(Sha256dHash::from_hex(txid)?),
+                None => None,
+            };
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let mut history = query
+                .get_history(&script_hash[..], after_txid.as_ref(), TX_LIMIT)?
+                .ok_or_else(|| {
+                    HttpError::not_found("after_txid not found in history".to_string())
+                })?;
+            if !mempool_param(uri)? {
+                history.retain(|(height, _)| *height != MEMPOOL_HEIGHT);
+            }
+            let mut txs = history_txs(history, query)?;
+            attach_txs_data(&mut txs, config, query);
+            json_response(txs, TTL_SHORT)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"recent"),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"recent"),
+        ) => {
+            let blocks = match query_params(uri).get("blocks") {
+                Some(blocks) => blocks.parse::<usize>()?.min(RECENT_TXS_BLOCKS_MAX),
+                None => BLOCK_LIMIT,
+            };
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let mut history = query.get_recent_history(&script_hash[..], blocks)?;
+            if !mempool_param(uri)? {
+                history.retain(|(height, _)| *height != MEMPOOL_HEIGHT);
+            }
+            let mut txs = history_txs(history, query)?;
+            attach_txs_data(&mut txs, config, query);
+            json_response(txs, TTL_SHORT)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(start_index),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(start_index),
+        ) => {
+            let start_index = start_index.parse().unwrap_or(0u32) as usize;
+
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let status = script_status(query, &script_hash, mempool_param(uri)?)?;
+            let txs = status.history_txs();
+
+            if txs.len() == 0 {
+                return json_response(json!([]), TTL_SHORT);
+            } else if start_index >= txs.len() {
+                bail!(HttpError::not_found("start index out of range".to_string()));
+            } else if start_index % TX_LIMIT != 0 {
+                bail!(HttpError::from(format!(
+                    "start index must be a multipication of {}",
+                    TX_LIMIT
+                )));
+            }
+
+            let mut txs = txs
+                .iter()
+                .skip(start_index)
+                .take(TX_LIMIT)
+                .map(|t| TransactionValue::from((*t).clone()))
+                .collect();
+            attach_txs_data(&mut txs, config, query);
+
+            json_response(txs, TTL_SHORT)
         }
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), Some(&"utxo"), None)
         | (
@@ -571,8 +2035,8 @@ fn handle_request(
             Some(&"utxo"),
             None,
         ) => {
-            let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let status = query.status(&script_hash[..])?;
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            let status = script_status(query, &script_hash, mempool_param(uri)?)?;
             let utxos: Vec<UtxoValue> = status
                 .unspent()
                 .into_iter()
@@ -581,6 +2045,58 @@ fn handle_request(
             // @XXX no paging, but query.status() is limited to 30 funding txs
             json_response(utxos, TTL_SHORT)
         }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"balance"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"balance"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            json_response(query.get_balance(&script_hash[..])?, TTL_SHORT)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"first-funding"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"first-funding"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, &config.address_network)?;
+            match query.get_first_funding(&script_hash[..])? {
+                Some((txid, Some(header))) => json_response(
+                    json!({
+                        "txid": txid.be_hex_string(),
+                        "status": TransactionStatus::confirmed(&header),
+                        "block_time": header.header().time,
+                    }),
+                    ttl_by_depth(Some(header.height()), query),
+                ),
+                // only funded by mempool transactions (an earlier one may still confirm)
+                Some((txid, None)) => json_response(
+                    json!({
+                        "txid": txid.be_hex_string(),
+                        "status": TransactionStatus::unconfirmed(),
+                    }),
+                    TTL_SHORT,
+                ),
+                None => Err(HttpError::not_found("Never funded".to_string())),
+            }
+        }
         (&Method::GET, Some(&"tx"), Some(hash), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let transaction = query
@@ -590,6 +2106,12 @@ fn handle_request(
             let ttl = ttl_by_depth(status.block_height, query);
 
             let mut value = TransactionValue::from(transaction);
+            if !status.confirmed {
+                value.fee_rank = query.get_mempool_fee_rank(&hash);
+                value.cpfp = query
+                    .get_mempool_tx_graph(&hash)
+                    .map(|graph| CpfpValue::from(&graph));
+            }
             value.status = Some(status);
             let value = attach_tx_data(value, config, query);
             json_response(value, ttl)
@@ -602,11 +2124,48 @@ fn handle_request(
             let ttl = ttl_by_depth(query.get_tx_status(&hash)?.block_height, query);
             http_message(StatusCode::OK, hex::encode(rawtx), ttl)
         }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"summary"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let transaction = query
+                .load_txn(&hash, None)
+                .map_err(|_| HttpError::not_found("Transaction not found".to_string()))?;
+            let ttl = ttl_by_depth(query.get_tx_status(&hash)?.block_height, query);
+            json_response(TxSummaryValue::from(&transaction), ttl)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let status = query.get_tx_status(&hash)?;
-            let ttl = ttl_by_depth(status.block_height, query);
-            json_response(status, ttl)
+            let status = TxStatusValue::from(query.get_tx_confirmations(&hash)?);
+            // not cached for long, as the confirmations change with each block
+            json_response(status, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"wait"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let params = query_params(uri);
+            let timeout = params
+                .get("timeout")
+                .map_or(TX_WAIT_TIMEOUT, |el| el.parse().unwrap_or(TX_WAIT_TIMEOUT))
+                .min(TX_WAIT_MAX_TIMEOUT);
+            let confirmed = params.get("confirmed").map_or(false, |el| el == "true");
+
+            // read before checking the status, so that an update done meanwhile is not missed
+            let checked = query.update_count();
+            let status = query.find_tx_status(&hash)?;
+            if timeout == 0 || tx_wait_done(status.as_ref(), confirmed) {
+                return Ok(tx_wait_response(Body::from(tx_wait_value(status)?)));
+            }
+            let (sender, receiver) = oneshot::channel();
+            waiters.add(TxWaiter {
+                txid: hash,
+                confirmed,
+                deadline: Instant::now() + Duration::from_secs(timeout),
+                checked,
+                sender,
+            })?;
+            let body = receiver
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "wait failed"))
+                .and_then(|value| value)
+                .into_stream();
+            Ok(tx_wait_response(Body::wrap_stream(body)))
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"merkle-proof"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
@@ -621,13 +2180,73 @@ fn handle_request(
                 ttl,
             )
         }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"replacements"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            json_response(query.get_tx_replacements(&hash), TTL_SHORT)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"cpfp"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let graph = query
+                .get_mempool_tx_graph(&hash)
+                .ok_or(HttpError::not_found(
+                    "Transaction not in mempool".to_string(),
+                ))?;
+            let mut value = serde_json::to_value(CpfpValue::from(&graph))?;
+            value["txid"] = json!(hash);
+            value["ancestor_txids"] =
+                json!(&graph.ancestor_txids[..graph.ancestor_txids.len().min(MEMPOOL_GRAPH_LIMIT)]);
+            value["descendant_txids"] = json!(
+                &graph.descendant_txids[..graph.descendant_txids.len().min(MEMPOOL_GRAPH_LIMIT)]
+            );
+            json_response(value, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"mempool"), Some(&"tx"), Some(hash), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let graph = query
+                .get_mempool_tx_graph(&hash)
+                .ok_or(HttpError::not_found(
+                    "Transaction not in mempool".to_string(),
+                ))?;
+            json_response(
+                json!({
+                    "txid": hash,
+                    "fee": graph.fee,
+                    "vsize": graph.vsize,
+                    "feerate": graph.fee as f32 / graph.vsize as f32,
+                    "effective_feerate": graph.effective_fee_rate,
+                    "ancestor_count": graph.ancestors.count,
+                    "ancestor_fee": graph.ancestors.fee,
+                    "ancestor_vsize": graph.ancestors.vsize,
+                    "ancestor_feerate": graph.ancestors.fee_rate(),
+                    "descendant_count": graph.descendants.count,
+                    "descendant_fee": graph.descendants.fee,
+                    "descendant_vsize": graph.descendants.vsize,
+                    "descendant_feerate": graph.descendants.fee_rate(),
+                    "depends_count": graph.depends.len(),
+                    "spentby_count": graph.spentby.len(),
+                    "depends": &graph.depends[..graph.depends.len().min(MEMPOOL_GRAPH_LIMIT)],
+                    "spentby": &graph.spentby[..graph.spentby.len().min(MEMPOOL_GRAPH_LIMIT)],
+                    "first_seen": graph.first_seen,
+                }),
+                TTL_SHORT,
+            )
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspend"), Some(index)) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let outpoint = (hash, index.parse::<usize>()?);
-            let spend = query.find_spending_by_outpoint(outpoint)?.map_or_else(
-                || SpendingValue::default(),
-                |spend| SpendingValue::from(spend),
-            );
+            let spend = if mempool_param(uri)? {
+                let mut spend = query.lookup_spend(outpoint)?.map_or_else(
+                    || SpendingValue::default(),
+                    |spend| SpendingValue::from(spend),
+                );
+                spend.replaced = query.lookup_replaced_spends(outpoint);
+                spend
+            } else {
+                query.lookup_confirmed_spend(outpoint)?.map_or_else(
+                    || SpendingValue::default(),
+                    |spend| SpendingValue::from(spend),
+                )
+            };
             let ttl = ttl_by_depth(
                 spend
                     .status
@@ -643,7 +2262,7 @@ fn handle_request(
                 .load_txn(&hash, None)
                 .map_err(|_| HttpError::not_found("Transaction not found".to_string()))?;
             let spends: Vec<SpendingValue> = query
-                .find_spending_for_funding_tx(tx)?
+                .lookup_spends(tx)?
                 .into_iter()
                 .map(|spend| {
                     spend.map_or_else(
@@ -655,6 +2274,51 @@ fn handle_request(
             // @TODO long ttl if all outputs are either spent long ago or unspendable
             json_response(spends, TTL_SHORT)
         }
+        (&Method::GET, Some(&"admin"), Some(&"status"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            admin_status(query)
+        }
+        (&Method::POST, Some(&"admin"), Some(&"pause"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            info!("pausing the index and mempool updates for maintenance");
+            query.pause_updates();
+            admin_status(query)
+        }
+        (&Method::POST, Some(&"admin"), Some(&"resume"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            info!("resuming the index and mempool updates");
+            query.resume_updates();
+            admin_status(query)
+        }
+        (&Method::POST, Some(&"admin"), Some(&"compact"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            query
+                .start_compaction()
+                .map_err(|e| HttpError(StatusCode::CONFLICT, e.to_string()))?;
+            info!("compacting the DB in the background");
+            admin_status(query)
+        }
+        (&Method::GET, Some(&"admin"), Some(&"db"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            let rows = query_params(uri)
+                .get("rows")
+                .map_or(false, |el| el == "true");
+            admin_db(query, rows)
+        }
+        (&Method::GET, Some(&"admin"), Some(&"tx-cache"), None, None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            tx_cache_status(query)
+        }
+        (&Method::POST, Some(&"admin"), Some(&"tx-cache"), Some(capacity), None) => {
+            authorize_admin(&req, config.admin_token.as_ref())?;
+            let capacity: usize = capacity.parse()?;
+            info!("resizing the transaction cache to {} entries", capacity);
+            query.set_tx_cache_capacity(capacity);
+            tx_cache_status(query)
+        }
+        (&Method::GET, Some(&"internal"), Some(&"status"), None, None) if config.internal_api => {
+            internal_status(query)
+        }
         _ => Err(HttpError::not_found(format!(
             "endpoint does not exist {:?}",
             uri.path()
@@ -662,6 +2326,441 @@ fn handle_request(
     }
 }
 
+// The admin endpoints require the configured token as a bearer token
+// (and don't exist without one).
+fn authorize_admin(req: &Request<Body>, admin_token: Option<&AdminToken>) -> Result<(), HttpError> {
+    let expected =
+        admin_token.ok_or_else(|| HttpError::not_found("endpoint does not exist".to_string()))?;
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            if value.starts_with("Bearer ") {
+                Some(&value["Bearer ".len()..])
+            } else {
+                None
+            }
+        });
+    match token {
+        Some(token) if expected.matches(token) => Ok(()),
+        _ => Err(HttpError(
+            StatusCode::UNAUTHORIZED,
+            "invalid admin token".to_string(),
+        )),
+    }
+}
+
+fn admin_status(query: &Query) -> Result<Response<Body>, HttpError> {
+    json_response(
+        json!({
+            "paused": query.updates_paused(),
+            "compacting": query.is_compacting(),
+            "height": query.get_best_height(),
+        }),
+        0,
+    )
+}
+
+// Only RocksDB's default column family is used (the rows' kind being given by their key's first
+// byte), and the rocksdb crate doesn't expose its properties: the cheap part is its size on disk,
+// while the per-prefix row counts are only computed on request, as they require a full scan.
+fn admin_db(query: &Query, rows: bool) -> Result<Response<Body>, HttpError> {
+    let mut family = json!({ "name": "default", "disk_size": query.db_disk_size() });
+    if rows {
+        let prefixes: serde_json::Map<String, Value> = query
+            .db_prefix_sizes()
+            .into_iter()
+            .map(|(prefix, (count, size))| {
+                let name = (prefix as char).to_string();
+                (name, json!({ "rows": count, "size": size }))
+            })
+            .collect();
+        family["prefixes"] = Value::Object(prefixes);
+    }
+    json_response(json!({ "column_families": [family] }), 0)
+}
+
+// From the state kept up-to-date by the indexing, the mempool and the caches (the daemon's tip being
+// cached too), so that it can be polled without scanning the DB.
+fn internal_status(query: &Query) -> Result<Response<Body>, HttpError> {
+    let chain = query.get_chain_status();
+    let mempool = query.get_mempool_summary();
+    let daemon_height = chain.daemon_height.map(|height| height as usize);
+    let (rate, eta) = query.index_progress();
+    let stage = if chain.initial_sync_done {
+        "incremental"
+    } else {
+        "initial_sync"
+    };
+    json_response(
+        json!({
+            "index": {
+                "tip_height": chain.tip_height,
+                "tip_hash": chain.tip_hash.be_hex_string(),
+                "stage": stage,
+                "blocks_remaining": daemon_height
+                    .map(|height| height.saturating_sub(chain.tip_height)),
+                "progress": daemon_height.map(|height| sync_progress(chain.tip_height, height)),
+                "rate": rate,
+                "eta": eta,
+            },
+            "daemon": {
+                "tip_height": chain.daemon_height,
+                "initial_block_download": chain.daemon_initial_block_download,
+            },
+            "mempool": { "count": mempool.count, "vsize": mempool.vsize },
+            "db": {
+                "column_families": [{ "name": "default", "disk_size": query.db_disk_size() }],
+            },
+            "caches": {
+                "history": query.history_cache_stats(),
+                "tx": query.tx_cache_stats(),
+            },
+            "uptime": query.uptime(),
+        }),
+        0,
+    )
+}
+
+// The indexed share of the daemon's blocks, as a percentage (with 2 decimals).
+fn sync_progress(tip_height: usize, daemon_height: usize) -> f64 {
+    if daemon_height == 0 || tip_height >= daemon_height {
+        return 100.0;
+    }
+    (tip_height as f64 * 10000.0 / daemon_height as f64).floor() / 100.0
+}
+
+fn tx_cache_status(query: &Query) -> Result<Response<Body>, HttpError> {
+    let (entries, capacity) = query.tx_cache_usage();
+    json_response(json!({ "entries": entries, "capacity": capacity }), 0)
+}
+
+// viacoind's addressindex RPCs (getaddresstxids, getaddressbalance and getaddressutxos), served on
+// POST /rpc with --address-rpc from the scripthash index, for the clients written against a node
+// running with -addressindex. Only the confirmed transactions are considered, as by viacoind.
+
+#[derive(Debug, PartialEq)]
+struct RpcError(i32, String);
+
+impl RpcError {
+    fn invalid_address() -> Self {
+        RpcError(RPC_INVALID_ADDRESS_OR_KEY, "Invalid address".to_string())
+    }
+}
+
+// Each failure has a stable code, with the same messages as REST: the internal errors are logged
+// in full (by HttpError::from), but only reported as a generic message.
+impl From<errors::Error> for RpcError {
+    fn from(e: errors::Error) -> Self {
+        let code = match *e.kind() {
+            errors::ErrorKind::Syncing(_) | errors::ErrorKind::Warmup(_) => RPC_IN_WARMUP,
+            errors::ErrorKind::HistoryTooLarge(_) => RPC_MISC_ERROR,
+            _ => RPC_INTERNAL_ERROR,
+        };
+        match HttpError::from(e) {
+            HttpError(StatusCode::NOT_FOUND, msg) => RpcError(RPC_INVALID_ADDRESS_OR_KEY, msg),
+            HttpError(StatusCode::TOO_MANY_REQUESTS, msg) => RpcError(RPC_MISC_ERROR, msg),
+            HttpError(_, msg) => RpcError(code, msg),
+        }
+    }
+}
+
+// Only returned by the parsing of the params (e.g. a malformed hex string or address).
+impl From<HttpError> for RpcError {
+    fn from(e: HttpError) -> Self {
+        match e.0 {
+            StatusCode::BAD_REQUEST => RpcError(RPC_INVALID_PARAMS, e.1),
+            StatusCode::NOT_FOUND => RpcError(RPC_INVALID_ADDRESS_OR_KEY, e.1),
+            _ => RpcError(RPC_INTERNAL_ERROR, e.1),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct AddressQuery {
+    addresses: Vec<String>,      // without duplicates, in the requested order
+    heights: Option<(u32, u32)>, // inclusive range, from `start` and `end`
+    chain_info: bool,
+}
+
+// The addresses are given either as a single string or as `{"addresses": [...]}`, with the
+// optional `start`/`end` heights (both needed) and `chainInfo` flag.
+fn parse_address_query(params: &Value) -> Result<AddressQuery, RpcError> {
+    let param = match *params {
+        Value::Array(ref params) => params.get(0),
+        Value::Null => None,
+        _ => {
+            return Err(RpcError(
+                RPC_INVALID_REQUEST,
+                "Params must be an array".to_string(),
+            ))
+        }
+    };
+    let (addresses, options) = match param {
+        Some(&Value::String(ref address)) => (vec![address.clone()], None),
+        Some(&Value::Object(ref options)) => {
+            let addresses = match options.get("addresses") {
+                Some(&Value::Array(ref addresses)) => addresses
+                    .iter()
+                    .map(|address| address.as_str().map(str::to_owned))
+                    .collect::<Option<Vec<String>>>()
+                    .ok_or_else(RpcError::invalid_address)?,
+                _ => {
+                    return Err(RpcError(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "Addresses is expected to be an array".to_string(),
+                    ))
+                }
+            };
+            (addresses, Some(options))
+        }
+        _ => return Err(RpcError::invalid_address()),
+    };
+    if addresses.len() > RPC_MAX_ADDRESSES {
+        return Err(RpcError(
+            RPC_INVALID_PARAMETER,
+            format!("Too many addresses (at most {})", RPC_MAX_ADDRESSES),
+        ));
+    }
+    let mut unique = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        if !unique.contains(&address) {
+            unique.push(address);
+        }
+    }
+    let option = |name| options.and_then(|options| options.get(name));
+    let heights = match (
+        option("start").and_then(Value::as_i64),
+        option("end").and_then(Value::as_i64),
+    ) {
+        (Some(start), Some(end)) => {
+            if start <= 0 || end <= 0 {
+                return Err(RpcError(
+                    RPC_INVALID_PARAMETER,
+                    "Start and end is expected to be greater than zero".to_string(),
+                ));
+            }
+            if end < start {
+                return Err(RpcError(
+                    RPC_INVALID_PARAMETER,
+                    "End value is expected to be greater than start".to_string(),
+                ));
+            }
+            let height = |h: i64| h.min(i64::from(u32::max_value())) as u32;
+            Some((height(start), height(end)))
+        }
+        _ => None,
+    };
+    Ok(AddressQuery {
+        addresses: unique,
+        heights,
+        chain_info: option("chainInfo")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+// Merges the confirmed histories of the addresses by height (keeping the order within a block),
+// listing each transaction once.
+fn merge_txids(histories: Vec<History>, heights: Option<(u32, u32)>) -> Vec<Sha256dHash> {
+    let mut txids: History = histories
+        .into_iter()
+        .flat_map(|history| history.into_iter())
+        .filter(|&(height, _)| height != MEMPOOL_HEIGHT)
+        .filter(|&(height, _)| {
+            heights.map_or(true, |(start, end)| start <= height && height <= end)
+        })
+        .collect();
+    txids.sort_by_key(|&(height, _)| height); // stable
+    let mut seen = HashSet::new();
+    txids
+        .into_iter()
+        .map(|(_, txid)| txid)
+        .filter(|txid| seen.insert(*txid))
+        .collect()
+}
+
+// The same amounts as the `chain_stats` of /address/:address, summed over the addresses.
+fn address_balance(stats: &[ScriptStats]) -> Value {
+    let received: u64 = stats.iter().map(|s| s.funded_txo_sum).sum();
+    let spent: u64 = stats.iter().map(|s| s.spent_txo_sum).sum();
+    json!({ "balance": received - spent, "received": received })
+}
+
+fn address_utxo(address: &str, script: &Script, output: &FundingOutput) -> Value {
+    json!({
+        "address": address,
+        "txid": output.txn_id.be_hex_string(),
+        "outputIndex": output.output_index,
+        "script": hex::encode(script.as_bytes()),
+        "satoshis": output.value,
+        "height": output.height,
+    })
+}
+
+fn rpc_method(
+    method: &str,
+    params: &Value,
+    query: &Query,
+    config: &Config,
+) -> Result<Value, RpcError> {
+    let scripthashes = |request: &AddressQuery| {
+        request
+            .addresses
+            .iter()
+            .map(|address| {
+                address_to_scripthash(address, &config.address_network)
+                    .map_err(|_| RpcError::invalid_address())
+            })
+            .collect::<Result<Vec<FullHash>, RpcError>>()
+    };
+    match method {
+        "getaddresstxids" => {
+            let request = parse_address_query(params)?;
+            let histories = scripthashes(&request)?
+                .iter()
+                .map(|hash| query.history_txids(&hash[..]))
+                .collect::<Result<Vec<History>, errors::Error>>()?;
+            let txids: Vec<String> = merge_txids(histories, request.heights)
+                .iter()
+                .map(Sha256dHash::be_hex_string)
+                .collect();
+            Ok(json!(txids))
+        }
+        "getaddressbalance" => {
+            let request = parse_address_query(params)?;
+            let stats = scripthashes(&request)?
+                .iter()
+                .map(|hash| Ok(query.get_script_stats(&hash[..])?.0))
+                .collect::<Result<Vec<ScriptStats>, errors::Error>>()?;
+            Ok(address_balance(&stats))
+        }
+        "getaddressutxos" => {
+            let request = parse_address_query(params)?;
+            // read before the UTXOs, so that they're at least as recent as the returned tip
+            let tip = query.get_best_header()?;
+            let mut utxos = vec![];
+            for (address, hash) in request.addresses.iter().zip(scripthashes(&request)?) {
+                let script = parse_address(address, &config.address_network)?;
+                let status = script_status(query, &hash, false)?;
+                utxos.extend(
+                    status
+                        .unspent()
+                        .into_iter()
+                        .map(|output| (output.height, address_utxo(address, &script, output))),
+                );
+            }
+            utxos.sort_by_key(|&(height, _)| height); // stable
+            let utxos: Vec<Value> = utxos.into_iter().map(|(_, utxo)| utxo).collect();
+            Ok(if request.chain_info {
+                json!({
+                    "utxos": utxos,
+                    "hash": tip.hash().be_hex_string(),
+                    "height": tip.height(),
+                })
+            } else {
+                json!(utxos)
+            })
+        }
+        _ => Err(RpcError(
+            RPC_METHOD_NOT_FOUND,
+            "Method not found".to_string(),
+        )),
+    }
+}
+
+fn rpc_result(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null, "id": id }),
+        Err(RpcError(code, message)) => json!({
+            "result": null,
+            "error": { "code": code, "message": message },
+            "id": id,
+        }),
+    }
+}
+
+fn rpc_call(request: &Value, query: &Query, config: &Config) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let result = match request.get("method").and_then(Value::as_str) {
+        Some(method) => rpc_method(
+            method,
+            request.get("params").unwrap_or(&Value::Null),
+            query,
+            config,
+        ),
+        None => Err(RpcError(RPC_INVALID_REQUEST, "Missing method".to_string())),
+    };
+    rpc_result(id, result)
+}
+
+// Handles a single call or a batch of them, as viacoind does.
+fn rpc_reply(body: &[u8], query: &Query, config: &Config) -> Vec<u8> {
+    let reply = if body.len() > RPC_MAX_BODY {
+        rpc_result(
+            Value::Null,
+            Err(RpcError(
+                RPC_INVALID_REQUEST,
+                "Request too large".to_string(),
+            )),
+        )
+    } else {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Array(calls)) => Value::Array(
+                calls
+                    .iter()
+                    .map(|call| rpc_call(call, query, config))
+                    .collect(),
+            ),
+            Ok(call) => rpc_call(&call, query, config),
+            Err(_) => rpc_result(
+                Value::Null,
+                Err(RpcError(RPC_PARSE_ERROR, "Parse error".to_string())),
+            ),
+        }
+    };
+    serde_json::to_vec(&reply).expect("failed to serialize the RPC reply")
+}
+
+fn is_rpc_request(req: &Request<Body>, config: &Config) -> bool {
+    config.address_rpc && *req.method() == Method::POST && req.uri().path() == "/rpc"
+}
+
+// The body is read on the server's event loop (without blocking it) before the request is given to
+// a worker, so that a slow client doesn't hold one up. Past the limit, the rest is skipped and the
+// request rejected by rpc_reply(). A body that fails to be read is left empty (a parse error).
+fn read_rpc_body(req: Request<Body>) -> RequestFuture {
+    let (parts, body) = req.into_parts();
+    Box::new(
+        body.fold(vec![], |mut body, chunk| {
+            if body.len() <= RPC_MAX_BODY {
+                body.extend_from_slice(&chunk);
+            }
+            Ok::<_, ::hyper::Error>(body)
+        })
+        .then(move |body| {
+            let body = Body::from(body.unwrap_or_default());
+            Ok::<_, oneshot::Canceled>(Request::from_parts(parts, body))
+        }),
+    )
+}
+
+// Always with a 200 status, the errors being part of the reply. The body was already read (see
+// read_rpc_body()), so waiting for it doesn't block.
+fn rpc_response(req: Request<Body>, query: &Query, config: &Config) -> Response<Body> {
+    let body = req
+        .into_body()
+        .concat2()
+        .wait()
+        .map(|body| body.to_vec())
+        .unwrap_or_default();
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(rpc_reply(&body, query, config)))
+        .unwrap()
+}
+
 fn http_message(
     status: StatusCode,
     message: String,
@@ -675,13 +2774,530 @@ fn http_message(
         .unwrap())
 }
 
-fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
-    let value = serde_json::to_string(&value)?;
-    Ok(Response::builder()
+fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
+    let value = serde_json::to_string(&value)?;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .body(Body::from(value))
+        .unwrap())
+}
+
+fn binary_response(body: Vec<u8>, ttl: u32) -> Result<Response<Body>, HttpError> {
+    Ok(Response::builder()
+        .header("Content-Type", "application/octet-stream")
+        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .body(Body::from(body))
+        .unwrap())
+}
+
+// Loads the history's transactions, along with their confirmation status.
+fn history_txs(history: History, query: &Query) -> Result<Vec<TransactionValue>, HttpError> {
+    let txids: Vec<(Sha256dHash, Option<Sha256dHash>)> = history
+        .iter()
+        .map(|&(height, txid)| {
+            let blockhash = if height == MEMPOOL_HEIGHT {
+                None
+            } else {
+                query
+                    .get_headers(&[height as usize])
+                    .first()
+                    .map(|header| *header.hash())
+            };
+            (txid, blockhash)
+        })
+        .collect();
+    let txns = query.load_txns(&txids);
+    history
+        .into_iter()
+        .zip(txids.into_iter().zip(txns.into_iter()))
+        .map(|((height, _), ((_, blockhash), txn))| {
+            Ok(TransactionValue::from(TxnHeight {
+                txn: txn?,
+                height,
+                blockhash: blockhash.unwrap_or_default(),
+            }))
+        })
+        .collect()
+}
+
+fn stream_history(history: History, streams: &StreamPool) -> Response<Body> {
+    let txids = history.into_iter().map(|(_, txid)| txid).collect();
+    streams.stream(txids, None)
+}
+
+// Loads the transactions one at a time, sending each as a line. Sending blocks while the client is
+// slow to consume, and fails once it disconnects. A failure to load a transaction aborts the
+// response (instead of ending it as if complete).
+fn produce_stream(job: StreamJob, query: &Arc<Query>, config: &Config) {
+    let StreamJob {
+        txids,
+        blockhash,
+        mut sender,
+    } = job;
+    for txid in txids {
+        let line = stream_tx_line(&txid, blockhash.as_ref(), query, config).map_err(|e| {
+            warn!("failed to stream tx {}: {:?}", txid, e);
+            io::Error::new(io::ErrorKind::Other, "failed to load transaction")
+        });
+        let failed = line.is_err();
+        sender = match sender.send(line.map(Chunk::from)).wait() {
+            Ok(sender) => sender,
+            Err(_) => break, // client disconnected
+        };
+        if failed {
+            break;
+        }
+    }
+}
+
+fn stream_tx_line(
+    txid: &Sha256dHash,
+    blockhash: Option<&Sha256dHash>,
+    query: &Arc<Query>,
+    config: &Config,
+) -> Result<String, HttpError> {
+    let mut value = TransactionValue::from(query.load_txn(txid, blockhash)?);
+    value.status = Some(query.get_tx_status(txid)?);
+    let value = attach_tx_data(value, config, query);
+    let mut line = serde_json::to_string(&value)?;
+    line.push('\n');
+    Ok(line)
+}
+
+// The `mempool` query parameter: `?mempool=false` excludes the unconfirmed transactions.
+fn mempool_param(uri: &Uri) -> Result<bool, HttpError> {
+    match query_params(uri).get("mempool").map(|value| value.as_str()) {
+        None | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(_) => Err(HttpError::from(
+            "mempool must be 'true' or 'false'".to_string(),
+        )),
+    }
+}
+
+fn script_status(
+    query: &Query,
+    script_hash: &FullHash,
+    include_mempool: bool,
+) -> Result<Status, errors::Error> {
+    let status = query.status(&script_hash[..])?;
+    Ok(if include_mempool {
+        status
+    } else {
+        status.confirmed_only()
+    })
+}
+
+fn query_params(uri: &Uri) -> HashMap<String, String> {
+    uri.query().map_or_else(HashMap::new, |query| {
+        form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    })
+}
+
+struct TxWaiter {
+    txid: Sha256dHash,
+    confirmed: bool, // wait for the tx to confirm, not only to be seen (in mempool)
+    deadline: Instant,
+    checked: u64, // the update count when the tx status was last checked
+    sender: oneshot::Sender<Result<Chunk, io::Error>>,
+}
+
+// The pending tx status long-polls, completed by a single thread as the index and mempool
+// are updated (or when their timeout expires). Waiters whose client disconnected are dropped.
+#[derive(Clone)]
+struct TxWaiters {
+    waiters: Arc<Mutex<Vec<TxWaiter>>>,
+    count: Gauge,
+}
+
+impl TxWaiters {
+    fn start(query: Arc<Query>, metrics: &Metrics) -> TxWaiters {
+        let waiters = TxWaiters {
+            waiters: Arc::new(Mutex::new(vec![])),
+            count: metrics.gauge(MetricOpts::new(
+                "rest_tx_waiters",
+                "# of pending tx status long-polls",
+            )),
+        };
+        let cloned = waiters.clone();
+        spawn_thread("tx_waiters", move || cloned.run(&query));
+        waiters
+    }
+
+    fn add(&self, waiter: TxWaiter) -> Result<(), HttpError> {
+        let mut waiters = self.waiters.lock().unwrap();
+        if waiters.len() >= TX_WAIT_MAX_WAITERS {
+            bail!(HttpError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many pending requests, please try again later".to_string()
+            ));
+        }
+        waiters.push(waiter);
+        self.count.set(waiters.len() as i64);
+        Ok(())
+    }
+
+    fn run(&self, query: &Query) {
+        let mut updates = query.update_count();
+        loop {
+            updates = query.wait_for_update(updates, Duration::from_secs(TX_WAIT_CHECK_INTERVAL));
+            let now = Instant::now();
+            // the status is checked without holding the lock (new waiters are added meanwhile)
+            let pending = mem::replace(&mut *self.waiters.lock().unwrap(), vec![]);
+            let pending: Vec<TxWaiter> = pending
+                .into_iter()
+                .filter_map(|waiter| check_waiter(waiter, query, updates, now))
+                .collect();
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.extend(pending);
+            self.count.set(waiters.len() as i64);
+        }
+    }
+}
+
+// Returns the waiter back if it should keep waiting, otherwise responds to it.
+fn check_waiter(
+    mut waiter: TxWaiter,
+    query: &Query,
+    updates: u64,
+    now: Instant,
+) -> Option<TxWaiter> {
+    if waiter.sender.is_canceled() {
+        return None; // client disconnected
+    }
+    let expired = now >= waiter.deadline;
+    if waiter.checked == updates && !expired {
+        return Some(waiter); // no update since the last check
+    }
+    waiter.checked = updates;
+    let value = query
+        .find_tx_status(&waiter.txid)
+        .map_err(HttpError::from)
+        .and_then(|status| {
+            if expired || tx_wait_done(status.as_ref(), waiter.confirmed) {
+                tx_wait_value(status).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+    let value = match value {
+        Ok(None) => return Some(waiter),
+        Ok(Some(value)) => Ok(Chunk::from(value)),
+        Err(e) => {
+            warn!("failed to check tx {} status: {:?}", waiter.txid, e);
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "failed to check tx status",
+            ))
+        }
+    };
+    let _ = waiter.sender.send(value); // fails only if the client disconnected meanwhile
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TipFormat {
+    Json, // newline-delimited JSON objects (/blocks/tip/subscribe)
+    Sse,  // Server-Sent Events (/events)
+}
+
+struct TipSubscriber {
+    sender: mpsc::Sender<Result<Chunk, io::Error>>,
+    format: TipFormat,
+    sent: Sha256dHash,                  // the last tip sent
+    mempool_interval: Option<Duration>, // between the SSE mempool events
+    mempool_sent: Instant,
+}
+
+impl TipSubscriber {
+    fn new(
+        sender: mpsc::Sender<Result<Chunk, io::Error>>,
+        format: TipFormat,
+        mempool_interval: Option<Duration>,
+    ) -> TipSubscriber {
+        TipSubscriber {
+            sender,
+            format,
+            sent: Sha256dHash::default(),
+            mempool_interval,
+            mempool_sent: Instant::now(),
+        }
+    }
+
+    // Returns false if the subscriber should be dropped.
+    fn send_tip(&mut self, hash: Sha256dHash, line: &str, event: &str) -> bool {
+        if self.sent == hash {
+            return true;
+        }
+        self.sent = hash;
+        let message = match self.format {
+            TipFormat::Json => line,
+            TipFormat::Sse => event,
+        };
+        self.send(message.to_owned())
+    }
+
+    fn mempool_due(&self, now: Instant) -> bool {
+        self.mempool_interval
+            .map_or(false, |interval| now >= self.mempool_sent + interval)
+    }
+
+    // Fails if the buffer is full (the client being too slow) or if the client disconnected.
+    fn send(&mut self, message: String) -> bool {
+        self.sender.try_send(Ok(Chunk::from(message))).is_ok()
+    }
+}
+
+// A long-poll for the next tip (/blocks/tip/hash?wait=<secs>).
+struct TipWaiter {
+    last: Sha256dHash,
+    deadline: Instant,
+    sender: oneshot::Sender<Result<Chunk, io::Error>>,
+}
+
+#[derive(Default)]
+struct TipListeners {
+    subscribers: Vec<TipSubscriber>,
+    waiters: Vec<TipWaiter>,
+}
+
+// The streams of new tips and the long-polls for them, notified by a single thread as the index
+// is updated. Sending never blocks: the subscribers whose buffer is full (i.e. slow clients) or
+// that disconnected are dropped. The tip is read and sent under the lock (also taken to
+// subscribe), so that each stream gets the tips in order.
+#[derive(Clone)]
+struct TipSubscribers {
+    listeners: Arc<Mutex<TipListeners>>,
+    subscribers: Gauge,
+    waiters: Gauge,
+}
+
+impl TipSubscribers {
+    fn start(query: Arc<Query>, metrics: &Metrics) -> TipSubscribers {
+        let subscribers = TipSubscribers {
+            listeners: Arc::new(Mutex::new(TipListeners::default())),
+            subscribers: metrics.gauge(MetricOpts::new(
+                "rest_tip_subscribers",
+                "# of streams of new tips (including the event streams)",
+            )),
+            waiters: metrics.gauge(MetricOpts::new(
+                "rest_tip_waiters",
+                "# of pending new tip long-polls",
+            )),
+        };
+        let cloned = subscribers.clone();
+        spawn_thread("tip_subscribers", move || cloned.run(&query));
+        subscribers
+    }
+
+    // Streams the current tip, then the next ones.
+    fn subscribe(
+        &self,
+        query: &Query,
+        format: TipFormat,
+        mempool_interval: Option<Duration>,
+    ) -> Result<Response<Body>, HttpError> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.subscribers.len() >= TIP_SUBSCRIBERS_MAX {
+            bail!(HttpError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many subscribers, please try again later".to_string()
+            ));
+        }
+        let tip = query.get_best_header()?;
+        let (sender, receiver) = mpsc::channel::<Result<Chunk, io::Error>>(STREAM_BUFFER);
+        let mut subscriber = TipSubscriber::new(sender, format, mempool_interval);
+        subscriber.send_tip(*tip.hash(), &tip_line(&tip)?, &tip_event(&tip)?);
+        listeners.subscribers.push(subscriber);
+        self.subscribers.set(listeners.subscribers.len() as i64);
+
+        let body = receiver
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "stream failed"))
+            .and_then(|line| line);
+        let content_type = match format {
+            TipFormat::Json => "application/x-ndjson",
+            TipFormat::Sse => "text/event-stream",
+        };
+        Ok(Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", "no-cache")
+            .body(Body::wrap_stream(body))
+            .unwrap())
+    }
+
+    // Responds with the tip's hash once it's not `last` (right away if it already isn't).
+    fn wait(
+        &self,
+        query: &Query,
+        last: Option<Sha256dHash>,
+        timeout: Duration,
+    ) -> Result<Response<Body>, HttpError> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let tip = query.get_best_header_hash();
+        let last = match last {
+            Some(last) if last == tip && timeout > Duration::from_secs(0) => last,
+            _ => return http_message(StatusCode::OK, tip.be_hex_string(), TTL_SHORT),
+        };
+        if listeners.waiters.len() >= TIP_WAITERS_MAX {
+            bail!(HttpError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many pending requests, please try again later".to_string()
+            ));
+        }
+        let (sender, receiver) = oneshot::channel();
+        listeners.waiters.push(TipWaiter {
+            last,
+            deadline: Instant::now() + timeout,
+            sender,
+        });
+        self.waiters.set(listeners.waiters.len() as i64);
+
+        let body = receiver
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "wait failed"))
+            .and_then(|value| value)
+            .into_stream();
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Cache-Control", "no-cache")
+            .body(Body::wrap_stream(body))
+            .unwrap())
+    }
+
+    fn run(&self, query: &Query) {
+        let mut updates = query.update_count();
+        loop {
+            updates = query.wait_for_update(updates, Duration::from_secs(TX_WAIT_CHECK_INTERVAL));
+            let mut listeners = self.listeners.lock().unwrap();
+            if listeners.subscribers.is_empty() && listeners.waiters.is_empty() {
+                continue;
+            }
+            let tip = match query.get_best_header() {
+                Ok(tip) => tip,
+                Err(_) => continue, // no headers indexed yet
+            };
+            let now = Instant::now();
+            let waiters = mem::replace(&mut listeners.waiters, vec![]);
+            listeners.waiters = check_tip_waiters(waiters, *tip.hash(), now);
+            match (tip_line(&tip), tip_event(&tip)) {
+                (Ok(line), Ok(event)) => {
+                    notify_tip(&mut listeners.subscribers, *tip.hash(), &line, &event)
+                }
+                _ => warn!("failed to format tip {}", tip.hash()),
+            }
+            notify_mempool(&mut listeners.subscribers, now, || {
+                mempool_event(&query.get_mempool_summary())
+            });
+            self.subscribers.set(listeners.subscribers.len() as i64);
+            self.waiters.set(listeners.waiters.len() as i64);
+        }
+    }
+}
+
+// Sends the tip to the subscribers that haven't got it yet, dropping the ones that can't get it.
+fn notify_tip(subscribers: &mut Vec<TipSubscriber>, hash: Sha256dHash, line: &str, event: &str) {
+    let pending = mem::replace(subscribers, vec![]);
+    subscribers.extend(pending.into_iter().filter_map(|mut subscriber| {
+        if subscriber.send_tip(hash, line, event) {
+            Some(subscriber)
+        } else {
+            None
+        }
+    }));
+}
+
+// Sends the mempool summary to the event streams it's due to (computing it only if there are
+// any), dropping the ones that can't get it.
+fn notify_mempool<F>(subscribers: &mut Vec<TipSubscriber>, now: Instant, event: F)
+where
+    F: FnOnce() -> String,
+{
+    if !subscribers
+        .iter()
+        .any(|subscriber| subscriber.mempool_due(now))
+    {
+        return;
+    }
+    let event = event();
+    let pending = mem::replace(subscribers, vec![]);
+    subscribers.extend(pending.into_iter().filter_map(|mut subscriber| {
+        if !subscriber.mempool_due(now) {
+            return Some(subscriber);
+        }
+        subscriber.mempool_sent = now;
+        if subscriber.send(event.clone()) {
+            Some(subscriber)
+        } else {
+            None
+        }
+    }));
+}
+
+// Responds to the waiters whose tip changed or whose timeout expired, returning the other ones.
+fn check_tip_waiters(waiters: Vec<TipWaiter>, tip: Sha256dHash, now: Instant) -> Vec<TipWaiter> {
+    waiters
+        .into_iter()
+        .filter_map(|waiter| {
+            if waiter.sender.is_canceled() {
+                return None; // client disconnected
+            }
+            if waiter.last == tip && now < waiter.deadline {
+                return Some(waiter);
+            }
+            // fails only if the client disconnected meanwhile
+            let _ = waiter.sender.send(Ok(Chunk::from(tip.be_hex_string())));
+            None
+        })
+        .collect()
+}
+
+// Like Electrum's header notifications (with the raw header's hex).
+fn tip_line(tip: &HeaderEntry) -> Result<String, HttpError> {
+    let mut line = serde_json::to_string(&json!({
+        "height": tip.height(),
+        "hex": hex::encode(serialize(tip.header())),
+    }))?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn tip_event(tip: &HeaderEntry) -> Result<String, HttpError> {
+    let data = serde_json::to_string(&json!({
+        "hash": tip.hash().be_hex_string(),
+        "height": tip.height(),
+    }))?;
+    Ok(format!("event: block\ndata: {}\n\n", data))
+}
+
+fn mempool_event(summary: &MempoolSummary) -> String {
+    format!(
+        "event: mempool\ndata: {}\n\n",
+        serde_json::to_string(summary).unwrap()
+    )
+}
+
+fn tx_wait_done(status: Option<&TransactionStatus>, confirmed: bool) -> bool {
+    match status {
+        Some(status) => status.confirmed || !confirmed,
+        None => false,
+    }
+}
+
+fn tx_wait_value(status: Option<TransactionStatus>) -> Result<String, HttpError> {
+    Ok(serde_json::to_string(&json!({
+        "seen": status.is_some(),
+        "status": status,
+    }))?)
+}
+
+fn tx_wait_response(body: Body) -> Response<Body> {
+    Response::builder()
         .header("Content-Type", "application/json")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
-        .body(Body::from(value))
-        .unwrap())
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
 }
 
 fn blocks(query: &Arc<Query>, start_height: Option<usize>) -> Result<Response<Body>, HttpError> {
@@ -709,6 +3325,12 @@ fn blocks(query: &Arc<Query>, start_height: Option<usize>) -> Result<Response<Bo
     json_response(values, TTL_SHORT)
 }
 
+// A transaction with this many confirmations would have survived the deepest reorg seen so far
+// (orphaning `max_reorg_depth` blocks), with a margin for the deeper ones yet to come.
+fn recommended_confirmations(max_reorg_depth: usize) -> usize {
+    max_reorg_depth + 1 + CONFIRMATIONS_MARGIN
+}
+
 fn to_scripthash(
     script_type: &str,
     script_str: &str,
@@ -716,24 +3338,54 @@ fn to_scripthash(
 ) -> Result<FullHash, HttpError> {
     match script_type {
         "address" => address_to_scripthash(script_str, network),
-        "scripthash" => Ok(full_hash(&hex::decode(script_str)?)),
+        "scripthash" => parse_scripthash(script_str),
+        "script" => script_to_scripthash(script_str),
         _ => bail!("Invalid script type".to_string()),
     }
 }
 
-fn address_to_scripthash(addr: &str, network: &Network) -> Result<FullHash, HttpError> {
-    let addr = Address::from_str(addr)?;
-    if addr.network != *network
-        && !(addr.network == Network::Testnet && *network == Network::Regtest)
-    {
-        bail!(HttpError::from("Address on invalid network".to_string()))
+// Any script is indexed by its hash, including the ones without an address (e.g. bare multisig
+// or OP_RETURN outputs).
+fn script_to_scripthash(script_str: &str) -> Result<FullHash, HttpError> {
+    let script = hex::decode(script_str)?;
+    Ok(compute_script_hash(&script))
+}
+
+// A malformed scripthash fails the request, while a never used one gets an empty history (and
+// zero balances), like any other scripthash.
+fn parse_scripthash(script_str: &str) -> Result<FullHash, HttpError> {
+    let hash = hex::decode(script_str)?;
+    if hash.len() != HASH_LEN {
+        bail!(HttpError::from(format!(
+            "Invalid scripthash: expected {} bytes, got {}",
+            HASH_LEN,
+            hash.len()
+        )));
     }
-    Ok(compute_script_hash(&addr.script_pubkey().into_bytes()))
+    Ok(full_hash(&hash))
+}
+
+fn address_to_scripthash(addr: &str, network: &Network) -> Result<FullHash, HttpError> {
+    let script = parse_address(addr, network)?;
+    Ok(compute_script_hash(&script.into_bytes()))
+}
+
+fn parse_address(addr: &str, network: &Network) -> Result<Script, HttpError> {
+    address_to_script(addr, network).map_err(|e| HttpError::from(e.to_string()))
 }
 
 #[derive(Debug)]
 struct HttpError(StatusCode, String);
 
+// While syncing (with --serve-during-sync), what isn't found may be in the blocks still to index.
+fn while_syncing(e: HttpError, query: &Query) -> HttpError {
+    if e.0 == StatusCode::NOT_FOUND && query.is_syncing() {
+        let syncing = errors::ErrorKind::Syncing(query.get_best_height());
+        return HttpError(StatusCode::SERVICE_UNAVAILABLE, syncing.to_string());
+    }
+    e
+}
+
 impl HttpError {
     fn not_found(msg: String) -> Self {
         HttpError(StatusCode::NOT_FOUND, msg)
@@ -768,6 +3420,18 @@ impl From<FromHexError> for HttpError {
 }
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
+        if let errors::ErrorKind::HistoryTooLarge(_) = *e.kind() {
+            // refused by policy (--max-history), not worth a warning
+            return HttpError(StatusCode::PAYLOAD_TOO_LARGE, e.to_string());
+        }
+        if let errors::ErrorKind::Syncing(_) = *e.kind() {
+            return HttpError(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+        }
+        if let errors::ErrorKind::BlockUnavailable(_) = *e.kind() {
+            // still failing after the retries (--block-fetch-retries), unlike an unknown block
+            warn!("{}", e);
+            return HttpError(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+        }
         warn!("errors::Error: {:?}", e);
         match e.description().to_string().as_ref() {
             "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
@@ -797,9 +3461,840 @@ impl From<encode::Error> for HttpError {
 
 #[cfg(test)]
 mod tests {
-    use rest::HttpError;
-    use serde_json::Value;
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::Sha256dHash;
+    use bitcoin::{BitcoinHash, OutPoint, Script, Transaction, TxIn, TxOut};
+    use config::ConfigBuilder;
+    use errors::{self, ResultExt};
+    use futures::sync::{mpsc, oneshot};
+    use futures::Stream;
+    use hex;
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use hyper::rt::{self, Future};
+    use hyper::service::service_fn_ok;
+    use hyper::Request;
+    use hyper::{Body, Method, Response, Server, StatusCode};
+    use index::compute_script_hash;
+    use mempool::MEMPOOL_HEIGHT;
+    use metrics::Metrics;
+    use query::{FundingOutput, OutputSpend, ScriptStats, TxnHeight};
+    use rest::{
+        address_balance, address_utxo, authenticated, authorize_admin, bind_unix_socket,
+        busy_response, check_tip_waiters, client_addr, forwarded_for, http_message, json_response,
+        mempool_param, merge_txids, notify_mempool, notify_tip, overloaded_response, parse_address,
+        parse_address_query, preflight_response, read_rpc_body, recommended_confirmations,
+        route_label, rpc_result, search_matches, set_response_headers, throttled_response,
+        to_scripthash, tx_wait_done, tx_wait_value, unauthorized_response, AddressQuery,
+        Backpressure, CompactionGate, ConnectionReuse, Cost, HttpError, RateLimiter, RequestPool,
+        RpcError, SearchMatch, SpendingValue, TipFormat, TipSubscriber, TipWaiter,
+        TransactionValue, TxOutValue, TxStatusValue, TxSummaryValue, UtxoValue,
+        ValidateAddressValue, RPC_INTERNAL_ERROR, RPC_INVALID_ADDRESS_OR_KEY,
+        RPC_INVALID_PARAMETER, RPC_INVALID_PARAMS, RPC_INVALID_REQUEST, RPC_IN_WARMUP,
+        RPC_MAX_ADDRESSES, RPC_MAX_BODY, RPC_METHOD_NOT_FOUND, RPC_MISC_ERROR, STREAM_BUFFER,
+        TTL_LONG, TTL_SHORT,
+    };
+    use serde_json::{self, Value};
     use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testutil::temp_path;
+    use util::{HeaderEntry, TransactionStatus};
+
+    #[test]
+    fn test_bind_unix_socket() {
+        let dir = temp_path("unix-socket");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rest.sock");
+
+        // a stale socket, left behind by an unclean shutdown, is replaced
+        drop(net::UnixListener::bind(&path).unwrap());
+        assert!(net::UnixStream::connect(&path).is_err());
+        let listener = bind_unix_socket(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        net::UnixStream::connect(&path).unwrap();
+        drop(listener);
+
+        // but anything else is left alone
+        let file = dir.join("rest.txt");
+        fs::write(&file, "not a socket").unwrap();
+        let err = bind_unix_socket(&file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "not a socket");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_txout_value() {
+        for bytes in vec![
+            vec![0x4c],                               // OP_PUSHDATA1 without a length
+            vec![0x4e, 0xff, 0xff, 0xff, 0xff, 0x01], // OP_PUSHDATA4 past the end
+            vec![0x76, 0xa9, 0x14, 0x01],             // truncated p2pkh
+        ] {
+            let value = TxOutValue::from(TxOut {
+                value: 1,
+                script_pubkey: Script::from(bytes.clone()),
+            });
+            assert_eq!(value.scriptpubkey_type, "nonstandard");
+            assert!(value.scriptpubkey_address.is_none());
+            let json: Value =
+                serde_json::from_str(&serde_json::to_string(&value).unwrap()).unwrap();
+            assert_eq!(json["scriptpubkey"].as_str().unwrap(), hex::encode(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_recommended_confirmations() {
+        assert_eq!(recommended_confirmations(0), 6); // the usual advice, without reorgs
+        assert_eq!(recommended_confirmations(1), 7);
+        assert_eq!(recommended_confirmations(10), 16);
+    }
+
+    #[test]
+    fn test_tx_status_value() {
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root: Sha256dHash::default(),
+            time: 1234,
+            bits: 0,
+            nonce: 0,
+        };
+        let entry = HeaderEntry::new(100, header);
+        let value = serde_json::to_value(TxStatusValue::from(Some((entry, 3)))).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "confirmed": true,
+                "block_height": 100,
+                "block_hash": header.bitcoin_hash().be_hex_string(),
+                "block_time": 1234,
+                "confirmations": 3,
+            })
+        );
+
+        // in the mempool, or reorged away (see HeaderList::confirmations)
+        let value = serde_json::to_value(TxStatusValue::from(None)).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "confirmed": false,
+                "block_height": null,
+                "block_hash": null,
+                "block_time": null,
+                "confirmations": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_txout_value_script_types() {
+        let hash20: Vec<u8> = (1..21).collect();
+        let hash32: Vec<u8> = (1..33).collect();
+        let script = |parts: &[&[u8]]| Script::from(parts.concat());
+        let cases = vec![
+            (script(&[&[0x21, 0x02], &hash32, &[0xac]]), "p2pk", None),
+            (
+                script(&[&[0x76, 0xa9, 0x14], &hash20, &[0x88, 0xac]]),
+                "p2pkh",
+                Some("mfcHP2WMCVLsVZA8yrovmhMgxNFW9r98xw"),
+            ),
+            (
+                script(&[&[0xa9, 0x14], &hash20, &[0x87]]),
+                "p2sh",
+                Some("2MsLZ5FqqYpjM1Q1W4X81zMVZTF9gdbhVwd"),
+            ),
+            (
+                script(&[&[0x00, 0x14], &hash20]),
+                "v0_p2wpkh",
+                Some("bcrt1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5phstwt"),
+            ),
+            (
+                script(&[&[0x00, 0x20], &hash32]),
+                "v0_p2wsh",
+                Some("bcrt1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5z5tpwxqergd3c8g7rusq7snjn6"),
+            ),
+            (script(&[&[0x6a, 0x01, 0x00]]), "op_return", None),
+            (Script::new(), "empty", None),
+            (script(&[&[0x51]]), "nonstandard", None),
+        ];
+        for (script_pubkey, script_type, address) in cases {
+            let bytes = script_pubkey[..].to_vec();
+            let value = TxOutValue::new(
+                TxOut {
+                    value: 1,
+                    script_pubkey,
+                },
+                &Network::Regtest,
+            );
+            assert_eq!(value.scriptpubkey_type, script_type);
+            assert_eq!(
+                value.scriptpubkey_address,
+                address.map(|address| address.to_owned())
+            );
+            let json: Value =
+                serde_json::from_str(&serde_json::to_string(&value).unwrap()).unwrap();
+            assert_eq!(json["scriptpubkey"].as_str().unwrap(), hex::encode(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_tx_summary_value() {
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: Script::new(),
+        };
+        let txin = || TxIn {
+            previous_output: OutPoint {
+                txid: Sha256dHash::default(),
+                vout: 0,
+            },
+            script_sig: Script::new(),
+            sequence: 0xffff_ffff,
+            witness: vec![],
+        };
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![txin(), txin()],
+            output: vec![txout(1000), txout(0), txout(234)],
+        };
+        let summary = TxSummaryValue::from(&txn);
+        assert_eq!(
+            summary,
+            TxSummaryValue {
+                vin_count: 2,
+                vout_count: 3,
+                total_out_value: 1234,
+            }
+        );
+
+        // the same counts as /tx/:txid
+        let full = serde_json::to_value(&TransactionValue::from(txn)).unwrap();
+        assert_eq!(full["vin"].as_array().unwrap().len(), summary.vin_count);
+        assert_eq!(full["vout"].as_array().unwrap().len(), summary.vout_count);
+    }
+
+    #[test]
+    fn test_spending_value() {
+        let json = |value: SpendingValue| serde_json::to_value(&value).unwrap();
+        assert_eq!(json(SpendingValue::default()), json!({ "spent": false }));
+
+        let txid = Sha256dHash::from_hex(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        )
+        .unwrap();
+        let value = json(SpendingValue::from(OutputSpend {
+            txid,
+            vin: 1,
+            status: TransactionStatus::unconfirmed(),
+        }));
+        assert_eq!(value["spent"], true);
+        assert_eq!(value["txid"], txid.be_hex_string());
+        assert_eq!(value["vin"], 1);
+        assert_eq!(value["status"]["confirmed"], false);
+    }
+
+    #[test]
+    fn test_validate_address() {
+        let validate = |addr, network| {
+            let value = ValidateAddressValue::new(addr, &network);
+            serde_json::to_value(&value).unwrap()
+        };
+        let value = validate("EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp", Network::Bitcoin);
+        assert_eq!(value["isvalid"], true);
+        assert_eq!(value["type"], "p2sh");
+        assert_eq!(
+            value["scriptpubkey"],
+            "a9140102030405060708090a0b0c0d0e0f101112131487"
+        );
+        let script = hex::decode("a9140102030405060708090a0b0c0d0e0f101112131487").unwrap();
+        assert_eq!(
+            value["scripthash"].as_str().unwrap(),
+            hex::encode(compute_script_hash(&script))
+        );
+        let value = validate(
+            "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+            Network::Bitcoin,
+        );
+        assert_eq!(value["type"], "v0_p2wpkh");
+
+        // wrong network, bad checksum and garbage are all reported the same way
+        for (addr, network) in vec![
+            ("EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp", Network::Testnet),
+            (
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+                Network::Regtest,
+            ),
+            ("Va69zgapoGopnDag15VBNgUDnBwmjFWfP5", Network::Bitcoin),
+            ("notanaddress", Network::Bitcoin),
+        ] {
+            assert_eq!(validate(addr, network), json!({ "isvalid": false }));
+        }
+    }
+
+    #[test]
+    fn test_search_matches() {
+        let block = Sha256dHash::from_data(b"block");
+        let tx = Sha256dHash::from_data(b"tx");
+        let search = |q: &str| {
+            search_matches(
+                q,
+                &Network::Bitcoin,
+                |height| if height == 10 { Some(block) } else { None },
+                |hash| *hash == block,
+                |txid| Ok(*txid == tx || *txid == block),
+            )
+            .unwrap()
+        };
+        let block_match = SearchMatch::new("block", block.be_hex_string());
+        assert_eq!(search("10"), vec![block_match]);
+        assert!(search("11").is_empty());
+        assert_eq!(
+            search(&tx.be_hex_string()),
+            vec![SearchMatch::new("tx", tx.be_hex_string())]
+        );
+        // ambiguous: both of them are reported
+        let matches = search(&block.be_hex_string());
+        assert_eq!(
+            matches.iter().map(|m| m.match_type).collect::<Vec<_>>(),
+            vec!["block", "tx"]
+        );
+        assert_eq!(matches[1].path, format!("/tx/{}", block.be_hex_string()));
+        let address = "EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp";
+        assert_eq!(
+            search(address),
+            vec![SearchMatch::new("address", address.to_string())]
+        );
+        assert!(search("notfound").is_empty());
+        assert!(search(&"z".repeat(64)).is_empty());
+    }
+
+    #[test]
+    fn test_route_label() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let address = "VdMPvn7vUTSzbYjiMDs1jku9wAh1Ri2Y1A";
+        for (path, route) in vec![
+            ("/blocks/tip/height", "/blocks/tip/height"),
+            ("/blocks/tip/locator", "/blocks/tip/locator"),
+            ("/blocks", "/blocks/:start_height"),
+            ("/blocks/at-time/1546300800", "/blocks/at-time/:timestamp"),
+            ("/blocks/1234", "/blocks/:start_height"),
+            ("/block-height/10/time", "/block-height/:height/time"),
+            ("/block/TXID/header", "/block/:hash/header"),
+            ("/block/TXID/raw", "/block/:hash/raw"),
+            ("/block/TXID/output-types", "/block/:hash/output-types"),
+            ("/block-header/TXID/raw", "/block-header/:hash/raw"),
+            ("/admin/tx-cache", "/admin/tx-cache"),
+            ("/admin/compact", "/admin/compact"),
+            ("/block/TXID/txs/25", "/block/:hash/txs"),
+            ("/block/TXID/txs/stream", "/block/:hash/txs/stream"),
+            ("/tx/TXID", "/tx/:txid"),
+            ("/tx/TXID/outspend/1", "/tx/:txid/outspend/:vout"),
+            ("/tx/TXID/cpfp", "/tx/:txid/cpfp"),
+            ("/tx/TXID/summary", "/tx/:txid/summary"),
+            ("/mempool/tx/TXID", "/mempool/tx/:txid"),
+            ("/mempool/min-fee", "/mempool/min-fee"),
+            ("/mempool", "/mempool"),
+            ("/confirmations/recommended", "/confirmations/recommended"),
+            ("/summary", "/summary"),
+            ("/status", "/status"),
+            ("/address/ADDR/txs", "/address/:address/txs"),
+            ("/address/ADDR/txs/25", "/address/:address/txs"),
+            ("/address/ADDR/txs/stream", "/address/:address/txs/stream"),
+            ("/address/ADDR/txs/recent", "/address/:address/txs/recent"),
+            (
+                "/scripthash/TXID/txs/recent",
+                "/scripthash/:hash/txs/recent",
+            ),
+            (
+                "/address/ADDR/scriptpubkey",
+                "/address/:address/scriptpubkey",
+            ),
+            ("/scripthash/TXID/utxo", "/scripthash/:hash/utxo"),
+            ("/address/ADDR/balance", "/address/:address/balance"),
+            ("/scripthash/TXID/balance", "/scripthash/:hash/balance"),
+            (
+                "/scripthash/TXID/first-funding",
+                "/scripthash/:hash/first-funding",
+            ),
+            (
+                "/address/ADDR/first-funding",
+                "/address/:address/first-funding",
+            ),
+            ("/tx/TXID/wait", "/tx/:txid/wait"),
+            ("/events", "/events"),
+            ("/address-prefix/via1q", "/address-prefix/:prefix"),
+            ("/validate-address/ADDR", "/validate-address/:address"),
+            ("/search/TXID", "/search/:query"),
+            ("/opreturn/abcd", "/opreturn/:hexprefix"),
+            ("/admin/status", "/admin/status"),
+            ("/admin/db", "/admin/db"),
+            ("/internal/status", "/internal/status"),
+            ("/script/6a04deadbeef/txs", "/script/:hex/txs"),
+            ("/tx/TXID/unknown", "other"),
+            ("/unknown", "other"),
+        ] {
+            let path = path.replace("TXID", txid).replace("ADDR", address);
+            assert_eq!(route_label(&Method::GET, &path), route, "{}", path);
+        }
+        assert_eq!(route_label(&Method::POST, "/tx"), "other");
+        assert_eq!(route_label(&Method::POST, "/rpc"), "/rpc");
+        assert_eq!(route_label(&Method::GET, "/rpc"), "other");
+    }
+
+    #[test]
+    fn test_parse_address_query() {
+        let (a, b) = (
+            "EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp",
+            "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+        );
+        let parse = |params: Value| parse_address_query(&params);
+        assert_eq!(
+            parse(json!([a])).unwrap(),
+            AddressQuery {
+                addresses: vec![a.to_string()],
+                heights: None,
+                chain_info: false,
+            }
+        );
+        assert_eq!(
+            parse(json!([{ "addresses": [a, b, a], "start": 10, "end": 20, "chainInfo": true }]))
+                .unwrap(),
+            AddressQuery {
+                addresses: vec![a.to_string(), b.to_string()],
+                heights: Some((10, 20)),
+                chain_info: true,
+            }
+        );
+        // like viacoind, a single bound is ignored
+        assert_eq!(
+            parse(json!([{ "addresses": [a], "start": 10 }]))
+                .unwrap()
+                .heights,
+            None
+        );
+
+        let error = |params: Value| parse(params).unwrap_err().0;
+        assert_eq!(error(json!([])), RPC_INVALID_ADDRESS_OR_KEY);
+        assert_eq!(error(json!([1])), RPC_INVALID_ADDRESS_OR_KEY);
+        assert_eq!(
+            error(json!([{ "addresses": a }])),
+            RPC_INVALID_ADDRESS_OR_KEY
+        );
+        assert_eq!(
+            error(json!([{ "addresses": [1] }])),
+            RPC_INVALID_ADDRESS_OR_KEY
+        );
+        assert_eq!(error(json!({ "addresses": [a] })), RPC_INVALID_REQUEST);
+        assert_eq!(
+            error(json!([{ "addresses": [a], "start": 0, "end": 20 }])),
+            RPC_INVALID_PARAMETER
+        );
+        assert_eq!(
+            error(json!([{ "addresses": [a], "start": 20, "end": 10 }])),
+            RPC_INVALID_PARAMETER
+        );
+        let many: Vec<String> = (0..=RPC_MAX_ADDRESSES).map(|i| i.to_string()).collect();
+        assert_eq!(error(json!([{ "addresses": many }])), RPC_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_merge_txids() {
+        let txid = |n: u8| Sha256dHash::from_data(&[n]);
+        let first = vec![
+            (5, txid(1)),
+            (5, txid(2)),
+            (8, txid(3)),
+            (MEMPOOL_HEIGHT, txid(4)),
+        ];
+        let second = vec![(3, txid(5)), (8, txid(3)), (9, txid(6))];
+
+        // a single address' confirmed history, as listed (newest first) by /address/:address/txs
+        assert_eq!(
+            merge_txids(vec![first.clone()], None),
+            vec![txid(1), txid(2), txid(3)]
+        );
+        assert_eq!(
+            merge_txids(vec![first.clone(), second.clone()], None),
+            vec![txid(5), txid(1), txid(2), txid(3), txid(6)]
+        );
+        assert_eq!(
+            merge_txids(vec![first, second], Some((5, 8))),
+            vec![txid(1), txid(2), txid(3)]
+        );
+    }
+
+    #[test]
+    fn test_address_balance() {
+        let stats = |funded_txo_sum, spent_txo_sum| ScriptStats {
+            funded_txo_sum,
+            spent_txo_sum,
+            ..ScriptStats::default()
+        };
+        // the chain_stats of /address/:address
+        assert_eq!(
+            address_balance(&[stats(500, 200)]),
+            json!({ "balance": 300, "received": 500 })
+        );
+        assert_eq!(
+            address_balance(&[stats(500, 200), stats(50, 50)]),
+            json!({ "balance": 300, "received": 550 })
+        );
+        assert_eq!(address_balance(&[]), json!({ "balance": 0, "received": 0 }));
+    }
+
+    #[test]
+    fn test_address_utxo() {
+        let address = "EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp";
+        let script = parse_address(address, &Network::Bitcoin).unwrap();
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let output = FundingOutput {
+            txn_id: txn.txid(),
+            txn: Some(TxnHeight {
+                txn,
+                height: 100,
+                blockhash: Sha256dHash::default(),
+            }),
+            height: 100,
+            output_index: 2,
+            value: 1234,
+        };
+        let utxo = address_utxo(address, &script, &output);
+        assert_eq!(utxo["address"], address);
+        assert_eq!(utxo["script"], hex::encode(script.as_bytes()));
+
+        // the same output as listed by /address/:address/utxo
+        let native = serde_json::to_value(&UtxoValue::from(output)).unwrap();
+        assert_eq!(utxo["txid"], native["txid"]);
+        assert_eq!(utxo["outputIndex"], native["vout"]);
+        assert_eq!(utxo["satoshis"], native["value"]);
+        assert_eq!(utxo["height"], native["status"]["block_height"]);
+    }
+
+    #[test]
+    fn test_rpc_errors() {
+        let error = |kind: errors::ErrorKind| RpcError::from(errors::Error::from(kind));
+        assert_eq!(
+            error(errors::ErrorKind::Syncing(123)),
+            RpcError(
+                RPC_IN_WARMUP,
+                "still syncing, best height is 123".to_string()
+            )
+        );
+        assert_eq!(
+            error(errors::ErrorKind::Warmup("x".to_string())).0,
+            RPC_IN_WARMUP
+        );
+        assert_eq!(
+            error(errors::ErrorKind::HistoryTooLarge(10)),
+            RpcError(
+                RPC_MISC_ERROR,
+                "history too large: more than 10 confirmed funding transactions".to_string()
+            )
+        );
+        assert_eq!(
+            RpcError::from(errors::Error::from(
+                "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}"
+            )),
+            RpcError(RPC_INVALID_ADDRESS_OR_KEY, "Block not found".to_string())
+        );
+        // the internal errors' details aren't sent to the client
+        for e in vec![
+            errors::Error::from("secret details"),
+            errors::Error::from(errors::ErrorKind::Connection("secret details".to_string()))
+                .chain_err(|| "failed to query the daemon"),
+        ] {
+            let RpcError(code, message) = RpcError::from(e);
+            assert_eq!(code, RPC_INTERNAL_ERROR);
+            assert_eq!(message, "We encountered an error. Please try again later.");
+        }
+        // malformed params
+        assert_eq!(
+            RpcError::from(HttpError::from(hex::decode("zz").unwrap_err())),
+            RpcError(RPC_INVALID_PARAMS, "Invalid hex string".to_string())
+        );
+        assert_eq!(
+            RpcError::from(parse_address("notanaddress", &Network::Bitcoin).unwrap_err()).0,
+            RPC_INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn test_read_rpc_body() {
+        let read = |body: Vec<u8>| {
+            let req = Request::post("/rpc").body(Body::from(body)).unwrap();
+            let req = read_rpc_body(req).wait().unwrap();
+            assert_eq!(req.uri().path(), "/rpc");
+            req.into_body().concat2().wait().unwrap().to_vec()
+        };
+        assert_eq!(read(b"{}".to_vec()), b"{}".to_vec());
+        // enough of an oversized body is kept for the request to be rejected
+        let oversized = read(vec![b' '; 2 * RPC_MAX_BODY]);
+        assert!(oversized.len() > RPC_MAX_BODY);
+    }
+
+    #[test]
+    fn test_rpc_result() {
+        assert_eq!(
+            rpc_result(json!(1), Ok(json!(["txid"]))),
+            json!({ "result": ["txid"], "error": null, "id": 1 })
+        );
+        assert_eq!(
+            rpc_result(
+                json!("a"),
+                Err(RpcError(
+                    RPC_METHOD_NOT_FOUND,
+                    "Method not found".to_string()
+                ))
+            ),
+            json!({
+                "result": null,
+                "error": { "code": -32601, "message": "Method not found" },
+                "id": "a",
+            })
+        );
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let header_value = |response: &Response<Body>, name: header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+        for (path, ttl, cache_control) in vec![
+            ("/tx/TXID/hex", TTL_LONG, "public, max-age=157784630"),
+            ("/block/TXID", TTL_LONG, "public, max-age=157784630"),
+            ("/tx/TXID/status", TTL_SHORT, "no-store"), // its confirmations change with the tip
+            ("/blocks/tip/hash", TTL_SHORT, "no-store"),
+            ("/address/ADDR/txs", TTL_SHORT, "no-store"),
+            ("/address/ADDR/first-funding", TTL_SHORT, "no-store"),
+            ("/scripthash/TXID/utxo", TTL_SHORT, "no-store"),
+            ("/mempool/tx/TXID", TTL_SHORT, "no-store"),
+        ] {
+            let path = path.replace("TXID", txid);
+            let route = route_label(&Method::GET, &path);
+            let mut response = http_message(StatusCode::OK, "".to_string(), ttl).unwrap();
+            set_response_headers(&mut response, route, None);
+            assert_eq!(
+                header_value(&response, header::CACHE_CONTROL),
+                Some(cache_control.to_owned()),
+                "{}",
+                path
+            );
+            assert_eq!(
+                header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                None
+            );
+
+            let mut response = json_response(json!([]), ttl).unwrap();
+            set_response_headers(&mut response, route, Some("https://wallet.example"));
+            assert_eq!(
+                header_value(&response, header::CACHE_CONTROL),
+                Some(cache_control.to_owned()),
+                "{}",
+                path
+            );
+            assert_eq!(
+                header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                Some("https://wallet.example".to_owned())
+            );
+        }
+
+        let response = preflight_response("*");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*".to_owned())
+        );
+        assert!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap()
+                .contains("GET")
+        );
+    }
+
+    #[test]
+    fn test_mempool_param() {
+        let param = |uri: &str| mempool_param(&uri.parse().unwrap()).ok();
+        assert_eq!(param("/address/ADDR/utxo"), Some(true));
+        assert_eq!(param("/address/ADDR/utxo?mempool=true"), Some(true));
+        assert_eq!(param("/address/ADDR/utxo?mempool=false"), Some(false));
+        assert_eq!(
+            param("/address/ADDR/txs?after_txid=TXID&mempool=false"),
+            Some(false)
+        );
+        assert_eq!(param("/address/ADDR/utxo?mempool=0"), None);
+    }
+
+    #[test]
+    fn test_to_scripthash() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let parse = |script_type: &str, script_str: &str| {
+            to_scripthash(script_type, script_str, &Network::Bitcoin).map_err(|e| e.0)
+        };
+        assert_eq!(hex::encode(parse("scripthash", hash).unwrap()), hash);
+        assert_eq!(
+            hex::encode(parse("scripthash", &hash.to_uppercase()[..]).unwrap()),
+            hash
+        );
+        // malformed scripthashes (of the wrong length, or not hex) are bad requests
+        for script_str in vec![
+            &hash[..62],
+            &format!("{}00", hash)[..],
+            &hash[1..],
+            "notahash",
+            "",
+        ] {
+            assert_eq!(
+                parse("scripthash", script_str).unwrap_err(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(
+            parse("address", "notanaddress").unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+        // the raw script is hashed (here an empty one)
+        assert_eq!(hex::encode(parse("script", "").unwrap()), hash);
+        let multisig = "5121020202020202020202020202020202020202020202020202020202020202020202\
+                        2103030303030303030303030303030303030303030303030303030303030303030352ae";
+        assert_eq!(
+            parse("script", multisig).unwrap(),
+            compute_script_hash(&hex::decode(multisig).unwrap())
+        );
+        assert_eq!(parse("script", "6a0").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let script = |addr: &str, network| {
+            parse_address(addr, &network)
+                .map(|script| hex::encode(script.as_bytes()))
+                .map_err(|e| e.0)
+        };
+        // the scripts of each address type, with the hashes 0x01..0x14 and 0x01..0x20
+        let cases = vec![
+            (
+                Network::Bitcoin,
+                "Va69zgapoGopnDag15VBNgUDnBwmjFWfP4",
+                "76a9140102030405060708090a0b0c0d0e0f101112131488ac",
+            ),
+            (
+                Network::Bitcoin,
+                "EHFEaZFspRCXhkHP58q4wv8Ks29vhY28Rp",
+                "a9140102030405060708090a0b0c0d0e0f101112131487",
+            ),
+            (
+                Network::Bitcoin,
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+                "00140102030405060708090a0b0c0d0e0f1011121314",
+            ),
+            (
+                Network::Bitcoin,
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5z5tpwxqergd3c8g7rusq0u024e",
+                "00200102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+            ),
+            (
+                Network::Testnet,
+                "t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs",
+                "76a9140102030405060708090a0b0c0d0e0f101112131488ac",
+            ),
+            (
+                Network::Testnet,
+                "tvia1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc550402a",
+                "00140102030405060708090a0b0c0d0e0f1011121314",
+            ),
+        ];
+        for (network, addr, hex) in cases {
+            assert_eq!(script(addr, network).unwrap(), hex);
+        }
+        // malformed, or of another network
+        for (network, addr) in vec![
+            (Network::Bitcoin, "notanaddress"),
+            (Network::Bitcoin, "t71w8mGxZNktaVPXNa92XhiH2SNbZCTEGs"),
+            (
+                Network::Testnet,
+                "via1qqypqxpq9qcrsszg2pvxq6rs0zqg3yyc5rakxeh",
+            ),
+        ] {
+            assert_eq!(script(addr, network).unwrap_err(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_history_too_large() {
+        let err = HttpError::from(errors::Error::from(errors::ErrorKind::HistoryTooLarge(10)));
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            err.1,
+            "history too large: more than 10 confirmed funding transactions"
+        );
+    }
+
+    #[test]
+    fn test_sync_progress() {
+        assert_eq!(sync_progress(0, 0), 100.0);
+        assert_eq!(sync_progress(0, 1000), 0.0);
+        assert_eq!(sync_progress(1, 3), 33.33);
+        assert_eq!(sync_progress(999, 1000), 99.9);
+        assert_eq!(sync_progress(1000, 1000), 100.0);
+        assert_eq!(sync_progress(1001, 1000), 100.0); // the daemon's height is cached
+    }
+
+    #[test]
+    fn test_syncing() {
+        let err = HttpError::from(errors::Error::from(errors::ErrorKind::Syncing(1234)));
+        assert_eq!(err.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.1, "still syncing, best height is 1234");
+    }
+
+    #[test]
+    fn test_block_unavailable() {
+        let err = errors::ErrorKind::BlockUnavailable("connection refused".to_string());
+        let err = HttpError::from(errors::Error::from(err));
+        assert_eq!(err.0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_tx_wait() {
+        let unconfirmed = TransactionStatus::unconfirmed();
+        let confirmed = TransactionStatus {
+            confirmed: true,
+            block_height: Some(10),
+            block_hash: None,
+        };
+        assert!(!tx_wait_done(None, false));
+        assert!(!tx_wait_done(None, true));
+        assert!(tx_wait_done(Some(&unconfirmed), false));
+        assert!(!tx_wait_done(Some(&unconfirmed), true));
+        assert!(tx_wait_done(Some(&confirmed), false));
+        assert!(tx_wait_done(Some(&confirmed), true));
+
+        let value: Value = serde_json::from_str(&tx_wait_value(None).unwrap()).unwrap();
+        assert_eq!(value, json!({ "seen": false, "status": null }));
+        let value: Value = serde_json::from_str(&tx_wait_value(Some(confirmed)).unwrap()).unwrap();
+        assert_eq!(value["seen"], json!(true));
+        assert_eq!(value["status"]["block_height"], json!(10));
+    }
 
     #[test]
     fn test_parse_query_param() {
@@ -862,4 +4357,397 @@ mod tests {
 
         assert!(err.is_err());
     }
+
+    #[test]
+    fn test_authorize_admin() {
+        let config = ConfigBuilder::default()
+            .daemon_dir(PathBuf::from("/nonexistent"))
+            .jsonrpc_import(true)
+            .cookies(vec!["user:password".to_owned()])
+            .admin_token("0123456789abcdef".to_owned())
+            .build()
+            .unwrap();
+        let token = config.admin_token.as_ref();
+        let request = |authorization: Option<&str>| {
+            let mut builder = Request::post("/admin/pause");
+            if let Some(value) = authorization {
+                builder.header(header::AUTHORIZATION, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        assert!(authorize_admin(&request(Some("Bearer 0123456789abcdef")), token).is_ok());
+        for authorization in vec![
+            None,
+            Some("Bearer 0123456789abcdeF"),
+            Some("Bearer "),
+            Some("Basic 0123456789abcdef"),
+            Some("0123456789abcdef"),
+        ] {
+            let err = authorize_admin(&request(authorization), token).unwrap_err();
+            assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+        }
+        // without a token, the admin endpoints don't exist
+        let err = authorize_admin(&request(Some("Bearer 0123456789abcdef")), None).unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_http_auth() {
+        let config = ConfigBuilder::default()
+            .daemon_dir(PathBuf::from("/nonexistent"))
+            .jsonrpc_import(true)
+            .cookies(vec!["user:password".to_owned()])
+            .http_auth("alice:secret".to_owned())
+            .build()
+            .unwrap();
+        let auth = config.http_auth.as_ref();
+        let request = |authorization: Option<&str>| {
+            let mut builder = Request::get("/blocks/tip/height");
+            if let Some(value) = authorization {
+                builder.header(header::AUTHORIZATION, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        // "alice:secret"
+        assert!(authenticated(
+            &request(Some("Basic YWxpY2U6c2VjcmV0")),
+            auth
+        ));
+        assert!(!authenticated(
+            &request(Some("Basic YWxpY2U6c2VjcmVU")),
+            auth
+        ));
+        assert!(!authenticated(&request(None), auth));
+        // open without credentials
+        assert!(authenticated(&request(None), None));
+
+        let response = unauthorized_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"electrs\", charset=\"UTF-8\""
+        );
+    }
+
+    #[test]
+    fn test_connection_reuse() {
+        let closes = |reuse: &ConnectionReuse| {
+            let mut response = Response::new(Body::empty());
+            reuse.limit(&mut response);
+            response.headers().get(header::CONNECTION).is_some()
+        };
+        let reuse = ConnectionReuse::new(3, Duration::from_secs(60));
+        assert!(!closes(&reuse));
+        assert!(!closes(&reuse));
+        assert!(closes(&reuse));
+        let reuse = ConnectionReuse::new(3, Duration::from_secs(0));
+        assert!(closes(&reuse));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let peer: SocketAddr = "10.0.0.1:41000".parse().unwrap();
+        let forwarded = Some("1.1.1.1, 2.2.2.2, 10.0.0.2");
+
+        // the header is ignored unless the peer is a trusted proxy
+        assert_eq!(client_addr(peer, forwarded, &[]), ip("10.0.0.1"));
+        assert_eq!(
+            client_addr(peer, forwarded, &[ip("10.0.0.2")]),
+            ip("10.0.0.1")
+        );
+        assert_eq!(client_addr(peer, None, &[ip("10.0.0.1")]), ip("10.0.0.1"));
+
+        // the rightmost untrusted entry, as the client can prepend anything
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(client_addr(peer, forwarded, &trusted[..1]), ip("10.0.0.2"));
+        assert_eq!(client_addr(peer, forwarded, &trusted), ip("2.2.2.2"));
+        assert_eq!(
+            client_addr(peer, Some("unknown, 10.0.0.2"), &trusted),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            client_addr(
+                peer,
+                Some("[2001:db8::1]:5000,3.3.3.3:80"),
+                &[ip("10.0.0.1")]
+            ),
+            ip("3.3.3.3")
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_for(&headers), None);
+        headers.append("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        headers.append(
+            "x-forwarded-for",
+            HeaderValue::from_static("2.2.2.2, 3.3.3.3"),
+        );
+        assert_eq!(
+            forwarded_for(&headers),
+            Some("1.1.1.1,2.2.2.2, 3.3.3.3".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = |builder: ConfigBuilder| {
+            let config = builder
+                .daemon_dir(PathBuf::from("/nonexistent"))
+                .jsonrpc_import(true)
+                .cookies(vec!["user:password".to_owned()])
+                .build()
+                .unwrap();
+            RateLimiter::new(&config, &Metrics::new("127.0.0.1:0".parse().unwrap()))
+        };
+        let client: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "5.6.7.8".parse().unwrap();
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let rl = limiter(ConfigBuilder::default().rate_limit(2).rate_limit_burst(3));
+        for _ in 0..3 {
+            assert!(rl.allow_at(client, Cost::Expensive, at(0)));
+        }
+        assert!(!rl.allow_at(client, Cost::Expensive, at(0)));
+        // the other buckets aren't affected
+        assert!(rl.allow_at(client, Cost::Cheap, at(0)));
+        assert!(rl.allow_at(other, Cost::Expensive, at(0)));
+        // refilled at 2 requests per second
+        assert!(!rl.allow_at(client, Cost::Expensive, at(400)));
+        assert!(rl.allow_at(client, Cost::Expensive, at(600)));
+        assert!(!rl.allow_at(client, Cost::Expensive, at(600)));
+        // the cheap requests get 10 times more
+        for _ in 1..30 {
+            assert!(rl.allow_at(client, Cost::Cheap, at(0)));
+        }
+        assert!(!rl.allow_at(client, Cost::Cheap, at(0)));
+        assert!(rl.allow_at(client, Cost::Cheap, at(100)));
+        // loopback clients are exempt by default
+        for _ in 0..10 {
+            assert!(rl.allow_at(local, Cost::Expensive, at(0)));
+        }
+        let rl = limiter(
+            ConfigBuilder::default()
+                .rate_limit(2)
+                .rate_limit_burst(3)
+                .rate_limit_loopback(true),
+        );
+        for _ in 0..3 {
+            assert!(rl.allow_at(local, Cost::Expensive, at(0)));
+        }
+        assert!(!rl.allow_at(local, Cost::Expensive, at(0)));
+
+        // disabled by default
+        let rl = limiter(ConfigBuilder::default());
+        for _ in 0..100 {
+            assert!(rl.allow_at(client, Cost::Expensive, at(0)));
+        }
+
+        assert_eq!(Cost::of("/address/:address/txs"), Cost::Expensive);
+        assert_eq!(Cost::of("/tx/:txid"), Cost::Cheap);
+        let response = throttled_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().get("Retry-After").is_some());
+    }
+
+    #[test]
+    fn test_request_pool() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let pool = RequestPool::start(
+            1,
+            1,
+            Backpressure::Reject,
+            move |req: Request<Body>| {
+                if req.uri().path() == "/panic" {
+                    panic!("handler failure");
+                }
+                released.lock().unwrap().recv().unwrap();
+                Response::new(Body::empty())
+            },
+            &metrics,
+        );
+        let request = |path| Request::get(path).body(Body::empty()).unwrap();
+
+        let first = pool.submit(request("/")).unwrap();
+        // wait for the worker to take it
+        while pool.workers.queued.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let second = pool.submit(request("/")).unwrap();
+        // the queue is full
+        assert!(pool.submit(request("/")).is_none());
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        assert_eq!(first.wait().unwrap().status(), StatusCode::OK);
+        assert_eq!(second.wait().unwrap().status(), StatusCode::OK);
+
+        // the worker survives a panicking handler
+        let failed = pool.submit(request("/panic")).unwrap();
+        assert_eq!(
+            failed.wait().unwrap().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        release.send(()).unwrap();
+        assert_eq!(
+            pool.submit(request("/")).unwrap().wait().unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            overloaded_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn test_compaction_gate() {
+        let gate = CompactionGate::new(true);
+        assert!(gate.allow("/address/:address/txs", false));
+        assert!(!gate.allow("/address/:address/txs", true));
+        assert!(!gate.allow("/tx/:txid/merkle-proof", true));
+        // the cheap ones are still served
+        assert!(gate.allow("/tx/:txid", true));
+        assert!(gate.allow("/address/:address/txs", false));
+
+        let gate = CompactionGate::new(false);
+        assert!(gate.allow("/address/:address/txs", true));
+        assert_eq!(busy_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_notify_tip() {
+        let hash = |i: u8| Sha256dHash::from_data(&[i]);
+        let subscriber = |sender| TipSubscriber::new(sender, TipFormat::Json, None);
+        let (fast, lines) = mpsc::channel(STREAM_BUFFER);
+        let (slow, _unread) = mpsc::channel(0);
+        let (gone, disconnected) = mpsc::channel(STREAM_BUFFER);
+        drop(disconnected);
+        let mut subscribers = vec![subscriber(fast), subscriber(slow), subscriber(gone)];
+
+        notify_tip(&mut subscribers, hash(1), "1\n", "event");
+        assert_eq!(subscribers.len(), 2);
+        notify_tip(&mut subscribers, hash(1), "1\n", "event"); // already sent
+        notify_tip(&mut subscribers, hash(2), "2\n", "event");
+        notify_tip(&mut subscribers, hash(3), "3\n", "event");
+        // the slow subscriber is dropped instead of blocking the others
+        assert_eq!(subscribers.len(), 1);
+
+        drop(subscribers);
+        let lines: Vec<Vec<u8>> = lines
+            .wait()
+            .map(|line| line.unwrap().unwrap().to_vec())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![b"1\n".to_vec(), b"2\n".to_vec(), b"3\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_notify_mempool() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        let (events, received) = mpsc::channel(STREAM_BUFFER);
+        let (tips, _unread) = mpsc::channel(STREAM_BUFFER);
+        let mut subscribers = vec![
+            TipSubscriber::new(events, TipFormat::Sse, Some(interval)),
+            TipSubscriber::new(tips, TipFormat::Json, None),
+        ];
+        subscribers[0].mempool_sent = start;
+
+        let mut computed = 0;
+        notify_mempool(&mut subscribers, start + Duration::from_secs(1), || {
+            computed += 1;
+            "mempool".to_string()
+        });
+        assert_eq!(computed, 0); // not due yet
+        notify_mempool(&mut subscribers, start + interval, || {
+            computed += 1;
+            "mempool".to_string()
+        });
+        assert_eq!(computed, 1);
+        assert_eq!(subscribers.len(), 2);
+
+        drop(subscribers);
+        let events: Vec<Vec<u8>> = received
+            .wait()
+            .map(|event| event.unwrap().unwrap().to_vec())
+            .collect();
+        assert_eq!(events, vec![b"mempool".to_vec()]);
+    }
+
+    #[test]
+    fn test_check_tip_waiters() {
+        let hash = |i: u8| Sha256dHash::from_data(&[i]);
+        let now = Instant::now();
+        let waiter = |last, deadline| {
+            let (sender, receiver) = oneshot::channel();
+            (
+                TipWaiter {
+                    last,
+                    deadline,
+                    sender,
+                },
+                receiver,
+            )
+        };
+        let (outdated, outdated_rx) = waiter(hash(1), now + Duration::from_secs(10));
+        let (current, _current_rx) = waiter(hash(2), now + Duration::from_secs(10));
+        let (expired, expired_rx) = waiter(hash(2), now);
+        let (gone, gone_rx) = waiter(hash(2), now + Duration::from_secs(10));
+        drop(gone_rx);
+
+        let pending = check_tip_waiters(vec![outdated, current, expired, gone], hash(2), now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].last, hash(2));
+        for receiver in vec![outdated_rx, expired_rx] {
+            let tip = receiver.wait().unwrap().unwrap();
+            assert_eq!(tip.to_vec(), hash(2).be_hex_string().into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let new_service = || {
+            let reuse = ConnectionReuse::new(3, Duration::from_secs(60));
+            service_fn_ok(move |_: Request<Body>| {
+                let mut response = Response::new(Body::from("ok"));
+                reuse.limit(&mut response);
+                response
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .http1_keepalive(true)
+            .serve(new_service);
+        let addr = server.local_addr();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = server
+            .with_graceful_shutdown(rx)
+            .map_err(|e| eprintln!("server error: {}", e));
+        let thread = thread::spawn(move || rt::run(server));
+
+        // the requests are pipelined over a single connection, which the server closes after the
+        // response to the last one
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+        }
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+        let responses = responses.to_lowercase();
+        assert_eq!(responses.matches("http/1.1 200 ok").count(), 3);
+        assert_eq!(responses.matches("connection: close").count(), 1);
+        // only the last response asks to close it
+        assert!(responses.find("connection: close") > responses.rfind("http/1.1 200 ok"));
+
+        tx.send(()).unwrap();
+        thread.join().unwrap();
+    }
 }
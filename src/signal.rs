@@ -1,34 +1,87 @@
 use chan;
 use chan_signal;
+use std::process;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use util::spawn_thread;
+
 use errors::*;
 
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
-    signal: chan::Receiver<chan_signal::Signal>,
+    receiver: chan::Receiver<()>, // closed when the first signal is received
+    sender: Arc<Mutex<Option<chan::Sender<()>>>>, // dropped to close it
+    signal: Arc<Mutex<Option<chan_signal::Signal>>>,
+}
+
+// Records the first signal, waking up all the waiters.
+fn interrupt(
+    sender: &Mutex<Option<chan::Sender<()>>>,
+    signal: &Mutex<Option<chan_signal::Signal>>,
+    sig: chan_signal::Signal,
+) {
+    let mut signal = signal.lock().unwrap();
+    if signal.is_none() {
+        *signal = Some(sig);
+    }
+    sender.lock().unwrap().take();
 }
 
 impl Waiter {
     pub fn new() -> Waiter {
+        let signals = chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]);
+        let (sender, receiver) = chan::sync::<()>(0);
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        let signal = Arc::new(Mutex::new(None));
+        let (waiters, received) = (sender.clone(), signal.clone());
+        spawn_thread("signal", move || {
+            let sig = signals.recv().expect("signal channel closed");
+            info!(
+                "received SIG{:?}, shutting down (repeat to exit immediately)",
+                sig
+            );
+            interrupt(&waiters, &received, sig);
+            if let Some(sig) = signals.recv() {
+                warn!("received SIG{:?} again, exiting immediately", sig);
+                process::exit(1);
+            }
+        });
         Waiter {
-            signal: chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]),
+            receiver,
+            sender,
+            signal,
         }
     }
+    /// Shuts down the other threads waiting on this (or a cloned) waiter as if SIGTERM was
+    /// received, e.g. when one of several network instances fails.
+    pub fn shutdown(&self) {
+        interrupt(&self.sender, &self.signal, chan_signal::Signal::TERM);
+    }
     pub fn wait(&self, duration: Duration) -> Result<()> {
-        let signal = &self.signal;
+        let receiver = &self.receiver;
         let timeout = chan::after(duration);
         chan_select! {
-            signal.recv() -> s => {
-                if let Some(sig) = s {
-                    bail!(ErrorKind::Interrupt(sig));
-                }
-            },
+            receiver.recv() => {},
             timeout.recv() => {},
         }
-        Ok(())
+        self.poll()
+    }
+    /// Like `wait()`, but also returns once `wakeup` is notified (e.g. of a new block).
+    pub fn wait_for(&self, duration: Duration, wakeup: &chan::Receiver<()>) -> Result<()> {
+        let receiver = &self.receiver;
+        let timeout = chan::after(duration);
+        chan_select! {
+            receiver.recv() => {},
+            wakeup.recv() => {},
+            timeout.recv() => {},
+        }
+        self.poll()
     }
     pub fn poll(&self) -> Result<()> {
-        self.wait(Duration::from_secs(0))
+        if let Some(sig) = *self.signal.lock().unwrap() {
+            bail!(ErrorKind::Interrupt(sig));
+        }
+        Ok(())
     }
 }
@@ -1,12 +1,24 @@
 use bincode;
+use bitcoin::network::constants::Network;
 use rocksdb;
 
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use config::Config;
+use index;
 use util::Bytes;
 
-const DB_VERSION: u32 = 1;
+use errors::*;
+
+const SNAPSHOT_BATCH_SIZE: usize = 100_000; // # of rows copied per write to another DB
+
+// 2: added the spending index ('S' rows), 3: the address index ('a' rows), 4: the outputs ('o' rows)
+const DB_VERSION: u32 = 4;
 
 #[derive(Clone)]
 pub struct Row {
@@ -23,11 +35,80 @@ impl Row {
 pub trait ReadStore: Sync {
     fn get(&self, key: &[u8]) -> Option<Bytes>;
     fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+
+    /// Like `scan()`, but returns at most `limit` rows (stores may avoid reading the others).
+    fn scan_limit(&self, prefix: &[u8], limit: usize) -> Vec<Row> {
+        let mut rows = self.scan(prefix);
+        rows.truncate(limit);
+        rows
+    }
 }
 
 pub trait WriteStore: Sync {
     fn write(&self, rows: Vec<Row>);
     fn flush(&self);
+    /// Deletes the rows with these keys.
+    fn delete(&self, keys: Vec<Bytes>);
+    /// Deletes all the rows with this prefix.
+    fn delete_prefix(&self, prefix: &[u8]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "snappy" => Some(Compression::Snappy),
+            "lz4" => Some(Compression::Lz4),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn to_rocksdb(&self) -> rocksdb::DBCompressionType {
+        match self {
+            Compression::None => rocksdb::DBCompressionType::None,
+            Compression::Snappy => rocksdb::DBCompressionType::Snappy,
+            Compression::Lz4 => rocksdb::DBCompressionType::Lz4,
+            Compression::Zstd => rocksdb::DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// RocksDB tuning knobs (0 means "use the default").
+#[derive(Debug, Clone)]
+pub struct DBOptions {
+    pub compression: Compression,
+    pub cache_mb: usize,
+    pub max_open_files: usize,
+    pub parallelism: usize,
+}
+
+impl Default for DBOptions {
+    fn default() -> Self {
+        DBOptions {
+            compression: Compression::Snappy,
+            cache_mb: 0,
+            max_open_files: 0,
+            parallelism: 0,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -35,25 +116,37 @@ struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    read_only: bool,
+    db: DBOptions,
 }
 
 pub struct DBStore {
     db: rocksdb::DB,
     opts: Options,
+    compacting: AtomicBool, // during compact_all()
 }
 
 impl DBStore {
     fn open_opts(opts: Options) -> Self {
-        debug!("opening DB at {:?}", opts.path);
+        debug!("opening DB at {:?} with {:?}", opts.path, opts.db);
         let mut db_opts = rocksdb::Options::default();
-        db_opts.create_if_missing(true);
+        db_opts.create_if_missing(!opts.read_only);
         // db_opts.set_keep_log_file_num(10);
-        db_opts.set_max_open_files(if opts.bulk_import { 16 } else { 256 });
+        db_opts.set_max_open_files(match opts.db.max_open_files {
+            0 if opts.bulk_import => 16,
+            0 => 256,
+            n => n as i32,
+        });
+        if opts.db.parallelism > 0 {
+            db_opts.increase_parallelism(opts.db.parallelism as i32);
+            db_opts.set_max_background_compactions(opts.db.parallelism as i32);
+        }
         db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+        db_opts.set_compression_type(opts.db.compression.to_rocksdb());
         db_opts.set_target_file_size_base(256 << 20);
         db_opts.set_write_buffer_size(256 << 20);
-        db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
+        // for initial bulk load (and a read-only DB's files are never rewritten)
+        db_opts.set_disable_auto_compactions(opts.bulk_import || opts.read_only);
         db_opts.set_advise_random_on_open(!opts.bulk_import); // bulk load uses sequential I/O
         if opts.low_memory == false {
             db_opts.set_compaction_readahead_size(1 << 20);
@@ -61,21 +154,56 @@ impl DBStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
+        if opts.db.cache_mb > 0 {
+            block_opts.set_lru_cache(opts.db.cache_mb << 20);
+        }
+        db_opts.set_block_based_table_factory(&block_opts);
         DBStore {
             db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
             opts,
+            compacting: AtomicBool::new(false),
         }
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
+    pub fn open(path: &Path, low_memory: bool, db_opts: &DBOptions) -> Self {
         DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
             low_memory,
+            read_only: false,
+            db: db_opts.clone(),
         })
     }
 
+    /// Opens an existing RocksDB only to read from it (e.g. to serve queries without indexing):
+    /// writing to it panics. RocksDB (as of the rocksdb crate's 0.10 release) has no read-only
+    /// mode, so the DB's lock is still taken.
+    pub fn open_read_only(path: &Path, db_opts: &DBOptions) -> Result<Self> {
+        if !path.is_dir() {
+            bail!("no DB found at {:?}", path);
+        }
+        Ok(DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: false,
+            low_memory: false,
+            read_only: true,
+            db: db_opts.clone(),
+        }))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.opts.read_only
+    }
+
+    fn check_writable(&self) {
+        assert!(
+            !self.opts.read_only,
+            "writing to the read-only DB at {:?}",
+            self.opts.path
+        );
+    }
+
     pub fn enable_compaction(self) -> Self {
         let mut opts = self.opts.clone();
         if opts.bulk_import == true {
@@ -99,6 +227,124 @@ impl DBStore {
         store
     }
 
+    /// Compacts the whole key range (i.e. the default column family, the only one used), dropping the
+    /// deleted rows' tombstones, and returns its duration. Unlike `compact()`, it keeps the DB open.
+    pub fn compact_all(&self) -> Duration {
+        let before = self.disk_size();
+        let start = Instant::now();
+        info!("compacting DB ({:.1} MB on disk)", before as f64 / 1e6);
+        self.compacting.store(true, Ordering::SeqCst);
+        self.db.compact_range(None, None);
+        self.compacting.store(false, Ordering::SeqCst);
+        let elapsed = start.elapsed();
+        info!(
+            "compacted DB in {:.1} s: {:.1} MB -> {:.1} MB on disk",
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1e3,
+            before as f64 / 1e6,
+            self.disk_size() as f64 / 1e6
+        );
+        elapsed
+    }
+
+    /// Whether `compact_all()` is running (e.g. to spare the DB from expensive queries).
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::SeqCst)
+    }
+
+    /// The total size of the DB files, in bytes.
+    pub fn disk_size(&self) -> u64 {
+        fs::read_dir(&self.opts.path)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.and_then(|entry| entry.metadata()).ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// The number of rows with this prefix and their total (uncompressed) size, in bytes.
+    pub fn prefix_size(&self, prefix: &[u8]) -> (usize, u64) {
+        self.iter_scan(prefix).fold((0, 0), |(count, size), row| {
+            (count + 1, size + (row.key.len() + row.value.len()) as u64)
+        })
+    }
+
+    /// The number of rows with this prefix and their size on disk, in bytes, measured by copying
+    /// them to a temporary DB next to this one (compressed alike, and compacted), removed once
+    /// measured: the rocksdb crate (as of its 0.10 release) doesn't expose RocksDB's estimates.
+    /// It reads all of them, so it takes a while.
+    pub fn prefix_disk_size(&self, prefix: &[u8]) -> (usize, u64) {
+        let path = self.opts.path.with_extension("measure");
+        let _ = fs::remove_dir_all(&path); // left by an interrupted measure
+        let target = DBStore::open(&path, self.opts.low_memory, &self.opts.db);
+        let (mut rows, mut count) = (vec![], 0);
+        for row in self.iter_scan(prefix) {
+            rows.push(row);
+            count += 1;
+            if rows.len() == SNAPSHOT_BATCH_SIZE {
+                target.write(mem::replace(&mut rows, vec![]));
+            }
+        }
+        target.write(rows);
+        target.db.compact_range(None, None); // flushes the rows (written without WAL) to disk
+        let size = target.disk_size();
+        drop(target);
+        let _ = fs::remove_dir_all(&path);
+        (count, size)
+    }
+
+    /// The number of rows and their total (uncompressed) size, in bytes, per key prefix (i.e. the
+    /// rows' first byte, telling their kind). It scans the whole DB, so it takes a while.
+    pub fn prefix_sizes(&self) -> BTreeMap<u8, (usize, u64)> {
+        let mut sizes = BTreeMap::new();
+        for (key, value) in self.db.iterator(rocksdb::IteratorMode::Start) {
+            let entry = sizes.entry(key[0]).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += (key.len() + value.len()) as u64;
+        }
+        sizes
+    }
+
+    /// Copies the DB (as of a snapshot taken when called, so writers aren't blocked) to a new DB
+    /// at `path`, which must not exist, returning the number of copied rows and the copy's size on
+    /// disk, in bytes. The rocksdb crate (as of its 0.10 release) doesn't expose RocksDB's
+    /// checkpoints, so the rows are copied one by one.
+    pub fn snapshot(&self, path: &Path) -> Result<(usize, u64)> {
+        if path.exists() {
+            bail!("{:?} already exists", path);
+        }
+        let start = Instant::now();
+        let target = DBStore::open(path, self.opts.low_memory, &self.opts.db);
+        let snapshot = self.db.snapshot();
+        let (mut rows, mut count) = (vec![], 0);
+        for (key, value) in snapshot.iterator(rocksdb::IteratorMode::Start) {
+            rows.push(Row {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            });
+            count += 1;
+            if rows.len() == SNAPSHOT_BATCH_SIZE {
+                target.write(mem::replace(&mut rows, vec![]));
+                info!("copied {} rows to {:?}", count, path);
+            }
+        }
+        target.write(rows);
+        target.flush();
+        let target = target.compact(); // persists the rows (written without WAL)
+        let size = target.disk_size();
+        drop(target);
+        let elapsed = start.elapsed();
+        info!(
+            "copied {} rows ({:.1} MB on disk) to {:?} in {:.1} s",
+            count,
+            size as f64 / 1e6,
+            path,
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1e3
+        );
+        Ok((count, size))
+    }
+
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
         ScanIterator {
             prefix: prefix.to_vec(),
@@ -155,10 +401,15 @@ impl ReadStore for DBStore {
         }
         rows
     }
+
+    fn scan_limit(&self, prefix: &[u8], limit: usize) -> Vec<Row> {
+        self.iter_scan(prefix).take(limit).collect()
+    }
 }
 
 impl WriteStore for DBStore {
     fn write(&self, rows: Vec<Row>) {
+        self.check_writable();
         let mut batch = rocksdb::WriteBatch::default();
         for row in rows {
             batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
@@ -170,12 +421,75 @@ impl WriteStore for DBStore {
     }
 
     fn flush(&self) {
+        if self.opts.read_only {
+            return; // nothing was written
+        }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         opts.disable_wal(false);
         let empty = rocksdb::WriteBatch::default();
         self.db.write_opt(empty, &opts).unwrap();
     }
+
+    fn delete(&self, keys: Vec<Bytes>) {
+        self.check_writable();
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete(key.as_slice()).unwrap();
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(!self.opts.bulk_import);
+        opts.disable_wal(self.opts.bulk_import);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+
+    fn delete_prefix(&self, prefix: &[u8]) {
+        self.check_writable();
+        let mut batch = rocksdb::WriteBatch::default();
+        for row in self.iter_scan(prefix) {
+            batch.delete(row.key.as_slice()).unwrap();
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(!self.opts.bulk_import);
+        opts.disable_wal(self.opts.bulk_import);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+}
+
+/// Schedules the full compactions done in the background (see `--auto-compact-interval-hours`): one
+/// per interval, only started during the quiet window (if any).
+pub struct CompactionSchedule {
+    interval: Duration,
+    window: Option<(u32, u32)>, // UTC hours [start, end), wrapping around midnight if start > end
+    last: Instant,              // the last scheduled compaction (or startup)
+}
+
+impl CompactionSchedule {
+    pub fn new(interval: Duration, window: Option<(u32, u32)>) -> Self {
+        CompactionSchedule {
+            interval,
+            window,
+            last: Instant::now(),
+        }
+    }
+
+    fn in_window(&self, hour: u32) -> bool {
+        match self.window {
+            Some((start, end)) if start <= end => start <= hour && hour < end,
+            Some((start, end)) => start <= hour || hour < end,
+            None => true,
+        }
+    }
+
+    /// Whether a compaction should start now (at this UTC hour), the next one being due an interval
+    /// later if so.
+    pub fn is_due(&mut self, now: Instant, hour: u32) -> bool {
+        if now.duration_since(self.last) < self.interval || !self.in_window(hour) {
+            return false;
+        }
+        self.last = now;
+        true
+    }
 }
 
 impl Drop for DBStore {
@@ -198,24 +512,523 @@ pub fn full_compaction(store: DBStore) -> DBStore {
     store
 }
 
+/// Enables auto compactions before the initial indexing (e.g. to serve queries meanwhile), so that
+/// it isn't followed by a full compaction.
+pub fn skip_full_compaction(store: DBStore) -> DBStore {
+    let store = store.enable_compaction();
+    store.write(vec![full_compaction_marker()]);
+    store
+}
+
 pub fn is_fully_compacted(store: &ReadStore) -> bool {
     let marker = store.get(&full_compaction_marker().key);
     marker.is_some()
 }
 
-pub fn verify_index_compatibility(store: &DBStore, config: &Config) {
-    let compatibility_bytes =
-        bincode::serialize(&(config.network_type, DB_VERSION, config.extended_db_enabled)).unwrap();
+/// What the DB was indexed for (its 'C' row), so that it isn't used with another configuration.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct DBInfo {
+    network: Network,
+    version: u32,
+    extended_db: bool, // the optional indexes (raw transactions, spends and addresses)
+}
 
-    match store.get(b"C") {
-        None => store.write(vec![Row {
+impl DBInfo {
+    fn to_row(&self) -> Row {
+        Row {
             key: b"C".to_vec(),
-            value: compatibility_bytes,
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+}
+
+/// Upgrades the DB from the `from` version to the `to` one, by building the rows added since
+/// (e.g. a new index) from the existing ones, written to the sink in batches (returning their
+/// number). The new version is written once they're all persisted: as the rows only depend on the
+/// existing ones, an interrupted migration is simply run again.
+pub struct Migration {
+    pub from: u32,
+    pub to: u32,
+    pub run: fn(&DBStore, &WriteStore, Network) -> usize,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        to: 2,
+        run: index::spends_migration,
+    },
+    Migration {
+        from: 2,
+        to: 3,
+        run: index::addresses_migration,
+    },
+    Migration {
+        from: 3,
+        to: 4,
+        run: index::txos_migration,
+    },
+];
+
+/// Checks that the DB was indexed with this configuration, migrating it from older versions
+/// (unless it's read-only). Otherwise, it has to be reindexed (see `ErrorKind::IncompatibleDB`).
+pub fn verify_index_compatibility(store: &DBStore, config: &Config) -> Result<()> {
+    verify_compression(store, config.db_options.compression)?;
+    let new_db = store.get(b"C").is_none();
+    upgrade(
+        store,
+        DBInfo {
+            network: config.network_type,
+            version: DB_VERSION,
+            extended_db: config.extended_db_enabled,
+        },
+    )?;
+    verify_opreturn_index(store, config.index_opreturn, new_db)
+}
+
+/// Whether the DB has the OP_RETURN index (see `index::OpReturnRow`), i.e. it was created with
+/// `--index-opreturn` (whose 'R' rows are then written, and searched).
+pub fn opreturn_indexed(store: &ReadStore) -> bool {
+    store.get(b"r").is_some()
+}
+
+// The OP_RETURN index is only built for a new DB (it would otherwise miss the already indexed
+// blocks), and can't be left behind as it would miss the new ones.
+fn verify_opreturn_index(store: &DBStore, enabled: bool, new_db: bool) -> Result<()> {
+    match (opreturn_indexed(store), enabled) {
+        (true, true) | (false, false) => (),
+        (true, false) => bail!(ErrorKind::IncompatibleDB(
+            "it was indexed with --index-opreturn".to_owned()
+        )),
+        (false, true) if new_db => store.write(vec![Row {
+            key: b"r".to_vec(),
+            value: vec![],
         }]),
-        Some(x) => {
-            if x != compatibility_bytes {
-                panic!("Incompatible database found. Changing --light mode requires a reindex.");
+        (false, true) => warn!(
+            "the DB was indexed without --index-opreturn, so OP_RETURN payloads aren't indexed \
+             (reindex to build it)"
+        ),
+    }
+    Ok(())
+}
+
+fn upgrade(store: &DBStore, expected: DBInfo) -> Result<()> {
+    let mut info: DBInfo = match store.get(b"C") {
+        Some(value) => bincode::deserialize(&value).chain_err(|| {
+            ErrorKind::IncompatibleDB("unrecognized version information".to_owned())
+        })?,
+        None if store.is_read_only() => bail!("the DB isn't indexed yet"),
+        None => {
+            store.write(vec![expected.to_row()]); // a new DB
+            return Ok(());
+        }
+    };
+    if info.network != expected.network {
+        bail!(ErrorKind::IncompatibleDB(format!(
+            "it was indexed for {:?}, not {:?}",
+            info.network, expected.network
+        )));
+    }
+    if info.extended_db != expected.extended_db {
+        bail!(ErrorKind::IncompatibleDB(format!(
+            "it was indexed {} --light",
+            if info.extended_db { "without" } else { "with" }
+        )));
+    }
+    if info.version > expected.version {
+        bail!(ErrorKind::IncompatibleDB(format!(
+            "its version ({}) is newer than this release's ({})",
+            info.version, expected.version
+        )));
+    }
+    if info.version < expected.version && store.is_read_only() {
+        bail!(ErrorKind::IncompatibleDB(format!(
+            "its version ({}) has to be migrated to this release's ({}), which requires indexing",
+            info.version, expected.version
+        )));
+    }
+    while info.version < expected.version {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == info.version)
+            .chain_err(|| {
+                ErrorKind::IncompatibleDB(format!("no migration from version {}", info.version))
+            })?;
+        info!(
+            "migrating the DB from version {} to {}",
+            migration.from, migration.to
+        );
+        let start = Instant::now();
+        let count = (migration.run)(store, store, info.network);
+        store.flush();
+        info.version = migration.to;
+        store.write(vec![info.to_row()]);
+        store.flush();
+        info!(
+            "migrated the DB to version {} ({} rows written) in {:?}",
+            info.version,
+            count,
+            start.elapsed()
+        );
+    }
+    Ok(())
+}
+
+fn verify_compression(store: &DBStore, compression: Compression) -> Result<()> {
+    let stored = match store.get(b"Z") {
+        Some(name) => Some(String::from_utf8(name).expect("invalid compression marker")),
+        // databases created before compression became configurable use snappy
+        None if store.get(b"C").is_some() => Some(Compression::Snappy.name().to_owned()),
+        None => None,
+    };
+    match stored {
+        Some(ref name) if name == compression.name() => (),
+        Some(name) => bail!(ErrorKind::IncompatibleDB(format!(
+            "it was created with '{}' compression, not '{}' (keep it with --db-compression={})",
+            name,
+            compression.name(),
+            name
+        ))),
+        None => (),
+    }
+    if store.is_read_only() {
+        return Ok(());
+    }
+    store.write(vec![Row {
+        key: b"Z".to_vec(),
+        value: compression.name().as_bytes().to_vec(),
+    }]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::Transaction;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::Sha256dHash;
+    use errors::{Error, ErrorKind};
+    use index::index_block;
+    use std::fs;
+    use std::panic;
+    use std::time::Duration;
+    use store::{
+        opreturn_indexed, upgrade, verify_opreturn_index, CompactionSchedule, Compression, DBInfo,
+        DBOptions, DBStore, ReadStore, Row, WriteStore, DB_VERSION,
+    };
+    use testutil::{make_block, make_tx, temp_path};
+
+    #[test]
+    fn test_compression_roundtrip() {
+        for compression in &[
+            Compression::None,
+            Compression::Snappy,
+            Compression::Lz4,
+            Compression::Zstd,
+        ] {
+            let path = temp_path(compression.name());
+            let opts = DBOptions {
+                compression: *compression,
+                ..DBOptions::default()
+            };
+            let store = DBStore::open(&path, false, &opts);
+            store.write(vec![
+                Row {
+                    key: b"k1".to_vec(),
+                    value: b"v1".to_vec(),
+                },
+                Row {
+                    key: b"k2".to_vec(),
+                    value: vec![0u8; 1 << 16],
+                },
+            ]);
+            store.flush();
+            let store = store.compact();
+            assert_eq!(store.get(b"k1"), Some(b"v1".to_vec()));
+            assert_eq!(store.get(b"k2"), Some(vec![0u8; 1 << 16]));
+            assert_eq!(store.scan(b"k").len(), 2);
+            drop(store);
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_scan_limit() {
+        let path = temp_path("scan");
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        let row = |key: &str| Row {
+            key: key.as_bytes().to_vec(),
+            value: b"v".to_vec(),
+        };
+        store.write(vec![
+            row("aVab"),
+            row("aVac"),
+            row("aVad"),
+            row("aVb"),
+            row("b"),
+        ]);
+
+        let keys = |rows: Vec<Row>| -> Vec<Vec<u8>> { rows.into_iter().map(|r| r.key).collect() };
+        assert_eq!(
+            keys(store.scan_limit(b"aVa", 2)),
+            vec![b"aVab".to_vec(), b"aVac".to_vec()]
+        );
+        assert_eq!(store.scan_limit(b"aVa", 10).len(), 3);
+        assert!(store.scan_limit(b"aVc", 10).is_empty());
+        assert_eq!(store.prefix_size(b"a"), (4, 4 + 4 + 4 + 3 + 4 * 1));
+        let sizes = store.prefix_sizes();
+        assert_eq!(sizes.keys().cloned().collect::<Vec<u8>>(), vec![b'a', b'b']);
+        assert_eq!(sizes[&b'a'], store.prefix_size(b"a"));
+        assert_eq!(sizes[&b'b'], (1, 2));
+        let (count, disk_size) = store.prefix_disk_size(b"a");
+        assert_eq!(count, 4);
+        assert!(disk_size > 0);
+        assert!(!path.with_extension("measure").exists());
+
+        store.delete_prefix(b"aVa");
+        assert!(store.scan(b"aVa").is_empty());
+        assert_eq!(store.scan(b"a").len(), 1);
+        assert_eq!(store.scan(b"b").len(), 1);
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_all() {
+        let path = temp_path("compact");
+        let store = DBStore::open(&path, false, &DBOptions::default()).enable_compaction();
+        // incompressible (pseudo-random) values, so the deleted ones take some space
+        let mut state = 1u64;
+        let mut random_byte = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        };
+        let rows = (0..256)
+            .map(|i| Row {
+                key: format!("k{:08}", i).into_bytes(),
+                value: (0..4096).map(|_| random_byte()).collect(),
+            })
+            .collect();
+        store.write(rows);
+        store.write(vec![Row {
+            key: b"v".to_vec(),
+            value: b"1".to_vec(),
+        }]);
+        store.compact_all();
+        let size = store.disk_size();
+        assert!(size > 256 * 4096);
+
+        store.delete_prefix(b"k");
+        store.compact_all();
+        assert!(store.disk_size() < size);
+        assert!(store.scan(b"k").is_empty());
+        assert_eq!(store.get(b"v"), Some(b"1".to_vec()));
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_schedule() {
+        let hour = Duration::from_secs(3600);
+        let mut schedule = CompactionSchedule::new(hour * 24, None);
+        let start = schedule.last;
+        assert!(!schedule.is_due(start + hour, 3));
+        assert!(schedule.is_due(start + hour * 24, 3));
+        assert!(!schedule.is_due(start + hour * 25, 3)); // restarted
+        assert!(schedule.is_due(start + hour * 48, 12));
+
+        // only started during the window, however late
+        let mut schedule = CompactionSchedule::new(hour, Some((2, 5)));
+        let start = schedule.last;
+        assert!(!schedule.is_due(start + hour * 2, 1));
+        assert!(!schedule.is_due(start + hour * 2, 5));
+        assert!(schedule.is_due(start + hour * 2, 2));
+        assert!(!schedule.is_due(start + hour * 2, 4)); // restarted
+
+        // wrapping around midnight
+        let mut schedule = CompactionSchedule::new(hour, Some((22, 4)));
+        let start = schedule.last;
+        for hour in &[4, 12, 21] {
+            assert!(!schedule.is_due(start + Duration::from_secs(7200), *hour));
+        }
+        assert!(schedule.is_due(start + Duration::from_secs(7200), 23));
+        let mut schedule = CompactionSchedule::new(hour, Some((22, 4)));
+        let start = schedule.last;
+        assert!(schedule.is_due(start + Duration::from_secs(7200), 0));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = temp_path("read-only");
+        assert!(DBStore::open_read_only(&path, &DBOptions::default()).is_err());
+
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        let info = DBInfo {
+            network: Network::Regtest,
+            version: DB_VERSION - 1,
+            extended_db: true,
+        };
+        store.write(vec![Row {
+            key: b"k".to_vec(),
+            value: b"v".to_vec(),
+        }]);
+        store.flush();
+        drop(store);
+
+        let store = DBStore::open_read_only(&path, &DBOptions::default()).unwrap();
+        assert!(store.is_read_only());
+        assert_eq!(store.get(b"k"), Some(b"v".to_vec()));
+        store.flush(); // a no-op
+        let expected = DBInfo {
+            version: DB_VERSION,
+            ..info
+        };
+        assert!(upgrade(&store, expected).is_err()); // not indexed yet
+        drop(store);
+
+        // an older version isn't migrated
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        store.write(vec![info.to_row()]);
+        drop(store);
+        let store = DBStore::open_read_only(&path, &DBOptions::default()).unwrap();
+        let expected = DBInfo {
+            version: DB_VERSION,
+            ..info
+        };
+        assert!(is_incompatible(upgrade(&store, expected)));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            store.write(vec![Row {
+                key: b"k".to_vec(),
+                value: b"w".to_vec(),
+            }])
+        }));
+        assert!(result.is_err());
+        assert_eq!(store.get(b"k"), Some(b"v".to_vec()));
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    // paying to a P2PKH address, so that it's indexed
+    fn pay_to_address(prev_txid: Sha256dHash, pubkey_hash: u8) -> Transaction {
+        let mut script = vec![0x76, 0xa9, 0x14]; // P2PKH
+        script.extend_from_slice(&[pubkey_hash; 20]);
+        script.extend_from_slice(&[0x88, 0xac]);
+        let mut tx = make_tx(&[(prev_txid, 0)], &[1000]);
+        tx.output[0].script_pubkey = Script::from(script);
+        tx
+    }
+
+    fn is_incompatible(result: Result<(), Error>) -> bool {
+        match result {
+            Err(Error(ErrorKind::IncompatibleDB(_), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_migration() {
+        let path = temp_path("migration");
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        let coinbase = pay_to_address(Sha256dHash::default(), 1);
+        let spending = pay_to_address(coinbase.txid(), 2);
+        let block = make_block(Sha256dHash::default(), 0, vec![coinbase, spending]);
+        let rows = index_block(&block, 0, true, false, Network::Bitcoin);
+        let info = |version| DBInfo {
+            network: Network::Bitcoin,
+            version,
+            extended_db: true,
+        };
+
+        // indexed by version 1, i.e. without the spends ('S' rows), the addresses ('a' rows) nor
+        // the outputs ('o' rows)
+        let (added, mut v1_rows): (Vec<Row>, Vec<Row>) = rows
+            .into_iter()
+            .partition(|row| row.key[0] == b'S' || row.key[0] == b'a' || row.key[0] == b'o');
+        assert_eq!(added.len(), 1 + 2 + 2); // a spend, 2 addresses and 2 outputs
+        v1_rows.push(info(1).to_row());
+        store.write(v1_rows);
+
+        assert!(is_incompatible(upgrade(
+            &store,
+            DBInfo {
+                network: Network::Testnet,
+                ..info(DB_VERSION)
             }
+        )));
+        assert!(is_incompatible(upgrade(
+            &store,
+            DBInfo {
+                extended_db: false,
+                ..info(DB_VERSION)
+            }
+        )));
+        assert!(store.scan(b"S").is_empty()); // not migrated
+
+        upgrade(&store, info(DB_VERSION)).unwrap();
+        for row in added {
+            assert_eq!(store.get(&row.key), Some(row.value));
         }
+        assert_eq!(store.get(b"C"), Some(info(DB_VERSION).to_row().value));
+        upgrade(&store, info(DB_VERSION)).unwrap(); // already up-to-date
+
+        // from a newer release
+        store.write(vec![info(DB_VERSION + 1).to_row()]);
+        assert!(is_incompatible(upgrade(&store, info(DB_VERSION))));
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_migration() {
+        let path = temp_path("interrupted-migration");
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        let coinbase = pay_to_address(Sha256dHash::default(), 1);
+        let spending = pay_to_address(coinbase.txid(), 2);
+        let block = make_block(Sha256dHash::default(), 0, vec![coinbase, spending]);
+        let info = |version| DBInfo {
+            network: Network::Bitcoin,
+            version,
+            extended_db: true,
+        };
+
+        // the migration to version 4 (the outputs' 'o' rows) wrote one of its batches, but not the
+        // new version, before being interrupted
+        let (txos, mut v3_rows): (Vec<Row>, Vec<Row>) =
+            index_block(&block, 0, true, false, Network::Bitcoin)
+                .into_iter()
+                .partition(|row| row.key[0] == b'o');
+        assert_eq!(txos.len(), 2);
+        v3_rows.push(txos[0].clone());
+        v3_rows.push(info(3).to_row());
+        store.write(v3_rows);
+
+        upgrade(&store, info(4)).unwrap();
+        assert_eq!(store.scan(b"o").len(), 2);
+        for row in txos {
+            assert_eq!(store.get(&row.key), Some(row.value));
+        }
+        assert_eq!(store.get(b"C"), Some(info(4).to_row().value));
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_opreturn_index() {
+        let path = temp_path("opreturn-db");
+        let store = DBStore::open(&path, false, &DBOptions::default());
+        // an existing DB isn't indexed retroactively
+        verify_opreturn_index(&store, true, /*new_db=*/ false).unwrap();
+        assert!(!opreturn_indexed(&store));
+        verify_opreturn_index(&store, false, false).unwrap();
+
+        verify_opreturn_index(&store, true, /*new_db=*/ true).unwrap();
+        assert!(opreturn_indexed(&store));
+        verify_opreturn_index(&store, true, false).unwrap();
+        // the index would miss the blocks indexed without it
+        assert!(is_incompatible(verify_opreturn_index(&store, false, false)));
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
     }
 }
@@ -0,0 +1,58 @@
+// Fixtures shared by the unit tests.
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::util::hash::Sha256dHash;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// A transaction spending the given outpoints, with an output paying each value to `OP_TRUE`.
+pub fn make_tx(inputs: &[(Sha256dHash, u32)], values: &[u64]) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: inputs
+            .iter()
+            .map(|&(txid, vout)| TxIn {
+                previous_output: OutPoint { txid, vout },
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            })
+            .collect(),
+        output: values
+            .iter()
+            .map(|value| TxOut {
+                value: *value,
+                script_pubkey: Script::from(vec![0x51]),
+            })
+            .collect(),
+    }
+}
+
+/// A block (without a valid merkle root nor proof of work), told apart by its nonce.
+pub fn make_block(prev_blockhash: Sha256dHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+    Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: Sha256dHash::default(),
+            time: 0,
+            bits: 0,
+            nonce,
+        },
+        txdata,
+    }
+}
+
+/// A path in the temporary directory, unique to the test (by `name`) and process, without the
+/// leftovers of a previous run.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("electrs-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
@@ -0,0 +1,261 @@
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::hash::Sha256dHash;
+use std::sync::Mutex;
+
+use metrics::{CounterVec, Gauge, MetricOpts, Metrics};
+use store::{ReadStore, Row, WriteStore};
+use util::HASH_LEN;
+
+const TX_PREFIX: u8 = b'Q'; // 'Q' + txid -> seq + rawtx
+const ORDER_PREFIX: u8 = b'q'; // 'q' + seq (big-endian, for FIFO order) + txid -> entry size
+const SEQ_LEN: usize = 8;
+const EVICTION_BATCH: usize = 100; // # of entries scanned at once when evicting
+
+fn tx_key(txid: &Sha256dHash) -> Vec<u8> {
+    let mut key = vec![TX_PREFIX];
+    key.extend_from_slice(&txid[..]);
+    key
+}
+
+fn order_key(seq: u64, txid: &Sha256dHash) -> Vec<u8> {
+    let mut key = vec![ORDER_PREFIX];
+    key.extend_from_slice(&seq.to_be_bytes());
+    key.extend_from_slice(&txid[..]);
+    key
+}
+
+fn parse_order_key(key: &[u8]) -> Option<(u64, Sha256dHash)> {
+    if key.len() != 1 + SEQ_LEN + HASH_LEN {
+        return None;
+    }
+    let mut seq = [0u8; SEQ_LEN];
+    seq.copy_from_slice(&key[1..1 + SEQ_LEN]);
+    let txid = deserialize(&key[1 + SEQ_LEN..]).ok()?;
+    Some((u64::from_be_bytes(seq), txid))
+}
+
+fn parse_size(value: &[u8]) -> u64 {
+    let mut size = [0u8; 8];
+    if value.len() != size.len() {
+        return 0; // corrupt, counted as empty until evicted
+    }
+    size.copy_from_slice(value);
+    u64::from_be_bytes(size)
+}
+
+struct State {
+    next_seq: u64,
+    size: u64, // in bytes, of the keys and values of both rows of each entry
+    entries: usize,
+}
+
+/// Keeps the transactions fetched from the daemon (with `--light`) in the DB, so that they
+/// survive restarts, between the in-memory LRU cache and the daemon. The oldest entries are
+/// evicted first (whether used since or not) to keep the total size under `max_size`.
+pub struct DiskTxCache {
+    max_size: u64,
+    state: Mutex<State>,
+
+    // monitoring
+    lookups: CounterVec,
+    size: Gauge,
+    entries: Gauge,
+}
+
+impl DiskTxCache {
+    /// Resumes the cache stored in `store` (scanning its entries' sizes).
+    pub fn open(store: &ReadStore, max_size: u64, metrics: &Metrics) -> DiskTxCache {
+        let mut state = State {
+            next_seq: 0,
+            size: 0,
+            entries: 0,
+        };
+        for row in store.scan(&[ORDER_PREFIX]) {
+            if let Some((seq, _)) = parse_order_key(&row.key) {
+                state.next_seq = state.next_seq.max(seq + 1);
+            }
+            state.size += parse_size(&row.value);
+            state.entries += 1;
+        }
+        let cache = DiskTxCache {
+            max_size,
+            state: Mutex::new(state),
+            lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_tx_disk_cache",
+                    "# of on-disk transaction cache lookups with --light (hit, miss or corrupt)",
+                ),
+                &["type"],
+            ),
+            size: metrics.gauge(MetricOpts::new(
+                "query_tx_disk_cache_bytes",
+                "Total size of the on-disk transaction cache",
+            )),
+            entries: metrics.gauge(MetricOpts::new(
+                "query_tx_disk_cache_entries",
+                "# of transactions in the on-disk cache",
+            )),
+        };
+        cache.update_gauges(&cache.state.lock().unwrap());
+        cache
+    }
+
+    /// A corrupt (or truncated) entry is a miss, so that the transaction is fetched again.
+    pub fn get(&self, store: &ReadStore, txid: &Sha256dHash) -> Option<Transaction> {
+        let value = match store.get(&tx_key(txid)) {
+            Some(value) => value,
+            None => {
+                self.lookups.with_label_values(&["miss"]).inc();
+                return None;
+            }
+        };
+        let tx = if value.len() > SEQ_LEN {
+            deserialize::<Transaction>(&value[SEQ_LEN..])
+                .ok()
+                .filter(|tx| tx.txid() == *txid)
+        } else {
+            None
+        };
+        match tx {
+            Some(tx) => {
+                self.lookups.with_label_values(&["hit"]).inc();
+                Some(tx)
+            }
+            None => {
+                warn!("corrupt on-disk cache entry of tx {}", txid);
+                self.lookups.with_label_values(&["corrupt"]).inc();
+                None
+            }
+        }
+    }
+
+    /// Adds the transactions in a single write, then evicts the oldest entries if needed.
+    pub fn insert(&self, store: &ReadStore, write_store: &WriteStore, txs: &[&Transaction]) {
+        if txs.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut rows = Vec::with_capacity(2 * txs.len());
+        for tx in txs {
+            let txid = tx.txid();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+
+            let mut value = seq.to_be_bytes().to_vec();
+            value.extend_from_slice(&serialize(*tx));
+            let key = tx_key(&txid);
+            let order_key = order_key(seq, &txid);
+            let size = (key.len() + value.len() + order_key.len() + 8) as u64;
+            rows.push(Row { key, value });
+            rows.push(Row {
+                key: order_key,
+                value: size.to_be_bytes().to_vec(),
+            });
+            state.size += size;
+            state.entries += 1;
+        }
+        write_store.write(rows);
+        self.evict(store, write_store, &mut state);
+        self.update_gauges(&state);
+    }
+
+    fn evict(&self, store: &ReadStore, write_store: &WriteStore, state: &mut State) {
+        while state.size > self.max_size {
+            let rows = store.scan_limit(&[ORDER_PREFIX], EVICTION_BATCH);
+            if rows.is_empty() {
+                state.size = 0; // nothing left to evict (shouldn't happen)
+                state.entries = 0;
+                return;
+            }
+            let mut keys = vec![];
+            for row in rows {
+                if state.size <= self.max_size {
+                    break;
+                }
+                if let Some((seq, txid)) = parse_order_key(&row.key) {
+                    // unless it was cached again since (with a newer order row)
+                    let key = tx_key(&txid);
+                    let current = store.get(&key);
+                    if current.map_or(false, |value| value.starts_with(&seq.to_be_bytes())) {
+                        keys.push(key);
+                    }
+                }
+                state.size = state.size.saturating_sub(parse_size(&row.value));
+                state.entries = state.entries.saturating_sub(1);
+                keys.push(row.key);
+            }
+            write_store.delete(keys);
+        }
+    }
+
+    fn update_gauges(&self, state: &State) {
+        self.size.set(state.size as i64);
+        self.entries.set(state.entries as i64);
+    }
+
+    /// The # of cached transactions and their total size (in bytes).
+    pub fn usage(&self) -> (usize, u64) {
+        let state = self.state.lock().unwrap();
+        (state.entries, state.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::transaction::Transaction;
+    use bitcoin::util::hash::Sha256dHash;
+    use metrics::Metrics;
+    use std::fs;
+    use store::{DBOptions, DBStore, ReadStore, Row, WriteStore};
+    use testutil::{make_tx, temp_path};
+    use txcache::{tx_key, DiskTxCache};
+
+    #[test]
+    fn test_disk_tx_cache() {
+        let path = temp_path("txcache");
+        let metrics = || Metrics::new("127.0.0.1:0".parse().unwrap());
+        let new_tx = |value| make_tx(&[(Sha256dHash::default(), 0)], &[value]);
+        let txs: Vec<Transaction> = (0..10).map(new_tx).collect();
+        let store = DBStore::open(&path, false, &DBOptions::default());
+
+        let cache = DiskTxCache::open(&store, 1 << 20, &metrics());
+        assert!(cache.get(&store, &txs[0].txid()).is_none());
+        cache.insert(&store, &store, &txs.iter().collect::<Vec<_>>());
+        assert_eq!(cache.get(&store, &txs[3].txid()), Some(txs[3].clone()));
+        let (entries, size) = cache.usage();
+        assert_eq!(entries, 10);
+        let entry_size = size / 10;
+
+        // resumed after a restart, evicting the oldest entries to fit a smaller size
+        let cache = DiskTxCache::open(&store, entry_size * 4, &metrics());
+        assert_eq!(cache.usage(), (10, size));
+        cache.insert(&store, &store, &[&new_tx(10)]);
+        assert_eq!(cache.usage(), (4, entry_size * 4));
+        for tx in &txs[..7] {
+            assert!(cache.get(&store, &tx.txid()).is_none());
+        }
+        for tx in txs[7..].iter().chain(&[new_tx(10)]) {
+            assert_eq!(cache.get(&store, &tx.txid()), Some(tx.clone()));
+        }
+
+        // corrupt entries are misses
+        let txid = txs[8].txid();
+        let mut value = store.get(&tx_key(&txid)).unwrap();
+        let len = value.len();
+        value.truncate(len - 1);
+        store.write(vec![Row {
+            key: tx_key(&txid),
+            value,
+        }]);
+        assert!(cache.get(&store, &txid).is_none());
+        store.write(vec![Row {
+            key: tx_key(&txid),
+            value: vec![1, 2, 3],
+        }]);
+        assert!(cache.get(&store, &txid).is_none());
+
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
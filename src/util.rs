@@ -1,6 +1,7 @@
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::consensus::encode::serialize;
 use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+use bitcoin::Script;
 use errors::*;
 use std::collections::HashMap;
 use std::fmt;
@@ -14,7 +15,7 @@ pub type Bytes = Vec<u8>;
 pub type HeaderMap = HashMap<Sha256dHash, BlockHeader>;
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
-const HASH_LEN: usize = 32;
+pub const HASH_LEN: usize = 32;
 pub const HASH_PREFIX_LEN: usize = 8;
 
 pub type FullHash = [u8; HASH_LEN];
@@ -59,13 +60,14 @@ pub struct BlockStatus {
     pub next_best: Option<Sha256dHash>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct BlockMeta {
     pub tx_count: u32,
     pub size: u32,
     pub weight: u32,
 }
 
+#[derive(Clone)]
 pub struct BlockHeaderMeta {
     pub header_entry: HeaderEntry,
     pub meta: BlockMeta,
@@ -111,6 +113,15 @@ pub struct HeaderEntry {
 }
 
 impl HeaderEntry {
+    /// For headers outside of the `HeaderList` (e.g. of orphaned blocks).
+    pub fn new(height: usize, header: BlockHeader) -> HeaderEntry {
+        HeaderEntry {
+            height,
+            hash: header.bitcoin_hash(),
+            header,
+        }
+    }
+
     pub fn hash(&self) -> &Sha256dHash {
         &self.hash
     }
@@ -179,7 +190,7 @@ impl HeaderList {
         let new_height: usize = if prev_blockhash == null_hash {
             0
         } else {
-            self.header_by_blockhash(&prev_blockhash)
+            self.header_by_hash(&prev_blockhash)
                 .expect(&format!("{} is not part of the blockchain", prev_blockhash))
                 .height()
                 + 1
@@ -221,7 +232,7 @@ impl HeaderList {
             new_headers.len(),
             new_height
         );
-        self.headers.split_off(new_height); // keep [0..new_height) entries
+        self.truncate(new_height);
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
@@ -231,16 +242,34 @@ impl HeaderList {
         }
     }
 
-    pub fn header_by_blockhash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
-        let height = self.heights.get(blockhash)?;
-        let header = self.headers.get(*height)?;
-        if *blockhash == *header.hash() {
-            Some(header)
-        } else {
-            None
+    /// Drops the headers above the given block (e.g. after a reorg), making it the new tip.
+    pub fn rewind(&mut self, blockhash: &Sha256dHash) {
+        let height = self
+            .header_by_hash(blockhash)
+            .expect(&format!("{} is not part of the blockchain", blockhash))
+            .height();
+        debug!("rewinding {} headers", self.headers.len() - height - 1);
+        self.truncate(height + 1);
+        self.tip = *blockhash;
+    }
+
+    // Keeps the [0..len) entries, dropping the hashes of the others (so that orphaned blocks
+    // aren't found by hash, and the maps don't grow with each reorg).
+    fn truncate(&mut self, len: usize) {
+        for entry in self.headers.split_off(len) {
+            self.heights.remove(entry.hash());
         }
     }
 
+    pub fn header_by_hash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
+        let height = self.height_by_hash(blockhash)?;
+        self.header_by_height(height)
+    }
+
+    pub fn height_by_hash(&self, blockhash: &Sha256dHash) -> Option<usize> {
+        self.heights.get(blockhash).cloned()
+    }
+
     pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
         self.headers.get(height).map(|entry| {
             assert_eq!(entry.height(), height);
@@ -248,6 +277,64 @@ impl HeaderList {
         })
     }
 
+    /// The block at `height` if it's still `blockhash` (i.e. it wasn't reorged away), with its #
+    /// of confirmations: 1 for the tip.
+    pub fn confirmations(
+        &self,
+        height: usize,
+        blockhash: &Sha256dHash,
+    ) -> Option<(&HeaderEntry, usize)> {
+        let header = self.header_by_height(height)?;
+        if header.hash() != blockhash {
+            return None;
+        }
+        Some((header, self.headers.len() - height))
+    }
+
+    /// The median of the timestamps of this block and of its 10 predecessors (fewer near genesis).
+    /// Unlike the timestamps themselves, it never decreases along the chain.
+    pub fn median_time_past(&self, height: usize) -> Option<u32> {
+        if height >= self.headers.len() {
+            return None;
+        }
+        let mut times: Vec<u32> = self.headers[height.saturating_sub(10)..=height]
+            .iter()
+            .map(|entry| entry.header().time)
+            .collect();
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+
+    /// The last block whose median time past is at most `time` (none if it predates genesis).
+    /// Its own timestamp may be later than `time`, as timestamps can go backwards.
+    pub fn header_by_time(&self, time: u32) -> Option<&HeaderEntry> {
+        // the blocks below `low` are at most `time`, the ones from `high` on are later
+        let (mut low, mut high) = (0, self.headers.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.median_time_past(mid).unwrap() <= time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.checked_sub(1)
+            .and_then(|height| self.header_by_height(height))
+    }
+
+    /// The hashes of the block locator (as used by `getheaders`) for the current tip: the last
+    /// blocks, then stepping back exponentially further (2, 4, 8... blocks), ending at genesis.
+    pub fn locator(&self) -> Vec<Sha256dHash> {
+        let tip_height = match self.headers.len() {
+            0 => return vec![],
+            len => len - 1,
+        };
+        locator_heights(tip_height)
+            .into_iter()
+            .map(|height| *self.headers[height].hash())
+            .collect()
+    }
+
     pub fn equals(&self, other: &HeaderList) -> bool {
         self.headers.last() == other.headers.last()
     }
@@ -272,6 +359,24 @@ impl HeaderList {
     }
 }
 
+// Like Bitcoin Core's CChain::GetLocator(), the step starts doubling after 10 heights are listed.
+fn locator_heights(tip_height: usize) -> Vec<usize> {
+    let mut heights = vec![];
+    let mut height = tip_height;
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            break;
+        }
+        height = height.saturating_sub(step);
+        if heights.len() > 10 {
+            step *= 2;
+        }
+    }
+    heights
+}
+
 pub struct SyncChannel<T> {
     tx: SyncSender<T>,
     rx: Receiver<T>,
@@ -332,61 +437,231 @@ where
         .unwrap()
 }
 
-use bitcoin::network::constants::Network;
-use bitcoin::util::address::{Address, Payload};
-use bitcoin::util::hash::Hash160;
-use bitcoin::Script;
-use viacoin_bech32::constants::Network as B32Network;
-use viacoin_bech32::{u5, WitnessProgram};
-
-// @XXX we can't use any of the Address:p2{...}h utility methods, since they expect the pre-image data, which we don't have.
-// we must instead create the Payload manually, which results in code duplication with the p2{...}h methods, especially for witness programs.
-// ideally, this should be implemented as part of the rust-bitcoin lib.
-pub fn script_to_address(script: &Script, network: &Network) -> Option<String> {
-    let payload = if script.is_p2pkh() {
-        Some(Payload::PubkeyHash(Hash160::from(&script[3..23])))
+/// A credential (e.g. a daemon RPC cookie), redacted from the `Debug` and `Display` outputs so that
+/// it doesn't end up in the logs or error messages. Its value is only read through `expose()`.
+#[derive(Clone, PartialEq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+// TODO should the following something to put inside rust-elements lib?
+pub fn script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
     } else if script.is_p2sh() {
-        Some(Payload::ScriptHash(Hash160::from(&script[2..22])))
+        "p2sh"
     } else if script.is_v0_p2wpkh() {
-        Some(Payload::WitnessProgram(
-            WitnessProgram::new(
-                u5::try_from_u8(0).expect("0<32"),
-                script[2..22].to_vec(),
-                to_bech_network(network),
-            )
-            .unwrap(),
-        ))
+        "v0_p2wpkh"
     } else if script.is_v0_p2wsh() {
-        Some(Payload::WitnessProgram(
-            WitnessProgram::new(
-                u5::try_from_u8(0).expect("0<32"),
-                script[2..34].to_vec(),
-                to_bech_network(network),
-            )
-            .unwrap(),
-        ))
+        "v0_p2wsh"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
     } else {
-        None
-    };
-
-    Some(
-        Address {
-            payload: payload?,
-            network: *network,
-        }
-        .to_string(),
-    )
-}
-
-fn to_bech_network(network: &Network) -> B32Network {
-    match network {
-        Network::Bitcoin => B32Network::Viacoin,
-        Network::Testnet => B32Network::ViacoinTestnet,
-        Network::Regtest => B32Network::Regtest,
+        "nonstandard" // still available as raw hex in `scriptpubkey`
     }
 }
 
 pub fn get_script_asm(script: &Script) -> String {
+    // strip the "Script(...)" debug formatting, which handles malformed scripts too
     let asm = format!("{:?}", script);
-    (&asm[7..asm.len() - 1]).to_string()
+    if asm.starts_with("Script(") && asm.ends_with(')') {
+        asm[7..asm.len() - 1].to_string()
+    } else {
+        asm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use util::{locator_heights, HeaderList};
+
+    // `time` gives each header's timestamp (from its nonce)
+    fn make_chain<F>(prev_blockhash: Sha256dHash, nonces: Vec<u32>, time: F) -> Vec<BlockHeader>
+    where
+        F: Fn(u32) -> u32,
+    {
+        let mut chain: Vec<BlockHeader> = vec![];
+        let mut prev_blockhash = prev_blockhash;
+        for nonce in nonces {
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time: time(nonce),
+                bits: 0,
+                nonce,
+            };
+            prev_blockhash = header.bitcoin_hash();
+            chain.push(header);
+        }
+        chain
+    }
+
+    // As on regtest: `invalidateblock` of block 6 (rewinding to block 5), then a competing chain
+    // mined on top of block 5.
+    #[test]
+    fn test_rewind() {
+        let mut headers = HeaderList::empty();
+        let chain = make_chain(Sha256dHash::default(), (0..10).collect(), |_| 0);
+        let entries = headers.order(chain.clone());
+        headers.apply(entries);
+
+        headers.rewind(&chain[5].bitcoin_hash());
+        assert_eq!(headers.len(), 6);
+        assert_eq!(*headers.tip(), chain[5].bitcoin_hash());
+        for header in &chain[6..] {
+            assert!(headers.header_by_hash(&header.bitcoin_hash()).is_none());
+            assert!(!headers.heights.contains_key(&header.bitcoin_hash()));
+        }
+        assert_eq!(headers.heights.len(), headers.len());
+
+        let fork = make_chain(chain[5].bitcoin_hash(), (100..102).collect(), |_| 0);
+        let entries = headers.order(fork.clone());
+        assert_eq!(entries[0].height(), 6);
+        headers.apply(entries);
+        assert_eq!(headers.len(), 8);
+        assert_eq!(*headers.tip(), fork[1].bitcoin_hash());
+        for (height, header) in chain[..6].iter().chain(fork.iter()).enumerate() {
+            let entry = headers.header_by_hash(&header.bitcoin_hash()).unwrap();
+            assert_eq!(entry.height(), height);
+        }
+        assert_eq!(headers.heights.len(), headers.len());
+    }
+
+    #[test]
+    fn test_locator_heights() {
+        assert_eq!(locator_heights(0), vec![0]);
+        assert_eq!(locator_heights(5), vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(
+            locator_heights(100),
+            vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 89, 87, 83, 75, 59, 27, 0]
+        );
+        // no duplicate heights, even when the step overshoots genesis
+        assert_eq!(
+            locator_heights(12),
+            vec![12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut headers = HeaderList::empty();
+        assert!(headers.locator().is_empty());
+
+        let chain = make_chain(Sha256dHash::default(), (0..1000).collect(), |_| 0);
+        let entries = headers.order(chain.clone());
+        headers.apply(entries);
+
+        let locator = headers.locator();
+        assert_eq!(locator.len(), 12 + 8 + 1); // the 12 last blocks, 8 growing steps and genesis
+        assert_eq!(locator.first(), Some(&chain[999].bitcoin_hash()));
+        assert_eq!(locator.last(), Some(&chain[0].bitcoin_hash()));
+        let expected: Vec<Sha256dHash> = locator_heights(999)
+            .into_iter()
+            .map(|height| chain[height].bitcoin_hash())
+            .collect();
+        assert_eq!(locator, expected);
+    }
+
+    #[test]
+    fn test_reorg() {
+        let mut headers = HeaderList::empty();
+        let chain = make_chain(Sha256dHash::default(), (0..10).collect(), |_| 0);
+        let entries = headers.order(chain.clone());
+        headers.apply(entries);
+        let confirmations = |headers: &HeaderList, height, header: &BlockHeader| {
+            headers
+                .confirmations(height, &header.bitcoin_hash())
+                .map(|(_, confirmations)| confirmations)
+        };
+        assert_eq!(confirmations(&headers, 9, &chain[9]), Some(1));
+        assert_eq!(confirmations(&headers, 7, &chain[7]), Some(3));
+        assert_eq!(confirmations(&headers, 7, &chain[6]), None);
+
+        // blocks 6..10 are replaced by 3 others
+        let fork = make_chain(chain[5].bitcoin_hash(), (100..103).collect(), |_| 0);
+        let entries = headers.order(fork.clone());
+        assert_eq!(entries[0].height(), 6);
+        headers.apply(entries);
+        assert_eq!(headers.len(), 9);
+        assert_eq!(*headers.tip(), fork[2].bitcoin_hash());
+        for header in &chain[6..] {
+            assert!(headers.header_by_hash(&header.bitcoin_hash()).is_none());
+            assert_eq!(headers.height_by_hash(&header.bitcoin_hash()), None);
+        }
+        for (height, header) in chain[..6].iter().chain(fork.iter()).enumerate() {
+            let hash = header.bitcoin_hash();
+            assert_eq!(headers.height_by_hash(&hash), Some(height));
+            assert_eq!(headers.header_by_hash(&hash).unwrap().height(), height);
+            assert_eq!(*headers.header_by_height(height).unwrap().hash(), hash);
+        }
+        assert_eq!(headers.heights.len(), headers.len());
+        assert_eq!(confirmations(&headers, 7, &chain[7]), None);
+        assert_eq!(confirmations(&headers, 6, &fork[0]), Some(3));
+        assert_eq!(confirmations(&headers, 5, &chain[5]), Some(4));
+
+        headers.rewind(&chain[3].bitcoin_hash());
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.height_by_hash(&fork[0].bitcoin_hash()), None);
+        assert_eq!(headers.height_by_hash(&chain[3].bitcoin_hash()), Some(3));
+        assert_eq!(headers.heights.len(), headers.len());
+        assert_eq!(confirmations(&headers, 3, &chain[3]), Some(1));
+        assert_eq!(confirmations(&headers, 5, &chain[5]), None); // beyond the tip
+    }
+
+    #[test]
+    fn test_header_by_time() {
+        let mut headers = HeaderList::empty();
+        assert!(headers.header_by_time(1000).is_none());
+
+        // block 3's timestamp goes backwards
+        let times = [100, 200, 300, 250, 400, 500];
+        let chain = make_chain(Sha256dHash::default(), (0..6).collect(), |nonce| {
+            times[nonce as usize]
+        });
+        let entries = headers.order(chain);
+        headers.apply(entries);
+
+        let mtps: Vec<u32> = (0..6)
+            .map(|height| headers.median_time_past(height).unwrap())
+            .collect();
+        assert_eq!(mtps, vec![100, 200, 200, 250, 250, 300]);
+        assert_eq!(headers.median_time_past(6), None);
+
+        let height_at = |time| headers.header_by_time(time).map(|entry| entry.height());
+        assert_eq!(height_at(99), None); // before genesis
+        assert_eq!(height_at(100), Some(0));
+        assert_eq!(height_at(199), Some(0));
+        assert_eq!(height_at(200), Some(2));
+        assert_eq!(height_at(260), Some(4)); // block 4's own timestamp is later
+        assert_eq!(height_at(1000), Some(5));
+    }
 }
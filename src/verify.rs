@@ -0,0 +1,479 @@
+use bincode;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+use hex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use daemon::Daemon;
+use index::{RawTxRow, TxRow};
+use metrics::{Counter, CounterVec, MetricOpts, Metrics};
+use store::ReadStore;
+use util::{spawn_thread, BlockMeta, FullHash};
+
+use errors::*;
+
+const PROGRESS_INTERVAL: usize = 10_000; // # of verified blocks between progress logs
+const SAMPLES_QUEUE: usize = 10; // # of sampled answers waiting to be cross-checked (more are skipped)
+const SAMPLE_MAX_ITEMS: usize = 100; // # of (most recent) history entries and outputs cross-checked
+const CHECK_INTERVAL_MS: u64 = 1000; // between cross-checks, to spare the daemon
+const RECHECK_DELAY_MS: u64 = 5000; // before re-checking a mismatch
+
+// The best chain's blockhashes (from the genesis block), walking the headers back from the last
+// indexed block. A gap (or a corrupted header) stops the walk, so only the blocks above it are
+// returned.
+fn indexed_chain(store: &ReadStore, problems: &mut Vec<String>) -> Vec<Sha256dHash> {
+    let null_hash = Sha256dHash::default();
+    let mut blockhash: Sha256dHash = match store.get(b"L").map(|value| deserialize(&value)) {
+        Some(Ok(blockhash)) => blockhash,
+        Some(Err(_)) => {
+            problems.push("corrupted last indexed block ('L' row)".to_owned());
+            return vec![];
+        }
+        None if store.scan_limit(b"B", 1).is_empty() => return vec![], // nothing indexed yet
+        None => {
+            problems.push("missing last indexed block ('L' row)".to_owned());
+            return vec![];
+        }
+    };
+    let mut chain = vec![];
+    while blockhash != null_hash {
+        let value = match store.get(&[b"B", &blockhash[..]].concat()) {
+            Some(value) => value,
+            None => {
+                problems.push(format!(
+                    "missing header of block {} ({} blocks below the tip): the chain has a gap",
+                    blockhash,
+                    chain.len()
+                ));
+                break;
+            }
+        };
+        let header: BlockHeader = match deserialize(&value) {
+            Ok(ref header) if header.bitcoin_hash() == blockhash => header.clone(),
+            _ => {
+                problems.push(format!("corrupted header of block {}", blockhash));
+                break;
+            }
+        };
+        chain.push(blockhash);
+        blockhash = header.prev_blockhash;
+    }
+    chain.reverse();
+    chain
+}
+
+// The rows of the extended DB: the block's metadata and txids, and its transactions' confirmation
+// and raw transaction rows.
+fn verify_block(store: &ReadStore, blockhash: &Sha256dHash, problems: &mut Vec<String>) {
+    match store.get(&[b"M", &blockhash[..]].concat()) {
+        Some(value) => {
+            if bincode::deserialize::<BlockMeta>(&value).is_err() {
+                problems.push(format!("corrupted metadata of block {}", blockhash));
+            }
+        }
+        None => problems.push(format!("missing metadata of block {}", blockhash)),
+    }
+    let txids: Vec<Sha256dHash> = match store.get(&[b"X", &blockhash[..]].concat()) {
+        Some(value) => match bincode::deserialize(&value) {
+            Ok(txids) => txids,
+            Err(_) => {
+                problems.push(format!("corrupted txids of block {}", blockhash));
+                return;
+            }
+        },
+        None => {
+            problems.push(format!("missing txids of block {}", blockhash));
+            return;
+        }
+    };
+    for txid in &txids {
+        match store.get(&TxRow::filter_full(txid)) {
+            Some(value) => {
+                if bincode::deserialize::<(u32, Sha256dHash)>(&value).is_err() {
+                    problems.push(format!("corrupted tx {} (block {})", txid, blockhash));
+                }
+            }
+            None => problems.push(format!("missing tx {} (block {})", txid, blockhash)),
+        }
+        if store.get(&RawTxRow::filter_full(txid)).is_none() {
+            problems.push(format!("missing raw tx {} (block {})", txid, blockhash));
+        }
+    }
+}
+
+/// Checks the index, returning the problems found (none if it's sound): that the headers link the
+/// last indexed block back to the genesis block and, with the extended DB, that each of these
+/// blocks has all its rows. Nothing is fixed, and it must not run while the index is updated.
+pub fn verify_index(store: &ReadStore, extended_db: bool) -> Vec<String> {
+    let mut problems = vec![];
+    let chain = indexed_chain(store, &mut problems);
+    info!("verifying {} indexed blocks", chain.len());
+    if extended_db {
+        for (i, blockhash) in chain.iter().enumerate() {
+            verify_block(store, blockhash, &mut problems);
+            if (i + 1) % PROGRESS_INTERVAL == 0 {
+                info!(
+                    "verified {}/{} blocks ({} problems found)",
+                    i + 1,
+                    chain.len(),
+                    problems.len()
+                );
+            }
+        }
+    }
+    problems
+}
+
+/// An index answer about a script, to cross-check against the daemon: its most recent confirmed
+/// transactions and unspent outputs.
+pub struct Sample {
+    script_hash: FullHash,
+    tip: Sha256dHash,                 // the indexed tip the answer was derived at
+    history: Vec<(u32, Sha256dHash)>, // (height, txid), ordered
+    unspent: Vec<(Sha256dHash, usize, u64)>, // (txid, output index, value), confirmed
+}
+
+impl Sample {
+    pub fn new(
+        script_hash: FullHash,
+        tip: Sha256dHash,
+        history: &[(u32, Sha256dHash)],
+        unspent: &[(Sha256dHash, usize, u64)],
+    ) -> Sample {
+        Sample {
+            script_hash,
+            tip,
+            history: history[history.len().saturating_sub(SAMPLE_MAX_ITEMS)..].to_vec(),
+            unspent: unspent[unspent.len().saturating_sub(SAMPLE_MAX_ITEMS)..].to_vec(),
+        }
+    }
+}
+
+// How the sample differs from the daemon's answer (nothing if it doesn't): each transaction must
+// be in the daemon's block at its height, and each output in the daemon's UTXO set (ignoring its
+// mempool, as the sample does) with the same value. Only the sample's entries are checked: finding
+// the ones missing from the index would need scanning the whole chain.
+fn mismatches<B, T>(sample: &Sample, mut block_txids: B, txouts: T) -> Result<Vec<String>>
+where
+    B: FnMut(u32) -> Result<Vec<Sha256dHash>>,
+    T: FnOnce(&[(Sha256dHash, usize)]) -> Result<Vec<Option<u64>>>,
+{
+    let mut problems = vec![];
+    let mut blocks: HashMap<u32, Vec<Sha256dHash>> = HashMap::new();
+    for (height, txid) in &sample.history {
+        if !blocks.contains_key(height) {
+            let txids = block_txids(*height)?;
+            blocks.insert(*height, txids);
+        }
+        if !blocks[height].contains(txid) {
+            problems.push(format!(
+                "tx {} isn't in the daemon's block at height {}",
+                txid, height
+            ));
+        }
+    }
+    if !sample.unspent.is_empty() {
+        let outpoints: Vec<(Sha256dHash, usize)> = sample
+            .unspent
+            .iter()
+            .map(|(txid, vout, _)| (*txid, *vout))
+            .collect();
+        for ((txid, vout, value), txout) in sample.unspent.iter().zip(txouts(&outpoints)?) {
+            match txout {
+                None => problems.push(format!(
+                    "output {}:{} isn't in the daemon's UTXO set",
+                    txid, vout
+                )),
+                Some(daemon_value) if daemon_value != *value => problems.push(format!(
+                    "output {}:{} has value {} for the daemon, not {}",
+                    txid, vout, daemon_value, value
+                )),
+                Some(_) => (),
+            }
+        }
+    }
+    Ok(problems)
+}
+
+// Returns None if the mismatches may be a race with a new block or a reorg: the daemon's tip moved
+// away from the sample's. A mismatch is re-checked once, so the daemon settles meanwhile.
+fn check_sample(daemon: &Daemon, sample: &Sample) -> Result<Option<Vec<String>>> {
+    let check = || {
+        mismatches(
+            sample,
+            |height| daemon.getblocktxids(&daemon.getblockhash(height as usize)?),
+            |outpoints| daemon.gettxouts(outpoints),
+        )
+    };
+    let mut problems = check()?;
+    if !problems.is_empty() {
+        thread::sleep(Duration::from_millis(RECHECK_DELAY_MS));
+        problems = check()?;
+    }
+    if !problems.is_empty() && daemon.getbestblockhash()? != sample.tip {
+        return Ok(None);
+    }
+    Ok(Some(problems))
+}
+
+/// Cross-checks one in `sampling` of the index answers against the daemon
+/// (--verification-sampling), in a background thread: the queries are neither delayed nor
+/// altered, and the samples arriving while the daemon is busy with the previous ones are skipped.
+pub struct Verifier {
+    sampling: usize,
+    queries: AtomicUsize,
+    samples: Mutex<SyncSender<Sample>>,
+    skipped: CounterVec,
+}
+
+impl Verifier {
+    pub fn start(daemon: Daemon, sampling: usize, metrics: &Metrics) -> Verifier {
+        let (tx, rx) = sync_channel(SAMPLES_QUEUE);
+        let skipped = metrics.counter_vec(
+            MetricOpts::new(
+                "index_verification_skipped",
+                "# of sampled answers not cross-checked (daemon busy, tip moved, or RPC error)",
+            ),
+            &["reason"],
+        );
+        let verified = metrics.counter(MetricOpts::new(
+            "index_verified",
+            "# of sampled answers matching the daemon's",
+        ));
+        let mismatch = metrics.counter(MetricOpts::new(
+            "index_mismatch",
+            "# of sampled answers differing from the daemon's",
+        ));
+        let thread_skipped = skipped.clone();
+        spawn_thread("verifier", move || {
+            run(daemon, rx, verified, mismatch, thread_skipped)
+        });
+        Verifier {
+            sampling,
+            queries: AtomicUsize::new(0),
+            samples: Mutex::new(tx),
+            skipped,
+        }
+    }
+
+    /// Whether this answer is to be cross-checked.
+    pub fn sampled(&self) -> bool {
+        self.queries.fetch_add(1, Ordering::Relaxed) % self.sampling == 0
+    }
+
+    pub fn check(&self, sample: Sample) {
+        if self.samples.lock().unwrap().try_send(sample).is_err() {
+            self.skipped.with_label_values(&["busy"]).inc();
+        }
+    }
+}
+
+fn run(
+    daemon: Daemon,
+    samples: Receiver<Sample>,
+    verified: Counter,
+    mismatch: Counter,
+    skipped: CounterVec,
+) {
+    for sample in samples.iter() {
+        match check_sample(&daemon, &sample) {
+            Ok(Some(ref problems)) if problems.is_empty() => verified.inc(),
+            Ok(Some(problems)) => {
+                mismatch.inc();
+                warn!(
+                    "index mismatch for script {} (at tip {}): {}",
+                    hex::encode(&sample.script_hash),
+                    sample.tip,
+                    problems.join("; ")
+                );
+            }
+            Ok(None) => skipped.with_label_values(&["tip"]).inc(),
+            Err(e) => {
+                skipped.with_label_values(&["error"]).inc();
+                debug!("failed to cross-check index answer: {}", e.display_chain());
+            }
+        }
+        thread::sleep(Duration::from_millis(CHECK_INTERVAL_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use errors::*;
+    use index::{index_block, last_indexed_block, TxRow, TxoRow};
+    use std::fs;
+    use store::{DBOptions, DBStore, ReadStore, Row, WriteStore};
+    use testutil::{make_block, make_tx, temp_path};
+    use verify::{mismatches, verify_index, Sample, SAMPLE_MAX_ITEMS};
+
+    #[test]
+    fn test_snapshot_and_verify() {
+        let dir = temp_path("verify");
+        let copy = temp_path("snapshot");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        assert!(verify_index(&store, true).is_empty()); // nothing indexed yet
+
+        let coinbase = make_tx(&[(Sha256dHash::default(), 0)], &[1000]);
+        let spending = make_tx(&[(coinbase.txid(), 0)], &[900]);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase]);
+        let block1 = make_block(block0.bitcoin_hash(), 0, vec![spending.clone()]);
+        for (height, block) in vec![&block0, &block1].into_iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+        }
+        store.write(vec![last_indexed_block(&block1.bitcoin_hash())]);
+        assert!(verify_index(&store, true).is_empty());
+
+        let (count, size) = store.snapshot(&copy).unwrap();
+        assert!(count > 0 && size > 0);
+        assert!(store.snapshot(&copy).is_err()); // already exists
+        let snapshot = DBStore::open(&copy, false, &DBOptions::default());
+        assert_eq!(snapshot.scan(b"").len(), count);
+        assert!(verify_index(&snapshot, true).is_empty());
+
+        // a missing transaction is only found with the extended DB's rows
+        snapshot.delete(vec![TxRow::filter_full(&spending.txid())]);
+        let problems = verify_index(&snapshot, true);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(&format!("{}", spending.txid())));
+        assert!(verify_index(&snapshot, false).is_empty());
+
+        // a corrupted header breaks the chain
+        snapshot.write(vec![Row {
+            key: [b"B", &block0.bitcoin_hash()[..]].concat(),
+            value: vec![0; 80],
+        }]);
+        let problems = verify_index(&snapshot, false);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(&format!("{}", block0.bitcoin_hash())));
+
+        // the original is left untouched
+        assert!(verify_index(&store, true).is_empty());
+        drop(store);
+        drop(snapshot);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&copy).unwrap();
+    }
+
+    // The index's answer for the given transactions and outputs, as Query derives it.
+    fn index_sample(
+        store: &ReadStore,
+        txids: &[Sha256dHash],
+        outpoints: &[(Sha256dHash, usize)],
+    ) -> Sample {
+        let history: Vec<(u32, Sha256dHash)> = txids
+            .iter()
+            .map(|txid| {
+                let key = TxRow::filter_full(txid);
+                let value = store.get(&key).unwrap();
+                (TxRow::from_row(&Row { key, value }).height, *txid)
+            })
+            .collect();
+        let unspent: Vec<(Sha256dHash, usize, u64)> = outpoints
+            .iter()
+            .map(|(txid, vout)| {
+                let key = TxoRow::filter(txid, *vout as u32);
+                let value = store.get(&key).unwrap();
+                let row = TxoRow::from_row(&Row { key, value });
+                (*txid, *vout, row.txout.value)
+            })
+            .collect();
+        Sample::new([0; 32], Sha256dHash::default(), &history, &unspent)
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let dir = temp_path("mismatches");
+        let store = DBStore::open(&dir, false, &DBOptions::default());
+        let coinbase = make_tx(&[(Sha256dHash::default(), 0)], &[1000]);
+        let spending = make_tx(&[(coinbase.txid(), 0)], &[900]);
+        let block0 = make_block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let block1 = make_block(block0.bitcoin_hash(), 0, vec![spending.clone()]);
+        let blocks = vec![&block0, &block1];
+        for (height, block) in blocks.iter().enumerate() {
+            store.write(index_block(
+                block,
+                height as u32,
+                true,
+                false,
+                Network::Regtest,
+            ));
+        }
+        // the daemon's answers: its blocks, and its UTXO set (only the spending tx's output)
+        let block_txids = |height: u32| -> Result<Vec<Sha256dHash>> {
+            Ok(blocks[height as usize]
+                .txdata
+                .iter()
+                .map(|txn| txn.txid())
+                .collect())
+        };
+        let txouts = |outpoints: &[(Sha256dHash, usize)]| -> Result<Vec<Option<u64>>> {
+            Ok(outpoints
+                .iter()
+                .map(|outpoint| {
+                    if *outpoint == (spending.txid(), 0) {
+                        Some(900)
+                    } else {
+                        None
+                    }
+                })
+                .collect())
+        };
+        let txids = vec![coinbase.txid(), spending.txid()];
+        let outpoints = vec![(spending.txid(), 0)];
+        let sample = index_sample(&store, &txids, &outpoints);
+        assert_eq!(sample.history, vec![(0, txids[0]), (1, txids[1])]);
+        assert!(mismatches(&sample, block_txids, txouts).unwrap().is_empty());
+
+        // a confirmation row with the wrong height
+        store.write(vec![TxRow::new(
+            &spending.txid(),
+            0,
+            &block0.bitcoin_hash(),
+        )
+        .to_row()]);
+        let sample = index_sample(&store, &txids, &outpoints);
+        let problems = mismatches(&sample, block_txids, txouts).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(&format!("{} isn't in", spending.txid())));
+
+        // an output with the wrong value, and one that isn't unspent
+        let mut txout = spending.output[0].clone();
+        txout.value = 800;
+        store.write(vec![TxoRow::new(&spending.txid(), 0, &txout).to_row()]);
+        let outpoints = vec![(coinbase.txid(), 0), (spending.txid(), 0)];
+        let sample = index_sample(&store, &txids, &outpoints);
+        let problems = mismatches(&sample, block_txids, txouts).unwrap();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[1].contains(&format!("{}:0 isn't in", coinbase.txid())));
+        assert!(problems[2].contains("value 900 for the daemon, not 800"));
+
+        // the daemon failing
+        let failing = |_: u32| -> Result<Vec<Sha256dHash>> { bail!("unavailable") };
+        assert!(mismatches(&sample, failing, txouts).is_err());
+
+        // only the most recent entries are checked
+        let history: Vec<(u32, Sha256dHash)> = (0..SAMPLE_MAX_ITEMS as u32 + 10)
+            .map(|height| (height, Sha256dHash::default()))
+            .collect();
+        let sample = Sample::new([0; 32], Sha256dHash::default(), &history, &[]);
+        assert_eq!(sample.history.len(), SAMPLE_MAX_ITEMS);
+        assert_eq!(sample.history[0].0, 10);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use chan;
+use hex;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use metrics::{CounterVec, MetricOpts, Metrics};
+use util::spawn_thread;
+
+use errors::*;
+
+// A minimal ZMTP 3.0 SUB client (enough for the daemon's `-zmqpub*` notifications), so that no
+// native ZeroMQ library is needed.
+
+const RECONNECT_DELAY: u64 = 5; // seconds
+const MAX_FRAME_SIZE: u64 = 32 << 20; // bytes (rawtx bodies are the largest)
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+fn greeting() -> Vec<u8> {
+    let mut greeting = vec![0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0x7f]; // signature
+    greeting.extend_from_slice(&[3, 0]); // version
+    let mut mechanism = b"NULL".to_vec();
+    mechanism.resize(20, 0);
+    greeting.extend(mechanism);
+    greeting.push(0); // as-server
+    greeting.extend_from_slice(&[0; 31]); // filler
+    greeting
+}
+
+fn frame(flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![];
+    if body.len() > 255 {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn ready_command() -> Vec<u8> {
+    let mut body = vec![5];
+    body.extend_from_slice(b"READY");
+    body.push(11);
+    body.extend_from_slice(b"Socket-Type");
+    body.extend_from_slice(&3u32.to_be_bytes());
+    body.extend_from_slice(b"SUB");
+    frame(FLAG_COMMAND, &body)
+}
+
+// Returns the frame's flags and body.
+fn read_frame(stream: &mut Read) -> Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8];
+    stream
+        .read_exact(&mut flags)
+        .chain_err(|| "failed to read frame")?;
+    let size = if flags[0] & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read frame")?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read frame")?;
+        u64::from(size[0])
+    };
+    if size > MAX_FRAME_SIZE {
+        bail!("too large frame: {} bytes", size);
+    }
+    let mut body = vec![0u8; size as usize];
+    stream
+        .read_exact(&mut body)
+        .chain_err(|| "failed to read frame")?;
+    Ok((flags[0], body))
+}
+
+// Returns the frames of the next message (skipping commands).
+fn read_message(stream: &mut Read) -> Result<Vec<Vec<u8>>> {
+    let mut frames = vec![];
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue; // e.g. a PING
+        }
+        frames.push(body);
+        if flags & FLAG_MORE == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+fn handshake(stream: &mut TcpStream, topics: &[&str]) -> Result<()> {
+    stream
+        .write_all(&greeting())
+        .chain_err(|| "failed to send greeting")?;
+    let mut peer = [0u8; 64];
+    stream
+        .read_exact(&mut peer)
+        .chain_err(|| "failed to read greeting")?;
+    if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 {
+        bail!("unsupported ZMTP greeting: {:?}", &peer[..12]);
+    }
+    if &peer[12..16] != b"NULL" {
+        bail!("unsupported ZMTP security mechanism (only NULL is)");
+    }
+    stream
+        .write_all(&ready_command())
+        .chain_err(|| "failed to send READY")?;
+    let (flags, body) = read_frame(stream)?;
+    if flags & FLAG_COMMAND == 0 || !body.starts_with(b"\x05READY") {
+        bail!("unexpected ZMTP handshake reply: {:?}", body);
+    }
+    for topic in topics {
+        let mut subscription = vec![1]; // subscribe (a message, as of ZMTP 3.0)
+        subscription.extend_from_slice(topic.as_bytes());
+        stream
+            .write_all(&frame(0, &subscription))
+            .chain_err(|| "failed to subscribe")?;
+    }
+    Ok(())
+}
+
+// Receives the notifications until the connection fails.
+fn run(
+    addr: SocketAddr,
+    topic: &str,
+    sender: &chan::Sender<()>,
+    connected: &AtomicBool,
+    notifications: &CounterVec,
+) -> Result<()> {
+    let mut stream =
+        TcpStream::connect(addr).chain_err(|| format!("failed to connect to {}", addr))?;
+    handshake(&mut stream, &[topic])?;
+    info!("subscribed to {} notifications from {}", topic, addr);
+    connected.store(true, Ordering::SeqCst);
+    loop {
+        let frames = read_message(&mut stream)?;
+        if frames.first().map(|t| &t[..]) != Some(topic.as_bytes()) {
+            continue;
+        }
+        if topic == "hashblock" {
+            if let Some(hash) = frames.get(1) {
+                debug!("new block {}", hex::encode(hash)); // sent in RPC (display) order
+            }
+        }
+        notifications.with_label_values(&[topic]).inc();
+        // at most one wake-up is pending, so a burst of notifications (e.g. while the index is
+        // catching up) is handled by a single update
+        chan_select! {
+            default => {},
+            sender.send(()) => {},
+        }
+    }
+}
+
+/// Wakes up the main loop on the daemon's ZMQ notifications (of new blocks or transactions).
+pub struct Notifier {
+    sender: chan::Sender<()>,
+    receiver: chan::Receiver<()>,
+    subscribers: Vec<Arc<AtomicBool>>, // whether each one is connected
+    notifications: CounterVec,
+}
+
+impl Notifier {
+    pub fn new(metrics: &Metrics) -> Notifier {
+        let (sender, receiver) = chan::sync(1);
+        Notifier {
+            sender,
+            receiver,
+            subscribers: vec![],
+            notifications: metrics.counter_vec(
+                MetricOpts::new("zmq_notifications", "# of ZMQ notifications received"),
+                &["topic"],
+            ),
+        }
+    }
+
+    /// Subscribes to the `topic` notifications (e.g. `hashblock` or `rawtx`) published at
+    /// `addr`, reconnecting after failures (e.g. a daemon restart).
+    pub fn subscribe(&mut self, addr: SocketAddr, topic: &'static str) {
+        let sender = self.sender.clone();
+        let connected = Arc::new(AtomicBool::new(false));
+        let notifications = self.notifications.clone();
+        self.subscribers.push(connected.clone());
+        spawn_thread("zmq", move || loop {
+            if let Err(err) = run(addr, topic, &sender, &connected, &notifications) {
+                warn!("ZMQ {} subscriber failed: {}", topic, err.display_chain());
+            }
+            connected.store(false, Ordering::SeqCst);
+            thread::sleep(Duration::from_secs(RECONNECT_DELAY));
+        });
+    }
+
+    /// Notified (once, until received) when any of the subscribed notifications is published.
+    pub fn receiver(&self) -> &chan::Receiver<()> {
+        &self.receiver
+    }
+
+    /// Whether there are subscribers, all connected (otherwise the daemon has to be polled).
+    pub fn is_connected(&self) -> bool {
+        !self.subscribers.is_empty()
+            && self
+                .subscribers
+                .iter()
+                .all(|connected| connected.load(Ordering::SeqCst))
+    }
+}
+
+/// Parses a ZMQ endpoint (`tcp://<addr>:<port>`, as used by the daemon's `-zmqpub*` options).
+pub fn parse_endpoint(endpoint: &str) -> Result<SocketAddr> {
+    if !endpoint.starts_with("tcp://") {
+        bail!("only tcp:// ZMQ endpoints are supported: {}", endpoint);
+    }
+    endpoint["tcp://".len()..]
+        .parse()
+        .chain_err(|| format!("invalid ZMQ endpoint: {}", endpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::util::hash::Sha256dHash;
+    use chan;
+    use metrics::Metrics;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use zmq::{frame, greeting, parse_endpoint, read_frame, read_message, Notifier, FLAG_MORE};
+
+    #[test]
+    fn test_frames() {
+        let short = frame(FLAG_MORE, b"hashblock");
+        assert_eq!(&short[..2], &[FLAG_MORE, 9]);
+        let body = vec![0x42; 1000];
+        let long = frame(0, &body);
+        assert_eq!(long.len(), 1 + 8 + 1000);
+
+        let mut stream: Vec<u8> = vec![];
+        stream.extend(short);
+        stream.extend(long);
+        let mut reader = &stream[..];
+        let frames = read_message(&mut reader).unwrap();
+        assert_eq!(frames, vec![b"hashblock".to_vec(), body]);
+        assert!(read_frame(&mut reader).is_err()); // no more frames
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            parse_endpoint("tcp://127.0.0.1:28332").unwrap(),
+            "127.0.0.1:28332".parse().unwrap()
+        );
+        assert!(parse_endpoint("127.0.0.1:28332").is_err());
+        assert!(parse_endpoint("ipc:///tmp/viacoind").is_err());
+        assert!(parse_endpoint("tcp://localhost").is_err());
+    }
+
+    #[test]
+    fn test_notifier() {
+        // a mock daemon, publishing a block notification once subscribed to
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, disconnect) = chan::sync::<()>(0);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut peer = [0u8; 64];
+            stream.read_exact(&mut peer).unwrap();
+            assert_eq!(&peer[12..16], b"NULL");
+            stream.write_all(&greeting()).unwrap();
+            let (_, ready) = read_frame(&mut stream).unwrap();
+            assert!(ready.ends_with(b"Socket-Type\x00\x00\x00\x03SUB"));
+            let mut ready = vec![5];
+            ready.extend_from_slice(b"READY");
+            stream.write_all(&frame(0x04, &ready)).unwrap();
+            let (_, subscription) = read_frame(&mut stream).unwrap();
+            assert_eq!(subscription, b"\x01hashblock".to_vec());
+
+            let hash = Sha256dHash::from_data(b"block");
+            stream.write_all(&frame(FLAG_MORE, b"hashblock")).unwrap();
+            stream.write_all(&frame(FLAG_MORE, &hash[..])).unwrap();
+            stream.write_all(&frame(0, &[0, 0, 0, 0])).unwrap(); // sequence
+            disconnect.recv(); // closed by the test
+        });
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let mut notifier = Notifier::new(&metrics);
+        assert!(!notifier.is_connected()); // nothing subscribed to
+        notifier.subscribe(addr, "hashblock");
+        let receiver = notifier.receiver();
+        let timeout = chan::after(Duration::from_secs(10));
+        chan_select! {
+            receiver.recv() => {},
+            timeout.recv() => panic!("no notification received"),
+        }
+        assert!(notifier.is_connected());
+
+        drop(done);
+        for _ in 0..100 {
+            if !notifier.is_connected() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("the disconnection wasn't noticed");
+    }
+}